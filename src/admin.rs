@@ -1,22 +1,25 @@
 use crate::pool::BanReason;
 use crate::server::ServerParameters;
+use crate::stats::address::AddressStats;
 use crate::stats::pool::PoolStats;
+use crate::stats::user::UserStats;
 use bytes::{Buf, BufMut, BytesMut};
 use log::{error, info, trace};
 use nix::sys::signal::{self, Signal};
 use nix::unistd::Pid;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 /// Admin database.
 use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::time::Instant;
 
-use crate::config::{get_config, reload_config, VERSION};
+use crate::config::{get_config, reload_config, Role, VERSION};
 use crate::errors::Error;
 use crate::messages::*;
 use crate::pool::ClientServerMap;
-use crate::pool::{get_all_pools, get_pool};
-use crate::stats::{get_client_stats, get_server_stats, ClientState, ServerState};
+use crate::pool::{get_all_pools, get_pool, ConnectionPool, PoolIdentifier};
+use crate::stats::{get_client_stats, get_server_stats, ClientState, ClientStats, ServerState};
 
 pub fn generate_server_parameters_for_admin() -> ServerParameters {
     let mut server_parameters = ServerParameters::new();
@@ -73,6 +76,10 @@ where
             trace!("RELOAD");
             reload(stream, client_server_map).await
         }
+        "RECONNECT" => {
+            trace!("RECONNECT");
+            reconnect(stream, query_parts, client_server_map).await
+        }
         "SET" => {
             trace!("SET");
             ignore_set(stream).await
@@ -81,9 +88,24 @@ where
             trace!("PAUSE");
             pause(stream, query_parts).await
         }
+        "DRAIN" => {
+            trace!("DRAIN");
+            drain(stream, query_parts, true).await
+        }
+        "UNDRAIN" => {
+            trace!("UNDRAIN");
+            drain(stream, query_parts, false).await
+        }
         "RESUME" => {
             trace!("RESUME");
-            resume(stream, query_parts).await
+
+            // RESUME <host> <port> puts a drained server back in rotation.
+            match query_parts.as_slice() {
+                [_, _, port] if port.parse::<u16>().is_ok() => {
+                    drain(stream, query_parts, false).await
+                }
+                _ => resume(stream, query_parts).await,
+            }
         }
         "SHUTDOWN" => {
             trace!("SHUTDOWN");
@@ -119,9 +141,28 @@ where
                 trace!("SHOW POOLS");
                 show_pools(stream).await
             }
+            "POOLS_EXTENDED" => {
+                trace!("SHOW POOLS_EXTENDED");
+                show_pools_extended(stream).await
+            }
+            "PREWARM" => {
+                trace!("SHOW PREWARM");
+                show_prewarm(stream).await
+            }
+            "SHARD"
+                if query_parts
+                    .get(2)
+                    .is_some_and(|part| part.eq_ignore_ascii_case("HEALTH")) =>
+            {
+                trace!("SHOW SHARD HEALTH");
+                show_shard_health(stream).await
+            }
             "CLIENTS" => {
                 trace!("SHOW CLIENTS");
-                show_clients(stream).await
+                match ClientFilter::parse(&query_parts[2..]) {
+                    Ok(filter) => show_clients(stream, filter).await,
+                    Err(err) => error_response(stream, &err).await,
+                }
             }
             "SERVERS" => {
                 trace!("SHOW SERVERS");
@@ -129,7 +170,11 @@ where
             }
             "STATS" => {
                 trace!("SHOW STATS");
-                show_stats(stream).await
+                if get_config().general.pgbouncer_compatible_stats {
+                    show_stats_pgbouncer(stream).await
+                } else {
+                    show_stats(stream).await
+                }
             }
             "VERSION" => {
                 trace!("SHOW VERSION");
@@ -139,6 +184,14 @@ where
                 trace!("SHOW USERS");
                 show_users(stream).await
             }
+            "USER_STATS" => {
+                trace!("SHOW USER_STATS");
+                show_user_stats(stream).await
+            }
+            "SLOW_CHECKOUTS" => {
+                trace!("SHOW SLOW_CHECKOUTS");
+                show_slow_checkouts(stream).await
+            }
             _ => error_response(stream, "Unsupported SHOW query against the admin database").await,
         },
         _ => error_response(stream, "Unsupported query against the admin database").await,
@@ -265,6 +318,27 @@ where
     write_all_half(stream, &res).await
 }
 
+/// Show client counts for each user across all pools.
+async fn show_user_stats<T>(stream: &mut T) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let user_lookup = UserStats::construct_user_lookup();
+    let mut res = BytesMut::new();
+    res.put(row_description(&UserStats::generate_header()));
+    user_lookup.iter().for_each(|(_username, user_stats)| {
+        res.put(data_row(&user_stats.generate_row()));
+    });
+    res.put(command_complete("SHOW"));
+
+    // ReadyForQuery
+    res.put_u8(b'Z');
+    res.put_i32(5);
+    res.put_u8(b'I');
+
+    write_all_half(stream, &res).await
+}
+
 /// Show utilization of connection pools for each shard and replicas.
 async fn show_pools<T>(stream: &mut T) -> Result<(), Error>
 where
@@ -296,18 +370,29 @@ where
     let detail_msg = [
         "",
         "SHOW HELP|CONFIG|DATABASES|POOLS|CLIENTS|SERVERS|USERS|VERSION",
+        "SHOW CLIENTS [<state>] [<db>]",
+        "SHOW CLIENTS WHERE <state|database|user|application_name> = '<value>' [AND ...]",
         // "SHOW PEERS|PEER_POOLS", // missing PEERS|PEER_POOLS
         // "SHOW FDS|SOCKETS|ACTIVE_SOCKETS|LISTS|MEM|STATE", // missing FDS|SOCKETS|ACTIVE_SOCKETS|MEM|STATE
         "SHOW LISTS",
+        "SHOW POOLS_EXTENDED",
+        "SHOW PREWARM",
+        "SHOW SHARD HEALTH",
         // "SHOW DNS_HOSTS|DNS_ZONES", // missing DNS_HOSTS|DNS_ZONES
         "SHOW STATS", // missing STATS_TOTALS|STATS_AVERAGES|TOTALS
+        "SHOW USER_STATS",
+        "SHOW SLOW_CHECKOUTS",
+        "BAN <host> <seconds> [FORCE_PRIMARY]",
         "SET key = arg",
         "RELOAD",
         "PAUSE [<db>, <user>]",
         "RESUME [<db>, <user>]",
+        "DRAIN <host> <port>",
+        "RESUME <host> <port>",
+        "UNDRAIN <host> <port>",
         // "DISABLE <db>", // missing
         // "ENABLE <db>", // missing
-        // "RECONNECT [<db>]", missing
+        "RECONNECT <db> <user>",
         // "KILL <db>",
         // "SUSPEND",
         "SHUTDOWN",
@@ -412,7 +497,13 @@ where
 {
     let host = match tokens.get(1) {
         Some(host) => host,
-        None => return error_response(stream, "usage: BAN hostname duration_seconds").await,
+        None => {
+            return error_response(
+                stream,
+                "usage: BAN hostname duration_seconds [FORCE_PRIMARY]",
+            )
+            .await
+        }
     };
 
     let duration_seconds = match tokens.get(2) {
@@ -422,13 +513,32 @@ where
                 return error_response(stream, "duration_seconds must be an integer").await;
             }
         },
-        None => return error_response(stream, "usage: BAN hostname duration_seconds").await,
+        None => {
+            return error_response(
+                stream,
+                "usage: BAN hostname duration_seconds [FORCE_PRIMARY]",
+            )
+            .await
+        }
     };
 
     if duration_seconds <= 0 {
         return error_response(stream, "duration_seconds must be >= 0").await;
     }
 
+    // Taking a primary out of rotation stops all writes, so it has to be asked for explicitly.
+    let force_primary = match tokens.get(3) {
+        Some(confirmation) if confirmation.eq_ignore_ascii_case("FORCE_PRIMARY") => true,
+        Some(_) => {
+            return error_response(
+                stream,
+                "usage: BAN hostname duration_seconds [FORCE_PRIMARY]",
+            )
+            .await
+        }
+        None => false,
+    };
+
     let columns = vec![
         ("db", DataType::Text),
         ("user", DataType::Text),
@@ -440,6 +550,11 @@ where
 
     for (id, pool) in get_all_pools().iter() {
         for address in pool.get_addresses_from_host(host) {
+            if address.role == Role::Primary && !force_primary {
+                info!("Not banning primary {:?} without FORCE_PRIMARY", address);
+                continue;
+            }
+
             if !pool.is_banned(&address) {
                 pool.ban(&address, BanReason::AdminBan(duration_seconds), None);
                 res.put(data_row(&vec![
@@ -505,6 +620,258 @@ where
     write_all_half(stream, &res).await
 }
 
+/// Take a server out of rotation without banning it, or put it back
+/// with RESUME or its alias UNDRAIN. Unlike a ban, it never expires.
+async fn drain<T>(stream: &mut T, tokens: Vec<&str>, out_of_rotation: bool) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let command = tokens[0].to_ascii_uppercase();
+
+    let (host, port) = match (tokens.get(1), tokens.get(2)) {
+        (Some(host), Some(port)) => match port.parse::<u16>() {
+            Ok(port) => (host, port),
+            Err(_) => return error_response(stream, "port must be an integer").await,
+        },
+        _ => return error_response(stream, &format!("usage: {} hostname port", command)).await,
+    };
+
+    let columns = vec![
+        ("db", DataType::Text),
+        ("user", DataType::Text),
+        ("role", DataType::Text),
+        ("host", DataType::Text),
+        ("port", DataType::Text),
+    ];
+    let mut res = BytesMut::new();
+    res.put(row_description(&columns));
+
+    for (id, pool) in get_all_pools().iter() {
+        for address in pool.get_addresses_from_host(host) {
+            if address.port != port || address.is_out_of_rotation() == out_of_rotation {
+                continue;
+            }
+
+            address.set_out_of_rotation(out_of_rotation);
+            res.put(data_row(&vec![
+                id.db.clone(),
+                id.user.clone(),
+                address.role.to_string(),
+                address.host,
+                address.port.to_string(),
+            ]));
+        }
+    }
+
+    res.put(command_complete(&command));
+
+    // ReadyForQuery
+    res.put_u8(b'Z');
+    res.put_i32(5);
+    res.put_u8(b'I');
+
+    write_all_half(stream, &res).await
+}
+
+/// Shows the health of every shard, one row per pool and shard.
+async fn show_shard_health<T>(stream: &mut T) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let columns = vec![
+        ("database", DataType::Text),
+        ("user", DataType::Text),
+        ("shard", DataType::Numeric),
+        ("primary", DataType::Text),
+        ("replicas", DataType::Numeric),
+        ("healthy_replicas", DataType::Numeric),
+        ("banned_replicas", DataType::Numeric),
+    ];
+
+    let mut res = BytesMut::new();
+    res.put(row_description(&columns));
+
+    for (id, pool) in get_all_pools().iter() {
+        for shard in 0..pool.shards() {
+            let health = pool.shard_health(shard);
+
+            res.put(data_row(&vec![
+                id.db.clone(),
+                id.user.clone(),
+                health.shard.to_string(),
+                health.primary.to_string(),
+                health.replicas.to_string(),
+                health.healthy_replicas.to_string(),
+                health.banned_replicas.to_string(),
+            ]));
+        }
+    }
+
+    res.put(command_complete("SHOW"));
+
+    // ReadyForQuery
+    res.put_u8(b'Z');
+    res.put_i32(5);
+    res.put_u8(b'I');
+
+    write_all_half(stream, &res).await
+}
+
+/// Show the connections of every server in every pool, busy or idle, and whether it's banned.
+async fn show_pools_extended<T>(stream: &mut T) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let columns = vec![
+        ("database", DataType::Text),
+        ("user", DataType::Text),
+        ("shard", DataType::Numeric),
+        ("role", DataType::Text),
+        ("host", DataType::Text),
+        ("port", DataType::Numeric),
+        ("connections", DataType::Numeric),
+        ("idle_connections", DataType::Numeric),
+        ("busy_connections", DataType::Numeric),
+        ("banned", DataType::Int4),
+        ("replica_lag_ms", DataType::Numeric),
+        ("lagging", DataType::Int4),
+        ("checkout_errors", DataType::Numeric),
+        ("bans", DataType::Numeric),
+        ("last_healthcheck_success", DataType::Text),
+        ("last_healthcheck_failure", DataType::Text),
+    ];
+
+    let mut res = BytesMut::new();
+    res.put(row_description(&columns));
+
+    for (id, pool) in get_all_pools().iter() {
+        for shard in 0..pool.shards() {
+            for server in 0..pool.servers(shard) {
+                let address = pool.address(shard, server);
+                let state = pool.pool_state(shard, server);
+
+                res.put(data_row(&vec![
+                    id.db.clone(),
+                    id.user.clone(),
+                    address.shard.to_string(),
+                    address.role.to_string(),
+                    address.host.clone(),
+                    address.port.to_string(),
+                    state.connections.to_string(),
+                    state.idle_connections.to_string(),
+                    pool.busy_connection_count(address).to_string(),
+                    (pool.is_banned(address) as u8).to_string(),
+                    address.stats.replica_lag().to_string(),
+                    (pool.is_lagging(address) as u8).to_string(),
+                    address.stats.checkout_errors().to_string(),
+                    address.stats.bans().to_string(),
+                    address
+                        .stats
+                        .last_healthcheck_success()
+                        .map(|at| at.to_string())
+                        .unwrap_or_default(),
+                    address
+                        .stats
+                        .last_healthcheck_failure()
+                        .map(|at| at.to_string())
+                        .unwrap_or_default(),
+                ]));
+            }
+        }
+    }
+
+    res.put(command_complete("SHOW"));
+
+    // ReadyForQuery
+    res.put_u8(b'Z');
+    res.put_i32(5);
+    res.put_u8(b'I');
+
+    write_all_half(stream, &res).await
+}
+
+/// Show how many of the connections each pool opens at startup are prewarmed.
+async fn show_prewarm<T>(stream: &mut T) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let columns = vec![
+        ("database", DataType::Text),
+        ("user", DataType::Text),
+        ("prewarmed_connections", DataType::Numeric),
+        ("startup_connections", DataType::Numeric),
+        ("prewarmed", DataType::Int4),
+    ];
+
+    let mut res = BytesMut::new();
+    res.put(row_description(&columns));
+
+    for (id, pool) in get_all_pools().iter() {
+        let (prewarmed, target) = pool.prewarm_progress();
+
+        res.put(data_row(&vec![
+            id.db.clone(),
+            id.user.clone(),
+            prewarmed.to_string(),
+            target.to_string(),
+            ((prewarmed >= target) as u8).to_string(),
+        ]));
+    }
+
+    res.put(command_complete("SHOW"));
+
+    // ReadyForQuery
+    res.put_u8(b'Z');
+    res.put_i32(5);
+    res.put_u8(b'I');
+
+    write_all_half(stream, &res).await
+}
+
+/// Show the slowest recent checkouts of each pool.
+async fn show_slow_checkouts<T>(stream: &mut T) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let columns = vec![
+        ("database", DataType::Text),
+        ("user", DataType::Text),
+        ("address", DataType::Text),
+        ("checkout_time_us", DataType::Numeric),
+        ("health_check", DataType::Int4),
+        ("candidates_tried", DataType::Numeric),
+        ("age_seconds", DataType::Numeric),
+        ("queue_time_us", DataType::Numeric),
+    ];
+
+    let mut res = BytesMut::new();
+    res.put(row_description(&columns));
+
+    for (id, pool) in get_all_pools().iter() {
+        for checkout in pool.slow_checkouts.get() {
+            res.put(data_row(&vec![
+                id.db.clone(),
+                id.user.clone(),
+                checkout.address,
+                checkout.checkout_time.to_string(),
+                (checkout.health_check as u8).to_string(),
+                checkout.candidates_tried.to_string(),
+                checkout.at.elapsed().as_secs().to_string(),
+                checkout.queue_time.to_string(),
+            ]));
+        }
+    }
+
+    res.put(command_complete("SHOW"));
+
+    // ReadyForQuery
+    res.put_u8(b'Z');
+    res.put_i32(5);
+    res.put_u8(b'I');
+
+    write_all_half(stream, &res).await
+}
+
 /// Shows all the bans
 async fn show_bans<T>(stream: &mut T) -> Result<(), Error>
 where
@@ -519,6 +886,9 @@ where
         ("ban_time", DataType::Text),
         ("ban_duration_seconds", DataType::Text),
         ("ban_remaining_seconds", DataType::Text),
+        ("port", DataType::Text),
+        ("shard", DataType::Text),
+        ("expires_at", DataType::Text),
     ];
     let mut res = BytesMut::new();
     res.put(row_description(&columns));
@@ -531,10 +901,7 @@ where
 
     for (id, pool) in get_all_pools().iter() {
         for (address, (ban_reason, ban_time)) in pool.get_bans().iter() {
-            let ban_duration = match ban_reason {
-                BanReason::AdminBan(duration) => *duration,
-                _ => pool.settings.ban_time,
-            };
+            let ban_duration = pool.ban_duration(ban_reason);
             let remaining = ban_duration - (now - ban_time.timestamp());
             if remaining <= 0 {
                 continue;
@@ -548,6 +915,9 @@ where
                 ban_time.to_string(),
                 ban_duration.to_string(),
                 remaining.to_string(),
+                address.port.to_string(),
+                address.shard.to_string(),
+                (*ban_time + chrono::Duration::seconds(ban_duration)).to_string(),
             ]));
         }
     }
@@ -585,6 +955,77 @@ where
     write_all_half(stream, &res).await
 }
 
+/// Re-create the pool for one database/user pair with fresh server connections,
+/// leaving all other pools alone.
+async fn reconnect<T>(
+    stream: &mut T,
+    tokens: Vec<&str>,
+    client_server_map: ClientServerMap,
+) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    // Accept both `RECONNECT db user` and `RECONNECT db, user`.
+    let parts: Vec<&str> = tokens
+        .iter()
+        .skip(1)
+        .flat_map(|token| token.split(','))
+        .filter(|part| !part.is_empty())
+        .collect();
+
+    if parts.len() != 2 {
+        return error_response(stream, "usage: RECONNECT <db> <user>").await;
+    }
+
+    let database = parts[0];
+    let user = parts[1];
+
+    if get_pool(database, user).is_none() {
+        return error_response(
+            stream,
+            &format!(
+                "No pool configured for database: {}, user: {}",
+                database, user
+            ),
+        )
+        .await;
+    }
+
+    info!(
+        "Reconnecting pool {{ database: {}, user: {} }}",
+        database, user
+    );
+
+    let identifier = PoolIdentifier::new(database, user);
+
+    if let Err(err) = ConnectionPool::recreate(client_server_map, &identifier).await {
+        error!(
+            "Could not reconnect pool {{ database: {}, user: {} }}: {}",
+            database, user, err
+        );
+
+        return error_response(
+            stream,
+            &format!(
+                "Could not reconnect pool for database: {}, user: {}: {}",
+                database, user, err
+            ),
+        )
+        .await;
+    }
+
+    let mut res = BytesMut::new();
+
+    res.put(command_complete("RECONNECT"));
+
+    // ReadyForQuery
+    res.put_u8(b'Z');
+    res.put_i32(5);
+    res.put_u8(b'I');
+
+    write_all_half(stream, &res).await
+}
+
 /// Shows current configuration.
 async fn show_config<T>(stream: &mut T) -> Result<(), Error>
 where
@@ -656,6 +1097,7 @@ where
         ("avg_xact_time", DataType::Numeric),
         ("avg_query_time", DataType::Numeric),
         ("avg_wait_time", DataType::Numeric),
+        ("total_statement_timeouts", DataType::Numeric),
     ];
 
     let mut res = BytesMut::new();
@@ -685,8 +1127,195 @@ where
     write_all_half(stream, &res).await
 }
 
+/// SHOW STATS with PgBouncer's columns and units: one row per database,
+/// adding up the servers of all its pools.
+async fn show_stats_pgbouncer<T>(stream: &mut T) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let columns = vec![
+        ("database", DataType::Text),
+        ("total_xact_count", DataType::Numeric),
+        ("total_query_count", DataType::Numeric),
+        ("total_received", DataType::Numeric),
+        ("total_sent", DataType::Numeric),
+        ("total_xact_time", DataType::Numeric),
+        ("total_query_time", DataType::Numeric),
+        ("total_wait_time", DataType::Numeric),
+        ("avg_xact_count", DataType::Numeric),
+        ("avg_query_count", DataType::Numeric),
+        ("avg_recv", DataType::Numeric),
+        ("avg_sent", DataType::Numeric),
+        ("avg_xact_time", DataType::Numeric),
+        ("avg_query_time", DataType::Numeric),
+        ("avg_wait_time", DataType::Numeric),
+    ];
+
+    let mut databases: BTreeMap<String, Vec<Arc<AddressStats>>> = BTreeMap::new();
+
+    for (user_pool, pool) in get_all_pools() {
+        for shard in 0..pool.shards() {
+            for server in 0..pool.servers(shard) {
+                let address = pool.address(shard, server);
+
+                databases
+                    .entry(user_pool.db.clone())
+                    .or_default()
+                    .push(address.stats.clone());
+            }
+        }
+    }
+
+    let mut res = BytesMut::new();
+    res.put(row_description(&columns));
+
+    for (database, addresses) in databases {
+        let servers: Vec<HashMap<String, u64>> = addresses
+            .iter()
+            .map(|stats| stats.as_ref().clone().into_iter().collect())
+            .collect();
+        let sum = |key: &str| servers.iter().map(|stats| stats[key]).sum::<u64>();
+
+        // Per transaction and per query averages, weighted by the traffic of each server.
+        let weighted = |avg: &str, count: &str| match sum(count) {
+            0 => 0,
+            total => {
+                servers
+                    .iter()
+                    .map(|stats| stats[avg] * stats[count])
+                    .sum::<u64>()
+                    / total
+            }
+        };
+
+        // PgBouncer reports times in microseconds, we track query times in milliseconds.
+        let row = vec![
+            database,
+            sum("total_xact_count").to_string(),
+            sum("total_query_count").to_string(),
+            sum("total_received").to_string(),
+            sum("total_sent").to_string(),
+            (sum("total_xact_time") * 1_000).to_string(),
+            (sum("total_query_time") * 1_000).to_string(),
+            sum("total_wait_time").to_string(),
+            sum("avg_xact_count").to_string(),
+            sum("avg_query_count").to_string(),
+            sum("avg_recv").to_string(),
+            sum("avg_sent").to_string(),
+            (weighted("avg_xact_time", "avg_xact_count") * 1_000).to_string(),
+            (weighted("avg_query_time", "avg_query_count") * 1_000).to_string(),
+            average_wait_time(&addresses).to_string(),
+        ];
+
+        res.put(data_row(&row));
+    }
+
+    res.put(command_complete("SHOW"));
+
+    // ReadyForQuery
+    res.put_u8(b'Z');
+    res.put_i32(5);
+    res.put_u8(b'I');
+
+    write_all_half(stream, &res).await
+}
+
+/// Average time (us) a client waited for a connection to any of these servers
+/// in the last stats period, like PgBouncer's avg_wait_time.
+fn average_wait_time(servers: &[Arc<AddressStats>]) -> u64 {
+    let (wait_time, waits) = servers
+        .iter()
+        .map(|stats| stats.period_waits())
+        .fold((0, 0), |(time, count), (t, c)| (time + t, count + c));
+
+    wait_time.checked_div(waits).unwrap_or(0)
+}
+
+/// Which clients SHOW CLIENTS lists, e.g. `SHOW CLIENTS waiting sharded_db`
+/// or `SHOW CLIENTS WHERE state = 'waiting' AND database = 'sharded_db'`.
+#[derive(Debug, Default)]
+struct ClientFilter {
+    state: Option<String>,
+    database: Option<String>,
+    user: Option<String>,
+    application_name: Option<String>,
+}
+
+impl ClientFilter {
+    const STATES: [&'static str; 3] = ["idle", "active", "waiting"];
+
+    fn parse(args: &[&str]) -> Result<ClientFilter, String> {
+        let mut filter = ClientFilter::default();
+
+        match args.first() {
+            None => (),
+
+            Some(first) if first.eq_ignore_ascii_case("WHERE") => {
+                for condition in args[1..].split(|arg| arg.eq_ignore_ascii_case("AND")) {
+                    let condition = condition.join(" ");
+                    let (column, value) = match condition.split_once('=') {
+                        Some((column, value)) => (column.trim(), value.trim().trim_matches('\'')),
+                        None => {
+                            return Err(format!("Invalid SHOW CLIENTS condition: {}", condition))
+                        }
+                    };
+
+                    let field = match column.to_ascii_lowercase().as_str() {
+                        "state" => &mut filter.state,
+                        "database" => &mut filter.database,
+                        "user" => &mut filter.user,
+                        "application_name" => &mut filter.application_name,
+                        _ => return Err(format!("Can't filter clients by {}", column)),
+                    };
+
+                    *field = Some(value.to_string());
+                }
+            }
+
+            // Positional form, the state and/or the database.
+            Some(_) => {
+                for arg in args {
+                    let lowercase = arg.to_ascii_lowercase();
+                    if Self::STATES.contains(&lowercase.as_str()) {
+                        filter.state = Some(lowercase);
+                    } else {
+                        filter.database = Some(arg.to_string());
+                    }
+                }
+            }
+        }
+
+        if let Some(state) = &mut filter.state {
+            *state = state.to_ascii_lowercase();
+            if !Self::STATES.contains(&state.as_str()) {
+                return Err(format!(
+                    "Unknown client state {}, expected one of: {}",
+                    state,
+                    Self::STATES.join(", ")
+                ));
+            }
+        }
+
+        Ok(filter)
+    }
+
+    fn matches(&self, client: &ClientStats) -> bool {
+        let matches = |filter: &Option<String>, value: String| match filter {
+            Some(filter) => *filter == value,
+            None => true,
+        };
+
+        matches(
+            &self.state,
+            client.state.load(Ordering::Relaxed).to_string(),
+        ) && matches(&self.database, client.pool_name())
+            && matches(&self.user, client.username())
+            && matches(&self.application_name, client.application_name())
+    }
+}
+
 /// Show currently connected clients
-async fn show_clients<T>(stream: &mut T) -> Result<(), Error>
+async fn show_clients<T>(stream: &mut T, filter: ClientFilter) -> Result<(), Error>
 where
     T: tokio::io::AsyncWrite + std::marker::Unpin,
 {
@@ -709,6 +1338,10 @@ where
     res.put(row_description(&columns));
 
     for (_, client) in new_map {
+        if !filter.matches(&client) {
+            continue;
+        }
+
         let max_wait = client.max_wait_time.load(Ordering::Relaxed);
         let row = vec![
             format!("{:#010X}", client.client_id()),
@@ -761,6 +1394,7 @@ where
         ("prepare_cache_miss", DataType::Numeric),
         ("prepare_cache_eviction", DataType::Numeric),
         ("prepare_cache_size", DataType::Numeric),
+        ("zone", DataType::Text),
     ];
 
     let new_map = get_server_stats();
@@ -800,6 +1434,7 @@ where
                 .prepared_cache_size
                 .load(Ordering::Relaxed)
                 .to_string(),
+            server.zone().unwrap_or_default(),
         ];
 
         res.put(data_row(&row));
@@ -997,3 +1632,78 @@ where
 
     write_all_half(stream, &res).await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_average_wait_time() {
+        assert_eq!(average_wait_time(&[]), 0);
+
+        // One slow checkout on a quiet server, nine fast ones on a busy one.
+        let quiet = Arc::new(AddressStats::default());
+        quiet.wait_time_add(1_000);
+
+        let busy = Arc::new(AddressStats::default());
+        for _ in 0..9 {
+            busy.wait_time_add(100);
+        }
+
+        let servers = [quiet, busy];
+        for stats in &servers {
+            stats.update_averages();
+            stats.reset_current_counts();
+        }
+
+        assert_eq!(servers[0].period_waits(), (1_000, 1));
+        assert_eq!(servers[1].period_waits(), (900, 9));
+        assert_eq!(average_wait_time(&servers), 190);
+    }
+
+    #[test]
+    fn test_client_filter() {
+        let client = ClientStats::new(1, "worker", "app", "sharded_db", Instant::now());
+        client.state.store(ClientState::Waiting, Ordering::Relaxed);
+
+        // Everyone without filters.
+        assert!(ClientFilter::parse(&[]).unwrap().matches(&client));
+
+        // Positional form, in any order and case.
+        assert!(ClientFilter::parse(&["WAITING", "sharded_db"])
+            .unwrap()
+            .matches(&client));
+        assert!(ClientFilter::parse(&["sharded_db", "waiting"])
+            .unwrap()
+            .matches(&client));
+        assert!(!ClientFilter::parse(&["active"]).unwrap().matches(&client));
+        assert!(!ClientFilter::parse(&["other_db"]).unwrap().matches(&client));
+
+        // WHERE form, split on spaces like the admin query is.
+        let filter = ClientFilter::parse(&[
+            "where",
+            "state",
+            "=",
+            "'waiting'",
+            "AND",
+            "user='app'",
+            "and",
+            "application_name",
+            "=",
+            "'worker'",
+        ])
+        .unwrap();
+        assert_eq!(filter.state.as_deref(), Some("waiting"));
+        assert_eq!(filter.user.as_deref(), Some("app"));
+        assert!(filter.matches(&client));
+        assert!(
+            !ClientFilter::parse(&["WHERE", "database", "=", "'other_db'"])
+                .unwrap()
+                .matches(&client)
+        );
+
+        assert!(ClientFilter::parse(&["WHERE", "state", "=", "'stuck'"]).is_err());
+        assert!(ClientFilter::parse(&["WHERE", "address", "=", "'1'"]).is_err());
+        assert!(ClientFilter::parse(&["WHERE", "state"]).is_err());
+    }
+}
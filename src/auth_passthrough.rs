@@ -1,6 +1,8 @@
+use crate::config::AuthMethod;
 use crate::errors::Error;
 use crate::pool::ConnectionPool;
 use crate::server::Server;
+use crate::tls::ServerTls;
 use log::debug;
 
 #[derive(Clone, Debug)]
@@ -8,6 +10,8 @@ pub struct AuthPassthrough {
     password: String,
     query: String,
     user: String,
+    min_server_auth_method: AuthMethod,
+    server_tls: Option<ServerTls>,
 }
 
 impl AuthPassthrough {
@@ -17,18 +21,35 @@ impl AuthPassthrough {
             password: password.to_string(),
             query: query.to_string(),
             user: user.to_string(),
+            min_server_auth_method: crate::config::Pool::default_min_server_auth_method(),
+            server_tls: None,
         }
     }
 
+    /// Refuse servers asking for a weaker authentication method than `min_server_auth_method`.
+    pub fn with_min_server_auth_method(mut self, min_server_auth_method: AuthMethod) -> Self {
+        self.min_server_auth_method = min_server_auth_method;
+        self
+    }
+
+    /// Connect to the server with the pool's TLS settings, `None` if `server_tls` is off.
+    pub fn with_server_tls(mut self, server_tls: Option<ServerTls>) -> Self {
+        self.server_tls = server_tls;
+        self
+    }
+
     /// Returns an AuthPassthrough given the pool configuration.
     /// If any of required values is not set, None is returned.
     pub fn from_pool_config(pool_config: &crate::config::Pool) -> Option<Self> {
         if pool_config.is_auth_query_configured() {
-            return Some(AuthPassthrough::new(
-                pool_config.auth_query.as_ref().unwrap(),
-                pool_config.auth_query_user.as_ref().unwrap(),
-                pool_config.auth_query_password.as_ref().unwrap(),
-            ));
+            return Some(
+                AuthPassthrough::new(
+                    pool_config.auth_query.as_ref().unwrap(),
+                    pool_config.auth_query_user.as_ref().unwrap(),
+                    pool_config.auth_query_password.as_ref().unwrap(),
+                )
+                .with_min_server_auth_method(pool_config.min_server_auth_method),
+            );
         }
 
         None
@@ -41,10 +62,12 @@ impl AuthPassthrough {
             auth_query: pool_settings.auth_query.clone(),
             auth_query_password: pool_settings.auth_query_password.clone(),
             auth_query_user: pool_settings.auth_query_user.clone(),
+            min_server_auth_method: pool_settings.min_server_auth_method,
             ..Default::default()
         };
 
         AuthPassthrough::from_pool_config(&pool_config)
+            .map(|apt| apt.with_server_tls(pool_settings.server_tls.clone()))
     }
 
     /// Connects to server and executes auth_query for the specified address.
@@ -81,6 +104,9 @@ impl AuthPassthrough {
             min_pool_size: None,
             connect_timeout: None,
             idle_timeout: None,
+            client_auth_mode: None,
+            max_client_connections: None,
+            default_role: None,
         };
 
         let user = &address.username;
@@ -89,7 +115,15 @@ impl AuthPassthrough {
 
         let auth_query = self.query.replace("$1", user);
 
-        match Server::exec_simple_query(address, &auth_user, &auth_query).await {
+        match Server::exec_simple_query(
+            address,
+            &auth_user,
+            &auth_query,
+            self.min_server_auth_method,
+            self.server_tls.as_ref(),
+        )
+        .await
+        {
             Ok(password_data) => {
                 if password_data.len() == 2 && password_data.first().unwrap() == user {
                     if let Some(stripped_hash) = password_data
@@ -0,0 +1,342 @@
+//! Push ban and unban events to an external sink, e.g. for alerting.
+use arc_swap::ArcSwapOption;
+use async_trait::async_trait;
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::client::conn::http1;
+use hyper::{Method, Request, Uri};
+use hyper_util::rt::TokioIo;
+use log::{error, info, warn};
+use once_cell::sync::Lazy;
+use serde_derive::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+use crate::config::{Address, Role};
+use crate::errors::Error;
+use crate::pool::BanReason;
+
+/// The running exporter, replaced when its settings change on reload.
+static SINK: Lazy<ArcSwapOption<Sink>> = Lazy::new(|| ArcSwapOption::from(None));
+
+/// Settings of the running exporter and the queue feeding it.
+struct Sink {
+    sink: String,
+    queue_size: usize,
+    events: Sender<BanEvent>,
+}
+
+/// Longest a sink or a collector gets to take a POST, connecting included.
+const POST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Events dropped because the exporter couldn't keep up.
+static DROPPED_BAN_EVENTS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BanEventKind {
+    Ban,
+    Unban,
+    UnbanAll,
+}
+
+/// A server got banned or unbanned.
+#[derive(Serialize, Debug, Clone)]
+pub struct BanEvent {
+    pub kind: BanEventKind,
+    pub timestamp: String,
+    pub pool_name: String,
+    pub username: String,
+    pub shard: usize,
+    /// Server name as in `SHOW DATABASES`, empty when all replicas of the shard are unbanned.
+    pub address: String,
+    pub host: String,
+    pub port: u16,
+    pub role: String,
+    pub reason: Option<String>,
+}
+
+impl BanEvent {
+    pub fn new(kind: BanEventKind, address: &Address, reason: Option<&BanReason>) -> BanEvent {
+        let (name, host, port, role) = match kind {
+            BanEventKind::UnbanAll => (String::new(), String::new(), 0, Role::Replica),
+            _ => (
+                address.name(),
+                address.host.clone(),
+                address.port,
+                address.role,
+            ),
+        };
+
+        BanEvent {
+            kind,
+            timestamp: chrono::offset::Utc::now().to_rfc3339(),
+            pool_name: address.pool_name.clone(),
+            username: address.username.clone(),
+            shard: address.shard,
+            address: name,
+            host,
+            port,
+            role: role.to_string(),
+            reason: reason.map(|reason| format!("{:?}", reason)),
+        }
+    }
+}
+
+/// Send the event to the sink, if any. Never waits, the event is dropped
+/// if the exporter is behind.
+pub fn emit(event: BanEvent) {
+    if let Some(sink) = SINK.load().as_ref() {
+        if sink.events.try_send(event).is_err() {
+            DROPPED_BAN_EVENTS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Number of events dropped because the queue was full.
+pub fn dropped() -> u64 {
+    DROPPED_BAN_EVENTS.load(Ordering::Relaxed)
+}
+
+/// Somewhere to send ban events to.
+#[async_trait]
+pub trait BanEventExporter: Send {
+    async fn export(&mut self, event: &BanEvent) -> Result<(), Error>;
+}
+
+/// Logs every event as a line of JSON.
+pub struct LogExporter;
+
+#[async_trait]
+impl BanEventExporter for LogExporter {
+    async fn export(&mut self, event: &BanEvent) -> Result<(), Error> {
+        match serde_json::to_string(event) {
+            Ok(json) => {
+                info!(target: "pgcat::ban_events", "{}", json);
+                Ok(())
+            }
+            Err(err) => Err(Error::ParseBytesError(err.to_string())),
+        }
+    }
+}
+
+/// POSTs every event as JSON to an HTTP endpoint.
+pub struct WebhookExporter {
+    uri: Uri,
+}
+
+#[async_trait]
+impl BanEventExporter for WebhookExporter {
+    async fn export(&mut self, event: &BanEvent) -> Result<(), Error> {
+        let body =
+            serde_json::to_string(event).map_err(|err| Error::ParseBytesError(err.to_string()))?;
+
+        post_json(&self.uri, body).await
+    }
+}
+
+/// POST a JSON body to an http:// endpoint, giving up after `POST_TIMEOUT`.
+pub(crate) async fn post_json(uri: &Uri, body: String) -> Result<(), Error> {
+    match tokio::time::timeout(POST_TIMEOUT, post(uri, body)).await {
+        Ok(result) => result,
+        Err(_) => Err(Error::SocketError(format!(
+            "{} timed out after {}ms",
+            uri,
+            POST_TIMEOUT.as_millis()
+        ))),
+    }
+}
+
+async fn post(uri: &Uri, body: String) -> Result<(), Error> {
+    let host = uri.host().unwrap_or("localhost");
+    let port = uri.port_u16().unwrap_or(80);
+
+    let stream = TcpStream::connect((host, port))
+        .await
+        .map_err(|err| Error::SocketError(err.to_string()))?;
+
+    let (mut sender, connection) = http1::handshake(TokioIo::new(stream))
+        .await
+        .map_err(|err| Error::SocketError(err.to_string()))?;
+
+    tokio::task::spawn(async move {
+        if let Err(err) = connection.await {
+            warn!("HTTP connection error: {:?}", err);
+        }
+    });
+
+    let path = uri
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(path)
+        .header("host", host)
+        .header("content-type", "application/json")
+        .body(Full::new(Bytes::from(body)))
+        .map_err(|err| Error::ClientError(err.to_string()))?;
+
+    let response = sender
+        .send_request(request)
+        .await
+        .map_err(|err| Error::SocketError(err.to_string()))?;
+
+    if !response.status().is_success() {
+        return Err(Error::SocketError(format!(
+            "{} returned {}",
+            uri,
+            response.status()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Build the exporter for the configured sink:
+/// `log` or an `http://` URL.
+pub fn exporter(sink: &str) -> Result<Box<dyn BanEventExporter>, Error> {
+    if sink == "log" {
+        return Ok(Box::new(LogExporter));
+    }
+
+    match sink.parse::<Uri>() {
+        Ok(uri) if uri.scheme_str() == Some("http") && uri.host().is_some() => {
+            Ok(Box::new(WebhookExporter { uri }))
+        }
+        _ => {
+            error!(
+                "Invalid ban_event_sink: {}, expected `log` or an http:// URL",
+                sink
+            );
+            Err(Error::BadConfig)
+        }
+    }
+}
+
+/// Start, restart or stop sending ban events to match the config.
+/// Nothing changes if the settings are the same as the running exporter's.
+/// The old exporter sends the events it has queued and stops.
+pub fn start(sink: Option<&str>, queue_size: usize) -> Result<(), Error> {
+    let sink = match sink {
+        Some(sink) => sink,
+        None => {
+            if SINK.swap(None).is_some() {
+                info!("Stopped sending ban events");
+            }
+            return Ok(());
+        }
+    };
+
+    if let Some(running) = SINK.load().as_ref() {
+        if running.sink == sink && running.queue_size == queue_size {
+            return Ok(());
+        }
+    }
+
+    let exporter = exporter(sink)?;
+    let (sender, receiver) = mpsc::channel(queue_size.max(1));
+
+    info!("Sending ban events to {}", sink);
+    tokio::task::spawn(export(receiver, exporter));
+
+    SINK.store(Some(Arc::new(Sink {
+        sink: sink.to_string(),
+        queue_size,
+        events: sender,
+    })));
+
+    Ok(())
+}
+
+async fn export(mut receiver: Receiver<BanEvent>, mut exporter: Box<dyn BanEventExporter>) {
+    while let Some(event) = receiver.recv().await {
+        if let Err(err) = exporter.export(&event).await {
+            warn!("Could not export ban event {:?}: {}", event, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_exporter() {
+        assert!(exporter("log").is_ok());
+        assert!(exporter("http://alerts.local:8080/pgcat").is_ok());
+        assert!(exporter("https://alerts.local/pgcat").is_err());
+        assert!(exporter("syslog").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_post_json_keeps_query() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0u8; 1024];
+            let len = stream.read(&mut request).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&request[..len]).to_string()
+        });
+
+        let uri = format!("http://127.0.0.1:{}/pgcat?token=abc", port)
+            .parse::<Uri>()
+            .unwrap();
+        post_json(&uri, "{}".to_string()).await.unwrap();
+
+        assert!(server
+            .await
+            .unwrap()
+            .starts_with("POST /pgcat?token=abc HTTP/1.1"));
+    }
+
+    #[tokio::test]
+    async fn test_start_on_reload() {
+        start(Some("log"), 10).unwrap();
+        let running = SINK.load_full().unwrap();
+
+        // Same settings, same exporter.
+        start(Some("log"), 10).unwrap();
+        assert!(Arc::ptr_eq(&running, &SINK.load_full().unwrap()));
+
+        // A new sink replaces it.
+        start(Some("http://alerts.local:8080/pgcat?token=abc"), 10).unwrap();
+        assert_eq!(
+            SINK.load().as_ref().unwrap().sink,
+            "http://alerts.local:8080/pgcat?token=abc"
+        );
+
+        // A bad sink keeps the running exporter.
+        assert!(start(Some("syslog"), 10).is_err());
+        assert!(SINK.load().is_some());
+
+        start(None, 10).unwrap();
+        assert!(SINK.load().is_none());
+    }
+
+    #[test]
+    fn test_ban_event_json() {
+        let event = BanEvent::new(
+            BanEventKind::Ban,
+            &Address::default(),
+            Some(&BanReason::FailedHealthCheck(1)),
+        );
+        let json = serde_json::to_value(&event).unwrap();
+
+        assert_eq!(json["kind"], "ban");
+        assert_eq!(json["address"], "pool_name_shard_0_replica_0");
+        assert_eq!(json["reason"], "FailedHealthCheck(1)");
+    }
+}
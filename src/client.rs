@@ -5,24 +5,30 @@ use bytes::{Buf, BufMut, BytesMut};
 use log::{debug, error, info, trace, warn};
 use once_cell::sync::Lazy;
 use std::collections::{HashMap, VecDeque};
+use std::mem;
 use std::sync::{atomic::AtomicUsize, Arc};
-use std::time::Instant;
-use tokio::io::{split, AsyncReadExt, BufReader, ReadHalf, WriteHalf};
+use std::time::{Duration, Instant};
+use tokio::io::{split, AsyncBufReadExt, AsyncReadExt, BufReader, ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
 use tokio::sync::broadcast::Receiver;
 use tokio::sync::mpsc::Sender;
 
 use crate::admin::{generate_server_parameters_for_admin, handle_admin};
 use crate::auth_passthrough::refetch_auth_hash;
-use crate::config::{get_config, get_idle_client_in_transaction_timeout, Address, PoolMode};
+use crate::config::{
+    get_config, get_idle_client_in_transaction_timeout, Address, ClientAuthMode, CopyHandling,
+    PauseClientBehavior, PoolMode,
+};
 use crate::constants::*;
 use crate::messages::*;
+use crate::otel;
 use crate::plugins::PluginOutput;
 use crate::pool::{get_pool, ClientServerMap, ConnectionPool};
 use crate::query_router::{Command, QueryRouter};
+use crate::scram::ScramServer;
 use crate::server::{Server, ServerParameters};
 use crate::stats::{ClientStats, ServerStats};
-use crate::tls::Tls;
+use crate::tls::{certificate_common_name, Tls};
 
 use tokio_rustls::server::TlsStream;
 
@@ -31,6 +37,9 @@ use tokio_rustls::server::TlsStream;
 pub static PREPARED_STATEMENT_COUNTER: Lazy<Arc<AtomicUsize>> =
     Lazy::new(|| Arc::new(AtomicUsize::new(0)));
 
+/// Error sent to clients when `copy_handling` is `reject`.
+const COPY_REJECTED: &str = "COPY is not allowed in transaction mode";
+
 /// Type of connection received from client.
 enum ClientConnectionType {
     Startup,
@@ -111,6 +120,9 @@ pub struct Client<S, T> {
 
     /// Buffered extended protocol data
     extended_protocol_data_buffer: VecDeque<ExtendedProtocolData>,
+
+    /// Timeout in milliseconds the next query asked for in a comment.
+    query_timeout: Option<u64>,
 }
 
 /// Client entrypoint.
@@ -196,6 +208,8 @@ pub async fn client_entrypoint(
                             client_server_map,
                             shutdown,
                             admin_only,
+                            None,
+                            None,
                         )
                         .await
                         {
@@ -250,6 +264,8 @@ pub async fn client_entrypoint(
                 client_server_map,
                 shutdown,
                 admin_only,
+                None,
+                None,
             )
             .await
             {
@@ -326,6 +342,12 @@ where
         Err(_) => return Err(Error::ClientBadStartup),
     };
 
+    // Don't let the client make us allocate an arbitrary amount of memory.
+    // The length includes itself and the protocol code.
+    if len < 8 || len as usize > get_config().general.max_startup_packet_size {
+        return Err(Error::ClientBadStartup);
+    }
+
     // Get the rest of the message.
     let mut startup = vec![0u8; len as usize - 4];
     match stream.read_exact(&mut startup).await {
@@ -380,10 +402,18 @@ pub async fn startup_tls(
         // TLS negotiation failed.
         Err(err) => {
             error!("TLS negotiation failed: {:?}", err);
-            return Err(Error::TlsError);
+            return Err(Error::TlsError(err.to_string()));
         }
     };
 
+    // The certificate, if the client sent one, was verified during the handshake.
+    let client_cert_common_name = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(certificate_common_name);
+
     // TLS negotiation successful.
     // Continue with regular startup using encrypted connection.
     match get_startup::<TlsStream<TcpStream>>(&mut stream).await {
@@ -400,6 +430,8 @@ pub async fn startup_tls(
                 client_server_map,
                 shutdown,
                 admin_only,
+                client_cert_common_name,
+                Some(tls.server_end_point.clone()),
             )
             .await
         }
@@ -413,6 +445,54 @@ pub async fn startup_tls(
     }
 }
 
+/// Authenticate the client with SCRAM-SHA-256, or SCRAM-SHA-256-PLUS over TLS.
+async fn scram_exchange<S, T>(
+    read: &mut S,
+    write: &mut T,
+    password: &str,
+    tls_server_end_point: Option<Vec<u8>>,
+) -> Result<(), Error>
+where
+    S: tokio::io::AsyncRead + std::marker::Unpin,
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let mut scram = ScramServer::new(password, tls_server_end_point);
+
+    sasl_mechanisms(write, &scram.mechanisms()).await?;
+
+    // SASLInitialResponse
+    let mut message = read_message(read).await?;
+    if message[0] != b'p' {
+        return Err(Error::ProtocolSyncError(format!(
+            "Expected p, got {}",
+            message[0] as char
+        )));
+    }
+    message.advance(mem::size_of::<u8>() + mem::size_of::<i32>());
+
+    let mechanism = message.read_string()?;
+    let len = message.get_i32();
+    if len < 0 || len as usize != message.len() {
+        return Err(Error::AuthError("malformed SASLInitialResponse".into()));
+    }
+
+    let server_first = scram.server_first(&mechanism, &message)?;
+    sasl_data(write, SASL_CONTINUE, &server_first).await?;
+
+    // SASLResponse
+    let mut message = read_message(read).await?;
+    if message[0] != b'p' {
+        return Err(Error::ProtocolSyncError(format!(
+            "Expected p, got {}",
+            message[0] as char
+        )));
+    }
+    message.advance(mem::size_of::<u8>() + mem::size_of::<i32>());
+
+    let server_final = scram.server_final(&message)?;
+    sasl_data(write, SASL_FINAL, &server_final).await
+}
+
 impl<S, T> Client<S, T>
 where
     S: tokio::io::AsyncRead + std::marker::Unpin,
@@ -424,6 +504,7 @@ where
 
     /// Handle Postgres client startup after TLS negotiation is complete
     /// or over plain text.
+    #[allow(clippy::too_many_arguments)]
     pub async fn startup(
         mut read: S,
         mut write: T,
@@ -432,8 +513,10 @@ where
         client_server_map: ClientServerMap,
         shutdown: Receiver<()>,
         admin_only: bool,
+        client_cert_common_name: Option<String>, // Verified TLS client certificate.
+        tls_server_end_point: Option<Vec<u8>>,   // Channel binding data, TLS only.
     ) -> Result<Client<S, T>, Error> {
-        let parameters = parse_startup(bytes.clone())?;
+        let parameters = parse_startup(bytes.clone(), get_config().general.max_startup_parameters)?;
 
         // This parameter is mandatory by the protocol.
         let username = match parameters.get("user") {
@@ -457,12 +540,48 @@ where
 
         let client_identifier = ClientIdentifier::new(application_name, username, pool_name);
 
+        // Replication connections put the server into walsender mode,
+        // it can't be shared with other clients.
+        if is_replication_startup(&parameters) {
+            warn!(
+                "Rejecting replication connection from {}: {}",
+                addr, client_identifier
+            );
+            error_response_terminal(
+                &mut write,
+                "replication connections are not supported by the pooler",
+            )
+            .await?;
+            return Err(Error::ClientBadStartup);
+        }
+
         let admin = ["pgcat", "pgbouncer"]
             .iter()
             .filter(|db| *db == pool_name)
             .count()
             == 1;
 
+        // Turn away clients matching a deny connection rule, e.g. to shed load
+        // from a misbehaving application during an incident.
+        if !admin {
+            if let Some(rule) = get_config().connection_denied_by(pool_name, username, &parameters)
+            {
+                warn!(
+                    "Rejecting connection from {} by connection rule on {}: {}",
+                    addr, rule.matcher.parameter, client_identifier
+                );
+                error_response_with_code(
+                    &mut write,
+                    rule.message
+                        .as_deref()
+                        .unwrap_or("connection rejected by the pooler"),
+                    "08004", // sqlserver_rejected_establishment_of_sqlconnection
+                )
+                .await?;
+                return Err(Error::ClientBadStartup);
+            }
+        }
+
         // Kick any client that's not admin while we're in admin-only mode.
         if !admin && admin_only {
             debug!(
@@ -477,52 +596,104 @@ where
             return Err(Error::ShuttingDown);
         }
 
+        // The database the client asked for may be served by another pool.
+        let selected_pool_name = match admin {
+            true => pool_name.clone(),
+            false => get_config().select_pool(pool_name, username, &parameters),
+        };
+
+        if &selected_pool_name != pool_name {
+            debug!(
+                "Client asked for {}, using pool {}",
+                pool_name, selected_pool_name
+            );
+        }
+
+        let pool_name = &selected_pool_name;
+        let client_identifier = ClientIdentifier::new(application_name, username, pool_name);
+
         // Generate random backend ID and secret key
         let process_id: i32 = rand::random();
         let secret_key: i32 = rand::random();
 
-        // Perform MD5 authentication.
-        // TODO: Add SASL support.
-        let salt = md5_challenge(&mut write).await?;
-
-        let code = match read.read_u8().await {
-            Ok(p) => p,
-            Err(_) => {
-                return Err(Error::ClientSocketError(
-                    "password code".into(),
-                    client_identifier,
-                ))
-            }
+        let client_auth_mode = match get_pool(pool_name, username) {
+            Some(pool) if !admin => Some(pool.settings.client_auth_mode),
+            _ => None,
         };
 
-        // PasswordMessage
-        if code as char != 'p' {
-            return Err(Error::ProtocolSyncError(format!(
-                "Expected p, got {}",
-                code as char
-            )));
-        }
+        // Users authenticated by their TLS client certificate skip the password exchange.
+        let cert_auth = client_auth_mode == Some(ClientAuthMode::Cert);
 
-        let len = match read.read_i32().await {
-            Ok(len) => len,
-            Err(_) => {
-                return Err(Error::ClientSocketError(
-                    "password message length".into(),
-                    client_identifier,
-                ))
+        // SCRAM replaces the MD5 exchange below.
+        let scram_auth = client_auth_mode == Some(ClientAuthMode::Scram);
+
+        if cert_auth {
+            let error = match client_cert_common_name {
+                Some(ref common_name) if common_name == username => None,
+                Some(ref common_name) => Some(format!(
+                    "client certificate common name {:?} does not match user {:?}",
+                    common_name, username
+                )),
+                None => Some(format!("no client certificate for user {:?}", username)),
+            };
+
+            if let Some(error) = error {
+                error_response_with_code(
+                    &mut write, &error, "28000", // invalid_authorization_specification
+                )
+                .await?;
+
+                return Err(Error::AuthError(format!("{} {}", error, client_identifier)));
             }
-        };
+        }
 
-        let mut password_response = vec![0u8; (len - 4) as usize];
+        // Perform MD5 authentication.
+        let (salt, password_response) = if cert_auth || scram_auth {
+            ([0; 4], Vec::new())
+        } else {
+            let salt = md5_challenge(&mut write).await?;
 
-        match read.read_exact(&mut password_response).await {
-            Ok(_) => (),
-            Err(_) => {
-                return Err(Error::ClientSocketError(
-                    "password message".into(),
-                    client_identifier,
-                ))
+            let code = match read.read_u8().await {
+                Ok(p) => p,
+                Err(_) => {
+                    return Err(Error::ClientSocketError(
+                        "password code".into(),
+                        client_identifier,
+                    ))
+                }
+            };
+
+            // PasswordMessage
+            if code as char != 'p' {
+                return Err(Error::ProtocolSyncError(format!(
+                    "Expected p, got {}",
+                    code as char
+                )));
             }
+
+            let len = match read.read_i32().await {
+                Ok(len) => len,
+                Err(_) => {
+                    return Err(Error::ClientSocketError(
+                        "password message length".into(),
+                        client_identifier,
+                    ))
+                }
+            };
+
+            let mut password_response = vec![0u8; (len - 4) as usize];
+
+            match read.read_exact(&mut password_response).await {
+                Ok(_) => (),
+                Err(_) => {
+                    return Err(Error::ClientSocketError(
+                        "password message".into(),
+                        client_identifier,
+                    ))
+                }
+            };
+
+            (salt, password_response)
         };
 
         let mut prepared_statements_enabled = false;
@@ -554,14 +725,20 @@ where
             let pool = match get_pool(pool_name, username) {
                 Some(pool) => pool,
                 None => {
-                    error_response(
-                        &mut write,
-                        &format!(
-                            "No pool configured for database: {:?}, user: {:?}",
-                            pool_name, username
-                        ),
-                    )
-                    .await?;
+                    // Tell the client whether it got the database or the user wrong.
+                    let (message, code) = if get_config().pools.contains_key(pool_name) {
+                        (
+                            format!("no such user: {:?} in database: {:?}", username, pool_name),
+                            "28000", // invalid_authorization_specification
+                        )
+                    } else {
+                        (
+                            format!("no such database: {:?}", pool_name),
+                            "3D000", // invalid_catalog_name
+                        )
+                    };
+
+                    error_response_with_code(&mut write, &message, code).await?;
 
                     return Err(Error::ClientGeneralError(
                         "Invalid pool name".into(),
@@ -570,96 +747,134 @@ where
                 }
             };
 
-            // Obtain the hash to compare, we give preference to that written in cleartext in config
-            // if there is nothing set in cleartext and auth passthrough (auth_query) is configured, we use the hash obtained
-            // when the pool was created. If there is no hash there, we try to fetch it one more time.
-            let password_hash = if let Some(password) = &pool.settings.user.password {
-                Some(md5_hash_password(username, password, &salt))
-            } else {
-                if !get_config().is_auth_query_configured() {
-                    wrong_password(&mut write, username).await?;
-                    return Err(Error::ClientAuthImpossible(username.into()));
+            if scram_auth {
+                // Checked by the config validation.
+                let password = pool.settings.user.password.clone().unwrap_or_default();
+
+                match scram_exchange(&mut read, &mut write, &password, tls_server_end_point).await {
+                    Ok(()) => (),
+                    Err(Error::AuthError(error)) => {
+                        error_response_with_code(
+                            &mut write,
+                            &format!(
+                                "SCRAM authentication failed for user {:?}: {}",
+                                username, error
+                            ),
+                            "28P01", // invalid_password
+                        )
+                        .await?;
+
+                        return Err(Error::AuthError(format!("{} {}", error, client_identifier)));
+                    }
+                    Err(err) => return Err(err),
                 }
+            } else if !cert_auth {
+                // Obtain the hash to compare, we give preference to that written in cleartext in config
+                // if there is nothing set in cleartext and auth passthrough (auth_query) is configured, we use the hash obtained
+                // when the pool was created. If there is no hash there, we try to fetch it one more time.
+                let password_hash = if let Some(password) = &pool.settings.user.password {
+                    Some(md5_hash_password(username, password, &salt))
+                } else {
+                    if !get_config().is_auth_query_configured() {
+                        wrong_password(&mut write, username).await?;
+                        return Err(Error::ClientAuthImpossible(username.into()));
+                    }
 
-                let mut hash = (*pool.auth_hash.read()).clone();
+                    let mut hash = (*pool.auth_hash.read()).clone();
 
-                if hash.is_none() {
-                    warn!(
-                        "Query auth configured \
-                          but no hash password found \
-                          for pool {}. Will try to refetch it.",
-                        pool_name
-                    );
+                    if hash.is_none() {
+                        warn!(
+                            "Query auth configured \
+                              but no hash password found \
+                              for pool {}. Will try to refetch it.",
+                            pool_name
+                        );
 
-                    match refetch_auth_hash(&pool).await {
-                        Ok(fetched_hash) => {
-                            warn!("Password for {}, obtained. Updating.", client_identifier);
+                        match refetch_auth_hash(&pool).await {
+                            Ok(fetched_hash) => {
+                                warn!("Password for {}, obtained. Updating.", client_identifier);
 
-                            {
-                                let mut pool_auth_hash = pool.auth_hash.write();
-                                *pool_auth_hash = Some(fetched_hash.clone());
+                                {
+                                    let mut pool_auth_hash = pool.auth_hash.write();
+                                    *pool_auth_hash = Some(fetched_hash.clone());
+                                }
+
+                                hash = Some(fetched_hash);
                             }
 
-                            hash = Some(fetched_hash);
+                            Err(err) => {
+                                wrong_password(&mut write, username).await?;
+
+                                return Err(Error::ClientAuthPassthroughError(
+                                    err.to_string(),
+                                    client_identifier,
+                                ));
+                            }
                         }
+                    };
+
+                    Some(md5_hash_second_pass(&hash.unwrap(), &salt))
+                };
+
+                // Once we have the resulting hash, we compare with what the client gave us.
+                // If they do not match and auth query is set up, we try to refetch the hash one more time
+                // to see if the password has changed since the pool was created.
+                //
+                // @TODO: we could end up fetching again the same password twice (see above).
+                if password_hash.unwrap() != password_response {
+                    warn!(
+                        "Invalid password {}, will try to refetch it.",
+                        client_identifier
+                    );
 
+                    let fetched_hash = match refetch_auth_hash(&pool).await {
+                        Ok(fetched_hash) => fetched_hash,
                         Err(err) => {
                             wrong_password(&mut write, username).await?;
 
-                            return Err(Error::ClientAuthPassthroughError(
-                                err.to_string(),
-                                client_identifier,
-                            ));
+                            return Err(err);
                         }
-                    }
-                };
+                    };
 
-                Some(md5_hash_second_pass(&hash.unwrap(), &salt))
-            };
+                    let new_password_hash = md5_hash_second_pass(&fetched_hash, &salt);
 
-            // Once we have the resulting hash, we compare with what the client gave us.
-            // If they do not match and auth query is set up, we try to refetch the hash one more time
-            // to see if the password has changed since the pool was created.
-            //
-            // @TODO: we could end up fetching again the same password twice (see above).
-            if password_hash.unwrap() != password_response {
-                warn!(
-                    "Invalid password {}, will try to refetch it.",
-                    client_identifier
-                );
+                    // Ok password changed in server an auth is possible.
+                    if new_password_hash == password_response {
+                        warn!(
+                            "Password for {}, changed in server. Updating.",
+                            client_identifier
+                        );
 
-                let fetched_hash = match refetch_auth_hash(&pool).await {
-                    Ok(fetched_hash) => fetched_hash,
-                    Err(err) => {
+                        {
+                            let mut pool_auth_hash = pool.auth_hash.write();
+                            *pool_auth_hash = Some(fetched_hash);
+                        }
+                    } else {
                         wrong_password(&mut write, username).await?;
-
-                        return Err(err);
-                    }
-                };
-
-                let new_password_hash = md5_hash_second_pass(&fetched_hash, &salt);
-
-                // Ok password changed in server an auth is possible.
-                if new_password_hash == password_response {
-                    warn!(
-                        "Password for {}, changed in server. Updating.",
-                        client_identifier
-                    );
-
-                    {
-                        let mut pool_auth_hash = pool.auth_hash.write();
-                        *pool_auth_hash = Some(fetched_hash);
+                        return Err(Error::ClientGeneralError(
+                            "Invalid password".into(),
+                            client_identifier,
+                        ));
                     }
-                } else {
-                    wrong_password(&mut write, username).await?;
-                    return Err(Error::ClientGeneralError(
-                        "Invalid password".into(),
-                        client_identifier,
-                    ));
                 }
             }
 
-            let transaction_mode = pool.settings.pool_mode == PoolMode::Transaction;
+            // Don't let a connection storm through to the servers.
+            if let Err(err) = pool.admit_client().await {
+                error_response_with_code(
+                    &mut write,
+                    &err.to_string(),
+                    "53300", // too_many_connections
+                )
+                .await?;
+                return Err(Error::ClientGeneralError(
+                    err.to_string(),
+                    client_identifier,
+                ));
+            }
+
+            // Auto mode starts in transaction mode, see `pin_session`.
+            let transaction_mode = pool.settings.pool_mode != PoolMode::Session;
             prepared_statements_enabled =
                 transaction_mode && pool.prepared_statement_cache.is_some();
 
@@ -728,6 +943,7 @@ where
             prepared_statements_enabled,
             prepared_statements: HashMap::new(),
             extended_protocol_data_buffer: VecDeque::new(),
+            query_timeout: None,
         })
     }
 
@@ -766,6 +982,7 @@ where
             prepared_statements_enabled: false,
             prepared_statements: HashMap::new(),
             extended_protocol_data_buffer: VecDeque::new(),
+            query_timeout: None,
         })
     }
 
@@ -888,6 +1105,11 @@ where
             match message[0] as char {
                 // Query
                 'Q' => {
+                    if self.copy_rejected(&pool, &message) {
+                        error_response(&mut self.write, COPY_REJECTED).await?;
+                        continue;
+                    }
+
                     if query_router.query_parser_enabled() {
                         match query_router.parse(&message) {
                             Ok(ast) => {
@@ -907,15 +1129,32 @@ where
                                     _ => (),
                                 };
 
-                                let _ = query_router.infer(&ast);
+                                if let Err(Error::BadQuery(error)) = query_router.infer(&ast) {
+                                    error_response(&mut self.write, &error).await?;
+                                    continue;
+                                }
 
                                 initial_parsed_ast = Some(ast);
                             }
                             Err(error) => {
-                                warn!(
-                                    "Query parsing error: {} (client: {})",
-                                    error, client_identifier
-                                );
+                                if query_router.exceeds_max_length(&message) {
+                                    // Don't let the previous query decide where this one goes.
+                                    debug!("Query too long to parse, using the default role");
+                                    query_router.use_default_role();
+                                } else {
+                                    warn!(
+                                        "Query parsing error: {} (client: {})",
+                                        error, client_identifier
+                                    );
+                                    pool.parse_failed();
+
+                                    if let Err(Error::BadQuery(error)) =
+                                        query_router.parse_failed(&error)
+                                    {
+                                        error_response(&mut self.write, &error).await?;
+                                        continue;
+                                    }
+                                }
                             }
                         }
                     }
@@ -937,18 +1176,44 @@ where
                                     plugin_output = Some(output);
                                 }
 
-                                let _ = query_router.infer(&ast);
+                                if let Err(Error::BadQuery(error)) = query_router.infer(&ast) {
+                                    plugin_output = Some(PluginOutput::Deny(error));
+                                }
+                            }
+                            Err(_) if query_router.exceeds_max_length(&message) => {
+                                debug!("Query too long to parse, using the default role");
+                                query_router.use_default_role();
                             }
                             Err(error) => {
                                 warn!(
                                     "Query parsing error: {} (client: {})",
                                     error, client_identifier
                                 );
+                                pool.parse_failed();
+
+                                if let Err(Error::BadQuery(error)) =
+                                    query_router.parse_failed(&error)
+                                {
+                                    plugin_output = Some(PluginOutput::Deny(error));
+                                }
                             }
                         };
                     }
 
-                    self.buffer_parse(message, &pool)?;
+                    let message = match query_router.rewrite_query(&message) {
+                        Ok(rewritten) => rewritten.unwrap_or(message),
+                        Err(Error::BadQuery(error)) => {
+                            plugin_output = Some(PluginOutput::Deny(error));
+                            message
+                        }
+                        Err(err) => return Err(err),
+                    };
+
+                    if self.copy_rejected(&pool, &message) {
+                        plugin_output = Some(PluginOutput::Deny(COPY_REJECTED.into()));
+                    }
+
+                    self.buffer_parse(message, &pool, &query_router, &client_identifier)?;
 
                     continue;
                 }
@@ -996,8 +1261,35 @@ where
                 continue;
             };
 
-            // Check if the pool is paused and wait until it's resumed.
-            pool.wait_paused().await;
+            // Check if the pool is paused and wait until it's resumed,
+            // unless the client prefers to be told to come back later.
+            if pool.settings.pause_client_behavior == PauseClientBehavior::RejectWithRetry
+                && pool.paused()
+            {
+                self.reset_buffered_state();
+                query_error_response(
+                    &mut self.write,
+                    "pool is paused, retry later",
+                    "57P03", // cannot_connect_now
+                )
+                .await?;
+                continue;
+            }
+
+            if let Err(err) = pool.wait_paused().await {
+                warn!(
+                    "Client {:?} gave up waiting for paused pool {}",
+                    self.addr, self.pool_name
+                );
+                self.reset_buffered_state();
+                query_error_response(
+                    &mut self.write,
+                    &err.to_string(),
+                    "57P03", // cannot_connect_now
+                )
+                .await?;
+                continue;
+            }
 
             // Refresh pool information, something might have changed.
             pool = self.get_pool().await?;
@@ -1009,14 +1301,51 @@ where
             }
 
             // Grab a server from the pool.
+            let target_server = query_router.take_target_server();
             let connection = match pool
-                .get(query_router.shard(), query_router.role(), &self.stats)
+                .get(
+                    query_router.shard(),
+                    query_router.role(),
+                    target_server.as_deref(),
+                    &self.stats,
+                    client_disconnected(&mut self.read),
+                )
                 .await
             {
                 Ok(conn) => {
                     debug!("Got connection from pool");
                     conn
                 }
+                Err(Error::CheckoutCancelled) => {
+                    debug!(
+                        "Client {:?} disconnected while waiting for a connection",
+                        self.addr
+                    );
+                    self.stats.disconnect();
+                    return Ok(());
+                }
+                Err(Error::PoolBusy) => {
+                    // The pool would rather turn clients away than make them wait.
+                    self.stats.idle();
+
+                    if message[0] as char == 'S' {
+                        self.reset_buffered_state();
+                    }
+
+                    query_error_response(
+                        &mut self.write,
+                        &Error::PoolBusy.to_string(),
+                        "53300", // too_many_connections
+                    )
+                    .await?;
+
+                    debug!(
+                        "Rejected client {:?}, all server connections are busy",
+                        self.addr
+                    );
+
+                    continue;
+                }
                 Err(err) => {
                     // Client is attempting to get results from the server,
                     // but we were unable to grab a connection from the pool
@@ -1057,8 +1386,12 @@ where
 
             let mut reference = connection.0;
             let address = connection.1;
+            let _application_connection = connection.3;
             let server = &mut *reference;
 
+            // Queries after the first one in the loop below didn't wait for the server.
+            let mut checkout = connection.2;
+
             // Server is assigned to the client in case the client wants to
             // cancel a query later.
             server.claim(self.process_id, self.secret_key);
@@ -1080,7 +1413,11 @@ where
 
             let mut initial_message = Some(message);
 
-            let idle_client_timeout_duration = match get_idle_client_in_transaction_timeout() {
+            let idle_client_timeout_duration = match pool
+                .settings
+                .idle_in_transaction_timeout
+                .unwrap_or_else(get_idle_client_in_transaction_timeout)
+            {
                 0 => tokio::time::Duration::MAX,
                 timeout => tokio::time::Duration::from_millis(timeout),
             };
@@ -1099,6 +1436,12 @@ where
                         // This is not an initial message so discard the initial_parsed_ast
                         initial_parsed_ast.take();
 
+                        // In session mode, the server sends LISTEN notifications while
+                        // the client is idle. Forward them until the client talks again.
+                        if !self.transaction_mode && !server.in_transaction() {
+                            self.forward_async_messages(server).await?;
+                        }
+
                         match tokio::time::timeout(
                             idle_client_timeout_duration,
                             read_message(&mut self.read),
@@ -1151,9 +1494,27 @@ where
 
                 trace!("Client message: {}", code);
 
+                if otel::enabled() && (code == 'Q' || code == 'P') {
+                    otel::emit(otel::RoutingSpan::new(&message, &address, &checkout));
+                    checkout = checkout.reused();
+                }
+
                 match code {
                     // Query
                     'Q' => {
+                        self.query_timeout = query_router.query_timeout(&message);
+
+                        if self.copy_rejected(&pool, &message) {
+                            error_response(&mut self.write, COPY_REJECTED).await?;
+
+                            if !server.in_transaction() {
+                                self.stats.idle();
+                                break;
+                            }
+
+                            continue;
+                        }
+
                         if query_router.query_parser_enabled() {
                             // We don't want to parse again if we already parsed it as the initial message
                             let ast = match initial_parsed_ast {
@@ -1161,10 +1522,32 @@ where
                                 None => match query_router.parse(&message) {
                                     Ok(ast) => Some(ast),
                                     Err(error) => {
-                                        warn!(
-                                            "Query parsing error: {} (client: {})",
-                                            error, client_identifier
-                                        );
+                                        if query_router.exceeds_max_length(&message) {
+                                            debug!(
+                                                "Query too long to parse, using the default role"
+                                            );
+                                            query_router.use_default_role();
+                                        } else {
+                                            warn!(
+                                                "Query parsing error: {} (client: {})",
+                                                error, client_identifier
+                                            );
+                                            pool.parse_failed();
+
+                                            if let Err(Error::BadQuery(error)) =
+                                                query_router.parse_failed(&error)
+                                            {
+                                                error_response(&mut self.write, &error).await?;
+
+                                                if self.transaction_mode && !server.in_transaction()
+                                                {
+                                                    self.stats.idle();
+                                                    break;
+                                                }
+
+                                                continue;
+                                            }
+                                        }
                                         None
                                     }
                                 },
@@ -1189,17 +1572,62 @@ where
                             }
                         }
 
+                        let message = match query_router.rewrite_query(&message) {
+                            Ok(rewritten) => rewritten.unwrap_or(message),
+                            Err(Error::BadQuery(error)) => {
+                                error_response(&mut self.write, &error).await?;
+
+                                if self.transaction_mode && !server.in_transaction() {
+                                    self.stats.idle();
+                                    break;
+                                }
+
+                                continue;
+                            }
+                            Err(err) => return Err(err),
+                        };
+
+                        if query_router.session_statement(&message) {
+                            self.pin_session(&pool, &client_identifier);
+                        }
+
+                        // Wait for a query slot if the pool caps concurrent queries.
+                        let _query_permit = match pool.acquire_query_permit().await {
+                            Ok(permit) => permit,
+                            Err(err) => {
+                                error_response(
+                                    &mut self.write,
+                                    &format!("could not execute query - {}", err),
+                                )
+                                .await?;
+
+                                if self.transaction_mode && !server.in_transaction() {
+                                    self.stats.idle();
+                                    break;
+                                }
+
+                                continue;
+                            }
+                        };
+
                         debug!("Sending query to server");
 
-                        self.send_and_receive_loop(
-                            code,
-                            Some(&message),
-                            server,
-                            &address,
-                            &pool,
-                            &self.stats.clone(),
-                        )
-                        .await?;
+                        match self
+                            .send_and_receive_loop(
+                                code,
+                                Some(&message),
+                                server,
+                                &address,
+                                &pool,
+                                &self.stats.clone(),
+                            )
+                            .await
+                        {
+                            Ok(()) => (),
+                            // The client got the error, the server is closed when it's released.
+                            Err(Error::StatementTimeout) => break,
+                            Err(err) => return Err(err),
+                        }
 
                         if !server.in_transaction() {
                             // Report transaction executed statistics.
@@ -1230,15 +1658,50 @@ where
                     // Parse
                     // The query with placeholders is here, e.g. `SELECT * FROM users WHERE email = $1 AND active = $2`.
                     'P' => {
+                        if let Some(timeout) = query_router.query_timeout(&message) {
+                            self.query_timeout = Some(timeout);
+                        }
+
                         if query_router.query_parser_enabled() {
-                            if let Ok(ast) = query_router.parse(&message) {
-                                if let Ok(output) = query_router.execute_plugins(&ast).await {
-                                    plugin_output = Some(output);
+                            match query_router.parse(&message) {
+                                Ok(ast) => {
+                                    if let Ok(output) = query_router.execute_plugins(&ast).await {
+                                        plugin_output = Some(output);
+                                    }
                                 }
+                                Err(_) if query_router.exceeds_max_length(&message) => {
+                                    debug!("Query too long to parse");
+                                }
+                                Err(error) => {
+                                    warn!(
+                                        "Query parsing error: {} (client: {})",
+                                        error, client_identifier
+                                    );
+                                    pool.parse_failed();
+
+                                    if let Err(Error::BadQuery(error)) =
+                                        query_router.parse_failed(&error)
+                                    {
+                                        plugin_output = Some(PluginOutput::Deny(error));
+                                    }
+                                }
+                            }
+                        }
+
+                        let message = match query_router.rewrite_query(&message) {
+                            Ok(rewritten) => rewritten.unwrap_or(message),
+                            Err(Error::BadQuery(error)) => {
+                                plugin_output = Some(PluginOutput::Deny(error));
+                                message
                             }
+                            Err(err) => return Err(err),
+                        };
+
+                        if self.copy_rejected(&pool, &message) {
+                            plugin_output = Some(PluginOutput::Deny(COPY_REJECTED.into()));
                         }
 
-                        self.buffer_parse(message, &pool)?;
+                        self.buffer_parse(message, &pool, &query_router, &client_identifier)?;
                     }
 
                     // Bind
@@ -1292,6 +1755,25 @@ where
                             _ => (),
                         };
 
+                        // Wait for a query slot if the pool caps concurrent queries.
+                        let _query_permit = match pool.acquire_query_permit().await {
+                            Ok(permit) => permit,
+                            Err(err) => {
+                                self.reset_buffered_state();
+                                error_response(
+                                    &mut self.write,
+                                    &format!("could not execute query - {}", err),
+                                )
+                                .await?;
+
+                                if self.transaction_mode && !server.in_transaction() {
+                                    break;
+                                }
+
+                                continue;
+                            }
+                        };
+
                         // Prepared statements can arrive like this
                         // 1. Without named describe
                         //      Client: Parse, with name, query and params
@@ -1445,15 +1927,21 @@ where
                         }
 
                         if should_send_to_server {
-                            self.send_and_receive_loop(
-                                code,
-                                None,
-                                server,
-                                &address,
-                                &pool,
-                                &self.stats.clone(),
-                            )
-                            .await?;
+                            match self
+                                .send_and_receive_loop(
+                                    code,
+                                    None,
+                                    server,
+                                    &address,
+                                    &pool,
+                                    &self.stats.clone(),
+                                )
+                                .await
+                            {
+                                Ok(()) => (),
+                                Err(Error::StatementTimeout) => break,
+                                Err(err) => return Err(err),
+                            }
                         }
 
                         self.buffer.clear();
@@ -1497,9 +1985,14 @@ where
                         // Clear the buffer
                         self.buffer.clear();
 
-                        let response = self
+                        let response = match self
                             .receive_server_message(server, &address, &pool, &self.stats.clone())
-                            .await?;
+                            .await
+                        {
+                            Ok(response) => response,
+                            Err(Error::StatementTimeout) => break,
+                            Err(err) => return Err(err),
+                        };
 
                         match write_all_flush(&mut self.write, &response).await {
                             Ok(_) => (),
@@ -1534,7 +2027,10 @@ where
             // The server is no longer bound to us, we can't cancel it's queries anymore.
             debug!("Releasing server back into the pool");
 
-            server.checkin_cleanup().await?;
+            // A bad server is closed by the pool, there is nothing to clean up.
+            if !server.is_bad() {
+                server.checkin_cleanup().await?;
+            }
 
             server.stats().idle();
             self.connected_to_server = false;
@@ -1618,8 +2114,21 @@ where
                     }
 
                     // SET SHARDING KEY TO
-                    (Command::SetShardingKey, _) => {
-                        custom_protocol_response_ok(&mut self.write, "SET SHARDING KEY").await?;
+                    (Command::SetShardingKey, value) => {
+                        if value.is_empty() {
+                            query_error_response(
+                                &mut self.write,
+                                &format!(
+                                    "sharding key can't be hashed by {}, staying on shard {:?}",
+                                    pool.settings.sharding_function, current_shard,
+                                ),
+                                "22023",
+                            )
+                            .await?;
+                        } else {
+                            custom_protocol_response_ok(&mut self.write, "SET SHARDING KEY")
+                                .await?;
+                        }
                     }
 
                     // SET SERVER ROLE TO
@@ -1727,7 +2236,21 @@ where
 
     /// Register and rewrite the parse statement to the clients statement cache
     /// and also the pool's statement cache. Add it to extended protocol data.
-    fn buffer_parse(&mut self, message: BytesMut, pool: &ConnectionPool) -> Result<(), Error> {
+    fn buffer_parse(
+        &mut self,
+        message: BytesMut,
+        pool: &ConnectionPool,
+        query_router: &QueryRouter,
+        client_identifier: &ClientIdentifier,
+    ) -> Result<(), Error> {
+        // Named prepared statements outlive the transaction unless
+        // we rename and track them ourselves.
+        if query_router.session_statement(&message)
+            || (!self.prepared_statements_enabled && !Parse::get_name(&message)?.is_empty())
+        {
+            self.pin_session(pool, client_identifier);
+        }
+
         // Avoid parsing if prepared statements not enabled
         if !self.prepared_statements_enabled {
             debug!("Anonymous parse message");
@@ -1883,10 +2406,34 @@ where
         }
     }
 
+    /// The query or Parse message is a COPY the pool doesn't allow.
+    fn copy_rejected(&self, pool: &ConnectionPool, message: &BytesMut) -> bool {
+        self.transaction_mode
+            && pool.settings.copy_handling == CopyHandling::Reject
+            && is_copy_query(message)
+    }
+
     fn reset_buffered_state(&mut self) {
         self.buffer.clear();
         self.extended_protocol_data_buffer.clear();
         self.response_message_queue_buffer.clear();
+        self.query_timeout = None;
+    }
+
+    /// In auto pool mode, keep the server until the client disconnects
+    /// once the client starts using session state.
+    fn pin_session(&mut self, pool: &ConnectionPool, client_identifier: &ClientIdentifier) {
+        if !self.transaction_mode || pool.settings.pool_mode != PoolMode::Auto {
+            return;
+        }
+
+        info!(
+            "Client {} uses session state, switching to session mode",
+            client_identifier
+        );
+
+        self.transaction_mode = false;
+        pool.auto_session_client();
     }
 
     /// Release the server from the client: it can't cancel its queries anymore.
@@ -1914,6 +2461,13 @@ where
         self.send_server_message(server, message, address, pool)
             .await?;
 
+        // The query asked for its own timeout, Postgres will cancel it and
+        // send the error like it does for any other cancelled query.
+        let _query_deadline = self
+            .query_timeout
+            .take()
+            .map(|timeout| server.cancel_after(Duration::from_millis(timeout)));
+
         let query_start = Instant::now();
         // Read all data the server has to offer, which can be multiple messages
         // buffered in 8196 bytes chunks.
@@ -1962,6 +2516,29 @@ where
         }
     }
 
+    /// Forward messages the server sends on its own, e.g. notifications,
+    /// until the client sends something.
+    async fn forward_async_messages(&mut self, server: &mut Server) -> Result<(), Error> {
+        loop {
+            // Both only wait for data without reading it, the loser can be dropped safely.
+            let server_readable = tokio::select! {
+                _ = self.read.fill_buf() => None,
+                readable = server.wait_readable() => Some(readable),
+            };
+
+            match server_readable {
+                // The client has something to say, or went away, read_message will tell.
+                None => return Ok(()),
+
+                Some(readable) => {
+                    readable?;
+                    let message = server.recv_async().await?;
+                    write_all_flush(&mut self.write, &message).await?;
+                }
+            }
+        }
+    }
+
     async fn receive_server_message(
         &mut self,
         server: &mut Server,
@@ -1969,7 +2546,7 @@ where
         pool: &ConnectionPool,
         client_stats: &ClientStats,
     ) -> Result<BytesMut, Error> {
-        let statement_timeout_duration = match pool.settings.user.statement_timeout {
+        let statement_timeout_duration = match pool.settings.statement_timeout {
             0 => tokio::time::Duration::MAX,
             timeout => tokio::time::Duration::from_millis(timeout),
         };
@@ -1993,6 +2570,16 @@ where
                 }
             },
             Err(_) => {
+                address.stats.statement_timeout();
+
+                // Stop the query on the server, it's not going to be read by anyone.
+                if let Err(err) = server.cancel_running_query().await {
+                    warn!(
+                        "Could not cancel query on {:?} after statement timeout: {:?}",
+                        address, err
+                    );
+                }
+
                 server.mark_bad(
                     format!(
                         "Statement timeout while talking to {:?} with user {}",
@@ -2000,14 +2587,37 @@ where
                     )
                     .as_str(),
                 );
-                pool.ban(address, BanReason::StatementTimeout, Some(client_stats));
-                error_response_terminal(&mut self.write, "pool statement timeout").await?;
+
+                if pool.settings.ban_on_statement_timeout {
+                    pool.ban(address, BanReason::StatementTimeout, Some(client_stats));
+                }
+
+                // Only the query fails, like it would with Postgres' own statement_timeout.
+                query_error_response(
+                    &mut self.write,
+                    "canceling statement due to pool statement timeout",
+                    "57014",
+                )
+                .await?;
                 Err(Error::StatementTimeout)
             }
         }
     }
 }
 
+/// Resolves when the client closes the connection. Data sent by the client
+/// is left in the buffer for the next read.
+async fn client_disconnected<R>(read: &mut R)
+where
+    R: tokio::io::AsyncBufRead + std::marker::Unpin,
+{
+    match read.fill_buf().await {
+        // Client sent more data, it's still there.
+        Ok(buf) if !buf.is_empty() => std::future::pending().await,
+        _ => (),
+    }
+}
+
 impl<S, T> Drop for Client<S, T> {
     fn drop(&mut self) {
         let mut guard = self.client_server_map.lock();
@@ -2020,3 +2630,104 @@ impl<S, T> Drop for Client<S, T> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_oversized_startup_packet() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+
+        // Only the length is sent, pgcat should bail before reading the rest.
+        client.write_i32(i32::MAX).await.unwrap();
+
+        assert!(matches!(
+            get_startup(&mut server).await,
+            Err(Error::ClientBadStartup)
+        ));
+
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_i32(4).await.unwrap();
+
+        assert!(matches!(
+            get_startup(&mut server).await,
+            Err(Error::ClientBadStartup)
+        ));
+    }
+
+    fn parse_message(name: &str, query: &str) -> BytesMut {
+        let mut message = BytesMut::new();
+        message.put_u8(b'P');
+        message.put_i32(4 + name.len() as i32 + 1 + query.len() as i32 + 1 + 2);
+        message.put_slice(name.as_bytes());
+        message.put_u8(0);
+        message.put_slice(query.as_bytes());
+        message.put_u8(0);
+        message.put_i16(0);
+        message
+    }
+
+    #[tokio::test]
+    async fn test_auto_mode_extended_protocol() {
+        let mut pool = ConnectionPool::default();
+        pool.settings = Arc::new(crate::pool::PoolSettings {
+            pool_mode: PoolMode::Auto,
+            ..Default::default()
+        });
+        let query_router = QueryRouter::new();
+        let client_identifier = ClientIdentifier::new("app", "user", "pool");
+
+        let client = || async {
+            let (read, write) = tokio::io::duplex(64);
+            let (_shutdown_tx, shutdown) = tokio::sync::broadcast::channel(1);
+            let mut client = Client::cancel(
+                read,
+                write,
+                "127.0.0.1:5432".parse().unwrap(),
+                BytesMut::from(&[0u8; 8][..]),
+                ClientServerMap::default(),
+                shutdown,
+            )
+            .await
+            .unwrap();
+            client.transaction_mode = true;
+            client
+        };
+
+        // Unnamed statements without session state stay in transaction mode.
+        let mut c = client().await;
+        c.buffer_parse(
+            parse_message("", "SELECT 1"),
+            &pool,
+            &query_router,
+            &client_identifier,
+        )
+        .unwrap();
+        assert!(c.transaction_mode);
+
+        let mut c = client().await;
+        c.buffer_parse(
+            parse_message("", "SET search_path TO app"),
+            &pool,
+            &query_router,
+            &client_identifier,
+        )
+        .unwrap();
+        assert!(!c.transaction_mode);
+
+        // Named statements outlive the transaction without the statement cache.
+        let mut c = client().await;
+        c.buffer_parse(
+            parse_message("s1", "SELECT 1"),
+            &pool,
+            &query_router,
+            &client_identifier,
+        )
+        .unwrap();
+        assert!(!c.transaction_mode);
+
+        assert_eq!(pool.auto_session_clients(), 2);
+    }
+}
@@ -11,8 +11,9 @@ pub struct Args {
     #[arg(short, long, default_value_t = tracing::Level::INFO, env)]
     pub log_level: Level,
 
-    #[clap(short='F', long, value_enum, default_value_t=LogFormat::Text, env)]
-    pub log_format: LogFormat,
+    /// Overrides `log_format` in the config file.
+    #[clap(short = 'F', long, value_enum, env)]
+    pub log_format: Option<LogFormat>,
 
     #[arg(
         short,
@@ -22,6 +23,13 @@ pub struct Args {
         help = "disable colors in the log output"
     )]
     pub no_color: bool,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "load the config at PATH, connect to every server and exit, without serving clients"
+    )]
+    pub check_config: Option<String>,
 }
 
 pub fn parse() -> Args {
@@ -31,6 +39,7 @@ pub fn parse() -> Args {
 #[derive(ValueEnum, Clone, Debug)]
 pub enum LogFormat {
     Text,
+    #[value(alias = "json")]
     Structured,
     Debug,
 }
@@ -1,7 +1,8 @@
 /// Parse the configuration file.
 use arc_swap::ArcSwap;
 use log::{error, info};
-use once_cell::sync::Lazy;
+use once_cell::sync::{Lazy, OnceCell};
+use parking_lot::RwLock;
 use regex::Regex;
 use serde::{Deserializer, Serializer};
 use serde_derive::{Deserialize, Serialize};
@@ -10,15 +11,15 @@ use std::collections::hash_map::DefaultHasher;
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::hash::{Hash, Hasher};
 use std::path::Path;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
 
 use crate::dns_cache::CachedResolver;
 use crate::errors::Error;
-use crate::pool::{ClientServerMap, ConnectionPool};
-use crate::sharding::ShardingFunction;
+use crate::pool::{ClientServerMap, ConnectBackoff, ConnectionPool};
+use crate::sharding::{ShardingFunction, RANGE_HASH_MAX, RANGE_HASH_MIN};
 use crate::stats::AddressStats;
 use crate::tls::{load_certs, load_keys};
 
@@ -107,6 +108,30 @@ pub struct Address {
 
     /// Number of errors encountered since last successful checkout
     pub error_count: Arc<AtomicU64>,
+
+    /// Delay before the next connection attempt after failing to connect
+    pub connect_backoff: Arc<ConnectBackoff>,
+
+    /// Taken out of rotation by an admin with DRAIN, until RESUME
+    pub out_of_rotation: Arc<AtomicBool>,
+
+    /// Health checks failed in a row, used to ban the server for longer each time
+    pub health_check_failures: Arc<AtomicU32>,
+
+    /// Availability zone of the server, if known.
+    pub zone: Option<String>,
+
+    /// Share of the traffic the server gets with weighted load balancing.
+    pub weight: u32,
+
+    /// If it's a mirror, the share of queries it receives.
+    pub mirror_sample_rate: Fraction,
+
+    /// Name queries can use to pick this server, e.g. `/* pgcat: server=replica-3 */`.
+    pub label: Option<String>,
+
+    /// Role the server reported when last asked with `pg_is_in_recovery()`, if it was.
+    pub observed_role: Arc<RwLock<Option<Role>>>,
 }
 
 impl Default for Address {
@@ -125,6 +150,14 @@ impl Default for Address {
             mirrors: Vec::new(),
             stats: Arc::new(AddressStats::default()),
             error_count: Arc::new(AtomicU64::new(0)),
+            connect_backoff: Arc::new(ConnectBackoff::default()),
+            out_of_rotation: Arc::new(AtomicBool::new(false)),
+            health_check_failures: Arc::new(AtomicU32::new(0)),
+            zone: None,
+            weight: 1,
+            mirror_sample_rate: MirrorServerConfig::default_mirror_sample_rate(),
+            label: None,
+            observed_role: Arc::new(RwLock::new(None)),
         }
     }
 }
@@ -153,6 +186,10 @@ impl PartialEq for Address {
             && self.username == other.username
             && self.pool_name == other.pool_name
             && self.mirrors == other.mirrors
+            && self.zone == other.zone
+            && self.weight == other.weight
+            && self.mirror_sample_rate == other.mirror_sample_rate
+            && self.label == other.label
     }
 }
 impl Eq for Address {}
@@ -171,6 +208,10 @@ impl Hash for Address {
         self.username.hash(state);
         self.pool_name.hash(state);
         self.mirrors.hash(state);
+        self.zone.hash(state);
+        self.weight.hash(state);
+        self.mirror_sample_rate.hash(state);
+        self.label.hash(state);
     }
 }
 
@@ -178,7 +219,14 @@ impl Address {
     /// Address name (aka database) used in `SHOW STATS`, `SHOW DATABASES`, and `SHOW POOLS`.
     pub fn name(&self) -> String {
         match self.role {
-            Role::Primary => format!("{}_shard_{}_primary", self.pool_name, self.shard),
+            // Shards with several primaries number the others.
+            Role::Primary => match self.replica_number {
+                0 => format!("{}_shard_{}_primary", self.pool_name, self.shard),
+                primary_number => format!(
+                    "{}_shard_{}_primary_{}",
+                    self.pool_name, self.shard, primary_number
+                ),
+            },
             Role::Replica => format!(
                 "{}_shard_{}_replica_{}",
                 self.pool_name, self.shard, self.replica_number
@@ -201,6 +249,51 @@ impl Address {
     pub fn reset_error_count(&self) {
         self.error_count.store(0, Ordering::Relaxed);
     }
+
+    pub fn observed_role(&self) -> Option<Role> {
+        *self.observed_role.read()
+    }
+
+    pub fn set_observed_role(&self, role: Role) {
+        *self.observed_role.write() = Some(role);
+    }
+
+    /// Span giving the log lines about this server its context as fields.
+    pub fn span(&self) -> tracing::Span {
+        tracing::info_span!(
+            "server",
+            pool = %self.pool_name,
+            user = %self.username,
+            shard = self.shard,
+            role = %self.role,
+            host = %self.host,
+            port = self.port,
+        )
+    }
+
+    /// Count a failed health check, returns how many failed in a row.
+    pub fn health_check_failed(&self) -> u32 {
+        self.health_check_failures.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// The last health check failed.
+    pub fn health_check_failing(&self) -> bool {
+        self.health_check_failures.load(Ordering::Relaxed) > 0
+    }
+
+    pub fn health_check_passed(&self) {
+        self.health_check_failures.store(0, Ordering::Relaxed);
+    }
+
+    /// Stop (or resume) giving out connections to this server.
+    pub fn set_out_of_rotation(&self, out_of_rotation: bool) {
+        self.out_of_rotation
+            .store(out_of_rotation, Ordering::Relaxed);
+    }
+
+    pub fn is_out_of_rotation(&self) -> bool {
+        self.out_of_rotation.load(Ordering::Relaxed)
+    }
 }
 
 /// PostgreSQL user.
@@ -218,6 +311,9 @@ pub struct User {
     pub statement_timeout: u64,
     pub connect_timeout: Option<u64>,
     pub idle_timeout: Option<u64>,
+    pub client_auth_mode: Option<ClientAuthMode>,
+    pub max_client_connections: Option<u32>,
+    pub default_role: Option<String>,
 }
 
 impl Default for User {
@@ -234,6 +330,9 @@ impl Default for User {
             server_lifetime: None,
             connect_timeout: None,
             idle_timeout: None,
+            client_auth_mode: None,
+            max_client_connections: None,
+            default_role: None,
         }
     }
 }
@@ -250,6 +349,24 @@ impl User {
             }
         };
 
+        if self.max_client_connections == Some(0) {
+            error!(
+                "max_client_connections of {} must be at least 1",
+                self.username
+            );
+            return Err(Error::BadConfig);
+        }
+
+        if let Some(default_role) = &self.default_role {
+            if !["any", "primary", "replica"].contains(&default_role.as_str()) {
+                error!(
+                    "default_role of {} must be 'primary', 'replica', or 'any', got: '{}'",
+                    self.username, default_role
+                );
+                return Err(Error::BadConfig);
+            }
+        }
+
         Ok(())
     }
 }
@@ -271,6 +388,12 @@ pub struct General {
     #[serde(default = "General::default_connect_timeout")]
     pub connect_timeout: u64,
 
+    #[serde(default)] // 0
+    pub connect_retries: u32,
+
+    #[serde(default = "General::default_connect_retry_delay")]
+    pub connect_retry_delay: u64,
+
     #[serde(default = "General::default_idle_timeout")]
     pub idle_timeout: u64,
 
@@ -283,6 +406,9 @@ pub struct General {
     #[serde(default = "General::default_tcp_user_timeout")]
     pub tcp_user_timeout: u64,
 
+    #[serde(default = "General::default_log_format")]
+    pub log_format: LogFormat,
+
     #[serde(default)] // False
     pub log_client_connections: bool,
 
@@ -298,32 +424,63 @@ pub struct General {
     #[serde(default = "General::default_shutdown_timeout")]
     pub shutdown_timeout: u64,
 
+    #[serde(default = "General::default_max_pause_wait")]
+    pub max_pause_wait: u64,
+
+    #[serde(default = "General::default_min_idle_shortfall_threshold")]
+    pub min_idle_shortfall_threshold: u64,
+
     #[serde(default = "General::default_healthcheck_timeout")]
     pub healthcheck_timeout: u64,
 
+    #[serde(default = "General::default_healthcheck_query")]
+    pub healthcheck_query: String,
+
     #[serde(default = "General::default_healthcheck_delay")]
     pub healthcheck_delay: u64,
 
     #[serde(default = "General::default_ban_time")]
     pub ban_time: i64,
 
+    #[serde(default = "General::default_unban_strategy")]
+    pub unban_strategy: UnbanStrategy,
+
     #[serde(default = "General::default_idle_client_in_transaction_timeout")]
     pub idle_client_in_transaction_timeout: u64,
 
     #[serde(default = "General::default_server_lifetime")]
     pub server_lifetime: u64,
 
+    #[serde(default)] // 0
+    pub server_lifetime_jitter: u64,
+
+    #[serde(default)] // 0
+    pub reaper_rate_jitter: u64,
+
     #[serde(default = "General::default_server_round_robin")] // False
     pub server_round_robin: bool,
 
+    #[serde(default)] // False
+    pub fair_checkout: bool,
+
     #[serde(default = "General::default_worker_threads")]
     pub worker_threads: usize,
 
+    #[serde(default = "General::default_max_startup_parameters")]
+    pub max_startup_parameters: usize,
+
+    #[serde(default = "General::default_max_startup_packet_size")]
+    pub max_startup_packet_size: usize,
+
+    #[serde(default)] // None
+    pub query_parser_max_length: Option<usize>,
+
     #[serde(default)] // None
     pub autoreload: Option<u64>,
 
     pub tls_certificate: Option<String>,
     pub tls_private_key: Option<String>,
+    pub tls_client_ca_certificate: Option<String>,
 
     #[serde(default)] // false
     pub server_tls: bool,
@@ -334,9 +491,43 @@ pub struct General {
     pub admin_username: String,
     pub admin_password: String,
 
+    /// Render SHOW STATS with PgBouncer's columns, one row per database.
+    #[serde(default)] // false
+    pub pgbouncer_compatible_stats: bool,
+
     #[serde(default = "General::default_validate_config")]
     pub validate_config: bool,
 
+    #[serde(default)] // False
+    pub revalidate_on_reload: bool,
+
+    /// How often to ask servers if they are in recovery, to notice
+    /// failovers the config doesn't know about yet. Disabled if not set.
+    #[serde(default)] // None
+    pub role_check_interval: Option<u64>,
+
+    #[serde(default)] // None
+    pub validate_concurrency: Option<usize>,
+
+    pub zone: Option<String>,
+
+    pub ban_event_sink: Option<String>,
+
+    #[serde(default = "General::default_ban_event_queue_size")]
+    pub ban_event_queue_size: usize,
+
+    pub otlp_endpoint: Option<String>,
+
+    #[serde(default = "General::default_otlp_service_name")]
+    pub otlp_service_name: String,
+
+    #[serde(default = "General::default_otlp_queue_size")]
+    pub otlp_queue_size: usize,
+
+    /// Upper bounds (ms) of the checkout time histogram buckets.
+    #[serde(default = "General::default_checkout_time_buckets")]
+    pub checkout_time_buckets: Vec<f64>,
+
     // Support for auth query
     pub auth_query: Option<String>,
     pub auth_query_user: Option<String>,
@@ -360,6 +551,10 @@ impl General {
         1000
     }
 
+    pub fn default_connect_retry_delay() -> u64 {
+        100
+    }
+
     // These keepalive defaults should detect a dead connection within 30 seconds.
     // Tokio defaults to disabling keepalives which keeps dead connections around indefinitely.
     // This can lead to permanent server pool exhaustion
@@ -391,14 +586,34 @@ impl General {
         30
     }
 
+    pub fn default_max_pause_wait() -> u64 {
+        0
+    }
+
+    pub fn default_min_idle_shortfall_threshold() -> u64 {
+        60000
+    }
+
     pub fn default_healthcheck_timeout() -> u64 {
         1000
     }
 
+    pub fn default_healthcheck_query() -> String {
+        String::from(";") // Cheap query as it skips the query planner
+    }
+
     pub fn default_healthcheck_delay() -> u64 {
         30000
     }
 
+    pub fn default_unban_strategy() -> UnbanStrategy {
+        UnbanStrategy::All
+    }
+
+    pub fn default_log_format() -> LogFormat {
+        LogFormat::Text
+    }
+
     pub fn default_ban_time() -> i64 {
         60
     }
@@ -407,6 +622,32 @@ impl General {
         4
     }
 
+    pub fn default_max_startup_parameters() -> usize {
+        64
+    }
+
+    pub fn default_ban_event_queue_size() -> usize {
+        1000
+    }
+
+    pub fn default_otlp_service_name() -> String {
+        "pgcat".into()
+    }
+
+    pub fn default_otlp_queue_size() -> usize {
+        10000
+    }
+
+    pub fn default_checkout_time_buckets() -> Vec<f64> {
+        vec![
+            0.5, 1.0, 2.5, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 5000.0,
+        ]
+    }
+
+    pub fn default_max_startup_packet_size() -> usize {
+        10_000
+    }
+
     pub fn default_idle_client_in_transaction_timeout() -> u64 {
         0
     }
@@ -432,31 +673,56 @@ impl Default for General {
             enable_prometheus_exporter: Some(false),
             prometheus_exporter_port: 9930,
             connect_timeout: General::default_connect_timeout(),
+            connect_retries: 0,
+            connect_retry_delay: Self::default_connect_retry_delay(),
             idle_timeout: General::default_idle_timeout(),
             tcp_keepalives_idle: Self::default_tcp_keepalives_idle(),
             tcp_keepalives_count: Self::default_tcp_keepalives_count(),
             tcp_keepalives_interval: Self::default_tcp_keepalives_interval(),
             tcp_user_timeout: Self::default_tcp_user_timeout(),
+            log_format: Self::default_log_format(),
             log_client_connections: false,
             log_client_disconnections: false,
             dns_cache_enabled: false,
             dns_max_ttl: Self::default_dns_max_ttl(),
             shutdown_timeout: Self::default_shutdown_timeout(),
+            max_pause_wait: Self::default_max_pause_wait(),
+            min_idle_shortfall_threshold: Self::default_min_idle_shortfall_threshold(),
             healthcheck_timeout: Self::default_healthcheck_timeout(),
+            healthcheck_query: Self::default_healthcheck_query(),
             healthcheck_delay: Self::default_healthcheck_delay(),
             ban_time: Self::default_ban_time(),
+            unban_strategy: Self::default_unban_strategy(),
             idle_client_in_transaction_timeout: Self::default_idle_client_in_transaction_timeout(),
             server_lifetime: Self::default_server_lifetime(),
+            server_lifetime_jitter: 0,
+            reaper_rate_jitter: 0,
             server_round_robin: Self::default_server_round_robin(),
+            fair_checkout: false,
             worker_threads: Self::default_worker_threads(),
+            max_startup_parameters: Self::default_max_startup_parameters(),
+            max_startup_packet_size: Self::default_max_startup_packet_size(),
+            query_parser_max_length: None,
             autoreload: None,
             tls_certificate: None,
             tls_private_key: None,
+            tls_client_ca_certificate: None,
             server_tls: false,
             verify_server_certificate: false,
             admin_username: String::from("admin"),
             admin_password: String::from("admin"),
+            pgbouncer_compatible_stats: false,
             validate_config: true,
+            revalidate_on_reload: false,
+            role_check_interval: None,
+            validate_concurrency: None,
+            zone: None,
+            ban_event_sink: None,
+            ban_event_queue_size: Self::default_ban_event_queue_size(),
+            otlp_endpoint: None,
+            otlp_service_name: Self::default_otlp_service_name(),
+            otlp_queue_size: Self::default_otlp_queue_size(),
+            checkout_time_buckets: Self::default_checkout_time_buckets(),
             auth_query: None,
             auth_query_user: None,
             auth_query_password: None,
@@ -474,6 +740,11 @@ pub enum PoolMode {
 
     #[serde(alias = "session", alias = "Session")]
     Session,
+
+    /// Experimental: transaction mode until the client relies on session state,
+    /// then the server is kept until the client disconnects.
+    #[serde(alias = "auto", alias = "Auto")]
+    Auto,
 }
 
 impl std::fmt::Display for PoolMode {
@@ -481,6 +752,279 @@ impl std::fmt::Display for PoolMode {
         match self {
             PoolMode::Transaction => write!(f, "transaction"),
             PoolMode::Session => write!(f, "session"),
+            PoolMode::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+/// COPY handling in transaction mode:
+/// - pin: the server is held by the client until CopyDone/CopyFail,
+/// - reject: COPY statements are refused with an error.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Copy, Hash)]
+pub enum CopyHandling {
+    #[serde(alias = "pin", alias = "Pin")]
+    Pin,
+
+    #[serde(alias = "reject", alias = "Reject")]
+    Reject,
+}
+
+impl std::fmt::Display for CopyHandling {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CopyHandling::Pin => write!(f, "pin"),
+            CopyHandling::Reject => write!(f, "reject"),
+        }
+    }
+}
+
+/// Verification of the certificates of TLS connections to the servers:
+/// - none: anything goes, like `sslmode=require`,
+/// - ca: signed by a trusted CA, like `sslmode=verify-ca`,
+/// - full: signed by a trusted CA and issued for the host, like `sslmode=verify-full`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Copy, Hash)]
+pub enum ServerTlsVerify {
+    #[serde(alias = "none", alias = "None")]
+    None,
+
+    #[serde(alias = "ca", alias = "Ca")]
+    Ca,
+
+    #[serde(alias = "full", alias = "Full")]
+    Full,
+}
+
+impl std::fmt::Display for ServerTlsVerify {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServerTlsVerify::None => write!(f, "none"),
+            ServerTlsVerify::Ca => write!(f, "ca"),
+            ServerTlsVerify::Full => write!(f, "full"),
+        }
+    }
+}
+
+/// Where a query goes when the query parser can't parse it:
+/// - default_role: the pool's default role,
+/// - primary: the primary, unknown queries could be writes,
+/// - error: nowhere, the client gets an error.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Copy, Hash)]
+pub enum ParseFailureAction {
+    #[serde(alias = "default_role", alias = "DefaultRole")]
+    DefaultRole,
+
+    #[serde(alias = "primary", alias = "Primary")]
+    Primary,
+
+    #[serde(alias = "error", alias = "Error")]
+    Error,
+}
+
+impl std::fmt::Display for ParseFailureAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseFailureAction::DefaultRole => write!(f, "default_role"),
+            ParseFailureAction::Primary => write!(f, "primary"),
+            ParseFailureAction::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// What to do when the servers of a pool report different
+/// `server_encoding`, `TimeZone` or `standard_conforming_strings`:
+/// - warn: log the difference,
+/// - error: fail the pool validation.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Copy, Hash)]
+pub enum ServerParameterMismatch {
+    #[serde(alias = "warn", alias = "Warn")]
+    Warn,
+
+    #[serde(alias = "error", alias = "Error")]
+    Error,
+}
+
+impl std::fmt::Display for ServerParameterMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServerParameterMismatch::Warn => write!(f, "warn"),
+            ServerParameterMismatch::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// What to do with client queries while the pool is paused:
+/// - block: wait until the pool is resumed,
+/// - reject_with_retry: return a retryable error right away.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Copy, Hash)]
+pub enum PauseClientBehavior {
+    #[serde(alias = "block", alias = "Block")]
+    Block,
+
+    #[serde(alias = "reject_with_retry", alias = "RejectWithRetry")]
+    RejectWithRetry,
+}
+
+impl std::fmt::Display for PauseClientBehavior {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PauseClientBehavior::Block => write!(f, "block"),
+            PauseClientBehavior::RejectWithRetry => write!(f, "reject_with_retry"),
+        }
+    }
+}
+
+/// What to do when every server connection of the pool is in use:
+/// - wait: queue for a connection, up to `connect_timeout`,
+/// - reject: fail the query right away, so the client can go elsewhere or retry.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Copy, Hash)]
+pub enum CheckoutFailureMode {
+    #[serde(alias = "wait", alias = "Wait")]
+    Wait,
+
+    #[serde(alias = "reject", alias = "Reject")]
+    Reject,
+}
+
+impl std::fmt::Display for CheckoutFailureMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CheckoutFailureMode::Wait => write!(f, "wait"),
+            CheckoutFailureMode::Reject => write!(f, "reject"),
+        }
+    }
+}
+
+/// How to check that a server connection is alive before handing it out:
+/// - query: run `healthcheck_query`, the empty query `;` by default,
+/// - sync: send a Sync message and wait for ReadyForQuery, no SQL involved.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Copy, Hash)]
+pub enum HealthcheckMode {
+    #[serde(alias = "query", alias = "Query")]
+    Query,
+
+    #[serde(alias = "sync", alias = "Sync")]
+    Sync,
+}
+
+impl std::fmt::Display for HealthcheckMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HealthcheckMode::Query => write!(f, "query"),
+            HealthcheckMode::Sync => write!(f, "sync"),
+        }
+    }
+}
+
+/// A number between 0.0 and 1.0. Floats are neither Eq nor Hash, so compare their bits.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(transparent)]
+pub struct Fraction(pub f64);
+
+impl Eq for Fraction {}
+
+impl Hash for Fraction {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.to_bits().hash(state);
+    }
+}
+
+impl std::fmt::Display for Fraction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// What to unban when all replicas of a shard are banned:
+/// - all: every replica at once,
+/// - one: only the replica banned the longest ago, the others rejoin as their bans expire.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Copy, Hash)]
+pub enum UnbanStrategy {
+    #[serde(alias = "all", alias = "All")]
+    All,
+
+    #[serde(alias = "one", alias = "One")]
+    One,
+}
+
+impl std::fmt::Display for UnbanStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UnbanStrategy::All => write!(f, "all"),
+            UnbanStrategy::One => write!(f, "one"),
+        }
+    }
+}
+
+/// How log lines are written:
+/// - text: human readable lines,
+/// - json: one JSON object per line, with the context of the line, e.g. the server, in separate fields.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Copy, Hash)]
+pub enum LogFormat {
+    #[serde(alias = "text", alias = "Text")]
+    Text,
+
+    #[serde(alias = "json", alias = "Json")]
+    Json,
+}
+
+impl std::fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// How clients prove who they are:
+/// - password: MD5 password exchange,
+/// - scram: SCRAM-SHA-256 exchange, bound to the TLS connection with SCRAM-SHA-256-PLUS if the client supports it,
+/// - cert: the common name of the TLS client certificate must match the user.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Copy, Hash)]
+pub enum ClientAuthMode {
+    #[serde(alias = "password", alias = "Password")]
+    Password,
+
+    #[serde(alias = "scram", alias = "Scram", alias = "scram-sha-256")]
+    Scram,
+
+    #[serde(alias = "cert", alias = "Cert")]
+    Cert,
+}
+
+impl std::fmt::Display for ClientAuthMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientAuthMode::Password => write!(f, "password"),
+            ClientAuthMode::Scram => write!(f, "scram"),
+            ClientAuthMode::Cert => write!(f, "cert"),
+        }
+    }
+}
+
+/// Authentication methods a server can ask for, from weakest to strongest.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Copy, Hash)]
+pub enum AuthMethod {
+    #[serde(alias = "trust", alias = "Trust")]
+    Trust,
+
+    #[serde(alias = "md5", alias = "MD5")]
+    Md5,
+
+    #[serde(
+        alias = "scram-sha-256",
+        alias = "scram_sha_256",
+        alias = "SCRAM-SHA-256"
+    )]
+    ScramSha256,
+}
+
+impl std::fmt::Display for AuthMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthMethod::Trust => write!(f, "trust"),
+            AuthMethod::Md5 => write!(f, "md5"),
+            AuthMethod::ScramSha256 => write!(f, "scram-sha-256"),
         }
     }
 }
@@ -492,6 +1036,15 @@ pub enum LoadBalancingMode {
 
     #[serde(alias = "loc", alias = "LOC", alias = "least_outstanding_connections")]
     LeastOutstandingConnections,
+
+    #[serde(alias = "zone_aware", alias = "ZoneAware")]
+    ZoneAware,
+
+    #[serde(alias = "weighted", alias = "Weighted")]
+    Weighted,
+
+    #[serde(alias = "sticky_hash", alias = "StickyHash")]
+    StickyHash,
 }
 
 impl std::fmt::Display for LoadBalancingMode {
@@ -501,6 +1054,9 @@ impl std::fmt::Display for LoadBalancingMode {
             LoadBalancingMode::LeastOutstandingConnections => {
                 write!(f, "least_outstanding_connections")
             }
+            LoadBalancingMode::ZoneAware => write!(f, "zone_aware"),
+            LoadBalancingMode::Weighted => write!(f, "weighted"),
+            LoadBalancingMode::StickyHash => write!(f, "sticky_hash"),
         }
     }
 }
@@ -513,6 +1069,30 @@ pub struct Pool {
     #[serde(default = "Pool::default_load_balancing_mode")]
     pub load_balancing_mode: LoadBalancingMode,
 
+    /// Shuffle the servers before picking the least busy one in `loc` mode,
+    /// so ties go to a random server instead of the one listed first.
+    #[serde(default = "Pool::default_loc_shuffle")]
+    pub loc_shuffle: bool,
+
+    #[serde(default = "Pool::default_copy_handling")]
+    pub copy_handling: CopyHandling,
+
+    /// What validation does when servers of the pool run with different settings.
+    #[serde(default = "Pool::default_server_parameter_mismatch")]
+    pub server_parameter_mismatch: ServerParameterMismatch,
+
+    #[serde(default = "Pool::default_pause_client_behavior")]
+    pub pause_client_behavior: PauseClientBehavior,
+
+    #[serde(default = "Pool::default_checkout_failure_mode")]
+    pub checkout_failure_mode: CheckoutFailureMode,
+
+    #[serde(default = "Pool::default_healthcheck_mode")]
+    pub healthcheck_mode: HealthcheckMode,
+
+    #[serde(default = "Pool::default_client_auth_mode")]
+    pub client_auth_mode: ClientAuthMode,
+
     #[serde(default = "Pool::default_default_role")]
     pub default_role: String,
 
@@ -521,18 +1101,36 @@ pub struct Pool {
 
     pub query_parser_max_length: Option<usize>,
 
+    /// Where queries the parser can't parse go.
+    #[serde(default = "Pool::default_on_parse_failure")]
+    pub on_parse_failure: ParseFailureAction,
+
     #[serde(default)] // False
     pub query_parser_read_write_splitting: bool,
 
     #[serde(default)] // False
     pub primary_reads_enabled: bool,
 
+    /// Chance (0.0 to 1.0) that the primary is a candidate for reads that can go to any server.
+    pub primary_read_weight: Option<Fraction>,
+
     /// Maximum time to allow for establishing a new server connection.
     pub connect_timeout: Option<u64>,
 
     /// Close idle connections that have been opened for longer than this.
     pub idle_timeout: Option<u64>,
 
+    /// Maximum time to wait for a health check, overrides the general setting.
+    pub healthcheck_timeout: Option<u64>,
+
+    /// Skip health checks on connections used more recently than this,
+    /// overrides the general setting.
+    pub healthcheck_delay: Option<u64>,
+
+    /// Stop reading from replicas that are further behind the primary than this (ms).
+    /// Replication lag is measured by health checks.
+    pub max_replica_lag: Option<u64>,
+
     /// Close server connections that have been opened for longer than this.
     /// Only applied to idle connections. If the connection is actively used for
     /// longer than this period, the pool will not interrupt it.
@@ -541,10 +1139,48 @@ pub struct Pool {
     #[serde(default = "Pool::default_sharding_function")]
     pub sharding_function: ShardingFunction,
 
+    /// Sharding function for the keys found by `automatic_sharding_key`,
+    /// `sharding_function` if not set.
+    pub automatic_sharding_function: Option<ShardingFunction>,
+
     #[serde(default = "Pool::default_automatic_sharding_key")]
     pub automatic_sharding_key: Option<String>,
 
+    /// Take the sharding key from this parameter of prepared statements,
+    /// counted from 1 like `$1`, for clients that always bind their values.
+    pub automatic_sharding_key_parameter: Option<i16>,
+
+    #[serde(default = "Pool::default_allow_cross_shard")]
+    pub allow_cross_shard: bool,
+
+    /// Open replica connections with `default_transaction_read_only` on.
+    #[serde(default)]
+    pub enforce_read_only_on_replicas: bool,
+
+    /// Let queries pick a server by name with `/* pgcat: server=<name> */`.
+    #[serde(default)]
+    pub server_targeting_enabled: bool,
+
+    /// Maximum query duration for users that don't set their own `statement_timeout`.
+    pub statement_timeout: Option<u64>,
+
+    /// How long a client may stay idle inside a transaction, overrides
+    /// `idle_client_in_transaction_timeout`.
+    pub idle_in_transaction_timeout: Option<u64>,
+
+    #[serde(default = "Pool::default_ban_on_statement_timeout")]
+    pub ban_on_statement_timeout: bool,
+
+    #[serde(default)] // Empty
+    pub required_extensions: Vec<String>,
+
     pub sharding_key_regex: Option<String>,
+
+    /// Named groups of `sharding_key_regex` concatenated, in this order, into the sharding key.
+    /// Empty uses the first group.
+    #[serde(default)] // Empty
+    pub sharding_key_regex_groups: Vec<String>,
+
     pub shard_id_regex: Option<String>,
     pub regex_search_limit: Option<usize>,
 
@@ -555,18 +1191,74 @@ pub struct Pool {
     pub auth_query_user: Option<String>,
     pub auth_query_password: Option<String>,
 
+    /// Weakest authentication method the servers are allowed to ask for.
+    #[serde(default = "Pool::default_min_server_auth_method")]
+    pub min_server_auth_method: AuthMethod,
+
     #[serde(default = "Pool::default_cleanup_server_connections")]
     pub cleanup_server_connections: bool,
 
+    /// Query run instead of the default cleanup when a server connection with
+    /// altered session state is returned to the pool, e.g. `DISCARD ALL`.
+    pub cleanup_query: Option<String>,
+
     #[serde(default)] // False
     pub log_client_parameter_status_changes: bool,
 
-    #[serde(default = "Pool::default_prepared_statements_cache_size")]
-    pub prepared_statements_cache_size: usize,
+    /// Log the candidates and the server picked for every checkout.
+    #[serde(default)] // False
+    pub log_routing_decisions: bool,
 
-    pub plugins: Option<Plugins>,
-    pub shards: BTreeMap<String, Shard>,
-    pub users: BTreeMap<String, User>,
+    /// Route to servers by the role they report (see `role_check_interval`)
+    /// instead of the configured one.
+    #[serde(default)] // False
+    pub follow_role_changes: bool,
+
+    /// Verification of the server certificates when `server_tls` is enabled.
+    /// Defaults to `full` if `verify_server_certificate` is set, `none` otherwise.
+    pub server_tls_verify: Option<ServerTlsVerify>,
+
+    /// PEM bundle of the CAs trusted to sign the server certificates,
+    /// instead of the usual web roots.
+    pub server_tls_ca_file: Option<String>,
+
+    /// Server name sent in the TLS handshake (SNI) and checked against the
+    /// certificate, instead of the server host.
+    pub server_tls_sni_hostname: Option<String>,
+
+    #[serde(default = "Pool::default_prepared_statements_cache_size")]
+    pub prepared_statements_cache_size: usize,
+
+    /// Prepared statements kept on each server connection, the least recently
+    /// used one is closed past that. Defaults to `prepared_statements_cache_size`.
+    pub max_prepared_statements: Option<usize>,
+
+    /// Extra labels added to the Prometheus metrics of this pool,
+    /// e.g. the team owning it or its environment.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+
+    /// Maximum number of queries executing at the same time on all servers of this pool,
+    /// regardless of how many connections are checked out. Unlimited if not set.
+    pub max_concurrent_queries: Option<usize>,
+
+    /// How long a query waits for a slot when `max_concurrent_queries` is reached
+    /// before an error is returned to the client. 0 means error immediately.
+    #[serde(default = "Pool::default_max_concurrent_queries_wait_timeout")]
+    pub max_concurrent_queries_wait_timeout: u64,
+
+    /// Maximum number of new clients let into this pool every second,
+    /// to soften connection storms, e.g. during deploys. Unlimited if not set.
+    pub max_connections_per_second: Option<u32>,
+
+    /// How long a new client waits to be let in when `max_connections_per_second`
+    /// is reached before an error is returned to it. 0 means error immediately.
+    #[serde(default = "Pool::default_max_connections_per_second_wait_timeout")]
+    pub max_connections_per_second_wait_timeout: u64,
+
+    pub plugins: Option<Plugins>,
+    pub shards: BTreeMap<String, Shard>,
+    pub users: BTreeMap<String, User>,
     // Note, don't put simple fields below these configs. There's a compatibility issue with TOML that makes it
     // incompatible to have simple fields in TOML after complex objects. See
     // https://users.rust-lang.org/t/why-toml-to-string-get-error-valueaftertable/85903
@@ -597,10 +1289,50 @@ impl Pool {
         LoadBalancingMode::Random
     }
 
+    pub fn default_loc_shuffle() -> bool {
+        true
+    }
+
+    pub fn default_copy_handling() -> CopyHandling {
+        CopyHandling::Pin
+    }
+
+    pub fn default_on_parse_failure() -> ParseFailureAction {
+        ParseFailureAction::DefaultRole
+    }
+
+    pub fn default_server_parameter_mismatch() -> ServerParameterMismatch {
+        ServerParameterMismatch::Warn
+    }
+
+    pub fn default_pause_client_behavior() -> PauseClientBehavior {
+        PauseClientBehavior::Block
+    }
+
+    pub fn default_checkout_failure_mode() -> CheckoutFailureMode {
+        CheckoutFailureMode::Wait
+    }
+
+    pub fn default_healthcheck_mode() -> HealthcheckMode {
+        HealthcheckMode::Query
+    }
+
+    pub fn default_client_auth_mode() -> ClientAuthMode {
+        ClientAuthMode::Password
+    }
+
     pub fn default_automatic_sharding_key() -> Option<String> {
         None
     }
 
+    pub fn default_allow_cross_shard() -> bool {
+        true
+    }
+
+    pub fn default_ban_on_statement_timeout() -> bool {
+        true
+    }
+
     pub fn default_default_role() -> String {
         "any".into()
     }
@@ -613,10 +1345,98 @@ impl Pool {
         true
     }
 
+    pub fn default_min_server_auth_method() -> AuthMethod {
+        AuthMethod::Trust
+    }
+
     pub fn default_prepared_statements_cache_size() -> usize {
         0
     }
 
+    /// Prepared statements kept on each server connection,
+    /// 0 if prepared statements aren't tracked.
+    pub fn max_prepared_statements_per_server(&self) -> usize {
+        match self.prepared_statements_cache_size {
+            0 => 0,
+            cache_size => self.max_prepared_statements.unwrap_or(cache_size),
+        }
+    }
+
+    /// Whether `name` can be used as a Prometheus label on the metrics of this pool.
+    pub fn valid_label_name(name: &str) -> bool {
+        let mut chars = name.chars();
+        let valid = match chars.next() {
+            Some(first) => {
+                (first.is_ascii_alphabetic() || first == '_')
+                    && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+            }
+            None => false,
+        };
+
+        valid && !name.starts_with("__") && !crate::prometheus::METRIC_LABELS.contains(&name)
+    }
+
+    pub fn default_max_concurrent_queries_wait_timeout() -> u64 {
+        5000
+    }
+
+    pub fn default_max_connections_per_second_wait_timeout() -> u64 {
+        1000
+    }
+
+    /// With `range_hash`, every hash must belong to exactly one shard:
+    /// the ranges can't overlap or leave gaps, and cover all hashes.
+    fn validate_shard_ranges(&self) -> Result<(), Error> {
+        let mut ranges = Vec::new();
+
+        for (shard_idx, shard) in &self.shards {
+            match (shard.range_start, shard.range_end) {
+                (Some(start), Some(end)) if start <= end => ranges.push((start, end, shard_idx)),
+                (Some(start), Some(end)) => {
+                    error!(
+                        "Shard {} range_start {} is after its range_end {}",
+                        shard_idx, start, end
+                    );
+                    return Err(Error::BadConfig);
+                }
+                _ => {
+                    error!(
+                        "Shard {} needs a range_start and a range_end with the range_hash sharding function",
+                        shard_idx
+                    );
+                    return Err(Error::BadConfig);
+                }
+            }
+        }
+
+        ranges.sort();
+
+        let mut next = RANGE_HASH_MIN as i64;
+
+        for (start, end, shard_idx) in ranges {
+            if start as i64 != next {
+                error!(
+                    "Shard {} range starts at {}, expected {}: shard ranges must be contiguous and not overlap",
+                    shard_idx, start, next
+                );
+                return Err(Error::BadConfig);
+            }
+
+            next = end as i64 + 1;
+        }
+
+        if next != RANGE_HASH_MAX as i64 + 1 {
+            error!(
+                "Shard ranges end at {}, they must cover all hashes up to {}",
+                next - 1,
+                RANGE_HASH_MAX
+            );
+            return Err(Error::BadConfig);
+        }
+
+        Ok(())
+    }
+
     pub fn validate(&mut self) -> Result<(), Error> {
         match self.default_role.as_ref() {
             "any" => (),
@@ -645,6 +1465,23 @@ impl Pool {
             shard.validate()?;
         }
 
+        // Labels pick a single server, they can't be shared.
+        let mut labels = HashSet::new();
+        for server in self.shards.values().flat_map(|shard| &shard.servers) {
+            if let Some(label) = &server.label {
+                if !labels.insert(label) {
+                    error!("Server label '{}' is used by more than one server", label);
+                    return Err(Error::BadConfig);
+                }
+            }
+        }
+
+        if self.sharding_function == ShardingFunction::RangeHash
+            || self.automatic_sharding_function == Some(ShardingFunction::RangeHash)
+        {
+            self.validate_shard_ranges()?;
+        }
+
         for (option, name) in [
             (&self.shard_id_regex, "shard_id_regex"),
             (&self.sharding_key_regex, "sharding_key_regex"),
@@ -657,6 +1494,26 @@ impl Pool {
             }
         }
 
+        if !self.sharding_key_regex_groups.is_empty() {
+            let regex = match &self.sharding_key_regex {
+                Some(regex) => Regex::new(regex.as_str()).unwrap(),
+                None => {
+                    error!("sharding_key_regex_groups requires sharding_key_regex");
+                    return Err(Error::BadConfig);
+                }
+            };
+
+            for group in &self.sharding_key_regex_groups {
+                if !regex
+                    .capture_names()
+                    .any(|name| name == Some(group.as_str()))
+                {
+                    error!("sharding_key_regex has no capture group named '{}'", group);
+                    return Err(Error::BadConfig);
+                }
+            }
+        }
+
         if self.query_parser_read_write_splitting && !self.query_parser_enabled {
             error!(
                 "query_parser_read_write_splitting is only valid when query_parser_enabled is true"
@@ -669,6 +1526,10 @@ impl Pool {
             return Err(Error::BadConfig);
         }
 
+        if let Some(plugins) = &self.plugins {
+            plugins.validate()?;
+        }
+
         self.automatic_sharding_key = match &self.automatic_sharding_key {
             Some(key) => {
                 // No quotes in the key so we don't have to compare quoted
@@ -688,6 +1549,78 @@ impl Pool {
             None => None,
         };
 
+        if let Some(parameter) = self.automatic_sharding_key_parameter {
+            if parameter < 1 {
+                error!(
+                    "automatic_sharding_key_parameter must be at least 1, got {}",
+                    parameter
+                );
+                return Err(Error::BadConfig);
+            }
+        }
+
+        if let Some(Fraction(weight)) = self.primary_read_weight {
+            if !(0.0..=1.0).contains(&weight) {
+                error!(
+                    "primary_read_weight must be between 0.0 and 1.0, got {}",
+                    weight
+                );
+                return Err(Error::BadConfig);
+            }
+        }
+
+        if let Some(cleanup_query) = &self.cleanup_query {
+            validate_cleanup_query(cleanup_query)?;
+        }
+
+        if let Some(server_tls_ca_file) = &self.server_tls_ca_file {
+            match load_certs(Path::new(server_tls_ca_file)) {
+                Ok(certs) if !certs.is_empty() => (),
+                _ => {
+                    error!(
+                        "server_tls_ca_file '{}' has no valid certificate",
+                        server_tls_ca_file
+                    );
+                    return Err(Error::BadConfig);
+                }
+            }
+        }
+
+        if let Some(server_tls_sni_hostname) = &self.server_tls_sni_hostname {
+            if rustls::ServerName::try_from(server_tls_sni_hostname.as_str()).is_err() {
+                error!(
+                    "server_tls_sni_hostname '{}' is not a valid server name",
+                    server_tls_sni_hostname
+                );
+                return Err(Error::BadConfig);
+            }
+        }
+
+        if self.max_prepared_statements == Some(0) {
+            error!("max_prepared_statements must be greater than 0");
+            return Err(Error::BadConfig);
+        }
+
+        for label in self.labels.keys() {
+            if !Self::valid_label_name(label) {
+                error!(
+                    "labels: '{}' is not a valid label name or is used by PgCat",
+                    label
+                );
+                return Err(Error::BadConfig);
+            }
+        }
+
+        if self.max_concurrent_queries == Some(0) {
+            error!("max_concurrent_queries must be greater than 0");
+            return Err(Error::BadConfig);
+        }
+
+        if self.max_connections_per_second == Some(0) {
+            error!("max_connections_per_second must be greater than 0");
+            return Err(Error::BadConfig);
+        }
+
         if let DefaultShard::Shard(shard_number) = self.default_shard {
             if shard_number >= self.shards.len() {
                 error!("Invalid shard {:?}", shard_number);
@@ -708,26 +1641,63 @@ impl Default for Pool {
         Pool {
             pool_mode: Self::default_pool_mode(),
             load_balancing_mode: Self::default_load_balancing_mode(),
+            loc_shuffle: Self::default_loc_shuffle(),
+            copy_handling: Self::default_copy_handling(),
+            server_parameter_mismatch: Self::default_server_parameter_mismatch(),
+            pause_client_behavior: Self::default_pause_client_behavior(),
+            checkout_failure_mode: Self::default_checkout_failure_mode(),
+            healthcheck_mode: Self::default_healthcheck_mode(),
+            client_auth_mode: Self::default_client_auth_mode(),
             default_role: String::from("any"),
             query_parser_enabled: false,
             query_parser_max_length: None,
+            on_parse_failure: Self::default_on_parse_failure(),
             query_parser_read_write_splitting: false,
             primary_reads_enabled: false,
+            primary_read_weight: None,
             connect_timeout: None,
             idle_timeout: None,
+            healthcheck_timeout: None,
+            healthcheck_delay: None,
+            max_replica_lag: None,
             server_lifetime: None,
             sharding_function: ShardingFunction::PgBigintHash,
+            automatic_sharding_function: None,
             automatic_sharding_key: None,
+            automatic_sharding_key_parameter: None,
+            allow_cross_shard: Self::default_allow_cross_shard(),
+            enforce_read_only_on_replicas: false,
+            server_targeting_enabled: false,
+            statement_timeout: None,
+            idle_in_transaction_timeout: None,
+            ban_on_statement_timeout: Self::default_ban_on_statement_timeout(),
+            required_extensions: Vec::new(),
             sharding_key_regex: None,
+            sharding_key_regex_groups: Vec::new(),
             shard_id_regex: None,
             regex_search_limit: Some(1000),
             default_shard: Self::default_default_shard(),
             auth_query: None,
             auth_query_user: None,
             auth_query_password: None,
+            min_server_auth_method: Self::default_min_server_auth_method(),
             cleanup_server_connections: true,
+            cleanup_query: None,
             log_client_parameter_status_changes: false,
+            log_routing_decisions: false,
+            follow_role_changes: false,
+            server_tls_verify: None,
+            server_tls_ca_file: None,
+            server_tls_sni_hostname: None,
             prepared_statements_cache_size: Self::default_prepared_statements_cache_size(),
+            max_prepared_statements: None,
+            labels: BTreeMap::new(),
+            max_concurrent_queries: None,
+            max_concurrent_queries_wait_timeout: Self::default_max_concurrent_queries_wait_timeout(
+            ),
+            max_connections_per_second: None,
+            max_connections_per_second_wait_timeout:
+                Self::default_max_connections_per_second_wait_timeout(),
             plugins: None,
             shards: BTreeMap::from([(String::from("1"), Shard::default())]),
             users: BTreeMap::default(),
@@ -740,6 +1710,21 @@ pub struct ServerConfig {
     pub host: String,
     pub port: u16,
     pub role: Role,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zone: Option<String>,
+
+    #[serde(default = "ServerConfig::default_weight")]
+    pub weight: u32,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+impl ServerConfig {
+    pub fn default_weight() -> u32 {
+        1
+    }
 }
 
 // No Shard Specified handling.
@@ -791,6 +1776,15 @@ pub struct MirrorServerConfig {
     pub host: String,
     pub port: u16,
     pub mirroring_target_index: usize,
+
+    #[serde(default = "MirrorServerConfig::default_mirror_sample_rate")]
+    pub mirror_sample_rate: Fraction,
+}
+
+impl MirrorServerConfig {
+    pub fn default_mirror_sample_rate() -> Fraction {
+        Fraction(1.0)
+    }
 }
 
 /// Shard configuration.
@@ -799,6 +1793,14 @@ pub struct Shard {
     pub database: String,
     pub mirrors: Option<Vec<MirrorServerConfig>>,
     pub servers: Vec<ServerConfig>,
+
+    /// First hash of the shard with the `range_hash` sharding function.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range_start: Option<i32>,
+
+    /// Last hash of the shard with the `range_hash` sharding function.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range_end: Option<i32>,
 }
 
 impl Shard {
@@ -806,7 +1808,6 @@ impl Shard {
         // We use addresses as unique identifiers,
         // let's make sure they are unique in the config as well.
         let mut dup_check = HashSet::new();
-        let mut primary_count = 0;
 
         if self.servers.is_empty() {
             error!("Shard {} has no servers configured", self.database);
@@ -816,25 +1817,30 @@ impl Shard {
         for server in &self.servers {
             dup_check.insert(server);
 
-            // Check that we define only zero or one primary.
-            if server.role == Role::Primary {
-                primary_count += 1
+            if server.weight == 0 {
+                error!(
+                    "Shard {} server {}:{} weight must be at least 1",
+                    self.database, server.host, server.port
+                );
+                return Err(Error::BadConfig);
             }
         }
 
-        if primary_count > 1 {
-            error!(
-                "Shard {} has more than one primary configured",
-                self.database
-            );
-            return Err(Error::BadConfig);
-        }
-
         if dup_check.len() != self.servers.len() {
             error!("Shard {} contains duplicate server configs", self.database);
             return Err(Error::BadConfig);
         }
 
+        for mirror in self.mirrors.iter().flatten() {
+            if !(0.0..=1.0).contains(&mirror.mirror_sample_rate.0) {
+                error!(
+                    "Shard {} mirror {}:{} sample rate must be between 0 and 1, got {}",
+                    self.database, mirror.host, mirror.port, mirror.mirror_sample_rate
+                );
+                return Err(Error::BadConfig);
+            }
+        }
+
         Ok(())
     }
 }
@@ -848,7 +1854,12 @@ impl Default for Shard {
                 host: String::from("localhost"),
                 port: 5432,
                 role: Role::Primary,
+                zone: None,
+                weight: ServerConfig::default_weight(),
+                label: None,
             }],
+            range_start: None,
+            range_end: None,
         }
     }
 }
@@ -859,6 +1870,17 @@ pub struct Plugins {
     pub table_access: Option<TableAccess>,
     pub query_logger: Option<QueryLogger>,
     pub prewarmer: Option<Prewarmer>,
+    pub query_rewrite: Option<QueryRewrite>,
+}
+
+impl Plugins {
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Some(query_rewrite) = &self.query_rewrite {
+            query_rewrite.validate()?;
+        }
+
+        Ok(())
+    }
 }
 
 pub trait Plugin {
@@ -876,11 +1898,12 @@ impl std::fmt::Display for Plugins {
         }
         write!(
             f,
-            "interceptor: {}, table_access: {}, query_logger: {}, prewarmer: {}",
+            "interceptor: {}, table_access: {}, query_logger: {}, prewarmer: {}, query_rewrite: {}",
             is_enabled(self.intercept.as_ref()),
             is_enabled(self.table_access.as_ref()),
             is_enabled(self.query_logger.as_ref()),
             is_enabled(self.prewarmer.as_ref()),
+            is_enabled(self.query_rewrite.as_ref()),
         )
     }
 }
@@ -920,10 +1943,108 @@ impl Plugin for QueryLogger {
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default, Hash, Eq)]
+pub struct QueryRewrite {
+    pub enabled: bool,
+    pub rules: Vec<QueryRewriteRule>,
+}
+
+impl QueryRewrite {
+    pub fn validate(&self) -> Result<(), Error> {
+        for rule in &self.rules {
+            if let Err(parse_err) = Regex::new(&rule.pattern) {
+                error!(
+                    "query_rewrite pattern '{}' is not a valid Regex: {}",
+                    rule.pattern, parse_err
+                );
+                return Err(Error::BadConfig);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Plugin for QueryRewrite {
+    fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+}
+
+/// Queries matching `pattern` are rejected with the `reject` message if set,
+/// otherwise the matches are replaced with `replacement`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Hash, Eq)]
+pub struct QueryRewriteRule {
+    pub pattern: String,
+
+    /// Can refer to capture groups, e.g. `$1` or `${name}`.
+    #[serde(default)]
+    pub replacement: String,
+
+    pub reject: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default, Hash, Eq)]
 pub struct Prewarmer {
     pub enabled: bool,
-    pub queries: Vec<String>,
+    pub queries: Vec<PrewarmerQuery>,
+
+    /// Number of queries of the same order sent over the connection before
+    /// reading their results. The server still runs them one after another.
+    #[serde(default = "Prewarmer::default_pipeline_depth")]
+    pub pipeline_depth: usize,
+}
+
+impl Prewarmer {
+    pub fn default_pipeline_depth() -> usize {
+        1
+    }
+}
+
+/// A prewarmer query, either just the query or a table with its options.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Hash, Eq)]
+#[serde(untagged)]
+pub enum PrewarmerQuery {
+    Query(String),
+    Options {
+        query: String,
+
+        /// Drop the connection if the query fails.
+        #[serde(default = "PrewarmerQuery::default_required")]
+        required: bool,
+
+        /// Queries run by ascending order, the ones with the same order
+        /// don't depend on each other and can run together.
+        #[serde(default)]
+        order: usize,
+    },
+}
+
+impl PrewarmerQuery {
+    pub fn default_required() -> bool {
+        true
+    }
+
+    pub fn query(&self) -> &str {
+        match self {
+            PrewarmerQuery::Query(query) => query,
+            PrewarmerQuery::Options { query, .. } => query,
+        }
+    }
+
+    pub fn required(&self) -> bool {
+        match self {
+            PrewarmerQuery::Query(_) => Self::default_required(),
+            PrewarmerQuery::Options { required, .. } => *required,
+        }
+    }
+
+    pub fn order(&self) -> usize {
+        match self {
+            PrewarmerQuery::Query(_) => 0,
+            PrewarmerQuery::Options { order, .. } => *order,
+        }
+    }
 }
 
 impl Plugin for Prewarmer {
@@ -959,34 +2080,240 @@ impl Query {
     }
 }
 
-/// Configuration wrapper.
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
-pub struct Config {
-    // Serializer maintains the order of fields in the struct
-    // so we should always put simple fields before nested fields
-    // in all serializable structs to avoid ValueAfterTable errors
-    // These errors occur when the toml serializer is about to produce
-    // ambiguous toml structure like the one below
-    // [main]
-    // field1_under_main = 1
-    // field2_under_main = 2
-    // [main.subconf]
-    // field1_under_subconf = 1
-    // field3_under_main = 3 # This field will be interpreted as being under subconf and not under main
-    #[serde(default = "Config::default_path")]
-    pub path: String,
-
-    // General and global settings.
-    pub general: General,
-
-    // Plugins that should run in all pools.
-    pub plugins: Option<Plugins>,
+/// A rule's pattern, compiled once when the config is validated and kept
+/// with it, so it isn't compiled again for every client. Not part of the
+/// config itself: it's ignored when comparing, hashing and serializing.
+#[derive(Debug, Clone, Default)]
+pub struct CompiledRegex(OnceCell<Option<Regex>>);
 
-    // Connection pools.
-    pub pools: HashMap<String, Pool>,
+impl CompiledRegex {
+    /// The compiled `pattern`, `None` if it's invalid.
+    pub fn get(&self, pattern: &str) -> Option<&Regex> {
+        self.0.get_or_init(|| Regex::new(pattern).ok()).as_ref()
+    }
 }
 
-impl Config {
+impl PartialEq for CompiledRegex {
+    fn eq(&self, _other: &CompiledRegex) -> bool {
+        true
+    }
+}
+
+impl Eq for CompiledRegex {}
+
+impl Hash for CompiledRegex {
+    fn hash<H: Hasher>(&self, _state: &mut H) {}
+}
+
+/// The clients a rule applies to: by database and user,
+/// and with a regex on one of their startup parameters.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ClientMatcher {
+    /// Database requested by the client, `*` matches any database.
+    #[serde(default = "ClientMatcher::default_database")]
+    pub database: String,
+
+    /// Only apply the rule to this user.
+    pub user: Option<String>,
+
+    /// Startup parameter to look at, e.g. `application_name`.
+    pub parameter: String,
+
+    /// Regex the parameter value must match.
+    pub pattern: String,
+
+    /// `pattern`, compiled.
+    #[serde(skip)]
+    pub regex: CompiledRegex,
+}
+
+impl ClientMatcher {
+    pub fn default_database() -> String {
+        String::from("*")
+    }
+
+    /// The rule is for the client's database and user.
+    pub fn applies_to(&self, database: &str, user: &str) -> bool {
+        let user_matches = match self.user {
+            Some(ref rule_user) => rule_user == user,
+            None => true,
+        };
+
+        (self.database == "*" || self.database == database) && user_matches
+    }
+
+    /// The compiled `pattern`, `None` if it's invalid.
+    pub fn regex(&self) -> Option<&Regex> {
+        self.regex.get(&self.pattern)
+    }
+
+    /// Compile the pattern, it's kept for the clients checked against the rule.
+    pub fn compile(&self) -> Result<(), regex::Error> {
+        Regex::new(&self.pattern)?;
+        self.regex();
+
+        Ok(())
+    }
+}
+
+/// Send clients asking for `database` to another pool,
+/// picked with a regex on one of their startup parameters.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PoolSelectionRule {
+    #[serde(flatten)]
+    pub matcher: ClientMatcher,
+
+    /// Pool to use, can reference capture groups from the pattern, e.g. `app_$1`.
+    pub pool: String,
+}
+
+impl PoolSelectionRule {
+    /// Get the pool name if the rule matches the client.
+    pub fn select(
+        &self,
+        database: &str,
+        user: &str,
+        parameters: &HashMap<String, String>,
+    ) -> Option<String> {
+        if !self.matcher.applies_to(database, user) {
+            return None;
+        }
+
+        let value = parameters.get(&self.matcher.parameter)?;
+        let captures = self.matcher.regex()?.captures(value)?;
+
+        let mut pool = String::new();
+        captures.expand(&self.pool, &mut pool);
+
+        Some(pool)
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Err(err) = self.matcher.compile() {
+            error!(
+                "Pool selection rule for database '{}' has an invalid pattern: {}",
+                self.matcher.database, err
+            );
+            return Err(Error::BadConfig);
+        }
+
+        if self.pool.is_empty() {
+            error!(
+                "Pool selection rule for database '{}' must have a pool",
+                self.matcher.database
+            );
+            return Err(Error::BadConfig);
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether a client matching a connection rule is let in.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Copy, Hash)]
+pub enum ConnectionRuleAction {
+    #[serde(alias = "allow", alias = "Allow")]
+    Allow,
+
+    #[serde(alias = "deny", alias = "Deny")]
+    Deny,
+}
+
+impl std::fmt::Display for ConnectionRuleAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionRuleAction::Allow => write!(f, "allow"),
+            ConnectionRuleAction::Deny => write!(f, "deny"),
+        }
+    }
+}
+
+/// Let clients in or turn them away at startup, before they're given a pool,
+/// with a regex on one of their startup parameters.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ConnectionRule {
+    /// A missing parameter is an empty string.
+    #[serde(flatten)]
+    pub matcher: ClientMatcher,
+
+    pub action: ConnectionRuleAction,
+
+    /// Error sent to clients turned away by the rule.
+    pub message: Option<String>,
+}
+
+impl ConnectionRule {
+    /// Does the rule apply to the client?
+    pub fn matches(
+        &self,
+        database: &str,
+        user: &str,
+        parameters: &HashMap<String, String>,
+    ) -> bool {
+        if !self.matcher.applies_to(database, user) {
+            return false;
+        }
+
+        let value = parameters
+            .get(&self.matcher.parameter)
+            .map(|value| value.as_str())
+            .unwrap_or("");
+
+        match self.matcher.regex() {
+            Some(regex) => regex.is_match(value),
+            None => false,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Err(err) = self.matcher.compile() {
+            error!(
+                "Connection rule for parameter '{}' has an invalid pattern: {}",
+                self.matcher.parameter, err
+            );
+            return Err(Error::BadConfig);
+        }
+
+        Ok(())
+    }
+}
+
+/// Configuration wrapper.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Config {
+    // Serializer maintains the order of fields in the struct
+    // so we should always put simple fields before nested fields
+    // in all serializable structs to avoid ValueAfterTable errors
+    // These errors occur when the toml serializer is about to produce
+    // ambiguous toml structure like the one below
+    // [main]
+    // field1_under_main = 1
+    // field2_under_main = 2
+    // [main.subconf]
+    // field1_under_subconf = 1
+    // field3_under_main = 3 # This field will be interpreted as being under subconf and not under main
+    #[serde(default = "Config::default_path")]
+    pub path: String,
+
+    // General and global settings.
+    pub general: General,
+
+    // Plugins that should run in all pools.
+    pub plugins: Option<Plugins>,
+
+    // Rules mapping the database requested by clients to a pool.
+    #[serde(default)]
+    pub pool_selection_rules: Vec<PoolSelectionRule>,
+
+    // Rules letting clients in or turning them away at startup.
+    #[serde(default)]
+    pub connection_rules: Vec<ConnectionRule>,
+
+    // Connection pools.
+    pub pools: HashMap<String, Pool>,
+}
+
+impl Config {
     pub fn is_auth_query_configured(&self) -> bool {
         self.pools
             .iter()
@@ -997,6 +2324,88 @@ impl Config {
         String::from("pgcat.toml")
     }
 
+    /// Pick the pool for a client. The first selection rule pointing to a pool
+    /// the user has access to wins, otherwise it's the database the client asked for.
+    pub fn select_pool(
+        &self,
+        database: &str,
+        user: &str,
+        parameters: &HashMap<String, String>,
+    ) -> String {
+        for rule in self.pool_selection_rules.iter() {
+            let pool_name = match rule.select(database, user, parameters) {
+                Some(pool_name) => pool_name,
+                None => continue,
+            };
+
+            let has_user = self.pools.get(&pool_name).is_some_and(|pool| {
+                pool.users
+                    .values()
+                    .any(|pool_user| pool_user.username == user)
+            });
+
+            if has_user {
+                return pool_name;
+            }
+        }
+
+        database.to_string()
+    }
+
+    /// Verification of the server certificates of a pool.
+    pub fn server_tls_verify(&self, pool_name: &str) -> ServerTlsVerify {
+        match self
+            .pools
+            .get(pool_name)
+            .and_then(|pool| pool.server_tls_verify)
+        {
+            Some(server_tls_verify) => server_tls_verify,
+            None if self.general.verify_server_certificate => ServerTlsVerify::Full,
+            None => ServerTlsVerify::None,
+        }
+    }
+
+    /// The connection rule turning the client away, if any.
+    /// The first matching rule wins and clients matching no rule are let in.
+    pub fn connection_denied_by(
+        &self,
+        database: &str,
+        user: &str,
+        parameters: &HashMap<String, String>,
+    ) -> Option<&ConnectionRule> {
+        self.connection_rules
+            .iter()
+            .find(|rule| rule.matches(database, user, parameters))
+            .filter(|rule| rule.action == ConnectionRuleAction::Deny)
+    }
+
+    /// Replace `${ENV_VAR}` and `file:/path` references in the auth query
+    /// credentials with their values, so they don't have to be in the config file.
+    pub fn resolve_auth_query_secrets(&mut self) -> Result<(), Error> {
+        let general = &mut self.general;
+        let pools = self
+            .pools
+            .values_mut()
+            .map(|pool| (&mut pool.auth_query_user, &mut pool.auth_query_password));
+
+        for (user, password) in std::iter::once((
+            &mut general.auth_query_user,
+            &mut general.auth_query_password,
+        ))
+        .chain(pools)
+        {
+            if let Some(value) = user {
+                *value = resolve_secret(value)?;
+            }
+
+            if let Some(value) = password {
+                *value = resolve_secret(value)?;
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn fill_up_auth_query_config(&mut self) {
         for (_name, pool) in self.pools.iter_mut() {
             if pool.auth_query.is_none() {
@@ -1022,6 +2431,8 @@ impl Default for Config {
             path: Self::default_path(),
             general: General::default(),
             plugins: None,
+            pool_selection_rules: Vec::new(),
+            connection_rules: Vec::new(),
             pools: HashMap::default(),
         }
     }
@@ -1042,10 +2453,60 @@ impl From<&Config> for std::collections::HashMap<String, String> {
                         format!("pools.{}.load_balancing_mode", pool_name),
                         pool.load_balancing_mode.to_string(),
                     ),
+                    (
+                        format!("pools.{}.loc_shuffle", pool_name),
+                        pool.loc_shuffle.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.copy_handling", pool_name),
+                        pool.copy_handling.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.server_parameter_mismatch", pool_name),
+                        pool.server_parameter_mismatch.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.pause_client_behavior", pool_name),
+                        pool.pause_client_behavior.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.checkout_failure_mode", pool_name),
+                        pool.checkout_failure_mode.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.log_routing_decisions", pool_name),
+                        pool.log_routing_decisions.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.follow_role_changes", pool_name),
+                        pool.follow_role_changes.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.labels", pool_name),
+                        pool.labels
+                            .iter()
+                            .map(|(name, value)| format!("{}={}", name, value))
+                            .collect::<Vec<_>>()
+                            .join(","),
+                    ),
+                    (
+                        format!("pools.{}.healthcheck_mode", pool_name),
+                        pool.healthcheck_mode.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.client_auth_mode", pool_name),
+                        pool.client_auth_mode.to_string(),
+                    ),
                     (
                         format!("pools.{}.primary_reads_enabled", pool_name),
                         pool.primary_reads_enabled.to_string(),
                     ),
+                    (
+                        format!("pools.{}.primary_read_weight", pool_name),
+                        pool.primary_read_weight
+                            .unwrap_or(Fraction(1.0))
+                            .to_string(),
+                    ),
                     (
                         format!("pools.{}.query_parser_enabled", pool_name),
                         pool.query_parser_enabled.to_string(),
@@ -1057,10 +2518,41 @@ impl From<&Config> for std::collections::HashMap<String, String> {
                             None => String::from("unlimited"),
                         },
                     ),
+                    (
+                        format!("pools.{}.on_parse_failure", pool_name),
+                        pool.on_parse_failure.to_string(),
+                    ),
                     (
                         format!("pools.{}.query_parser_read_write_splitting", pool_name),
                         pool.query_parser_read_write_splitting.to_string(),
                     ),
+                    (
+                        format!("pools.{}.max_concurrent_queries", pool_name),
+                        match pool.max_concurrent_queries {
+                            Some(max_concurrent_queries) => max_concurrent_queries.to_string(),
+                            None => String::from("unlimited"),
+                        },
+                    ),
+                    (
+                        format!("pools.{}.max_connections_per_second", pool_name),
+                        match pool.max_connections_per_second {
+                            Some(max_connections_per_second) => {
+                                max_connections_per_second.to_string()
+                            }
+                            None => String::from("unlimited"),
+                        },
+                    ),
+                    (
+                        format!("pools.{}.max_replica_lag", pool_name),
+                        match pool.max_replica_lag {
+                            Some(max_replica_lag) => max_replica_lag.to_string(),
+                            None => String::from("unlimited"),
+                        },
+                    ),
+                    (
+                        format!("pools.{}.min_server_auth_method", pool_name),
+                        pool.min_server_auth_method.to_string(),
+                    ),
                     (
                         format!("pools.{}.default_role", pool_name),
                         pool.default_role.clone(),
@@ -1069,6 +2561,42 @@ impl From<&Config> for std::collections::HashMap<String, String> {
                         format!("pools.{}.sharding_function", pool_name),
                         pool.sharding_function.to_string(),
                     ),
+                    (
+                        format!("pools.{}.automatic_sharding_function", pool_name),
+                        pool.automatic_sharding_function
+                            .unwrap_or(pool.sharding_function)
+                            .to_string(),
+                    ),
+                    (
+                        format!("pools.{}.allow_cross_shard", pool_name),
+                        pool.allow_cross_shard.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.enforce_read_only_on_replicas", pool_name),
+                        pool.enforce_read_only_on_replicas.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.server_targeting_enabled", pool_name),
+                        pool.server_targeting_enabled.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.statement_timeout", pool_name),
+                        pool.statement_timeout.unwrap_or(0).to_string(),
+                    ),
+                    (
+                        format!("pools.{}.idle_in_transaction_timeout", pool_name),
+                        pool.idle_in_transaction_timeout
+                            .unwrap_or(config.general.idle_client_in_transaction_timeout)
+                            .to_string(),
+                    ),
+                    (
+                        format!("pools.{}.ban_on_statement_timeout", pool_name),
+                        pool.ban_on_statement_timeout.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.required_extensions", pool_name),
+                        pool.required_extensions.join(","),
+                    ),
                     (
                         format!("pools.{:?}.shard_count", pool_name),
                         pool.shards.len().to_string(),
@@ -1097,14 +2625,38 @@ impl From<&Config> for std::collections::HashMap<String, String> {
                 "connect_timeout".to_string(),
                 config.general.connect_timeout.to_string(),
             ),
+            (
+                "connect_retries".to_string(),
+                config.general.connect_retries.to_string(),
+            ),
+            (
+                "connect_retry_delay".to_string(),
+                config.general.connect_retry_delay.to_string(),
+            ),
             (
                 "idle_timeout".to_string(),
                 config.general.idle_timeout.to_string(),
             ),
+            (
+                "checkout_time_buckets".to_string(),
+                format!("{:?}", config.general.checkout_time_buckets),
+            ),
+            (
+                "server_lifetime_jitter".to_string(),
+                config.general.server_lifetime_jitter.to_string(),
+            ),
+            (
+                "reaper_rate_jitter".to_string(),
+                config.general.reaper_rate_jitter.to_string(),
+            ),
             (
                 "healthcheck_timeout".to_string(),
                 config.general.healthcheck_timeout.to_string(),
             ),
+            (
+                "healthcheck_query".to_string(),
+                config.general.healthcheck_query.clone(),
+            ),
             (
                 "shutdown_timeout".to_string(),
                 config.general.shutdown_timeout.to_string(),
@@ -1113,7 +2665,30 @@ impl From<&Config> for std::collections::HashMap<String, String> {
                 "healthcheck_delay".to_string(),
                 config.general.healthcheck_delay.to_string(),
             ),
+            (
+                "max_pause_wait".to_string(),
+                config.general.max_pause_wait.to_string(),
+            ),
+            (
+                "min_idle_shortfall_threshold".to_string(),
+                config.general.min_idle_shortfall_threshold.to_string(),
+            ),
             ("ban_time".to_string(), config.general.ban_time.to_string()),
+            (
+                "unban_strategy".to_string(),
+                config.general.unban_strategy.to_string(),
+            ),
+            (
+                "log_format".to_string(),
+                config.general.log_format.to_string(),
+            ),
+            (
+                "query_parser_max_length".to_string(),
+                match config.general.query_parser_max_length {
+                    Some(max_length) => max_length.to_string(),
+                    None => String::from("unlimited"),
+                },
+            ),
             (
                 "idle_client_in_transaction_timeout".to_string(),
                 config
@@ -1133,17 +2708,39 @@ impl Config {
     pub fn show(&self) {
         info!("Config path: {}", self.path);
         info!("Ban time: {}s", self.general.ban_time);
+        info!("Unban strategy: {}", self.general.unban_strategy);
         info!(
             "Idle client in transaction timeout: {}ms",
             self.general.idle_client_in_transaction_timeout
         );
         info!("Worker threads: {}", self.general.worker_threads);
+        info!(
+            "Max startup parameters: {}",
+            self.general.max_startup_parameters
+        );
+        info!(
+            "Max startup packet size: {} bytes",
+            self.general.max_startup_packet_size
+        );
+        info!(
+            "Query parser max length: {}",
+            match self.general.query_parser_max_length {
+                Some(max_length) => max_length.to_string(),
+                None => String::from("unlimited"),
+            }
+        );
         info!(
             "Healthcheck timeout: {}ms",
             self.general.healthcheck_timeout
         );
+        info!("Healthcheck query: {:?}", self.general.healthcheck_query);
         info!("Connection timeout: {}ms", self.general.connect_timeout);
+        info!(
+            "Connection retries: {}, starting {}ms apart",
+            self.general.connect_retries, self.general.connect_retry_delay
+        );
         info!("Idle timeout: {}ms", self.general.idle_timeout);
+        info!("Log format: {}", self.general.log_format);
         info!(
             "Log client connections: {}",
             self.general.log_client_connections
@@ -1154,11 +2751,63 @@ impl Config {
         );
         info!("Shutdown timeout: {}ms", self.general.shutdown_timeout);
         info!("Healthcheck delay: {}ms", self.general.healthcheck_delay);
+        info!("Max pause wait: {}ms", self.general.max_pause_wait);
+        info!(
+            "Min idle shortfall threshold: {}ms",
+            self.general.min_idle_shortfall_threshold
+        );
         info!(
             "Default max server lifetime: {}ms",
             self.general.server_lifetime
         );
+        info!(
+            "Server lifetime jitter: {}%",
+            self.general.server_lifetime_jitter
+        );
+        info!("Reaper rate jitter: {}%", self.general.reaper_rate_jitter);
         info!("Server round robin: {}", self.general.server_round_robin);
+        info!("Fair checkout: {}", self.general.fair_checkout);
+        info!(
+            "Revalidate on reload: {}",
+            self.general.revalidate_on_reload
+        );
+        info!(
+            "Role check interval: {}",
+            match self.general.role_check_interval {
+                Some(role_check_interval) => format!("{}ms", role_check_interval),
+                None => "disabled".to_string(),
+            }
+        );
+        info!(
+            "PgBouncer compatible stats: {}",
+            self.general.pgbouncer_compatible_stats
+        );
+        info!(
+            "Validate concurrency: {}",
+            match self.general.validate_concurrency {
+                Some(validate_concurrency) => validate_concurrency.to_string(),
+                None => String::from("unlimited"),
+            }
+        );
+        if let Some(zone) = &self.general.zone {
+            info!("Zone: {}", zone);
+        }
+        if let Some(ban_event_sink) = &self.general.ban_event_sink {
+            info!(
+                "Ban event sink: {}, queue size: {}",
+                ban_event_sink, self.general.ban_event_queue_size
+            );
+        }
+        if let Some(otlp_endpoint) = &self.general.otlp_endpoint {
+            info!(
+                "OTLP endpoint: {}, service name: {}, queue size: {}",
+                otlp_endpoint, self.general.otlp_service_name, self.general.otlp_queue_size
+            );
+        }
+        info!(
+            "Checkout time buckets: {:?}ms",
+            self.general.checkout_time_buckets
+        );
         match self.general.tls_certificate.clone() {
             Some(tls_certificate) => {
                 info!("TLS certificate: {}", tls_certificate);
@@ -1167,6 +2816,10 @@ impl Config {
                     info!("TLS private key: {}", tls_private_key);
                     info!("TLS support is enabled");
                 }
+
+                if let Some(tls_client_ca_certificate) = &self.general.tls_client_ca_certificate {
+                    info!("TLS client CA certificate: {}", tls_client_ca_certificate);
+                }
             }
 
             None => {
@@ -1185,6 +2838,18 @@ impl Config {
                 None => "not configured".into(),
             }
         );
+        for rule in self.pool_selection_rules.iter() {
+            info!(
+                "Pool selection rule: database '{}', {} =~ '{}' uses pool '{}'",
+                rule.matcher.database, rule.matcher.parameter, rule.matcher.pattern, rule.pool
+            );
+        }
+        for rule in self.connection_rules.iter() {
+            info!(
+                "Connection rule: database '{}', {} =~ '{}': {}",
+                rule.matcher.database, rule.matcher.parameter, rule.matcher.pattern, rule.action
+            );
+        }
 
         for (pool_name, pool_config) in &self.pools {
             // TODO: Make this output prettier (maybe a table?)
@@ -1207,6 +2872,36 @@ impl Config {
                 "[pool: {}] Load Balancing mode: {:?}",
                 pool_name, pool_config.load_balancing_mode
             );
+            if pool_config.load_balancing_mode == LoadBalancingMode::LeastOutstandingConnections {
+                info!(
+                    "[pool: {}] Shuffle servers before picking the least busy: {}",
+                    pool_name, pool_config.loc_shuffle
+                );
+            }
+            info!(
+                "[pool: {}] COPY handling: {}",
+                pool_name, pool_config.copy_handling
+            );
+            info!(
+                "[pool: {}] Server parameter mismatch: {}",
+                pool_name, pool_config.server_parameter_mismatch
+            );
+            info!(
+                "[pool: {}] Pause client behavior: {}",
+                pool_name, pool_config.pause_client_behavior
+            );
+            info!(
+                "[pool: {}] Checkout failure mode: {}",
+                pool_name, pool_config.checkout_failure_mode
+            );
+            info!(
+                "[pool: {}] Health check mode: {}",
+                pool_name, pool_config.healthcheck_mode
+            );
+            info!(
+                "[pool: {}] Client auth mode: {}",
+                pool_name, pool_config.client_auth_mode
+            );
             let connect_timeout = match pool_config.connect_timeout {
                 Some(connect_timeout) => connect_timeout,
                 None => self.general.connect_timeout,
@@ -1220,23 +2915,102 @@ impl Config {
                 None => self.general.idle_timeout,
             };
             info!("[pool: {}] Idle timeout: {}ms", pool_name, idle_timeout);
+            let healthcheck_timeout = match pool_config.healthcheck_timeout {
+                Some(healthcheck_timeout) => healthcheck_timeout,
+                None => self.general.healthcheck_timeout,
+            };
+            info!(
+                "[pool: {}] Health check timeout: {}ms",
+                pool_name, healthcheck_timeout
+            );
+            let healthcheck_delay = match pool_config.healthcheck_delay {
+                Some(healthcheck_delay) => healthcheck_delay,
+                None => self.general.healthcheck_delay,
+            };
+            info!(
+                "[pool: {}] Health check delay: {}ms",
+                pool_name, healthcheck_delay
+            );
+            let idle_in_transaction_timeout = match pool_config.idle_in_transaction_timeout {
+                Some(idle_in_transaction_timeout) => idle_in_transaction_timeout,
+                None => self.general.idle_client_in_transaction_timeout,
+            };
+            info!(
+                "[pool: {}] Idle in transaction timeout: {}ms",
+                pool_name, idle_in_transaction_timeout
+            );
+            info!(
+                "[pool: {}] Max replica lag: {}",
+                pool_name,
+                match pool_config.max_replica_lag {
+                    Some(max_replica_lag) => format!("{}ms", max_replica_lag),
+                    None => "unlimited".to_string(),
+                }
+            );
             info!(
                 "[pool: {}] Sharding function: {}",
                 pool_name,
                 pool_config.sharding_function.to_string()
             );
+            if let Some(automatic_sharding_function) = pool_config.automatic_sharding_function {
+                info!(
+                    "[pool: {}] Automatic sharding function: {}",
+                    pool_name, automatic_sharding_function
+                );
+            }
+            info!(
+                "[pool: {}] Allow cross-shard queries: {}",
+                pool_name, pool_config.allow_cross_shard
+            );
+            info!(
+                "[pool: {}] Enforce read-only on replicas: {}",
+                pool_name, pool_config.enforce_read_only_on_replicas
+            );
+            info!(
+                "[pool: {}] Server targeting: {}",
+                pool_name, pool_config.server_targeting_enabled
+            );
+            info!(
+                "[pool: {}] Statement timeout: {}",
+                pool_name,
+                pool_config.statement_timeout.unwrap_or(0)
+            );
+            info!(
+                "[pool: {}] Ban on statement timeout: {}",
+                pool_name, pool_config.ban_on_statement_timeout
+            );
+            info!(
+                "[pool: {}] Required extensions: {:?}",
+                pool_name, pool_config.required_extensions
+            );
             info!(
                 "[pool: {}] Primary reads: {}",
                 pool_name, pool_config.primary_reads_enabled
             );
+            info!(
+                "[pool: {}] Primary read weight: {}",
+                pool_name,
+                pool_config.primary_read_weight.unwrap_or(Fraction(1.0))
+            );
             info!(
                 "[pool: {}] Query router: {}",
                 pool_name, pool_config.query_parser_enabled
             );
 
             info!(
-                "[pool: {}] Query parser max length: {:?}",
-                pool_name, pool_config.query_parser_max_length
+                "[pool: {}] Query parser max length: {}",
+                pool_name,
+                match pool_config
+                    .query_parser_max_length
+                    .or(self.general.query_parser_max_length)
+                {
+                    Some(max_length) => max_length.to_string(),
+                    None => String::from("unlimited"),
+                }
+            );
+            info!(
+                "[pool: {}] On parse failure: {}",
+                pool_name, pool_config.on_parse_failure
             );
             info!(
                 "[pool: {}] Infer role from query: {}",
@@ -1260,18 +3034,93 @@ impl Config {
                     None => "default".to_string(),
                 }
             );
+            info!(
+                "[pool: {}] Minimum server auth method: {}",
+                pool_name, pool_config.min_server_auth_method
+            );
             info!(
                 "[pool: {}] Cleanup server connections: {}",
                 pool_name, pool_config.cleanup_server_connections
             );
+            info!(
+                "[pool: {}] Cleanup query: {}",
+                pool_name,
+                match pool_config.cleanup_query {
+                    Some(ref cleanup_query) => cleanup_query.as_str(),
+                    None => "default",
+                }
+            );
             info!(
                 "[pool: {}] Log client parameter status changes: {}",
                 pool_name, pool_config.log_client_parameter_status_changes
             );
+            info!(
+                "[pool: {}] Log routing decisions: {}",
+                pool_name, pool_config.log_routing_decisions
+            );
+            info!(
+                "[pool: {}] Follow role changes: {}",
+                pool_name, pool_config.follow_role_changes
+            );
+            if self.general.server_tls {
+                info!(
+                    "[pool: {}] Server TLS verify: {}",
+                    pool_name,
+                    self.server_tls_verify(pool_name)
+                );
+                info!(
+                    "[pool: {}] Server TLS CA file: {}",
+                    pool_name,
+                    match pool_config.server_tls_ca_file {
+                        Some(ref server_tls_ca_file) => server_tls_ca_file.as_str(),
+                        None => "web roots",
+                    }
+                );
+            }
             info!(
                 "[pool: {}] Prepared statements server cache size: {}",
                 pool_name, pool_config.prepared_statements_cache_size
             );
+            info!(
+                "[pool: {}] Max prepared statements per server connection: {}",
+                pool_name,
+                pool_config.max_prepared_statements_per_server()
+            );
+            if !pool_config.labels.is_empty() {
+                info!(
+                    "[pool: {}] Labels: {}",
+                    pool_name,
+                    pool_config
+                        .labels
+                        .iter()
+                        .map(|(name, value)| format!("{}={}", name, value))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            }
+            info!(
+                "[pool: {}] Max concurrent queries: {}",
+                pool_name,
+                match pool_config.max_concurrent_queries {
+                    Some(max_concurrent_queries) => format!(
+                        "{} (wait timeout: {}ms)",
+                        max_concurrent_queries, pool_config.max_concurrent_queries_wait_timeout
+                    ),
+                    None => "unlimited".to_string(),
+                }
+            );
+            info!(
+                "[pool: {}] Max connections per second: {}",
+                pool_name,
+                match pool_config.max_connections_per_second {
+                    Some(max_connections_per_second) => format!(
+                        "{} (wait timeout: {}ms)",
+                        max_connections_per_second,
+                        pool_config.max_connections_per_second_wait_timeout
+                    ),
+                    None => "unlimited".to_string(),
+                }
+            );
             info!(
                 "[pool: {}] Plugins: {}",
                 pool_name,
@@ -1294,7 +3143,12 @@ impl Config {
                 );
                 info!(
                     "[pool: {}][user: {}] Statement timeout: {}",
-                    pool_name, user.1.username, user.1.statement_timeout
+                    pool_name,
+                    user.1.username,
+                    match user.1.statement_timeout {
+                        0 => pool_config.statement_timeout.unwrap_or(0),
+                        statement_timeout => statement_timeout,
+                    }
                 );
                 info!(
                     "[pool: {}][user: {}] Pool mode: {}",
@@ -1332,11 +3186,67 @@ impl Config {
                         None => "not set".to_string(),
                     }
                 );
+                info!(
+                    "[pool: {}][user: {}] Client auth mode: {}",
+                    pool_name,
+                    user.1.username,
+                    user.1
+                        .client_auth_mode
+                        .unwrap_or(pool_config.client_auth_mode)
+                );
+                info!(
+                    "[pool: {}][user: {}] Max client connections: {}",
+                    pool_name,
+                    user.1.username,
+                    match user.1.max_client_connections {
+                        Some(max_client_connections) => max_client_connections.to_string(),
+                        None => "not set".to_string(),
+                    }
+                );
+                info!(
+                    "[pool: {}][user: {}] Default role: {}",
+                    pool_name,
+                    user.1.username,
+                    user.1
+                        .default_role
+                        .as_ref()
+                        .unwrap_or(&pool_config.default_role)
+                );
             }
         }
     }
 
     pub fn validate(&mut self) -> Result<(), Error> {
+        if let Some(plugins) = &self.plugins {
+            plugins.validate()?;
+        }
+
+        if self.general.healthcheck_query.trim().is_empty() {
+            error!("healthcheck_query cannot be empty");
+            return Err(Error::BadConfig);
+        }
+
+        if self.general.validate_concurrency == Some(0) {
+            error!("validate_concurrency must be at least 1");
+            return Err(Error::BadConfig);
+        }
+
+        if self.general.reaper_rate_jitter > 100 {
+            error!(
+                "reaper_rate_jitter must be a percentage between 0 and 100, got {}",
+                self.general.reaper_rate_jitter
+            );
+            return Err(Error::BadConfig);
+        }
+
+        if self.general.server_lifetime_jitter > 100 {
+            error!(
+                "server_lifetime_jitter must be a percentage between 0 and 100, got {}",
+                self.general.server_lifetime_jitter
+            );
+            return Err(Error::BadConfig);
+        }
+
         // Validation for auth_query feature
         if self.general.auth_query.is_some()
             && (self.general.auth_query_user.is_none()
@@ -1367,6 +3277,18 @@ impl Config {
                 return Err(Error::BadConfig);
             }
 
+            if pool.load_balancing_mode == LoadBalancingMode::ZoneAware
+                && self.general.zone.is_none()
+            {
+                error!(
+                    "Error in pool {{ {} }}. \
+                    zone_aware load balancing requires `general.zone` to be set",
+                    name
+                );
+
+                return Err(Error::BadConfig);
+            }
+
             for (_name, user_data) in pool.users.iter() {
                 if (pool.auth_query.is_none()
                     || pool.auth_query_password.is_none()
@@ -1385,6 +3307,27 @@ impl Config {
             }
         }
 
+        if let Some(ban_event_sink) = &self.general.ban_event_sink {
+            crate::ban_events::exporter(ban_event_sink)?;
+        }
+
+        if let Some(otlp_endpoint) = &self.general.otlp_endpoint {
+            crate::otel::endpoint(otlp_endpoint)?;
+        }
+
+        let buckets = &self.general.checkout_time_buckets;
+        if buckets
+            .iter()
+            .any(|bucket| !bucket.is_finite() || *bucket <= 0.0)
+            || buckets.windows(2).any(|pair| pair[0] >= pair[1])
+        {
+            error!(
+                "checkout_time_buckets must be positive and in increasing order, got {:?}",
+                buckets
+            );
+            return Err(Error::BadConfig);
+        }
+
         // Validate TLS!
         if let Some(tls_certificate) = self.general.tls_certificate.clone() {
             match load_certs(Path::new(&tls_certificate)) {
@@ -1413,14 +3356,104 @@ impl Config {
             }
         };
 
+        if let Some(tls_client_ca_certificate) = &self.general.tls_client_ca_certificate {
+            if self.general.tls_certificate.is_none() {
+                error!("tls_client_ca_certificate is set, but the tls_certificate is not");
+                return Err(Error::BadConfig);
+            }
+
+            if let Err(err) = load_certs(Path::new(tls_client_ca_certificate)) {
+                error!(
+                    "tls_client_ca_certificate is incorrectly configured: {:?}",
+                    err
+                );
+                return Err(Error::BadConfig);
+            }
+        }
+
+        // Certificate auth needs client certificates we can trust.
+        for (pool_name, pool) in self.pools.iter() {
+            for user in pool.users.values() {
+                let client_auth_mode = user.client_auth_mode.unwrap_or(pool.client_auth_mode);
+
+                if client_auth_mode == ClientAuthMode::Cert
+                    && self.general.tls_client_ca_certificate.is_none()
+                {
+                    error!(
+                        "[pool: {}][user: {}] client_auth_mode is cert, but the tls_client_ca_certificate is not set",
+                        pool_name, user.username
+                    );
+                    return Err(Error::BadConfig);
+                }
+
+                // SCRAM needs the password itself, the MD5 hash from auth_query won't do.
+                if client_auth_mode == ClientAuthMode::Scram && user.password.is_none() {
+                    error!(
+                        "[pool: {}][user: {}] client_auth_mode is scram, but the user has no password",
+                        pool_name, user.username
+                    );
+                    return Err(Error::BadConfig);
+                }
+            }
+        }
+
         for pool in self.pools.values_mut() {
             pool.validate()?;
         }
 
+        for rule in self.pool_selection_rules.iter() {
+            rule.validate()?;
+        }
+
+        for rule in self.connection_rules.iter() {
+            rule.validate()?;
+        }
+
         Ok(())
     }
 }
 
+/// Statements allowed in `cleanup_query`: they reset session state
+/// and don't touch data.
+const CLEANUP_STATEMENTS: [&str; 5] = ["DISCARD", "RESET", "DEALLOCATE", "CLOSE", "UNLISTEN"];
+
+/// Make sure `cleanup_query` only resets session state.
+pub fn validate_cleanup_query(query: &str) -> Result<(), Error> {
+    let statements = query
+        .split(';')
+        .map(|statement| statement.trim())
+        .filter(|statement| !statement.is_empty())
+        .collect::<Vec<&str>>();
+
+    if statements.is_empty() {
+        error!("cleanup_query must not be empty");
+        return Err(Error::BadConfig);
+    }
+
+    for statement in statements {
+        let keyword = statement.split_whitespace().next().unwrap_or_default();
+
+        let allowed = CLEANUP_STATEMENTS
+            .iter()
+            .any(|allowed| keyword.eq_ignore_ascii_case(allowed))
+            || statement
+                .split_whitespace()
+                .collect::<String>()
+                .eq_ignore_ascii_case("SELECTpg_advisory_unlock_all()");
+
+        if !allowed {
+            error!(
+                "cleanup_query can only use {}, or SELECT pg_advisory_unlock_all(), got: '{}'",
+                CLEANUP_STATEMENTS.join(", "),
+                statement
+            );
+            return Err(Error::BadConfig);
+        }
+    }
+
+    Ok(())
+}
+
 /// Get a read-only instance of the configuration
 /// from anywhere in the app.
 /// ArcSwap makes this cheap and quick.
@@ -1432,6 +3465,62 @@ pub fn get_idle_client_in_transaction_timeout() -> u64 {
     CONFIG.load().general.idle_client_in_transaction_timeout
 }
 
+pub fn get_fair_checkout() -> bool {
+    CONFIG.load().general.fair_checkout
+}
+
+pub fn get_min_idle_shortfall_threshold() -> u64 {
+    CONFIG.load().general.min_idle_shortfall_threshold
+}
+
+pub fn get_role_check_interval() -> Option<u64> {
+    CONFIG.load().general.role_check_interval
+}
+
+/// Get the value of a secret given as `${ENV_VAR}` or `file:/path`.
+/// Anything else is the value itself.
+pub fn resolve_secret(value: &str) -> Result<String, Error> {
+    if let Some(name) = value
+        .strip_prefix("${")
+        .and_then(|value| value.strip_suffix('}'))
+    {
+        return match std::env::var(name) {
+            Ok(secret) => Ok(secret),
+            Err(err) => {
+                error!("Could not read environment variable '{}': {}", name, err);
+                Err(Error::BadConfig)
+            }
+        };
+    }
+
+    if let Some(path) = value.strip_prefix("file:") {
+        return match std::fs::read_to_string(path) {
+            // Files usually end with a newline that isn't part of the secret.
+            Ok(secret) => Ok(secret.trim_end_matches(['\r', '\n']).to_string()),
+            Err(err) => {
+                error!("Could not read secret file '{}': {}", path, err);
+                Err(Error::BadConfig)
+            }
+        };
+    }
+
+    Ok(value.to_string())
+}
+
+/// The log format set in the config file, read on its own before the logger is set up.
+/// Errors in the file are reported later by `parse`.
+pub fn log_format(path: &str) -> Option<LogFormat> {
+    let contents = std::fs::read_to_string(path).ok()?;
+    let config: toml::Value = toml::from_str(&contents).ok()?;
+
+    config
+        .get("general")?
+        .get("log_format")?
+        .clone()
+        .try_into()
+        .ok()
+}
+
 /// Parse the configuration file located at the path.
 pub async fn parse(path: &str) -> Result<(), Error> {
     let mut contents = String::new();
@@ -1459,6 +3548,7 @@ pub async fn parse(path: &str) -> Result<(), Error> {
         }
     };
 
+    config.resolve_auth_query_secrets()?;
     config.fill_up_auth_query_config();
     config.validate()?;
 
@@ -1488,6 +3578,21 @@ pub async fn reload_config(client_server_map: ClientServerMap) -> Result<bool, E
         Err(err) => error!("DNS cache reinitialization error: {:?}", err),
     };
 
+    if let Err(err) = crate::ban_events::start(
+        new_config.general.ban_event_sink.as_deref(),
+        new_config.general.ban_event_queue_size,
+    ) {
+        error!("Ban event sink error: {:?}", err);
+    }
+
+    if let Err(err) = crate::otel::start(
+        new_config.general.otlp_endpoint.as_deref(),
+        &new_config.general.otlp_service_name,
+        new_config.general.otlp_queue_size,
+    ) {
+        error!("OTLP exporter error: {:?}", err);
+    }
+
     if old_config != new_config {
         info!("Config changed, reloading");
         ConnectionPool::from_config(client_server_map).await?;
@@ -1574,6 +3679,390 @@ mod test {
         assert_eq!(get_config().general.auth_query_password, None);
     }
 
+    #[test]
+    fn test_log_format() {
+        assert_eq!(log_format("pgcat.toml"), Some(LogFormat::Text));
+        assert_eq!(log_format("does-not-exist.toml"), None);
+    }
+
+    #[test]
+    fn test_select_pool() {
+        let mut config = Config::default();
+
+        let mut pool = Pool::default();
+        pool.users.insert(
+            "0".to_string(),
+            User {
+                username: "app_user".to_string(),
+                ..User::default()
+            },
+        );
+        config.pools.insert("app_acme".to_string(), pool);
+
+        config.pool_selection_rules.push(PoolSelectionRule {
+            matcher: ClientMatcher {
+                database: "app".to_string(),
+                user: None,
+                parameter: "application_name".to_string(),
+                pattern: "^tenant_(\\w+)$".to_string(),
+                regex: CompiledRegex::default(),
+            },
+            pool: "app_$1".to_string(),
+        });
+
+        let mut parameters = HashMap::new();
+        parameters.insert("application_name".to_string(), "tenant_acme".to_string());
+
+        assert_eq!(
+            config.select_pool("app", "app_user", &parameters),
+            "app_acme"
+        );
+
+        // Only for the database in the rule.
+        assert_eq!(
+            config.select_pool("other", "app_user", &parameters),
+            "other"
+        );
+
+        // The user must be allowed in the pool.
+        assert_eq!(config.select_pool("app", "someone", &parameters), "app");
+
+        // The pool must exist.
+        parameters.insert("application_name".to_string(), "tenant_other".to_string());
+        assert_eq!(config.select_pool("app", "app_user", &parameters), "app");
+
+        // No parameter, no rule.
+        assert_eq!(
+            config.select_pool("app", "app_user", &HashMap::new()),
+            "app"
+        );
+    }
+
+    #[test]
+    fn test_max_prepared_statements_per_server() {
+        let mut pool = Pool {
+            max_prepared_statements: Some(100),
+            ..Default::default()
+        };
+
+        // Not tracked without the prepared statements cache.
+        assert_eq!(pool.max_prepared_statements_per_server(), 0);
+
+        pool.prepared_statements_cache_size = 500;
+        assert_eq!(pool.max_prepared_statements_per_server(), 100);
+
+        pool.max_prepared_statements = None;
+        assert_eq!(pool.max_prepared_statements_per_server(), 500);
+    }
+
+    #[test]
+    fn test_valid_label_name() {
+        assert!(Pool::valid_label_name("team"));
+        assert!(Pool::valid_label_name("_cost_center2"));
+
+        assert!(!Pool::valid_label_name(""));
+        assert!(!Pool::valid_label_name("2team"));
+        assert!(!Pool::valid_label_name("cost-center"));
+        assert!(!Pool::valid_label_name("__name__"));
+
+        // Already set by PgCat.
+        assert!(!Pool::valid_label_name("pool"));
+        assert!(!Pool::valid_label_name("host"));
+        assert!(!Pool::valid_label_name("outcome"));
+        assert!(!Pool::valid_label_name("le"));
+    }
+
+    #[test]
+    fn test_user_default_role() {
+        let mut user = User {
+            default_role: Some("replica".to_string()),
+            ..User::default()
+        };
+        assert!(user.validate().is_ok());
+
+        user.default_role = Some("standby".to_string());
+        assert!(user.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_cleanup_query() {
+        for query in [
+            "DISCARD ALL",
+            "RESET ALL; CLOSE ALL;",
+            "reset role; deallocate all; unlisten *",
+            "RESET ALL; SELECT pg_advisory_unlock_all()",
+        ] {
+            assert!(validate_cleanup_query(query).is_ok(), "{}", query);
+        }
+
+        for query in ["", " ; ", "DELETE FROM users", "RESET ALL; SELECT 1"] {
+            assert!(validate_cleanup_query(query).is_err(), "{}", query);
+        }
+    }
+
+    #[test]
+    fn test_client_matcher_regex() {
+        let rule = ConnectionRule {
+            matcher: ClientMatcher {
+                database: "*".to_string(),
+                user: None,
+                parameter: "application_name".to_string(),
+                pattern: "^web$".to_string(),
+                regex: CompiledRegex::default(),
+            },
+            action: ConnectionRuleAction::Allow,
+            message: None,
+        };
+        let fresh = rule.clone();
+
+        // Validating the config compiles the pattern, the config clones keep it.
+        rule.validate().unwrap();
+        assert!(rule.clone().matcher.regex.0.get().is_some());
+        assert!(fresh.matcher.regex.0.get().is_none());
+
+        // Both kinds of rules have the matcher's fields at the top level.
+        let pool_rule: PoolSelectionRule = toml::from_str(
+            r#"
+            parameter = "application_name"
+            pattern = "^tenant_(\\w+)$"
+            pool = "app_$1"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(pool_rule.matcher.database, "*");
+        assert_eq!(
+            toml::from_str::<PoolSelectionRule>(&toml::to_string(&pool_rule).unwrap()).unwrap(),
+            pool_rule
+        );
+        assert_eq!(
+            toml::from_str::<ConnectionRule>(&toml::to_string(&rule).unwrap()).unwrap(),
+            rule
+        );
+
+        // It doesn't make the config different on reload.
+        assert_eq!(
+            Config {
+                connection_rules: vec![rule],
+                ..Config::default()
+            },
+            Config {
+                connection_rules: vec![fresh],
+                ..Config::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_connection_denied_by() {
+        let mut config = Config::default();
+
+        let matcher = |database: &str, pattern: &str| ClientMatcher {
+            database: database.to_string(),
+            user: None,
+            parameter: "application_name".to_string(),
+            pattern: pattern.to_string(),
+            regex: CompiledRegex::default(),
+        };
+
+        config.connection_rules.push(ConnectionRule {
+            matcher: matcher("*", "^web$"),
+            action: ConnectionRuleAction::Allow,
+            message: None,
+        });
+        config.connection_rules.push(ConnectionRule {
+            matcher: matcher("app", ".*"),
+            action: ConnectionRuleAction::Deny,
+            message: Some("app is closed".to_string()),
+        });
+
+        let mut parameters = HashMap::new();
+        parameters.insert("application_name".to_string(), "web".to_string());
+
+        // The first matching rule wins.
+        assert!(config
+            .connection_denied_by("app", "app_user", &parameters)
+            .is_none());
+
+        parameters.insert("application_name".to_string(), "worker".to_string());
+        assert_eq!(
+            config
+                .connection_denied_by("app", "app_user", &parameters)
+                .and_then(|rule| rule.message.clone()),
+            Some("app is closed".to_string())
+        );
+
+        // A missing parameter is an empty string.
+        assert!(config
+            .connection_denied_by("app", "app_user", &HashMap::new())
+            .is_some());
+
+        // No matching rule, the client is let in.
+        assert!(config
+            .connection_denied_by("other", "app_user", &parameters)
+            .is_none());
+    }
+
+    #[test]
+    fn test_server_zone() {
+        let shard: Shard = toml::from_str(
+            r#"
+            database = "shard0"
+            servers = [["127.0.0.1", 5432, "primary"], ["localhost", 5432, "replica", "us-east-1a"]]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(shard.servers[0].zone, None);
+        assert_eq!(shard.servers[1].zone, Some("us-east-1a".to_string()));
+    }
+
+    #[test]
+    fn test_loc_shuffle() {
+        let pool: Pool = toml::from_str(
+            r#"
+            load_balancing_mode = "loc"
+            shards = {}
+            users = {}
+            "#,
+        )
+        .unwrap();
+        assert!(pool.loc_shuffle);
+        assert!(Pool::default().loc_shuffle);
+
+        let pool: Pool = toml::from_str(
+            r#"
+            load_balancing_mode = "loc"
+            loc_shuffle = false
+            shards = {}
+            users = {}
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            pool.load_balancing_mode,
+            LoadBalancingMode::LeastOutstandingConnections
+        );
+        assert!(!pool.loc_shuffle);
+    }
+
+    #[test]
+    fn test_server_weight() {
+        let shard: Shard = toml::from_str(
+            r#"
+            database = "shard0"
+            servers = [
+              ["127.0.0.1", 5432, "primary"],
+              { host = "localhost", port = 5432, role = "replica", weight = 3 },
+            ]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(shard.servers[0].weight, 1);
+        assert_eq!(shard.servers[1].weight, 3);
+        assert!(shard.validate().is_ok());
+    }
+
+    #[test]
+    fn test_shard_ranges() {
+        let shard = |range_start, range_end| Shard {
+            range_start,
+            range_end,
+            ..Default::default()
+        };
+
+        let mut pool = Pool {
+            sharding_function: ShardingFunction::RangeHash,
+            ..Default::default()
+        };
+
+        pool.shards = BTreeMap::from([
+            ("0".to_string(), shard(Some(i32::MIN), Some(-1))),
+            ("1".to_string(), shard(Some(0), Some(i32::MAX))),
+        ]);
+        assert!(pool.validate_shard_ranges().is_ok());
+
+        // Gap
+        pool.shards
+            .insert("1".to_string(), shard(Some(1), Some(i32::MAX)));
+        assert!(pool.validate_shard_ranges().is_err());
+
+        // Overlap
+        pool.shards
+            .insert("1".to_string(), shard(Some(-1), Some(i32::MAX)));
+        assert!(pool.validate_shard_ranges().is_err());
+
+        // Not all hashes covered
+        pool.shards
+            .insert("1".to_string(), shard(Some(0), Some(1000)));
+        assert!(pool.validate_shard_ranges().is_err());
+
+        // Missing range
+        pool.shards.insert("1".to_string(), shard(None, None));
+        assert!(pool.validate_shard_ranges().is_err());
+    }
+
+    #[test]
+    fn test_prewarmer_queries() {
+        let prewarmer: Prewarmer = toml::from_str(
+            r#"
+            enabled = true
+            queries = [
+              "SELECT pg_prewarm('a')",
+              { query = "SELECT pg_prewarm('b')", required = false, order = 1 },
+            ]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(prewarmer.pipeline_depth, 1);
+        assert_eq!(prewarmer.queries[0].query(), "SELECT pg_prewarm('a')");
+        assert!(prewarmer.queries[0].required());
+        assert_eq!(prewarmer.queries[0].order(), 0);
+        assert_eq!(prewarmer.queries[1].query(), "SELECT pg_prewarm('b')");
+        assert!(!prewarmer.queries[1].required());
+        assert_eq!(prewarmer.queries[1].order(), 1);
+    }
+
+    #[test]
+    fn test_healthcheck_query() {
+        let mut config = Config::default();
+        assert_eq!(config.general.healthcheck_query, ";");
+        assert!(config.validate().is_ok());
+
+        config.general.healthcheck_query = String::from(" ");
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_resolve_secret() {
+        assert_eq!(resolve_secret("password").unwrap(), "password");
+
+        std::env::set_var("PGCAT_TEST_AUTH_QUERY_PASSWORD", "from_env");
+        assert_eq!(
+            resolve_secret("${PGCAT_TEST_AUTH_QUERY_PASSWORD}").unwrap(),
+            "from_env"
+        );
+        assert!(resolve_secret("${PGCAT_TEST_UNSET_VARIABLE}").is_err());
+
+        let path = std::env::temp_dir().join("pgcat_test_auth_query_password");
+        std::fs::write(&path, "from_file\n").unwrap();
+        assert_eq!(
+            resolve_secret(&format!("file:{}", path.display())).unwrap(),
+            "from_file"
+        );
+        std::fs::remove_file(&path).unwrap();
+        assert!(resolve_secret(&format!("file:{}", path.display())).is_err());
+
+        let mut config = Config::default();
+        config.general.auth_query_user = Some(String::from("${PGCAT_TEST_AUTH_QUERY_PASSWORD}"));
+        config.resolve_auth_query_secrets().unwrap();
+        assert_eq!(
+            config.general.auth_query_user,
+            Some(String::from("from_env"))
+        );
+    }
+
     #[tokio::test]
     async fn test_serialize_configs() {
         parse("pgcat.toml").await.unwrap();
@@ -19,6 +19,8 @@ pub const SASL: i32 = 10;
 pub const SASL_CONTINUE: i32 = 11;
 pub const SASL_FINAL: i32 = 12;
 pub const SCRAM_SHA_256: &str = "SCRAM-SHA-256";
+pub const SCRAM_SHA_256_PLUS: &str = "SCRAM-SHA-256-PLUS";
+pub const SCRAM_ITERATIONS: u32 = 4096;
 pub const NONCE_LENGTH: usize = 24;
 
 // AuthenticationOk
@@ -17,8 +17,12 @@ pub enum Error {
     ServerAuthError(String, ServerIdentifier),
     BadConfig,
     AllServersDown,
+    AllServersBanned,
+    CheckoutTimeout,
+    PauseWaitTimeout,
+    MaxClientConnections(u32),
     ClientError(String),
-    TlsError,
+    TlsError(String),
     StatementTimeout,
     DNSCachedError(String),
     ShuttingDown,
@@ -30,6 +34,18 @@ pub enum Error {
     QueryRouterError(String),
     InvalidShardId(usize),
     PreparedStatementError,
+    MaxConcurrentQueries,
+    MaxConnectionsPerSecond(u32),
+    CheckoutCancelled,
+    MissingExtensions(String),
+    PoolBusy,
+    UnknownServer(String),
+    ServerUnavailable(String),
+    /// The server asked for by name can't take the query, e.g. a replica for a write.
+    /// The server name, its role and the role the query needs.
+    WrongServerRole(String, String, String),
+    /// The server refused the connection for now, e.g. too many connections or starting up.
+    ServerTemporarilyUnavailable(String),
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -119,6 +135,76 @@ impl std::fmt::Display for Error {
             &Error::ServerAuthError(error, server_identifier) => {
                 write!(f, "{} for {}", error, server_identifier,)
             }
+            &Error::MaxConcurrentQueries => {
+                write!(f, "max_concurrent_queries limit reached")
+            }
+            &Error::MaxConnectionsPerSecond(max_connections_per_second) => {
+                write!(
+                    f,
+                    "max_connections_per_second limit of {} reached for this pool, retry later",
+                    max_connections_per_second
+                )
+            }
+            &Error::MissingExtensions(error) => {
+                write!(f, "missing required extensions: {}", error)
+            }
+            &Error::AllServersBanned => {
+                write!(f, "all servers are banned or out of rotation, retry later")
+            }
+            &Error::CheckoutTimeout => {
+                write!(
+                    f,
+                    "timed out waiting for a server connection, the pool may be too small"
+                )
+            }
+            &Error::MaxClientConnections(max_client_connections) => {
+                write!(
+                    f,
+                    "max_client_connections limit of {} reached for this application_name",
+                    max_client_connections
+                )
+            }
+            &Error::ShuttingDown => {
+                write!(f, "pgcat is shutting down")
+            }
+            &Error::PoolBusy => {
+                write!(f, "all server connections are busy, retry later")
+            }
+            &Error::UnknownServer(name) => {
+                write!(f, "server {} is not in this pool", name)
+            }
+            &Error::WrongServerRole(name, role, required) => {
+                write!(
+                    f,
+                    "server {} is a {} but the query needs a {}",
+                    name, role, required
+                )
+            }
+            &Error::ServerUnavailable(name) => {
+                write!(
+                    f,
+                    "server {} is banned, out of rotation or lagging, retry later",
+                    name
+                )
+            }
+            &Error::TlsError(error) => {
+                write!(f, "TLS error: {}", error)
+            }
+            &Error::QueryRouterParserError(error) => {
+                write!(f, "{}", error)
+            }
+            &Error::ServerTemporarilyUnavailable(error) => {
+                write!(f, "server temporarily unavailable: {}", error)
+            }
+            &Error::PauseWaitTimeout => {
+                write!(f, "server is in maintenance, retry later")
+            }
+            &Error::CheckoutCancelled => {
+                write!(
+                    f,
+                    "client disconnected while waiting for a server connection"
+                )
+            }
 
             // The rest can use Debug.
             err => write!(f, "{:?}", err),
@@ -1,5 +1,6 @@
 pub mod admin;
 pub mod auth_passthrough;
+pub mod ban_events;
 pub mod client;
 pub mod cmd_args;
 pub mod config;
@@ -9,6 +10,7 @@ pub mod errors;
 pub mod logger;
 pub mod messages;
 pub mod mirrors;
+pub mod otel;
 pub mod plugins;
 pub mod pool;
 pub mod prometheus;
@@ -1,4 +1,5 @@
 use crate::cmd_args::{Args, LogFormat};
+use crate::config;
 use tracing_subscriber;
 use tracing_subscriber::EnvFilter;
 
@@ -12,7 +13,13 @@ pub fn init(args: &Args) {
         .with_env_filter(filter)
         .with_ansi(!args.no_color);
 
-    match args.log_format {
+    let log_format = match (&args.log_format, config::log_format(&args.config_file)) {
+        (Some(log_format), _) => log_format.clone(),
+        (None, Some(config::LogFormat::Json)) => LogFormat::Structured,
+        (None, _) => LogFormat::Text,
+    };
+
+    match log_format {
         LogFormat::Structured => trace_sub.json().init(),
         LogFormat::Debug => trace_sub.pretty().init(),
         _ => trace_sub.init(),
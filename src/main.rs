@@ -60,12 +60,14 @@ use std::str::FromStr;
 use std::sync::Arc;
 use tokio::sync::broadcast;
 
+use pgcat::ban_events;
 use pgcat::cmd_args;
-use pgcat::config::{get_config, reload_config, VERSION};
+use pgcat::config::{get_config, get_role_check_interval, reload_config, VERSION};
 use pgcat::dns_cache;
 use pgcat::logger;
 use pgcat::messages::configure_socket;
-use pgcat::pool::{ClientServerMap, ConnectionPool};
+use pgcat::otel;
+use pgcat::pool::{get_all_pools, ClientServerMap, ConnectionPool};
 use pgcat::prometheus::start_metric_server;
 use pgcat::stats::{Collector, Reporter, REPORTER};
 
@@ -80,6 +82,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         std::process::exit(exitcode::CONFIG);
     }
 
+    if let Some(ref path) = args.check_config {
+        std::process::exit(check_config(path));
+    }
+
     // Create a transient runtime for loading the config for the first time.
     {
         let runtime = Builder::new_multi_thread().worker_threads(1).build()?;
@@ -164,6 +170,41 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             stats_collector.collect().await;
         });
 
+        if let Err(err) = ban_events::start(
+            config.general.ban_event_sink.as_deref(),
+            config.general.ban_event_queue_size,
+        ) {
+            error!("Ban event sink error: {:?}", err);
+            std::process::exit(exitcode::CONFIG);
+        }
+
+        if let Err(err) = otel::start(
+            config.general.otlp_endpoint.as_deref(),
+            &config.general.otlp_service_name,
+            config.general.otlp_queue_size,
+        ) {
+            error!("OTLP exporter error: {:?}", err);
+            std::process::exit(exitcode::CONFIG);
+        }
+
+        // The interval is read on every run, it can be set or changed on reload.
+        tokio::task::spawn(async move {
+            loop {
+                match get_role_check_interval() {
+                    Some(interval) => {
+                        tokio::time::sleep(tokio::time::Duration::from_millis(interval)).await;
+
+                        for pool in get_all_pools().values() {
+                            pool.check_roles().await;
+                        }
+                    }
+
+                    // Disabled, look again in a second.
+                    None => tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await,
+                }
+            }
+        });
+
         info!("Config autoreloader: {}", match config.general.autoreload {
             Some(interval) => format!("{} ms", interval),
             None => "disabled".into(),
@@ -222,17 +263,33 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     get_config().show();
                 },
 
-                // Initiate graceful shutdown sequence on sig int
-                _ = interrupt_signal.recv() => {
-                    info!("Got SIGINT");
+                // Initiate graceful shutdown sequence on SIGINT or SIGTERM.
+                // A second SIGTERM closes right away.
+                signal = async {
+                    tokio::select! {
+                        _ = interrupt_signal.recv() => "SIGINT",
+                        _ = term_signal.recv() => "SIGTERM",
+                    }
+                } => {
+                    info!("Got {}", signal);
 
                     // Don't want this to happen more than once
                     if admin_only {
+                        if signal == "SIGTERM" {
+                            info!("Closing with {} clients active", total_clients);
+                            break;
+                        }
+
                         continue;
                     }
 
                     admin_only = true;
 
+                    // Clients in a transaction finish it, no new transactions start.
+                    for pool in get_all_pools().values() {
+                        pool.drain();
+                    }
+
                     // Broadcast that client tasks need to finish
                     let _ = shutdown_tx.send(());
                     let exit_tx = exit_tx.clone();
@@ -254,11 +311,6 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     });
                 },
 
-                _ = term_signal.recv() => {
-                    info!("Got SIGTERM, closing with {} clients active", total_clients);
-                    break;
-                },
-
                 new_client = listener.accept() => {
                     let (socket, addr) = match new_client {
                         Ok((socket, addr)) => (socket, addr),
@@ -338,3 +390,117 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     });
     Ok(())
 }
+
+/// Load the config, build the pools and connect to every server,
+/// printing which ones are reachable. Returns the exit code.
+fn check_config(path: &str) -> i32 {
+    let runtime = match Builder::new_multi_thread().enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            error!("Could not start the runtime: {:?}", err);
+            return exitcode::SOFTWARE;
+        }
+    };
+
+    runtime.block_on(async {
+        if let Err(err) = pgcat::config::parse(path).await {
+            error!("Config parse error: {:?}", err);
+            return exitcode::CONFIG;
+        }
+
+        let client_server_map: ClientServerMap = Arc::new(Mutex::new(HashMap::new()));
+
+        // The servers are checked below, don't validate them twice.
+        if let Err(err) = ConnectionPool::from_config_unvalidated(client_server_map).await {
+            error!("Pool error: {:?}", err);
+            return exitcode::CONFIG;
+        }
+
+        let mut pools = get_all_pools().into_iter().collect::<Vec<_>>();
+        pools.sort_by(|a, b| (&a.0.db, &a.0.user).cmp(&(&b.0.db, &b.0.user)));
+
+        let mut healthy = true;
+
+        for (identifier, pool) in pools {
+            println!("[pool: {}][user: {}]", identifier.db, identifier.user);
+
+            let results = pool.validate_servers().await;
+
+            for shard in 0..pool.shards() {
+                let mut reachable = false;
+
+                for (address, result) in
+                    results.iter().filter(|(address, _)| address.shard == shard)
+                {
+                    let status = match result {
+                        Ok(true) => {
+                            reachable = true;
+                            String::from("ok")
+                        }
+                        Ok(false) => String::from("unreachable"),
+                        Err(err) => {
+                            healthy = false;
+                            err.to_string()
+                        }
+                    };
+
+                    println!(
+                        "  shard {} {} {}:{} {}",
+                        shard, address.role, address.host, address.port, status
+                    );
+                }
+
+                if !reachable {
+                    healthy = false;
+                    println!("  shard {} has no reachable server", shard);
+                }
+            }
+        }
+
+        if healthy {
+            exitcode::OK
+        } else {
+            exitcode::UNAVAILABLE
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check_config_exit_codes() {
+        let path = std::env::temp_dir().join("pgcat_test_check_config.toml");
+
+        assert_eq!(
+            check_config(path.join("does-not-exist").to_str().unwrap()),
+            exitcode::CONFIG
+        );
+
+        // Nothing listens on port 1.
+        std::fs::write(
+            &path,
+            r#"
+[general]
+host = "127.0.0.1"
+port = 6432
+admin_username = "admin"
+admin_password = "admin"
+
+[pools.check.users.0]
+username = "check"
+password = "check"
+pool_size = 1
+
+[pools.check.shards.0]
+servers = [["127.0.0.1", 1, "primary"]]
+database = "check"
+"#,
+        )
+        .unwrap();
+        assert_eq!(check_config(path.to_str().unwrap()), exitcode::UNAVAILABLE);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -11,7 +11,7 @@ use crate::client::PREPARED_STATEMENT_COUNTER;
 use crate::config::get_config;
 use crate::errors::Error;
 
-use crate::constants::MESSAGE_TERMINATOR;
+use crate::constants::{MESSAGE_TERMINATOR, SASL};
 use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::ffi::CString;
@@ -87,6 +87,42 @@ where
     Ok(salt)
 }
 
+/// Ask the client to authenticate with one of these SASL mechanisms.
+pub async fn sasl_mechanisms<S>(stream: &mut S, mechanisms: &[&str]) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let mut res = BytesMut::new();
+    res.put_u8(b'R');
+    res.put_i32(0);
+    res.put_i32(SASL);
+    for mechanism in mechanisms {
+        res.put_slice(mechanism.as_bytes());
+        res.put_u8(0);
+    }
+    res.put_u8(0);
+
+    let len = res.len() as i32 - 1;
+    res[1..5].copy_from_slice(&len.to_be_bytes());
+
+    write_all(stream, res).await
+}
+
+/// Send SASL challenge or outcome data to the client,
+/// `kind` is SASL_CONTINUE or SASL_FINAL.
+pub async fn sasl_data<S>(stream: &mut S, kind: i32, data: &[u8]) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let mut res = BytesMut::new();
+    res.put_u8(b'R');
+    res.put_i32(mem::size_of::<i32>() as i32 * 2 + data.len() as i32);
+    res.put_i32(kind);
+    res.put_slice(data);
+
+    write_all(stream, res).await
+}
+
 /// Give the client the process_id and secret we generated
 /// used in query cancellation.
 pub async fn backend_key_data<S>(
@@ -116,6 +152,36 @@ pub fn simple_query(query: &str) -> BytesMut {
     res
 }
 
+/// Check if a `Q`: Query or `P`: Parse message is a COPY statement.
+/// Best effort, only the first keyword of the query is looked at.
+pub fn is_copy_query(message: &[u8]) -> bool {
+    if message.len() < 5 {
+        return false;
+    }
+
+    let query = match message[0] {
+        b'Q' => &message[5..],
+
+        // The query follows the statement name.
+        b'P' => match message[5..].iter().position(|&byte| byte == 0) {
+            Some(end) => &message[5 + end + 1..],
+            None => return false,
+        },
+
+        _ => return false,
+    };
+
+    let query = String::from_utf8_lossy(query);
+    let mut keywords = query
+        .trim_start()
+        .split(|c: char| c.is_whitespace() || c == '\0' || c == ';');
+
+    match keywords.next() {
+        Some(keyword) => keyword.eq_ignore_ascii_case("copy"),
+        None => false,
+    }
+}
+
 /// Tell the client we're ready for another query.
 pub async fn send_ready_for_query<S>(stream: &mut S) -> Result<(), Error>
 where
@@ -126,7 +192,12 @@ where
 
 /// Send the startup packet the server. We're pretending we're a Pg client.
 /// This tells the server which user we are and what database we want.
-pub async fn startup<S>(stream: &mut S, user: &str, database: &str) -> Result<(), Error>
+pub async fn startup<S>(
+    stream: &mut S,
+    user: &str,
+    database: &str,
+    read_only: bool,
+) -> Result<(), Error>
 where
     S: tokio::io::AsyncWrite + std::marker::Unpin,
 {
@@ -147,6 +218,12 @@ where
     bytes.put(&b"database\0"[..]);
     bytes.put_slice(database.as_bytes());
     bytes.put_u8(0);
+
+    // Writes fail for the whole session, RESET ALL keeps it.
+    if read_only {
+        bytes.put(&b"default_transaction_read_only\0on\0"[..]);
+    }
+
     bytes.put_u8(0); // Null terminator
 
     let len = bytes.len() as i32 + 4i32;
@@ -220,7 +297,15 @@ pub fn parse_params(mut bytes: BytesMut) -> Result<HashMap<String, String>, Erro
 
 /// Parse StartupMessage parameters.
 /// e.g. user, database, application_name, etc.
-pub fn parse_startup(bytes: BytesMut) -> Result<HashMap<String, String>, Error> {
+pub fn parse_startup(
+    bytes: BytesMut,
+    max_parameters: usize,
+) -> Result<HashMap<String, String>, Error> {
+    // Every parameter is a pair of null-terminated strings.
+    if bytes.iter().filter(|byte| **byte == 0).count() / 2 > max_parameters {
+        return Err(Error::ClientBadStartup);
+    }
+
     let result = parse_params(bytes)?;
 
     // Minimum required parameters
@@ -232,6 +317,18 @@ pub fn parse_startup(bytes: BytesMut) -> Result<HashMap<String, String>, Error>
     Ok(result)
 }
 
+/// Check if the client asked for a replication connection in its startup parameters,
+/// e.g. `replication=database` for logical or `replication=true` for physical replication.
+pub fn is_replication_startup(parameters: &HashMap<String, String>) -> bool {
+    match parameters.get("replication") {
+        Some(replication) => !matches!(
+            replication.to_lowercase().as_str(),
+            "false" | "off" | "no" | "0"
+        ),
+        None => false,
+    }
+}
+
 /// Create md5 password hash given a salt.
 pub fn md5_hash_password(user: &str, password: &str, salt: &[u8]) -> Vec<u8> {
     let mut md5 = Md5::new();
@@ -336,19 +433,48 @@ pub async fn error_response_terminal<S>(stream: &mut S, message: &str) -> Result
 where
     S: tokio::io::AsyncWrite + std::marker::Unpin,
 {
+    // system_error, see Appendix A.
+    error_response_with_code(stream, message, "58000").await
+}
+
+/// Send a custom error message with a specific error code to the client,
+/// e.g. one the client knows it can retry.
+pub async fn error_response_with_code<S>(
+    stream: &mut S,
+    message: &str,
+    code: &str,
+) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    write_all_half(stream, &error_message("FATAL", message, code)).await
+}
+
+/// Send an error that only fails the current query, with a specific error code,
+/// and tell the client we are ready for the next query. The session stays usable.
+pub async fn query_error_response<S>(stream: &mut S, message: &str, code: &str) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    write_all_half(stream, &error_message("ERROR", message, code)).await?;
+    send_ready_for_query(stream).await
+}
+
+/// Create an ErrorResponse message with the given severity and error code.
+fn error_message(severity: &str, message: &str, code: &str) -> BytesMut {
     let mut error = BytesMut::new();
 
     // Error level
     error.put_u8(b'S');
-    error.put_slice(&b"FATAL\0"[..]);
+    error.put_slice(format!("{}\0", severity).as_bytes());
 
     // Error level (non-translatable)
     error.put_u8(b'V');
-    error.put_slice(&b"FATAL\0"[..]);
+    error.put_slice(format!("{}\0", severity).as_bytes());
 
     // Error code: not sure how much this matters.
     error.put_u8(b'C');
-    error.put_slice(&b"58000\0"[..]); // system_error, see Appendix A.
+    error.put_slice(format!("{}\0", code).as_bytes());
 
     // The short error message.
     error.put_u8(b'M');
@@ -364,7 +490,7 @@ where
     res.put_i32(error.len() as i32 + 4);
     res.put(error);
 
-    write_all_half(stream, &res).await
+    res
 }
 
 pub async fn wrong_password<S>(stream: &mut S, user: &str) -> Result<(), Error>
@@ -855,6 +981,17 @@ impl TryFrom<&BytesMut> for Parse {
     }
 }
 
+impl Parse {
+    pub fn query(&self) -> &str {
+        &self.query
+    }
+
+    /// The same statement with another query.
+    pub fn with_query(self, query: String) -> Parse {
+        Parse { query, ..self }
+    }
+}
+
 impl TryFrom<Parse> for BytesMut {
     type Error = Error;
 
@@ -1431,7 +1568,11 @@ impl PgErrorMsg {
 
 #[cfg(test)]
 mod tests {
-    use crate::messages::PgErrorMsg;
+    use crate::errors::Error;
+    use crate::messages::{
+        is_copy_query, is_replication_startup, parse_startup, simple_query, startup, PgErrorMsg,
+    };
+    use bytes::{BufMut, BytesMut};
     use log::{error, info};
 
     fn field(kind: char, content: &str) -> Vec<u8> {
@@ -1545,4 +1686,77 @@ mod tests {
             PgErrorMsg::parse(&only_mandatory_msg).unwrap()
         );
     }
+
+    #[test]
+    fn copy_query_detection() {
+        assert!(is_copy_query(&simple_query(
+            "COPY copy_test_table FROM STDIN CSV"
+        )));
+        assert!(is_copy_query(&simple_query(
+            "  copy copy_test_table TO STDOUT"
+        )));
+        assert!(is_copy_query(&simple_query("\n\tCoPy t FROM STDIN")));
+
+        assert!(!is_copy_query(&simple_query("SELECT 'COPY'")));
+        assert!(!is_copy_query(&simple_query("copycat")));
+        assert!(!is_copy_query(&simple_query("")));
+        assert!(!is_copy_query(b"P"));
+
+        let parse = |name: &str, query: &str| {
+            let mut message = BytesMut::new();
+            message.put_u8(b'P');
+            message.put_i32(4 + name.len() as i32 + 1 + query.len() as i32 + 1 + 2);
+            message.put_slice(format!("{}\0{}\0", name, query).as_bytes());
+            message.put_i16(0);
+            message
+        };
+
+        assert!(is_copy_query(&parse("", "COPY t FROM STDIN")));
+        assert!(is_copy_query(&parse("copy_stmt", " copy t TO STDOUT")));
+        assert!(!is_copy_query(&parse("copy", "SELECT 1")));
+    }
+
+    #[test]
+    fn startup_parameters_limit() {
+        let mut startup = BytesMut::from(&b"user\0postgres\0database\0postgres\0"[..]);
+        startup.extend_from_slice(b"\0");
+
+        assert!(parse_startup(startup.clone(), 2).is_ok());
+        assert_eq!(parse_startup(startup, 1), Err(Error::ClientBadStartup));
+    }
+
+    #[tokio::test]
+    async fn read_only_startup() {
+        for read_only in [false, true] {
+            let mut message = Vec::new();
+            startup(&mut message, "postgres", "db", read_only)
+                .await
+                .unwrap();
+
+            // Skip the length and the protocol number.
+            let parameters = parse_startup(BytesMut::from(&message[8..]), 10).unwrap();
+            assert_eq!(parameters["database"], "db");
+            assert_eq!(
+                parameters.contains_key("default_transaction_read_only"),
+                read_only
+            );
+        }
+    }
+
+    #[test]
+    fn replication_startup_detection() {
+        let mut parameters = std::collections::HashMap::new();
+        parameters.insert("user".to_string(), "postgres".to_string());
+        assert!(!is_replication_startup(&parameters));
+
+        for value in ["database", "true", "on", "1", "YES"] {
+            parameters.insert("replication".to_string(), value.to_string());
+            assert!(is_replication_startup(&parameters));
+        }
+
+        for value in ["false", "off", "0", "NO"] {
+            parameters.insert("replication".to_string(), value.to_string());
+            assert!(!is_replication_startup(&parameters));
+        }
+    }
 }
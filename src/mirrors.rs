@@ -1,3 +1,4 @@
+use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
 
 /// A mirrored PostgreSQL client.
@@ -5,9 +6,11 @@ use std::sync::Arc;
 use bb8::Pool;
 use bytes::{Bytes, BytesMut};
 use parking_lot::RwLock;
+use rand::{thread_rng, Rng};
 
 use crate::config::{get_config, Address, Role, User};
-use crate::pool::{ClientServerMap, ServerPool};
+use crate::pool::{ClientServerMap, ServerPool, ServerPoolSettings};
+use crate::tls::ServerTls;
 use log::{error, info, trace, warn};
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
@@ -23,13 +26,13 @@ impl MirroredClient {
     async fn create_pool(&self) -> Pool<ServerPool> {
         let config = get_config();
         let default = std::time::Duration::from_millis(10_000).as_millis() as u64;
-        let (connection_timeout, idle_timeout, _cfg, prepared_statement_cache_size) =
+        let (connection_timeout, idle_timeout, pool_config, prepared_statement_cache_size) =
             match config.pools.get(&self.address.pool_name) {
                 Some(cfg) => (
                     cfg.connect_timeout.unwrap_or(default),
                     cfg.idle_timeout.unwrap_or(default),
                     cfg.clone(),
-                    cfg.prepared_statements_cache_size,
+                    cfg.max_prepared_statements_per_server(),
                 ),
                 None => (default, default, crate::config::Pool::default(), 0),
             };
@@ -40,10 +43,17 @@ impl MirroredClient {
             self.database.as_str(),
             ClientServerMap::default(),
             Arc::new(RwLock::new(None)),
-            None,
-            true,
-            false,
-            prepared_statement_cache_size,
+            Arc::new(AtomicU64::new(0)),
+            ServerPoolSettings {
+                prepared_statement_cache_size,
+                min_server_auth_method: pool_config.min_server_auth_method,
+                server_tls: ServerTls::from_config(&config, &self.address.pool_name)
+                    .unwrap_or_else(|err| {
+                        warn!("Mirror server TLS settings are invalid: {:?}", err);
+                        None
+                    }),
+                ..Default::default()
+            },
         );
 
         Pool::builder()
@@ -119,6 +129,11 @@ impl MirroredClient {
 pub struct MirroringManager {
     pub byte_senders: Vec<Sender<Bytes>>,
     pub disconnect_senders: Vec<Sender<()>>,
+    /// Share of the transactions each mirror receives, in the same order as `byte_senders`.
+    pub sample_rates: Vec<f64>,
+    /// Mirrors picked for the current transaction, drawn with its first message
+    /// so its COPY data, Parse/Bind/Execute and BEGIN/COMMIT are never split.
+    sampled: Option<Vec<bool>>,
 }
 impl MirroringManager {
     pub fn from_addresses(
@@ -128,6 +143,7 @@ impl MirroringManager {
     ) -> MirroringManager {
         let mut byte_senders: Vec<Sender<Bytes>> = vec![];
         let mut exit_senders: Vec<Sender<()>> = vec![];
+        let mut sample_rates: Vec<f64> = vec![];
 
         addresses.iter().for_each(|mirror| {
             let (bytes_tx, bytes_rx) = channel::<Bytes>(10);
@@ -143,12 +159,15 @@ impl MirroringManager {
             };
             exit_senders.push(exit_tx);
             byte_senders.push(bytes_tx);
+            sample_rates.push(mirror.mirror_sample_rate.0);
             client.start();
         });
 
         Self {
             byte_senders,
             disconnect_senders: exit_senders,
+            sample_rates,
+            sampled: None,
         }
     }
 
@@ -163,15 +182,35 @@ impl MirroringManager {
         {
             return;
         }
-        let immutable_bytes = bytes.clone().freeze();
-        self.byte_senders.iter_mut().for_each(|sender| {
-            match sender.try_send(immutable_bytes.clone()) {
-                Ok(_) => {}
-                Err(err) => {
-                    warn!("Failed to send bytes to a mirror channel {}", err);
-                }
-            }
+        // Each transaction is sampled on its own, so every connection mirrors
+        // roughly the same share of its traffic.
+        let sample_rates = &self.sample_rates;
+        let sampled = self.sampled.get_or_insert_with(|| {
+            let mut rng = thread_rng();
+            sample_rates
+                .iter()
+                .map(|sample_rate| *sample_rate >= 1.0 || rng.gen_bool(*sample_rate))
+                .collect()
         });
+        let immutable_bytes = bytes.clone().freeze();
+        self.byte_senders
+            .iter_mut()
+            .zip(sampled.iter())
+            .filter(|(_, sampled)| **sampled)
+            .for_each(
+                |(sender, _)| match sender.try_send(immutable_bytes.clone()) {
+                    Ok(_) => {}
+                    Err(err) => {
+                        warn!("Failed to send bytes to a mirror channel {}", err);
+                    }
+                },
+            );
+    }
+
+    /// The server is idle again, the next message starts a new transaction
+    /// and gets its own sample.
+    pub fn transaction_over(&mut self) {
+        self.sampled = None;
     }
 
     pub fn disconnect(&mut self) {
@@ -188,3 +227,33 @@ impl MirroringManager {
             });
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sample_per_transaction() {
+        let (bytes_tx, mut bytes_rx) = channel::<Bytes>(100);
+        let mut manager = MirroringManager {
+            byte_senders: vec![bytes_tx],
+            disconnect_senders: vec![],
+            sample_rates: vec![0.5],
+            sampled: None,
+        };
+
+        for _ in 0..20 {
+            // E.g. Parse, Bind and Execute of the same transaction.
+            for _ in 0..3 {
+                manager.send(&BytesMut::from(&b"P"[..]));
+            }
+            manager.transaction_over();
+
+            let mut received = 0;
+            while bytes_rx.try_recv().is_ok() {
+                received += 1;
+            }
+            assert!(received == 0 || received == 3);
+        }
+    }
+}
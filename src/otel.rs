@@ -0,0 +1,358 @@
+//! Export query routing decisions as OpenTelemetry spans, over OTLP/HTTP with JSON.
+use arc_swap::ArcSwapOption;
+use hyper::Uri;
+use log::{error, info, warn};
+use once_cell::sync::Lazy;
+use rand::{thread_rng, RngCore};
+use regex::Regex;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+use crate::ban_events::post_json;
+use crate::config::Address;
+use crate::errors::Error;
+use crate::stats::SlowCheckout;
+
+/// The running exporter, replaced when its settings change on reload.
+static EXPORTER: Lazy<ArcSwapOption<Exporter>> = Lazy::new(|| ArcSwapOption::from(None));
+
+/// Settings of the running exporter and the queue feeding it.
+struct Exporter {
+    endpoint: String,
+    service_name: String,
+    queue_size: usize,
+    spans: Sender<RoutingSpan>,
+}
+
+/// Spans dropped because the exporter couldn't keep up.
+static DROPPED_SPANS: AtomicU64 = AtomicU64::new(0);
+
+/// Most spans sent to the collector in one request.
+const MAX_BATCH_SIZE: usize = 512;
+
+/// W3C trace context, e.g. `/* traceparent: 00-<trace id>-<span id>-01 */`
+/// or sqlcommenter's `/*traceparent='00-<trace id>-<span id>-01'*/`.
+static TRACEPARENT: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"traceparent\s*[:=]\s*'?00-([0-9a-f]{32})-([0-9a-f]{16})-[0-9a-f]{2}").unwrap()
+});
+
+/// Trace and span the client's query belongs to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceParent {
+    pub trace_id: String,
+    pub span_id: String,
+}
+
+impl TraceParent {
+    /// Find the trace context in a query comment, if any.
+    pub fn from_query(query: &str) -> Option<TraceParent> {
+        TRACEPARENT.captures(query).map(|captures| TraceParent {
+            trace_id: captures[1].to_string(),
+            span_id: captures[2].to_string(),
+        })
+    }
+}
+
+/// Where a client query was sent and how long it took to get a server for it.
+/// Only the first query after a checkout waited for the server, the ones after it
+/// reuse the connection.
+#[derive(Debug, Clone)]
+pub struct RoutingSpan {
+    pub parent: Option<TraceParent>,
+    pub end: SystemTime,
+    pub pool_name: String,
+    pub username: String,
+    pub shard: usize,
+    pub role: String,
+    pub address: String,
+    pub host: String,
+    pub port: u16,
+    pub health_check: bool,
+    pub candidates_tried: usize,
+    /// Microseconds.
+    pub checkout_time: u64,
+    /// Microseconds.
+    pub queue_time: u64,
+}
+
+impl RoutingSpan {
+    pub fn new(query: &[u8], address: &Address, checkout: &SlowCheckout) -> RoutingSpan {
+        RoutingSpan {
+            parent: TraceParent::from_query(&String::from_utf8_lossy(query)),
+            end: SystemTime::now(),
+            pool_name: address.pool_name.clone(),
+            username: address.username.clone(),
+            shard: address.shard,
+            role: address.role.to_string(),
+            address: checkout.address.clone(),
+            host: address.host.clone(),
+            port: address.port,
+            health_check: checkout.health_check,
+            candidates_tried: checkout.candidates_tried,
+            checkout_time: checkout.checkout_time,
+            queue_time: checkout.queue_time,
+        }
+    }
+
+    /// The span in the OTLP JSON encoding.
+    fn to_otlp(&self) -> Value {
+        let end = self.end.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let start = end.saturating_sub(Duration::from_micros(self.checkout_time));
+
+        let (trace_id, parent_span_id) = match &self.parent {
+            Some(parent) => (parent.trace_id.clone(), parent.span_id.clone()),
+            None => (random_id(16), String::new()),
+        };
+
+        json!({
+            "traceId": trace_id,
+            "spanId": random_id(8),
+            "parentSpanId": parent_span_id,
+            "name": "pgcat query",
+            "kind": 1, // SPAN_KIND_INTERNAL
+            "startTimeUnixNano": start.as_nanos().to_string(),
+            "endTimeUnixNano": end.as_nanos().to_string(),
+            "attributes": [
+                string_attribute("db.system", "postgresql"),
+                string_attribute("db.user", &self.username),
+                string_attribute("pgcat.pool", &self.pool_name),
+                int_attribute("pgcat.shard", self.shard as u64),
+                string_attribute("pgcat.role", &self.role),
+                string_attribute("pgcat.address", &self.address),
+                string_attribute("server.address", &self.host),
+                int_attribute("server.port", self.port as u64),
+                json!({ "key": "pgcat.health_check", "value": { "boolValue": self.health_check } }),
+                int_attribute("pgcat.candidates_tried", self.candidates_tried as u64),
+                int_attribute("pgcat.checkout_time_us", self.checkout_time),
+                int_attribute("pgcat.queue_time_us", self.queue_time),
+            ],
+        })
+    }
+}
+
+fn string_attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+fn int_attribute(key: &str, value: u64) -> Value {
+    // 64-bit integers are strings in the protobuf JSON mapping.
+    json!({ "key": key, "value": { "intValue": value.to_string() } })
+}
+
+/// Random hex trace or span ID of `bytes` bytes.
+fn random_id(bytes: usize) -> String {
+    let mut id = vec![0u8; bytes];
+    thread_rng().fill_bytes(&mut id);
+    id.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Request body for the OTLP/HTTP traces endpoint.
+fn export_request(spans: &[RoutingSpan], service_name: &str) -> Value {
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [string_attribute("service.name", service_name)],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "pgcat", "version": crate::config::VERSION },
+                "spans": spans.iter().map(|span| span.to_otlp()).collect::<Vec<Value>>(),
+            }],
+        }],
+    })
+}
+
+/// Spans are exported, checked before building one so there's no cost when disabled.
+pub fn enabled() -> bool {
+    EXPORTER.load().is_some()
+}
+
+/// Send the span to the collector. Never waits, the span is dropped
+/// if the exporter is behind.
+pub fn emit(span: RoutingSpan) {
+    if let Some(exporter) = EXPORTER.load().as_ref() {
+        if exporter.spans.try_send(span).is_err() {
+            DROPPED_SPANS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Number of spans dropped because the queue was full.
+pub fn dropped() -> u64 {
+    DROPPED_SPANS.load(Ordering::Relaxed)
+}
+
+/// Parse the OTLP/HTTP traces endpoint, e.g. `http://localhost:4318/v1/traces`.
+pub fn endpoint(endpoint: &str) -> Result<Uri, Error> {
+    match endpoint.parse::<Uri>() {
+        Ok(uri) if uri.scheme_str() == Some("http") && uri.host().is_some() => Ok(uri),
+        _ => {
+            error!(
+                "Invalid otlp_endpoint: {}, expected an http:// URL",
+                endpoint
+            );
+            Err(Error::BadConfig)
+        }
+    }
+}
+
+/// Start, restart or stop exporting spans to match the config.
+/// Nothing changes if the settings are the same as the running exporter's.
+/// The old exporter sends the spans it has queued and stops.
+pub fn start(
+    otlp_endpoint: Option<&str>,
+    service_name: &str,
+    queue_size: usize,
+) -> Result<(), Error> {
+    let otlp_endpoint = match otlp_endpoint {
+        Some(otlp_endpoint) => otlp_endpoint,
+        None => {
+            if EXPORTER.swap(None).is_some() {
+                info!("Stopped exporting query routing spans");
+            }
+            return Ok(());
+        }
+    };
+
+    if let Some(exporter) = EXPORTER.load().as_ref() {
+        if exporter.endpoint == otlp_endpoint
+            && exporter.service_name == service_name
+            && exporter.queue_size == queue_size
+        {
+            return Ok(());
+        }
+    }
+
+    let uri = endpoint(otlp_endpoint)?;
+    let (sender, receiver) = mpsc::channel(queue_size.max(1));
+
+    info!("Exporting query routing spans to {}", otlp_endpoint);
+    tokio::task::spawn(export(receiver, uri, service_name.to_string()));
+
+    EXPORTER.store(Some(Arc::new(Exporter {
+        endpoint: otlp_endpoint.to_string(),
+        service_name: service_name.to_string(),
+        queue_size,
+        spans: sender,
+    })));
+
+    Ok(())
+}
+
+async fn export(mut receiver: Receiver<RoutingSpan>, uri: Uri, service_name: String) {
+    while let Some(span) = receiver.recv().await {
+        // Send whatever piled up while the last request was in flight along with it.
+        let mut spans = vec![span];
+        while spans.len() < MAX_BATCH_SIZE {
+            match receiver.try_recv() {
+                Ok(span) => spans.push(span),
+                Err(_) => break,
+            }
+        }
+
+        let body = export_request(&spans, &service_name).to_string();
+
+        if let Err(err) = post_json(&uri, body).await {
+            warn!("Could not export {} spans: {}", spans.len(), err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_trace_parent() {
+        let parent = TraceParent {
+            trace_id: "4bf92f3577b34da6a3ce929d0e0e4736".into(),
+            span_id: "00f067aa0ba902b7".into(),
+        };
+
+        assert_eq!(
+            TraceParent::from_query(
+                "SELECT 1 /* traceparent: 00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01 */"
+            ),
+            Some(parent.clone())
+        );
+        assert_eq!(
+            TraceParent::from_query(
+                "SELECT 1 /*traceparent='00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01'*/"
+            ),
+            Some(parent)
+        );
+        assert_eq!(TraceParent::from_query("SELECT 1"), None);
+        assert_eq!(
+            TraceParent::from_query("SELECT 1 /* traceparent: 00-nothex-00f067aa0ba902b7-01 */"),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_start_on_reload() {
+        start(Some("http://localhost:4318/v1/traces"), "pgcat", 10).unwrap();
+        assert!(enabled());
+        let running = EXPORTER.load_full().unwrap();
+
+        // Same settings, same exporter.
+        start(Some("http://localhost:4318/v1/traces"), "pgcat", 10).unwrap();
+        assert!(Arc::ptr_eq(&running, &EXPORTER.load_full().unwrap()));
+
+        // A new endpoint replaces it.
+        start(Some("http://otel:4318/v1/traces"), "pgcat", 10).unwrap();
+        assert_eq!(
+            EXPORTER.load().as_ref().unwrap().endpoint,
+            "http://otel:4318/v1/traces"
+        );
+
+        // A bad endpoint keeps the running exporter.
+        assert!(start(Some("otel:4318"), "pgcat", 10).is_err());
+        assert!(enabled());
+
+        start(None, "pgcat", 10).unwrap();
+        assert!(!enabled());
+    }
+
+    #[test]
+    fn test_export_request() {
+        let checkout = SlowCheckout {
+            at: std::time::Instant::now(),
+            checkout_time: 1500,
+            address: "pool_name_shard_0_replica_0".into(),
+            health_check: true,
+            candidates_tried: 2,
+            queue_time: 0,
+        };
+        let span = RoutingSpan::new(
+            b"SELECT 1 /* traceparent: 00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01 */",
+            &Address::default(),
+            &checkout,
+        );
+
+        let request = export_request(&[span], "pgcat");
+        let span = &request["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+
+        assert_eq!(span["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(span["parentSpanId"], "00f067aa0ba902b7");
+        assert_eq!(span["spanId"].as_str().unwrap().len(), 16);
+        assert_eq!(
+            span["attributes"][5]["value"]["stringValue"],
+            "pool_name_shard_0_replica_0"
+        );
+        assert_eq!(span["attributes"][8]["value"]["boolValue"], true);
+
+        let start = span["startTimeUnixNano"]
+            .as_str()
+            .unwrap()
+            .parse::<u128>()
+            .unwrap();
+        let end = span["endTimeUnixNano"]
+            .as_str()
+            .unwrap()
+            .parse::<u128>()
+            .unwrap();
+        assert_eq!(end - start, 1_500_000);
+    }
+}
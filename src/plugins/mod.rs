@@ -11,6 +11,7 @@
 pub mod intercept;
 pub mod prewarmer;
 pub mod query_logger;
+pub mod query_rewrite;
 pub mod table_access;
 
 use crate::{errors::Error, query_router::QueryRouter};
@@ -20,6 +21,7 @@ use sqlparser::ast::Statement;
 
 pub use intercept::Intercept;
 pub use query_logger::QueryLogger;
+pub use query_rewrite::QueryRewrite;
 pub use table_access::TableAccess;
 
 #[derive(Clone, Debug, PartialEq)]
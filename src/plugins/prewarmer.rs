@@ -1,11 +1,13 @@
 //! Prewarm new connections before giving them to the client.
-use crate::{errors::Error, server::Server};
-use log::info;
+use crate::{config::PrewarmerQuery, errors::Error, server::Server};
+use log::{error, info, warn};
+use std::time::Instant;
 
 pub struct Prewarmer<'a> {
     pub enabled: bool,
     pub server: &'a mut Server,
-    pub queries: &'a Vec<String>,
+    pub queries: &'a Vec<PrewarmerQuery>,
+    pub pipeline_depth: usize,
 }
 
 impl<'a> Prewarmer<'a> {
@@ -14,15 +16,68 @@ impl<'a> Prewarmer<'a> {
             return Ok(());
         }
 
-        for query in self.queries {
-            info!(
-                "{} Prewarning with query: `{}`",
-                self.server.address(),
-                query
-            );
-            self.server.query(query).await?;
+        let start = Instant::now();
+
+        // Queries of the same order don't depend on each other, send them together.
+        let mut queries = self.queries.iter().collect::<Vec<&PrewarmerQuery>>();
+        queries.sort_by_key(|query| query.order());
+
+        let mut first = 0;
+
+        while first < queries.len() {
+            let order = queries[first].order();
+            let end = queries[first..]
+                .iter()
+                .position(|query| query.order() != order)
+                .map_or(queries.len(), |len| first + len);
+            let same_order = &queries[first..end];
+            first = end;
+
+            for batch in same_order.chunks(self.pipeline_depth.max(1)) {
+                for query in batch {
+                    info!(
+                        "{} Prewarning with query: `{}`",
+                        self.server.address(),
+                        query.query()
+                    );
+                }
+
+                let errors = self
+                    .server
+                    .query_pipeline(&batch.iter().map(|query| query.query()).collect::<Vec<_>>())
+                    .await?;
+
+                for (query, error) in batch.iter().zip(errors) {
+                    let Some(error) = error else {
+                        continue;
+                    };
+
+                    if query.required() {
+                        error!(
+                            "{} Prewarming query `{}` failed: {}",
+                            self.server.address(),
+                            query.query(),
+                            error
+                        );
+                        return Err(Error::ServerError);
+                    }
+
+                    warn!(
+                        "{} Optional prewarming query `{}` failed: {}",
+                        self.server.address(),
+                        query.query(),
+                        error
+                    );
+                }
+            }
         }
 
+        info!(
+            "{} Prewarmed in {}ms",
+            self.server.address(),
+            start.elapsed().as_millis()
+        );
+
         Ok(())
     }
 }
@@ -0,0 +1,161 @@
+//! Rewrite queries before they're sent to the server, or reject them,
+//! using regular expressions, e.g. to strip comments or to block
+//! statements the query parser doesn't understand.
+//!
+//! Unlike the other plugins, this one works on the query text and runs last,
+//! so routing and the other plugins see the query as the client sent it.
+
+use bytes::{Buf, BytesMut};
+use regex::Regex;
+use std::borrow::Cow;
+use std::io::Cursor;
+
+use crate::{
+    config,
+    errors::Error,
+    messages::{simple_query, BytesMutReader, Parse},
+};
+
+#[derive(Clone, Debug)]
+struct Rule {
+    pattern: Regex,
+    replacement: String,
+    reject: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct QueryRewrite {
+    rules: Vec<Rule>,
+}
+
+impl QueryRewrite {
+    /// Compile the rules, the patterns are checked by `config::QueryRewrite::validate`.
+    pub fn new(config: &config::QueryRewrite) -> QueryRewrite {
+        QueryRewrite {
+            rules: config
+                .rules
+                .iter()
+                .map(|rule| Rule {
+                    pattern: Regex::new(&rule.pattern).unwrap(),
+                    replacement: rule.replacement.clone(),
+                    reject: rule.reject.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Apply the rules in order. Returns `BadQuery` with the rule's message
+    /// if the query is rejected.
+    pub fn rewrite<'a>(&self, query: &'a str) -> Result<Cow<'a, str>, Error> {
+        let mut query = Cow::Borrowed(query);
+
+        for rule in &self.rules {
+            if let Some(reject) = &rule.reject {
+                if rule.pattern.is_match(&query) {
+                    return Err(Error::BadQuery(reject.clone()));
+                }
+
+                continue;
+            }
+
+            let rewritten = match rule.pattern.replace_all(&query, rule.replacement.as_str()) {
+                Cow::Owned(rewritten) => Some(rewritten),
+                Cow::Borrowed(_) => None,
+            };
+
+            if let Some(rewritten) = rewritten {
+                query = Cow::Owned(rewritten);
+            }
+        }
+
+        Ok(query)
+    }
+
+    /// Rewrite the query in a Query (Q) or Parse (P) message.
+    /// Returns `None` if the message doesn't change.
+    pub fn rewrite_message(&self, message: &BytesMut) -> Result<Option<BytesMut>, Error> {
+        match message[0] as char {
+            'Q' => {
+                let mut cursor = Cursor::new(message);
+                cursor.advance(5); // code and length
+                let query = cursor.read_string()?;
+
+                match self.rewrite(&query)? {
+                    Cow::Owned(query) => Ok(Some(simple_query(&query))),
+                    Cow::Borrowed(_) => Ok(None),
+                }
+            }
+
+            'P' => {
+                let parse: Parse = message.try_into()?;
+
+                let rewritten = match self.rewrite(parse.query())? {
+                    Cow::Owned(query) => query,
+                    Cow::Borrowed(_) => return Ok(None),
+                };
+
+                Ok(Some(parse.with_query(rewritten).try_into()?))
+            }
+
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::QueryRewriteRule;
+
+    #[test]
+    fn test_query_rewrite() {
+        let query_rewrite = QueryRewrite::new(&config::QueryRewrite {
+            enabled: true,
+            rules: vec![
+                QueryRewriteRule {
+                    pattern: r"(?i)^\s*VACUUM\b".into(),
+                    replacement: String::new(),
+                    reject: Some("VACUUM is not allowed".into()),
+                },
+                QueryRewriteRule {
+                    pattern: r"/\*.*?\*/\s*".into(),
+                    replacement: String::new(),
+                    reject: None,
+                },
+                QueryRewriteRule {
+                    pattern: r"\bold_users\b".into(),
+                    replacement: "users".into(),
+                    reject: None,
+                },
+            ],
+        });
+
+        assert_eq!(
+            query_rewrite.rewrite("SELECT 1").unwrap(),
+            Cow::Borrowed("SELECT 1")
+        );
+        assert_eq!(
+            query_rewrite
+                .rewrite("/* app: web */ SELECT * FROM old_users")
+                .unwrap(),
+            "SELECT * FROM users"
+        );
+        assert_eq!(
+            query_rewrite.rewrite("vacuum users"),
+            Err(Error::BadQuery("VACUUM is not allowed".into()))
+        );
+
+        assert_eq!(
+            query_rewrite
+                .rewrite_message(&simple_query("SELECT * FROM old_users"))
+                .unwrap(),
+            Some(simple_query("SELECT * FROM users"))
+        );
+        assert_eq!(
+            query_rewrite
+                .rewrite_message(&simple_query("SELECT 1"))
+                .unwrap(),
+            None
+        );
+    }
+}
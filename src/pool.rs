@@ -1,39 +1,59 @@
 use arc_swap::ArcSwap;
 use async_trait::async_trait;
-use bb8::{ManageConnection, Pool, PooledConnection, QueueStrategy};
+use bb8::{ManageConnection, Pool, PooledConnection, QueueStrategy, RunError};
 use chrono::naive::NaiveDateTime;
 use log::{debug, error, info, warn};
 use lru::LruCache;
 use once_cell::sync::Lazy;
 use parking_lot::{Mutex, RwLock};
 use rand::seq::SliceRandom;
-use rand::thread_rng;
+use rand::{thread_rng, Rng};
 use regex::Regex;
-use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::hash::{Hash, Hasher};
 use std::num::NonZeroUsize;
-use std::sync::atomic::AtomicU64;
+use std::sync::atomic::{AtomicU32, AtomicU64};
 use std::sync::{
     atomic::{AtomicBool, Ordering},
     Arc,
 };
 use std::time::Instant;
-use tokio::sync::Notify;
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
 
 use crate::config::{
-    get_config, Address, DefaultShard, General, LoadBalancingMode, Plugins, PoolMode, Role, User,
+    get_config, get_fair_checkout, get_min_idle_shortfall_threshold, Address, AuthMethod,
+    CheckoutFailureMode, ClientAuthMode, Config, CopyHandling, DefaultShard, General,
+    HealthcheckMode, LoadBalancingMode, MirrorServerConfig, ParseFailureAction,
+    PauseClientBehavior, Plugins, PoolMode, Role, ServerParameterMismatch, UnbanStrategy, User,
 };
 use crate::errors::Error;
 
 use crate::auth_passthrough::AuthPassthrough;
+use crate::ban_events::{self, BanEvent, BanEventKind};
 use crate::messages::Parse;
-use crate::plugins::prewarmer;
+use crate::plugins::{prewarmer, QueryRewrite};
 use crate::server::{Server, ServerParameters};
 use crate::sharding::ShardingFunction;
-use crate::stats::{AddressStats, ClientStats, ServerStats};
+use crate::stats::{
+    AddressStats, ApplicationConnections, ApplicationConnectionsGuard, CheckoutHistograms,
+    ClientStats, MinIdleShortfall, QueueDepth, QueueTime, ServerStats, SlowCheckout, SlowCheckouts,
+};
+use crate::tls::ServerTls;
 
 pub type ProcessId = i32;
 pub type SecretKey = i32;
+
+/// A server connection checked out by a client, with the server it's from, how the
+/// checkout went, and the `max_client_connections` slot it takes, if limited.
+pub type Checkout<'a> = (
+    PooledConnection<'a, ServerPool>,
+    Address,
+    SlowCheckout,
+    Option<ApplicationConnectionsGuard>,
+);
 pub type ServerHost = String;
 pub type ServerPort = u16;
 
@@ -46,10 +66,88 @@ pub type PoolMap = HashMap<PoolIdentifier, ConnectionPool>;
 /// The pool is recreated dynamically when the config is reloaded.
 pub static POOLS: Lazy<ArcSwap<PoolMap>> = Lazy::new(|| ArcSwap::from_pointee(HashMap::default()));
 
+/// Held while the pools are built and swapped, so a RELOAD and a RECONNECT
+/// running at the same time don't overwrite each other's pools.
+static BUILD_LOCK: Lazy<tokio::sync::Mutex<()>> = Lazy::new(|| tokio::sync::Mutex::new(()));
+
+/// Longest ban after repeated health check failures, as a multiple of `ban_time`.
+const MAX_BAN_TIME_MULTIPLIER_EXPONENT: u32 = 5;
+
+/// Limits how fast clients connect to each pool, see `max_connections_per_second`.
+/// Kept outside of the pools so reloading the config doesn't refill them.
+static CONNECTION_RATE_LIMITS: Lazy<Mutex<HashMap<PoolIdentifier, TokenBucket>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Limits how many servers are validated at the same time, see `validate_concurrency`.
+/// Shared by all pools so validating them together still respects the limit,
+/// replaced when the limit changes.
+static VALIDATE_SEMAPHORE: Lazy<Mutex<Option<SizedSemaphore>>> = Lazy::new(|| Mutex::new(None));
+
+/// A semaphore and the number of permits it was created with.
+type SizedSemaphore = (usize, Arc<Semaphore>);
+
+/// The shared validation semaphore for `validate_concurrency`, if there is a limit.
+fn validate_semaphore(validate_concurrency: Option<usize>) -> Option<Arc<Semaphore>> {
+    let validate_concurrency = validate_concurrency?;
+    let mut guard = VALIDATE_SEMAPHORE.lock();
+
+    match &*guard {
+        Some((limit, semaphore)) if *limit == validate_concurrency => Some(semaphore.clone()),
+        _ => {
+            let semaphore = Arc::new(Semaphore::new(validate_concurrency));
+            *guard = Some((validate_concurrency, semaphore.clone()));
+            Some(semaphore)
+        }
+    }
+}
+
+/// Token bucket allowing `rate` tokens per second, with bursts of up to `rate`.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: u32,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32) -> TokenBucket {
+        TokenBucket {
+            rate,
+            tokens: rate as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Take a token, returns how long to wait until it's available,
+    /// or `None` without taking it if that's longer than `max_wait`.
+    pub fn take(&mut self, max_wait: std::time::Duration) -> Option<std::time::Duration> {
+        let now = Instant::now();
+        let rate = self.rate as f64;
+
+        self.tokens = (self.tokens + (now - self.updated).as_secs_f64() * rate).min(rate);
+        self.updated = now;
+
+        let wait = match self.tokens >= 1.0 {
+            true => std::time::Duration::ZERO,
+            false => std::time::Duration::from_secs_f64((1.0 - self.tokens) / rate),
+        };
+
+        if wait > max_wait {
+            return None;
+        }
+
+        // Clients waiting for their token already own it.
+        self.tokens -= 1.0;
+
+        Some(wait)
+    }
+}
+
 // Reasons for banning a server.
 #[derive(Debug, PartialEq, Clone)]
 pub enum BanReason {
-    FailedHealthCheck,
+    // Number of health checks failed in a row.
+    FailedHealthCheck(u32),
     MessageSendFailed,
     MessageReceiveFailed,
     FailedCheckout,
@@ -143,6 +241,98 @@ impl From<&Address> for PoolIdentifier {
     }
 }
 
+/// Shortest delay before reconnecting to a server after a failed connection attempt.
+const CONNECT_BACKOFF_MIN_MS: u64 = 100;
+
+/// Longest delay before reconnecting to a server, however many attempts failed.
+const CONNECT_BACKOFF_MAX_MS: u64 = 10_000;
+
+/// Throttles connection attempts to a server that's down.
+/// The delay doubles with every consecutive failure.
+#[derive(Debug, Default)]
+pub struct ConnectBackoff {
+    /// Connection attempts that failed in a row.
+    failures: AtomicU32,
+
+    /// When the next attempt is allowed, in milliseconds since the UNIX epoch.
+    retry_at: AtomicU64,
+
+    /// The last failure was the server refusing connections for now,
+    /// e.g. too many connections, not the server being down.
+    temporary: AtomicBool,
+}
+
+impl ConnectBackoff {
+    fn now() -> u64 {
+        chrono::offset::Utc::now().timestamp_millis() as u64
+    }
+
+    /// Connecting to the server failed, wait longer before the next attempt.
+    pub fn failed(&self, temporary: bool) {
+        self.temporary.store(temporary, Ordering::Relaxed);
+
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed);
+        let delay = CONNECT_BACKOFF_MIN_MS
+            .saturating_mul(1 << failures.min(16))
+            .min(CONNECT_BACKOFF_MAX_MS);
+
+        self.retry_at.store(Self::now() + delay, Ordering::Relaxed);
+    }
+
+    /// Connected to the server, no need to wait anymore.
+    pub fn succeeded(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+        self.retry_at.store(0, Ordering::Relaxed);
+        self.temporary.store(false, Ordering::Relaxed);
+    }
+
+    /// The last connection attempt failed.
+    pub fn failing(&self) -> bool {
+        self.failures.load(Ordering::Relaxed) > 0
+    }
+
+    /// The server is refusing connections for now but isn't down.
+    pub fn temporary(&self) -> bool {
+        self.temporary.load(Ordering::Relaxed)
+    }
+
+    /// Check if we should wait before connecting to the server again.
+    pub fn throttled(&self) -> bool {
+        Self::now() < self.retry_at.load(Ordering::Relaxed)
+    }
+}
+
+/// Health of the primary of a shard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrimaryStatus {
+    Up,
+    /// Not banned yet, but the last health check or connection attempt failed.
+    Down,
+    Banned,
+    Missing,
+}
+
+impl Display for PrimaryStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PrimaryStatus::Up => write!(f, "up"),
+            PrimaryStatus::Down => write!(f, "down"),
+            PrimaryStatus::Banned => write!(f, "banned"),
+            PrimaryStatus::Missing => write!(f, "missing"),
+        }
+    }
+}
+
+/// Health of a single shard, built from its addresses and the banlist.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShardHealth {
+    pub shard: usize,
+    pub primary: PrimaryStatus,
+    pub replicas: usize,
+    pub healthy_replicas: usize,
+    pub banned_replicas: usize,
+}
+
 /// Pool settings.
 #[derive(Clone, Debug)]
 pub struct PoolSettings {
@@ -152,6 +342,30 @@ pub struct PoolSettings {
     /// Random or LeastOutstandingConnections.
     pub load_balancing_mode: LoadBalancingMode,
 
+    /// Break LeastOutstandingConnections ties at random, otherwise by config order.
+    pub loc_shuffle: bool,
+
+    /// Pin or Reject COPY in transaction mode.
+    pub copy_handling: CopyHandling,
+
+    /// Warn or fail validation when servers run with different settings.
+    pub server_parameter_mismatch: ServerParameterMismatch,
+
+    // What to do with queries while the pool is paused.
+    pub pause_client_behavior: PauseClientBehavior,
+
+    // Wait for a connection or fail right away when they're all in use.
+    pub checkout_failure_mode: CheckoutFailureMode,
+
+    // Log the candidates and the server picked for every checkout.
+    pub log_routing_decisions: bool,
+
+    // Route to servers by the role they report instead of the configured one.
+    pub follow_role_changes: bool,
+
+    // Extra labels added to the metrics of this pool.
+    pub labels: BTreeMap<String, String>,
+
     // Number of shards.
     pub shards: usize,
 
@@ -168,30 +382,95 @@ pub struct PoolSettings {
     // Max length of query the parser will parse.
     pub query_parser_max_length: Option<usize>,
 
+    // Where queries the parser can't parse go.
+    pub on_parse_failure: ParseFailureAction,
+
     // Infer role
     pub query_parser_read_write_splitting: bool,
 
     // Read from the primary as well or not.
     pub primary_reads_enabled: bool,
 
+    // Chance the primary is a candidate for reads that can go to any server.
+    pub primary_read_weight: Option<f64>,
+
     // Sharding function.
     pub sharding_function: ShardingFunction,
 
+    // Sharding function for the keys found by automatic sharding.
+    pub automatic_sharding_function: ShardingFunction,
+
+    // Hash range of each shard, in shard order, for the range_hash sharding function.
+    // Shared with the sharder of every routed query.
+    pub shard_ranges: Arc<Vec<(i32, i32)>>,
+
     // Sharding key
     pub automatic_sharding_key: Option<String>,
 
+    // Bind parameter holding the sharding key, counted from 1 like `$1`.
+    pub automatic_sharding_key_parameter: Option<i16>,
+
+    // Allow queries with sharding keys pointing to more than one shard.
+    pub allow_cross_shard: bool,
+
+    // Let queries pick a server by name in a comment.
+    pub server_targeting_enabled: bool,
+
+    // Maximum query duration, the user's or the pool's. 0 means no limit.
+    pub statement_timeout: u64,
+
+    // How long a client may be idle inside a transaction (ms), 0 means no limit.
+    // Falls back to the general setting, read live so a reload applies it.
+    pub idle_in_transaction_timeout: Option<u64>,
+
+    // Ban replicas when a query hits the statement timeout.
+    pub ban_on_statement_timeout: bool,
+
+    // Extensions every server must have installed.
+    pub required_extensions: Vec<String>,
+
+    // Availability zone PgCat runs in, preferred by zone aware load balancing.
+    pub zone: Option<String>,
+
+    // Maximum time to establish a new server connection.
+    pub connect_timeout: u64,
+
+    // How to run health checks.
+    pub healthcheck_mode: HealthcheckMode,
+
+    // Password or TLS client certificate.
+    pub client_auth_mode: ClientAuthMode,
+
     // Health check timeout
     pub healthcheck_timeout: u64,
 
+    // Query run by health checks in `query` mode.
+    pub healthcheck_query: String,
+
     // Health check delay
     pub healthcheck_delay: u64,
 
+    // Replicas further behind the primary than this (ms) don't serve reads.
+    pub max_replica_lag: Option<u64>,
+
     // Ban time
     pub ban_time: i64,
 
+    // What to unban when all replicas are banned.
+    pub unban_strategy: UnbanStrategy,
+
+    // Maximum number of servers validated at the same time, unlimited if not set.
+    pub validate_concurrency: Option<usize>,
+
+    // How long clients wait for a paused pool to resume, 0 waits forever.
+    pub max_pause_wait: u64,
+
     // Regex for searching for the sharding key in SQL statements
     pub sharding_key_regex: Option<Regex>,
 
+    // Named groups of the sharding key regex concatenated into the key, the first group if empty
+    pub sharding_key_regex_groups: Vec<String>,
+
     // Regex for searching for the shard id in SQL statements
     pub shard_id_regex: Option<Regex>,
 
@@ -206,8 +485,29 @@ pub struct PoolSettings {
     pub auth_query_user: Option<String>,
     pub auth_query_password: Option<String>,
 
+    // Weakest authentication method accepted from the servers, auth_query included.
+    pub min_server_auth_method: AuthMethod,
+
+    // TLS settings for the connections to the servers, auth_query included.
+    pub server_tls: Option<ServerTls>,
+
+    // Maximum number of queries executing concurrently on this pool's servers
+    pub max_concurrent_queries: Option<usize>,
+
+    // How long to wait for a query slot before returning an error
+    pub max_concurrent_queries_wait_timeout: u64,
+
+    // Maximum number of new clients let into this pool every second
+    pub max_connections_per_second: Option<u32>,
+
+    // How long a new client waits to be let in before getting an error
+    pub max_connections_per_second_wait_timeout: u64,
+
     /// Plugins
     pub plugins: Option<Plugins>,
+
+    // Compiled query_rewrite plugin rules
+    pub query_rewrite: Option<QueryRewrite>,
 }
 
 impl Default for PoolSettings {
@@ -215,27 +515,63 @@ impl Default for PoolSettings {
         PoolSettings {
             pool_mode: PoolMode::Transaction,
             load_balancing_mode: LoadBalancingMode::Random,
+            loc_shuffle: true,
+            copy_handling: CopyHandling::Pin,
+            server_parameter_mismatch: ServerParameterMismatch::Warn,
+            pause_client_behavior: PauseClientBehavior::Block,
+            checkout_failure_mode: CheckoutFailureMode::Wait,
+            log_routing_decisions: false,
+            follow_role_changes: false,
+            labels: BTreeMap::new(),
             shards: 1,
             user: User::default(),
             db: String::default(),
             default_role: None,
             query_parser_enabled: false,
             query_parser_max_length: None,
+            on_parse_failure: ParseFailureAction::DefaultRole,
             query_parser_read_write_splitting: false,
             primary_reads_enabled: true,
+            primary_read_weight: None,
             sharding_function: ShardingFunction::PgBigintHash,
+            automatic_sharding_function: ShardingFunction::PgBigintHash,
+            shard_ranges: Arc::default(),
             automatic_sharding_key: None,
+            automatic_sharding_key_parameter: None,
+            allow_cross_shard: true,
+            server_targeting_enabled: false,
+            statement_timeout: 0,
+            idle_in_transaction_timeout: None,
+            ban_on_statement_timeout: true,
+            required_extensions: Vec::new(),
+            zone: None,
             healthcheck_delay: General::default_healthcheck_delay(),
+            max_replica_lag: None,
+            connect_timeout: General::default_connect_timeout(),
+            healthcheck_mode: HealthcheckMode::Query,
+            client_auth_mode: ClientAuthMode::Password,
             healthcheck_timeout: General::default_healthcheck_timeout(),
+            healthcheck_query: General::default_healthcheck_query(),
             ban_time: General::default_ban_time(),
+            unban_strategy: General::default_unban_strategy(),
+            validate_concurrency: None,
+            max_pause_wait: General::default_max_pause_wait(),
             sharding_key_regex: None,
+            sharding_key_regex_groups: Vec::new(),
             shard_id_regex: None,
             regex_search_limit: 1000,
             default_shard: DefaultShard::Shard(0),
             auth_query: None,
             auth_query_user: None,
             auth_query_password: None,
+            min_server_auth_method: AuthMethod::Trust,
+            server_tls: None,
+            max_concurrent_queries: None,
+            max_concurrent_queries_wait_timeout: 5000,
+            max_connections_per_second: None,
+            max_connections_per_second_wait_timeout: 1000,
             plugins: None,
+            query_rewrite: None,
         }
     }
 }
@@ -259,6 +595,10 @@ pub struct ConnectionPool {
     /// on pool creation and save the startup parameters here.
     original_server_parameters: Arc<RwLock<ServerParameters>>,
 
+    /// Each server as it was when the pool was validated, by shard and server.
+    /// `revalidate()` compares against it on reload.
+    validated_servers: Arc<Mutex<HashMap<(usize, usize), ServerSnapshot>>>,
+
     /// Pool configuration.
     pub settings: Arc<PoolSettings>,
 
@@ -275,39 +615,157 @@ pub struct ConnectionPool {
     paused: Arc<AtomicBool>,
     paused_waiter: Arc<Notify>,
 
+    /// If the pool is shutting down, no new checkouts are allowed.
+    draining: Arc<AtomicBool>,
+
     /// AuthInfo
     pub auth_hash: Arc<RwLock<Option<String>>>,
 
     /// Cache
     pub prepared_statement_cache: Option<PreparedStatementCacheType>,
+
+    /// Limits the number of queries executing at the same time
+    /// if `max_concurrent_queries` is set. Shared by all users of the pool.
+    query_semaphore: Option<Arc<Semaphore>>,
+
+    /// Clients waiting for a connection from this pool.
+    pub queue_depth: Arc<QueueDepth>,
+
+    /// Time clients waited for their turn with `fair_checkout`. Kept across reloads.
+    pub queue_time: Arc<QueueTime>,
+
+    /// Clients take turns checking out connections from each shard if `fair_checkout`
+    /// is on. Tokio's mutex is fair, the longest waiting client goes next.
+    checkout_queue: Arc<Vec<tokio::sync::Mutex<()>>>,
+
+    /// The slowest recent checkouts from this pool.
+    pub slow_checkouts: Arc<SlowCheckouts>,
+
+    /// Checkout times from this pool by role, for the Prometheus histogram.
+    pub checkout_histograms: Arc<CheckoutHistograms>,
+
+    /// Servers that can't keep `min_pool_size` idle connections.
+    pub min_idle_shortfall: Arc<MinIdleShortfall>,
+
+    /// Times every replica of a shard was banned and `try_unban` had to
+    /// unban some of them to keep serving reads. Kept across reloads.
+    all_replicas_banned: Arc<AtomicU64>,
+
+    /// Queries the query parser couldn't parse. Kept across reloads.
+    parse_failures: Arc<AtomicU64>,
+
+    /// Clients in `auto` pool mode that started using session state and were
+    /// switched to session mode. Kept across reloads.
+    auto_session_clients: Arc<AtomicU64>,
+
+    /// Server connections held by clients for each `application_name`,
+    /// see `max_client_connections`. Kept across reloads.
+    application_connections: ApplicationConnections,
+
+    /// Open server connections that ran the prewarmer queries. Kept across reloads.
+    prewarmed_connections: Arc<AtomicU64>,
 }
 
 impl ConnectionPool {
     /// Construct the connection pool from the configuration.
     pub async fn from_config(client_server_map: ClientServerMap) -> Result<(), Error> {
+        Self::build(client_server_map, None, true).await
+    }
+
+    /// Construct the connection pool from the configuration without validating
+    /// it in the background, for callers that check the servers themselves.
+    pub async fn from_config_unvalidated(client_server_map: ClientServerMap) -> Result<(), Error> {
+        Self::build(client_server_map, None, false).await
+    }
+
+    /// Re-create a single pool from the current configuration, e.g. when its servers
+    /// moved, without touching the others. The new pool replaces the old one only
+    /// if it validates.
+    pub async fn recreate(
+        client_server_map: ClientServerMap,
+        identifier: &PoolIdentifier,
+    ) -> Result<(), Error> {
+        if get_pool(&identifier.db, &identifier.user).is_none() {
+            return Err(Error::ClientError(format!(
+                "no such pool: {}, {}",
+                identifier.db, identifier.user
+            )));
+        }
+
+        Self::build(client_server_map, Some(identifier), true).await
+    }
+
+    /// Build all pools, reusing the ones that haven't changed.
+    /// The `recreate` pool, if any, is always built from scratch.
+    /// New pools are validated in the background if `validate` and `validate_config` are set.
+    async fn build(
+        client_server_map: ClientServerMap,
+        recreate: Option<&PoolIdentifier>,
+        validate: bool,
+    ) -> Result<(), Error> {
+        let _build_guard = BUILD_LOCK.lock().await;
         let config = get_config();
 
         let mut new_pools = HashMap::new();
         let mut address_id: usize = 0;
 
+        // Pools that would be kept as they are, checked all at once.
+        let revalidated = match recreate.is_none() && config.general.revalidate_on_reload {
+            true => Self::revalidate_unchanged(&config).await,
+            false => HashMap::new(),
+        };
+
         for (pool_name, pool_config) in &config.pools {
             let new_pool_hash_value = pool_config.hash_value();
 
+            // Concurrent queries are capped for the database, not for each user.
+            // Keep the semaphore of the current pools if the limit hasn't changed,
+            // so pools re-created on their own still share it with the others.
+            let query_semaphore =
+                pool_config
+                    .max_concurrent_queries
+                    .map(|max_concurrent_queries| {
+                        get_all_pools()
+                            .iter()
+                            .find(|(identifier, pool)| {
+                                identifier.db == *pool_name
+                                    && pool.settings.max_concurrent_queries
+                                        == Some(max_concurrent_queries)
+                            })
+                            .and_then(|(_, pool)| pool.query_semaphore.clone())
+                            .unwrap_or_else(|| Arc::new(Semaphore::new(max_concurrent_queries)))
+                    });
+
             // There is one pool per database/user pair.
             for user in pool_config.users.values() {
                 let old_pool_ref = get_pool(pool_name, &user.username);
                 let identifier = PoolIdentifier::new(pool_name, &user.username);
+                let recreate_pool = recreate == Some(&identifier);
 
-                if let Some(pool) = old_pool_ref {
+                if let Some(pool) = &old_pool_ref {
                     // If the pool hasn't changed, get existing reference and insert it into the new_pools.
                     // We replace all pools at the end, but if the reference is kept, the pool won't get re-created (bb8).
-                    if pool.config_hash == new_pool_hash_value {
-                        info!(
-                            "[pool: {}][user: {}] has not changed",
-                            pool_name, user.username
-                        );
-                        new_pools.insert(identifier.clone(), pool.clone());
-                        continue;
+                    if pool.config_hash == new_pool_hash_value && !recreate_pool {
+                        // The config is the same but the servers behind it could have changed, e.g.
+                        // after a manual failover. Re-create the pool if it points to the wrong place.
+                        if !revalidated.get(&identifier).copied().unwrap_or(true) {
+                            warn!(
+                                "[pool: {}][user: {}] servers changed since the pool was created",
+                                pool_name, user.username
+                            );
+                        } else {
+                            info!(
+                                "[pool: {}][user: {}] has not changed",
+                                pool_name, user.username
+                            );
+                            let mut pool = pool.clone();
+                            pool.checkout_histograms = checkout_histograms(
+                                Some(&pool),
+                                &config.general.checkout_time_buckets,
+                            );
+                            new_pools.insert(identifier.clone(), pool);
+                            continue;
+                        }
                     }
                 }
 
@@ -319,6 +777,11 @@ impl ConnectionPool {
                 let mut shards = Vec::new();
                 let mut addresses = Vec::new();
                 let mut banlist = Vec::new();
+                let prewarmed_connections = match &old_pool_ref {
+                    Some(old_pool) => old_pool.prewarmed_connections.clone(),
+                    None => Arc::new(AtomicU64::new(0)),
+                };
+                let server_tls = ServerTls::from_config(&config, pool_name)?;
                 let mut shard_ids = pool_config
                     .shards
                     .clone()
@@ -334,9 +797,16 @@ impl ConnectionPool {
                     let mut pools = Vec::new();
                     let mut servers = Vec::new();
                     let mut replica_number = 0;
+                    let mut primary_number = 0;
 
                     // Load Mirror settings
                     for (address_index, server) in shard.servers.iter().enumerate() {
+                        // Primaries are numbered on their own.
+                        let server_number = match server.role {
+                            Role::Primary => primary_number,
+                            _ => replica_number,
+                        };
+
                         let mut mirror_addresses = vec![];
                         if let Some(mirror_settings_vec) = &shard.mirrors {
                             for (mirror_idx, mirror_settings) in
@@ -345,6 +815,18 @@ impl ConnectionPool {
                                 if mirror_settings.mirroring_target_index != address_index {
                                     continue;
                                 }
+
+                                info!(
+                                    "[pool: {}][user: {}] mirroring {}:{} to {}:{}, sample rate: {}",
+                                    pool_name,
+                                    user.username,
+                                    server.host,
+                                    server.port,
+                                    mirror_settings.host,
+                                    mirror_settings.port,
+                                    mirror_settings.mirror_sample_rate
+                                );
+
                                 mirror_addresses.push(Address {
                                     id: address_id,
                                     database: shard.database.clone(),
@@ -352,13 +834,21 @@ impl ConnectionPool {
                                     port: mirror_settings.port,
                                     role: server.role,
                                     address_index: mirror_idx,
-                                    replica_number,
+                                    replica_number: server_number,
                                     shard: shard_idx.parse::<usize>().unwrap(),
                                     username: user.username.clone(),
                                     pool_name: pool_name.clone(),
                                     mirrors: vec![],
                                     stats: Arc::new(AddressStats::default()),
                                     error_count: Arc::new(AtomicU64::new(0)),
+                                    connect_backoff: Arc::new(ConnectBackoff::default()),
+                                    out_of_rotation: Arc::new(AtomicBool::new(false)),
+                                    health_check_failures: Arc::new(AtomicU32::new(0)),
+                                    zone: server.zone.clone(),
+                                    weight: server.weight,
+                                    mirror_sample_rate: mirror_settings.mirror_sample_rate,
+                                    label: None,
+                                    observed_role: Arc::new(RwLock::new(None)),
                                 });
                                 address_id += 1;
                             }
@@ -371,23 +861,34 @@ impl ConnectionPool {
                             port: server.port,
                             role: server.role,
                             address_index,
-                            replica_number,
+                            replica_number: server_number,
                             shard: shard_idx.parse::<usize>().unwrap(),
                             username: user.username.clone(),
                             pool_name: pool_name.clone(),
                             mirrors: mirror_addresses,
                             stats: Arc::new(AddressStats::default()),
                             error_count: Arc::new(AtomicU64::new(0)),
+                            connect_backoff: Arc::new(ConnectBackoff::default()),
+                            out_of_rotation: Arc::new(AtomicBool::new(false)),
+                            health_check_failures: Arc::new(AtomicU32::new(0)),
+                            zone: server.zone.clone(),
+                            weight: server.weight,
+                            mirror_sample_rate: MirrorServerConfig::default_mirror_sample_rate(),
+                            label: server.label.clone(),
+                            observed_role: Arc::new(RwLock::new(None)),
                         };
 
                         address_id += 1;
 
-                        if server.role == Role::Replica {
-                            replica_number += 1;
+                        match server.role {
+                            Role::Primary => primary_number += 1,
+                            Role::Replica => replica_number += 1,
+                            Role::Mirror => (),
                         }
 
                         // We assume every server in the pool share user/passwords
-                        let auth_passthrough = AuthPassthrough::from_pool_config(pool_config);
+                        let auth_passthrough = AuthPassthrough::from_pool_config(pool_config)
+                            .map(|apt| apt.with_server_tls(server_tls.clone()));
 
                         if let Some(apt) = &auth_passthrough {
                             match apt.fetch_hash(&address).await {
@@ -421,19 +922,41 @@ impl ConnectionPool {
                             }
                         }
 
+                        let server_lifetime = match user.server_lifetime {
+                            Some(server_lifetime) => server_lifetime,
+                            None => match pool_config.server_lifetime {
+                                Some(server_lifetime) => server_lifetime,
+                                None => config.general.server_lifetime,
+                            },
+                        };
+
                         let manager = ServerPool::new(
                             address.clone(),
                             user.clone(),
                             &shard.database,
                             client_server_map.clone(),
                             pool_auth_hash.clone(),
-                            match pool_config.plugins {
-                                Some(ref plugins) => Some(plugins.clone()),
-                                None => config.plugins.clone(),
+                            prewarmed_connections.clone(),
+                            ServerPoolSettings {
+                                plugins: match pool_config.plugins {
+                                    Some(ref plugins) => Some(plugins.clone()),
+                                    None => config.plugins.clone(),
+                                },
+                                cleanup_connections: pool_config.cleanup_server_connections,
+                                cleanup_query: pool_config.cleanup_query.clone(),
+                                log_client_parameter_status_changes: pool_config
+                                    .log_client_parameter_status_changes,
+                                prepared_statement_cache_size: pool_config
+                                    .max_prepared_statements_per_server(),
+                                min_server_auth_method: pool_config.min_server_auth_method,
+                                server_lifetime,
+                                server_lifetime_jitter: config.general.server_lifetime_jitter,
+                                connect_retries: config.general.connect_retries,
+                                connect_retry_delay: config.general.connect_retry_delay,
+                                read_only: pool_config.enforce_read_only_on_replicas
+                                    && address.role == Role::Replica,
+                                server_tls: server_tls.clone(),
                             },
-                            pool_config.cleanup_server_connections,
-                            pool_config.log_client_parameter_status_changes,
-                            pool_config.prepared_statements_cache_size,
                         );
 
                         let connect_timeout = match user.connect_timeout {
@@ -452,18 +975,13 @@ impl ConnectionPool {
                             },
                         };
 
-                        let server_lifetime = match user.server_lifetime {
-                            Some(server_lifetime) => server_lifetime,
-                            None => match pool_config.server_lifetime {
-                                Some(server_lifetime) => server_lifetime,
-                                None => config.general.server_lifetime,
-                            },
-                        };
-
-                        let reaper_rate = *[idle_timeout, server_lifetime, POOL_REAPER_RATE]
-                            .iter()
-                            .min()
-                            .unwrap();
+                        let reaper_rate = jittered(
+                            *[idle_timeout, server_lifetime, POOL_REAPER_RATE]
+                                .iter()
+                                .min()
+                                .unwrap(),
+                            config.general.reaper_rate_jitter,
+                        );
 
                         let queue_strategy = match config.general.server_round_robin {
                             true => QueueStrategy::Fifo,
@@ -514,6 +1032,7 @@ impl ConnectionPool {
                     banlist: Arc::new(RwLock::new(banlist)),
                     config_hash: new_pool_hash_value,
                     original_server_parameters: Arc::new(RwLock::new(ServerParameters::new())),
+                    validated_servers: Arc::new(Mutex::new(HashMap::new())),
                     auth_hash: pool_auth_hash,
                     settings: Arc::new(PoolSettings {
                         pool_mode: match user.pool_mode {
@@ -521,30 +1040,95 @@ impl ConnectionPool {
                             None => pool_config.pool_mode,
                         },
                         load_balancing_mode: pool_config.load_balancing_mode,
+                        loc_shuffle: pool_config.loc_shuffle,
+                        copy_handling: pool_config.copy_handling,
+                        server_parameter_mismatch: pool_config.server_parameter_mismatch,
+                        pause_client_behavior: pool_config.pause_client_behavior,
+                        checkout_failure_mode: pool_config.checkout_failure_mode,
+                        log_routing_decisions: pool_config.log_routing_decisions,
+                        follow_role_changes: pool_config.follow_role_changes,
+                        labels: pool_config.labels.clone(),
                         // shards: pool_config.shards.clone(),
                         shards: shard_ids.len(),
                         user: user.clone(),
                         db: pool_name.clone(),
-                        default_role: match pool_config.default_role.as_str() {
+                        default_role: match user
+                            .default_role
+                            .as_deref()
+                            .unwrap_or(pool_config.default_role.as_str())
+                        {
                             "any" => None,
                             "replica" => Some(Role::Replica),
                             "primary" => Some(Role::Primary),
                             _ => unreachable!(),
                         },
                         query_parser_enabled: pool_config.query_parser_enabled,
-                        query_parser_max_length: pool_config.query_parser_max_length,
+                        query_parser_max_length: match pool_config.query_parser_max_length {
+                            Some(max_length) => Some(max_length),
+                            None => config.general.query_parser_max_length,
+                        },
+                        on_parse_failure: pool_config.on_parse_failure,
                         query_parser_read_write_splitting: pool_config
                             .query_parser_read_write_splitting,
                         primary_reads_enabled: pool_config.primary_reads_enabled,
+                        primary_read_weight: pool_config.primary_read_weight.map(|weight| weight.0),
                         sharding_function: pool_config.sharding_function,
+                        automatic_sharding_function: pool_config
+                            .automatic_sharding_function
+                            .unwrap_or(pool_config.sharding_function),
+                        shard_ranges: Arc::new(
+                            shard_ids
+                                .iter()
+                                .filter_map(|shard_idx| {
+                                    let shard = &pool_config.shards[shard_idx];
+                                    Some((shard.range_start?, shard.range_end?))
+                                })
+                                .collect(),
+                        ),
                         automatic_sharding_key: pool_config.automatic_sharding_key.clone(),
-                        healthcheck_delay: config.general.healthcheck_delay,
-                        healthcheck_timeout: config.general.healthcheck_timeout,
+                        automatic_sharding_key_parameter: pool_config
+                            .automatic_sharding_key_parameter,
+                        allow_cross_shard: pool_config.allow_cross_shard,
+                        server_targeting_enabled: pool_config.server_targeting_enabled,
+                        statement_timeout: match user.statement_timeout {
+                            0 => pool_config.statement_timeout.unwrap_or(0),
+                            statement_timeout => statement_timeout,
+                        },
+                        idle_in_transaction_timeout: pool_config.idle_in_transaction_timeout,
+                        ban_on_statement_timeout: pool_config.ban_on_statement_timeout,
+                        required_extensions: pool_config.required_extensions.clone(),
+                        zone: config.general.zone.clone(),
+                        connect_timeout: match user.connect_timeout {
+                            Some(connect_timeout) => connect_timeout,
+                            None => match pool_config.connect_timeout {
+                                Some(connect_timeout) => connect_timeout,
+                                None => config.general.connect_timeout,
+                            },
+                        },
+                        healthcheck_mode: pool_config.healthcheck_mode,
+                        client_auth_mode: match user.client_auth_mode {
+                            Some(client_auth_mode) => client_auth_mode,
+                            None => pool_config.client_auth_mode,
+                        },
+                        healthcheck_delay: match pool_config.healthcheck_delay {
+                            Some(healthcheck_delay) => healthcheck_delay,
+                            None => config.general.healthcheck_delay,
+                        },
+                        max_replica_lag: pool_config.max_replica_lag,
+                        healthcheck_timeout: match pool_config.healthcheck_timeout {
+                            Some(healthcheck_timeout) => healthcheck_timeout,
+                            None => config.general.healthcheck_timeout,
+                        },
+                        healthcheck_query: config.general.healthcheck_query.clone(),
                         ban_time: config.general.ban_time,
+                        unban_strategy: config.general.unban_strategy,
+                        validate_concurrency: config.general.validate_concurrency,
+                        max_pause_wait: config.general.max_pause_wait,
                         sharding_key_regex: pool_config
                             .sharding_key_regex
                             .clone()
                             .map(|regex| Regex::new(regex.as_str()).unwrap()),
+                        sharding_key_regex_groups: pool_config.sharding_key_regex_groups.clone(),
                         shard_id_regex: pool_config
                             .shard_id_regex
                             .clone()
@@ -554,26 +1138,86 @@ impl ConnectionPool {
                         auth_query: pool_config.auth_query.clone(),
                         auth_query_user: pool_config.auth_query_user.clone(),
                         auth_query_password: pool_config.auth_query_password.clone(),
+                        min_server_auth_method: pool_config.min_server_auth_method,
+                        server_tls: server_tls.clone(),
+                        max_concurrent_queries: pool_config.max_concurrent_queries,
+                        max_concurrent_queries_wait_timeout: pool_config
+                            .max_concurrent_queries_wait_timeout,
+                        max_connections_per_second: pool_config.max_connections_per_second,
+                        max_connections_per_second_wait_timeout: pool_config
+                            .max_connections_per_second_wait_timeout,
                         plugins: match pool_config.plugins {
                             Some(ref plugins) => Some(plugins.clone()),
                             None => config.plugins.clone(),
                         },
+                        query_rewrite: pool_config
+                            .plugins
+                            .as_ref()
+                            .or(config.plugins.as_ref())
+                            .and_then(|plugins| plugins.query_rewrite.as_ref())
+                            .filter(|query_rewrite| query_rewrite.enabled)
+                            .map(QueryRewrite::new),
                     }),
                     validated: Arc::new(AtomicBool::new(false)),
                     paused: Arc::new(AtomicBool::new(false)),
                     paused_waiter: Arc::new(Notify::new()),
+                    draining: Arc::new(AtomicBool::new(false)),
                     prepared_statement_cache: match pool_config.prepared_statements_cache_size {
                         0 => None,
                         _ => Some(Arc::new(Mutex::new(PreparedStatementCache::new(
                             pool_config.prepared_statements_cache_size,
                         )))),
                     },
+                    query_semaphore: query_semaphore.clone(),
+                    queue_depth: Arc::new(QueueDepth::default()),
+                    queue_time: match &old_pool_ref {
+                        Some(old_pool) => old_pool.queue_time.clone(),
+                        None => Arc::new(QueueTime::default()),
+                    },
+                    checkout_queue: Arc::new(
+                        (0..shard_ids.len())
+                            .map(|_| tokio::sync::Mutex::new(()))
+                            .collect(),
+                    ),
+                    slow_checkouts: Arc::new(SlowCheckouts::default()),
+                    min_idle_shortfall: Arc::new(MinIdleShortfall::default()),
+                    all_replicas_banned: match &old_pool_ref {
+                        Some(old_pool) => old_pool.all_replicas_banned.clone(),
+                        None => Arc::new(AtomicU64::new(0)),
+                    },
+                    parse_failures: match &old_pool_ref {
+                        Some(old_pool) => old_pool.parse_failures.clone(),
+                        None => Arc::new(AtomicU64::new(0)),
+                    },
+                    auto_session_clients: match &old_pool_ref {
+                        Some(old_pool) => old_pool.auto_session_clients.clone(),
+                        None => Arc::new(AtomicU64::new(0)),
+                    },
+                    application_connections: match &old_pool_ref {
+                        Some(old_pool) => old_pool.application_connections.clone(),
+                        None => ApplicationConnections::default(),
+                    },
+                    checkout_histograms: checkout_histograms(
+                        old_pool_ref.as_ref(),
+                        &config.general.checkout_time_buckets,
+                    ),
+                    prewarmed_connections,
                 };
 
+                // Don't send traffic to servers we already know are bad,
+                // and keep routing by the roles the servers reported.
+                if let Some(old_pool) = &old_pool_ref {
+                    pool.inherit_bans(old_pool);
+                    pool.inherit_observed_roles(old_pool);
+                }
+
                 // Connect to the servers to make sure pool configuration is valid
                 // before setting it globally.
-                // Do this async and somewhere else, we don't have to wait here.
-                if config.general.validate_config {
+                // Do this async and somewhere else, we don't have to wait here,
+                // unless this pool is being re-created on purpose.
+                if recreate_pool {
+                    pool.validate().await?;
+                } else if validate && config.general.validate_config {
                     let validate_pool = pool.clone();
                     tokio::task::spawn(async move {
                         let _ = validate_pool.validate().await;
@@ -596,144 +1240,663 @@ impl ConnectionPool {
     /// This also warms up the pool for clients that connect when
     /// the pooler starts up.
     pub async fn validate(&self) -> Result<(), Error> {
+        let (results, server_parameters) = self.check_servers().await;
+
+        // A server missing extensions fails the whole pool.
+        for (_, result) in results {
+            if let Err(err) = result {
+                self.validated.store(false, Ordering::Relaxed);
+                return Err(err);
+            }
+        }
+
+        // All shards should be running identical configurations.
+        let mismatches = server_parameter_mismatches(server_parameters);
+
+        for mismatch in &mismatches {
+            warn!("[pool: {}] {}", self.settings.db, mismatch);
+        }
+
+        if !mismatches.is_empty()
+            && self.settings.server_parameter_mismatch == ServerParameterMismatch::Error
+        {
+            error!(
+                "[pool: {}] Servers are running with different settings",
+                self.settings.db
+            );
+            self.validated.store(false, Ordering::Relaxed);
+            return Err(Error::BadConfig);
+        }
+
+        if !self.validated() {
+            error!("Could not validate connection pool");
+            return Err(Error::AllServersDown);
+        }
+
+        Ok(())
+    }
+
+    /// Connect to every server of the pool like `validate()`, returning
+    /// whether each one could be reached, or what's wrong with it.
+    pub async fn validate_servers(&self) -> Vec<(Address, Result<bool, Error>)> {
+        self.check_servers().await.0
+    }
+
+    /// `validate_servers()`, also returning the parameters reported by
+    /// each server that could be reached.
+    async fn check_servers(
+        &self,
+    ) -> (
+        Vec<(Address, Result<bool, Error>)>,
+        Vec<(Address, ServerParameters)>,
+    ) {
         let mut futures = Vec::new();
         let validated = Arc::clone(&self.validated);
+        let reported_parameters = Arc::new(Mutex::new(Vec::new()));
+
+        // Don't connect to every server at once on large deployments.
+        let semaphore = validate_semaphore(self.settings.validate_concurrency);
 
         for shard in 0..self.shards() {
             for server in 0..self.servers(shard) {
+                let semaphore = semaphore.clone();
                 let databases = self.databases.clone();
                 let validated = Arc::clone(&validated);
                 let pool_server_parameters = Arc::clone(&self.original_server_parameters);
+                let validated_servers = Arc::clone(&self.validated_servers);
+                let position = (shard, server);
+                let required_extensions = self.settings.required_extensions.clone();
+                let address = self.address(shard, server).clone();
+                let address_for_result = address.clone();
+                let connect_timeout = self.settings.connect_timeout;
+                let reported_parameters = Arc::clone(&reported_parameters);
 
                 let task = tokio::task::spawn(async move {
-                    let connection = match databases[shard][server].get().await {
-                        Ok(conn) => conn,
-                        Err(err) => {
+                    let _permit = match semaphore {
+                        Some(semaphore) => Some(semaphore.acquire_owned().await),
+                        None => None,
+                    };
+
+                    // A server that accepts the connection but never answers
+                    // shouldn't hold up the validation forever.
+                    let connection = match tokio::time::timeout(
+                        tokio::time::Duration::from_millis(connect_timeout),
+                        databases[shard][server].get(),
+                    )
+                    .await
+                    {
+                        Ok(Ok(conn)) => conn,
+                        Ok(Err(err)) => {
                             error!("Shard {} down or misconfigured: {:?}", shard, err);
-                            return;
+                            return Ok(false);
+                        }
+                        Err(_) => {
+                            error!(
+                                "Server {} timed out after {}ms during validation",
+                                address.name(),
+                                connect_timeout
+                            );
+                            return Ok(false);
                         }
                     };
 
-                    let proxy = connection;
-                    let server = &*proxy;
+                    let mut proxy = connection;
+                    let server = &mut *proxy;
+
+                    if !required_extensions.is_empty() {
+                        let missing = match tokio::time::timeout(
+                            tokio::time::Duration::from_millis(connect_timeout),
+                            server.missing_extensions(&required_extensions),
+                        )
+                        .await
+                        {
+                            Ok(missing) => missing?,
+                            Err(_) => {
+                                error!(
+                                    "Server {} timed out after {}ms checking extensions during validation",
+                                    address.name(),
+                                    connect_timeout
+                                );
+                                server.mark_bad("timed out during validation");
+                                return Ok(false);
+                            }
+                        };
+
+                        if !missing.is_empty() {
+                            error!(
+                                "Server {} is missing required extensions: {}",
+                                address.name(),
+                                missing.join(", ")
+                            );
+
+                            return Err(Error::MissingExtensions(format!(
+                                "{} on {}",
+                                missing.join(", "),
+                                address.name()
+                            )));
+                        }
+                    }
+
+                    // Remember what the server looks like, to tell if it changed on reload.
+                    match tokio::time::timeout(
+                        tokio::time::Duration::from_millis(connect_timeout),
+                        ServerSnapshot::take(server),
+                    )
+                    .await
+                    {
+                        Ok(Ok(snapshot)) => {
+                            validated_servers.lock().insert(position, snapshot);
+                        }
+                        _ => warn!(
+                            "Could not check if {} accepts writes during validation",
+                            address.name()
+                        ),
+                    }
+
                     let server_parameters: ServerParameters = server.server_parameters();
+                    reported_parameters
+                        .lock()
+                        .push((address, server_parameters.clone()));
 
                     let mut guard = pool_server_parameters.write();
                     *guard = server_parameters;
                     validated.store(true, Ordering::Relaxed);
+
+                    Ok(true)
                 });
 
-                futures.push(task);
+                futures.push((address_for_result, task));
             }
         }
 
-        futures::future::join_all(futures).await;
-
-        // TODO: compare server information to make sure
-        // all shards are running identical configurations.
-        if !self.validated() {
-            error!("Could not validate connection pool");
-            return Err(Error::AllServersDown);
-        }
-
-        Ok(())
-    }
+        let (addresses, tasks): (Vec<Address>, Vec<_>) = futures.into_iter().unzip();
+        let results = futures::future::join_all(tasks).await;
 
-    /// The pool can be used by clients.
-    ///
-    /// If not, we need to validate it first by connecting to servers.
-    /// Call `validate()` to do so.
-    pub fn validated(&self) -> bool {
-        self.validated.load(Ordering::Relaxed)
-    }
+        let results = addresses
+            .into_iter()
+            .zip(results)
+            .map(|(address, result)| {
+                // The task only fails if it panicked, the server wasn't validated.
+                (address, result.unwrap_or(Ok(false)))
+            })
+            .collect();
 
-    /// Pause the pool, allowing no more queries and make clients wait.
-    pub fn pause(&self) {
-        self.paused.store(true, Ordering::Relaxed);
-    }
+        let reported_parameters = std::mem::take(&mut *reported_parameters.lock());
 
-    /// Resume the pool, allowing queries and resuming any pending queries.
-    pub fn resume(&self) {
-        self.paused.store(false, Ordering::Relaxed);
-        self.paused_waiter.notify_waiters();
+        (results, reported_parameters)
     }
 
-    /// Check if the pool is paused.
-    pub fn paused(&self) -> bool {
-        self.paused.load(Ordering::Relaxed)
-    }
+    /// Revalidate the pools whose config didn't change, concurrently.
+    async fn revalidate_unchanged(config: &Config) -> HashMap<PoolIdentifier, bool> {
+        let mut unchanged = Vec::new();
 
-    /// Check if the pool is paused and wait until it's resumed.
-    pub async fn wait_paused(&self) -> bool {
-        let waiter = self.paused_waiter.notified();
-        let paused = self.paused.load(Ordering::Relaxed);
+        for (pool_name, pool_config) in &config.pools {
+            let hash_value = pool_config.hash_value();
 
-        if paused {
-            waiter.await;
+            for user in pool_config.users.values() {
+                if let Some(pool) = get_pool(pool_name, &user.username) {
+                    if pool.config_hash == hash_value {
+                        unchanged.push((PoolIdentifier::new(pool_name, &user.username), pool));
+                    }
+                }
+            }
         }
 
-        paused
+        let results =
+            futures::future::join_all(unchanged.iter().map(|(_, pool)| pool.revalidate())).await;
+
+        unchanged
+            .into_iter()
+            .map(|(identifier, _)| identifier)
+            .zip(results)
+            .collect()
     }
 
-    /// Get a connection from the pool.
-    pub async fn get(
-        &self,
-        shard: Option<usize>,       // shard number
-        role: Option<Role>,         // primary or replica
-        client_stats: &ClientStats, // client id
-    ) -> Result<(PooledConnection<'_, ServerPool>, Address), Error> {
-        let effective_shard_id = if self.shards() == 1 {
-            // The base, unsharded case
-            Some(0)
-        } else {
-            if !self.valid_shard_id(shard) {
-                // None is valid shard ID so it is safe to unwrap here
-                return Err(Error::InvalidShardId(shard.unwrap()));
-            }
-            shard
-        };
+    /// Lighter version of `validate()` for pools reused on config reload.
+    /// Checks one connection per server, all at once, and returns false if
+    /// a server started or stopped accepting writes, or its parameters changed,
+    /// since the pool was validated.
+    pub async fn revalidate(&self) -> bool {
+        let mut futures = Vec::new();
 
-        let mut candidates = self
-            .addresses
-            .iter()
-            .flatten()
-            .filter(|address| address.role == role)
-            .collect::<Vec<&Address>>();
+        for shard in 0..self.shards() {
+            for server in 0..self.servers(shard) {
+                let databases = self.databases.clone();
+                let address = self.address(shard, server).clone();
+                let healthcheck_timeout = self.settings.healthcheck_timeout;
+                let validated_servers = Arc::clone(&self.validated_servers);
+                let position = (shard, server);
 
-        // We start with a shuffled list of addresses even if we end up resorting
-        // this is meant to avoid hitting instance 0 everytime if the sorting metric
-        // ends up being the same for all instances
-        candidates.shuffle(&mut thread_rng());
+                let task = tokio::task::spawn(async move {
+                    let mut connection = match databases[shard][server].get().await {
+                        Ok(conn) => conn,
+                        Err(err) => {
+                            error!("Could not revalidate {:?}: {:?}", address, err);
+                            return true;
+                        }
+                    };
 
-        match effective_shard_id {
-            Some(shard_id) => candidates.retain(|address| address.shard == shard_id),
-            None => match self.settings.default_shard {
-                DefaultShard::Shard(shard_id) => {
-                    candidates.retain(|address| address.shard == shard_id)
-                }
-                DefaultShard::Random => (),
-                DefaultShard::RandomHealthy => {
-                    candidates.sort_by(|a, b| {
-                        b.error_count
-                            .load(Ordering::Relaxed)
-                            .partial_cmp(&a.error_count.load(Ordering::Relaxed))
-                            .unwrap()
-                    });
+                    let server = &mut *connection;
+
+                    let snapshot = match tokio::time::timeout(
+                        tokio::time::Duration::from_millis(healthcheck_timeout),
+                        ServerSnapshot::take(server),
+                    )
+                    .await
+                    {
+                        Ok(Ok(snapshot)) => snapshot,
+                        Ok(Err(err)) => {
+                            error!("Could not revalidate {:?}: {:?}", address, err);
+                            server.mark_bad("failed revalidation");
+                            return true;
+                        }
+                        Err(_) => {
+                            error!("Revalidation timeout on {:?}", address);
+                            server.mark_bad("revalidation timeout");
+                            return true;
+                        }
+                    };
+
+                    let mut validated_servers = validated_servers.lock();
+
+                    match validated_servers.get(&position) {
+                        Some(validated) => {
+                            let changes = snapshot.changes(validated);
+
+                            for change in &changes {
+                                warn!(
+                                    "{:?} changed since the pool was validated: {}",
+                                    address, change
+                                );
+                            }
+
+                            changes.is_empty()
+                        }
+
+                        // It couldn't be checked during validation, compare against it next time.
+                        None => {
+                            validated_servers.insert(position, snapshot);
+                            true
+                        }
+                    }
+                });
+
+                futures.push(task);
+            }
+        }
+
+        futures::future::join_all(futures)
+            .await
+            .into_iter()
+            .all(|result| result.unwrap_or(true))
+    }
+
+    /// Ask every server if it's in recovery and warn about the ones whose
+    /// role disagrees with the config, e.g. a replica promoted in a failover.
+    pub async fn check_roles(&self) {
+        let mut futures = Vec::new();
+
+        for shard in 0..self.shards() {
+            for server in 0..self.servers(shard) {
+                let databases = self.databases.clone();
+                let address = self.address(shard, server).clone();
+                let healthcheck_timeout = self.settings.healthcheck_timeout;
+                let follow_role_changes = self.settings.follow_role_changes;
+
+                let task = tokio::task::spawn(async move {
+                    // Don't take a connection clients could be waiting for.
+                    let in_recovery = tokio::time::timeout(
+                        tokio::time::Duration::from_millis(healthcheck_timeout),
+                        async {
+                            match databases[shard][server].dedicated_connection().await {
+                                Ok(mut connection) => connection
+                                    .in_recovery()
+                                    .await
+                                    .map_err(|err| format!("{:?}", err)),
+                                Err(err) => Err(format!("{:?}", err)),
+                            }
+                        },
+                    )
+                    .await;
+
+                    let observed_role = match in_recovery {
+                        Ok(Ok(true)) => Role::Replica,
+                        Ok(Ok(false)) => Role::Primary,
+                        Ok(Err(err)) => {
+                            debug!("Could not check the role of {:?}: {}", address, err);
+                            return;
+                        }
+                        Err(_) => {
+                            debug!("Role check timeout on {:?}", address);
+                            return;
+                        }
+                    };
+
+                    if observed_role != address.role {
+                        warn!(
+                            "{} is configured as {} but is running as {}{}",
+                            address.name(),
+                            address.role,
+                            observed_role,
+                            match follow_role_changes {
+                                true => ", routing to it as such",
+                                false => ", update the config",
+                            }
+                        );
+                    }
+
+                    address.set_observed_role(observed_role);
+                });
+
+                futures.push(task);
+            }
+        }
+
+        futures::future::join_all(futures).await;
+    }
+
+    /// Role used to pick servers for a query: the configured one, or the one
+    /// the server last reported if the pool follows role changes.
+    fn routing_role(&self, address: &Address) -> Role {
+        match self.settings.follow_role_changes {
+            true => address.observed_role().unwrap_or(address.role),
+            false => address.role,
+        }
+    }
+
+    /// The pool can be used by clients.
+    ///
+    /// If not, we need to validate it first by connecting to servers.
+    /// Call `validate()` to do so.
+    pub fn validated(&self) -> bool {
+        self.validated.load(Ordering::Relaxed)
+    }
+
+    /// Open prewarmed server connections, and how many the pool opens at startup:
+    /// `min_pool_size` on every server. Nothing to wait for without a prewarmer.
+    pub fn prewarm_progress(&self) -> (u64, u64) {
+        let prewarmer_enabled = self
+            .settings
+            .plugins
+            .as_ref()
+            .and_then(|plugins| plugins.prewarmer.as_ref())
+            .is_some_and(|prewarmer| prewarmer.enabled);
+
+        let target = match prewarmer_enabled {
+            true => {
+                self.settings.user.min_pool_size.unwrap_or(0) as u64
+                    * self.addresses.iter().flatten().count() as u64
+            }
+            false => 0,
+        };
+
+        (self.prewarmed_connections.load(Ordering::Relaxed), target)
+    }
+
+    /// Check if the connections opened at startup are all prewarmed.
+    pub fn prewarmed(&self) -> bool {
+        let (prewarmed, target) = self.prewarm_progress();
+        prewarmed >= target
+    }
+
+    /// Pause the pool, allowing no more queries and make clients wait.
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Relaxed);
+    }
+
+    /// Resume the pool, allowing queries and resuming any pending queries.
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Relaxed);
+        self.paused_waiter.notify_waiters();
+    }
+
+    /// Check if the pool is paused.
+    pub fn paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Drain the pool before shutting down. Connections already checked out
+    /// finish their work, new checkouts fail with `ShuttingDown`.
+    /// Clients waiting for a paused pool are let go to get that error too.
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+        self.paused_waiter.notify_waiters();
+    }
+
+    /// Check if the pool is draining.
+    pub fn draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Check if the pool is paused and wait until it's resumed,
+    /// or until `max_pause_wait` runs out.
+    pub async fn wait_paused(&self) -> Result<bool, Error> {
+        let waiter = self.paused_waiter.notified();
+        let paused = self.paused.load(Ordering::Relaxed);
+
+        if paused && !self.draining() {
+            match self.settings.max_pause_wait {
+                0 => waiter.await,
+                max_pause_wait => {
+                    if tokio::time::timeout(
+                        std::time::Duration::from_millis(max_pause_wait),
+                        waiter,
+                    )
+                    .await
+                    .is_err()
+                    {
+                        return Err(Error::PauseWaitTimeout);
+                    }
                 }
+            }
+        }
+
+        Ok(paused)
+    }
+
+    /// Reserve a slot to execute a query if the pool limits concurrent queries.
+    /// The slot is released when the returned permit is dropped.
+    pub async fn acquire_query_permit(&self) -> Result<Option<OwnedSemaphorePermit>, Error> {
+        let semaphore = match self.query_semaphore {
+            Some(ref semaphore) => semaphore.clone(),
+            None => return Ok(None),
+        };
+
+        match self.settings.max_concurrent_queries_wait_timeout {
+            0 => match semaphore.try_acquire_owned() {
+                Ok(permit) => Ok(Some(permit)),
+                Err(_) => Err(Error::MaxConcurrentQueries),
             },
+            timeout => match tokio::time::timeout(
+                tokio::time::Duration::from_millis(timeout),
+                semaphore.acquire_owned(),
+            )
+            .await
+            {
+                Ok(Ok(permit)) => Ok(Some(permit)),
+                _ => Err(Error::MaxConcurrentQueries),
+            },
+        }
+    }
+
+    /// Wait until a new client is allowed into the pool if it limits
+    /// how many clients connect every second.
+    pub async fn admit_client(&self) -> Result<(), Error> {
+        let rate = match self.settings.max_connections_per_second {
+            Some(rate) => rate,
+            None => return Ok(()),
         };
+        let max_wait =
+            std::time::Duration::from_millis(self.settings.max_connections_per_second_wait_timeout);
+
+        let wait = {
+            let mut limits = CONNECTION_RATE_LIMITS.lock();
+            let bucket = limits
+                .entry(PoolIdentifier::new(
+                    &self.settings.db,
+                    &self.settings.user.username,
+                ))
+                .or_insert_with(|| TokenBucket::new(rate));
+
+            // The limit was changed by a reload.
+            if bucket.rate != rate {
+                *bucket = TokenBucket::new(rate);
+            }
 
-        if self.settings.load_balancing_mode == LoadBalancingMode::LeastOutstandingConnections {
-            candidates.sort_by(|a, b| {
-                self.busy_connection_count(b)
-                    .partial_cmp(&self.busy_connection_count(a))
-                    .unwrap()
-            });
+            bucket.take(max_wait)
+        };
+
+        match wait {
+            Some(wait) => {
+                if !wait.is_zero() {
+                    tokio::time::sleep(wait).await;
+                }
+                Ok(())
+            }
+            None => Err(Error::MaxConnectionsPerSecond(rate)),
+        }
+    }
+
+    /// Times all replicas of a shard were banned and some had to be unbanned.
+    pub fn all_replicas_banned(&self) -> u64 {
+        self.all_replicas_banned.load(Ordering::Relaxed)
+    }
+
+    /// Count a query the query parser couldn't parse.
+    pub fn parse_failed(&self) {
+        self.parse_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn parse_failures(&self) -> u64 {
+        self.parse_failures.load(Ordering::Relaxed)
+    }
+
+    /// Count a client switched from transaction to session mode in `auto` pool mode.
+    pub fn auto_session_client(&self) {
+        self.auto_session_clients.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn auto_session_clients(&self) -> u64 {
+        self.auto_session_clients.load(Ordering::Relaxed)
+    }
+
+    /// Number of queries currently executing if the pool limits concurrent queries.
+    pub fn queries_in_flight(&self) -> u64 {
+        match (&self.query_semaphore, self.settings.max_concurrent_queries) {
+            (Some(semaphore), Some(max_concurrent_queries)) => {
+                max_concurrent_queries.saturating_sub(semaphore.available_permits()) as u64
+            }
+            _ => 0,
         }
+    }
+
+    /// Get a connection from the pool.
+    pub async fn get(
+        &self,
+        shard: Option<usize>,                   // shard number
+        role: Option<Role>,                     // primary or replica
+        target_server: Option<&str>,            // server asked for by name
+        client_stats: &ClientStats,             // client id
+        disconnected: impl Future<Output = ()>, // resolves if the client goes away
+    ) -> Result<Checkout<'_>, Error> {
+        let start = Instant::now();
+        let checkout = self
+            .checkout(shard, role, target_server, client_stats, disconnected)
+            .await;
+
+        match &checkout {
+            Ok((_, address, checkout, _)) => self
+                .checkout_histograms
+                .role(address.role, true)
+                .observe(checkout.checkout_time),
+
+            // Failures count for the role asked for. Reads that could go
+            // anywhere count as replica reads if there are replicas.
+            Err(_) => {
+                let role = role.unwrap_or_else(|| {
+                    match self
+                        .addresses
+                        .iter()
+                        .flatten()
+                        .any(|address| address.role == Role::Replica)
+                    {
+                        true => Role::Replica,
+                        false => Role::Primary,
+                    }
+                });
+                self.checkout_histograms
+                    .role(role, false)
+                    .observe(start.elapsed().as_micros() as u64);
+            }
+        }
+
+        checkout
+    }
+
+    async fn checkout(
+        &self,
+        shard: Option<usize>,
+        role: Option<Role>,
+        target_server: Option<&str>,
+        client_stats: &ClientStats,
+        disconnected: impl Future<Output = ()>,
+    ) -> Result<Checkout<'_>, Error> {
+        if self.draining() {
+            return Err(Error::ShuttingDown);
+        }
+
+        // Don't let one application take all the connections of the user.
+        // The connection is counted until the client gives it back.
+        let application_connection = match self.settings.user.max_client_connections {
+            Some(max_client_connections) => Some(
+                self.application_connections
+                    .enter(&client_stats.application_name(), max_client_connections)
+                    .ok_or(Error::MaxClientConnections(max_client_connections))?,
+            ),
+            None => None,
+        };
+
+        let effective_shard_id = if self.shards() == 1 {
+            // The base, unsharded case
+            Some(0)
+        } else {
+            if !self.valid_shard_id(shard) {
+                // None is valid shard ID so it is safe to unwrap here
+                return Err(Error::InvalidShardId(shard.unwrap()));
+            }
+            shard
+        };
+
+        let mut candidates = match target_server {
+            Some(name) => vec![self.target_server(name, role)?],
+            None => self.balanced_candidates(effective_shard_id, role, client_stats),
+        };
+
+        // Only describe the candidates when asked to, it's not free.
+        let routing_candidates = match self.settings.log_routing_decisions {
+            true => Some(self.describe_candidates(&candidates)),
+            false => None,
+        };
 
         // Indicate we're waiting on a server connection from a pool.
         let now = Instant::now();
         client_stats.waiting();
+        let _queue_depth_guard = self.queue_depth.enter();
+
+        // Stop waiting if the client disconnects, nobody is going to use the connection.
+        tokio::pin!(disconnected);
+
+        let fair_checkout = get_fair_checkout();
+        let mut queue_time = 0;
+
+        let mut candidates_tried = 0;
+
+        // Why candidates were skipped, to tell the client what went wrong
+        // if none of them work out.
+        let mut unavailable = 0;
+        let mut timed_out = 0;
+        let mut failed = 0;
+        let mut busy = 0;
 
         while !candidates.is_empty() {
             // Get the next candidate
@@ -742,6 +1905,14 @@ impl ConnectionPool {
                 None => break,
             };
 
+            candidates_tried += 1;
+
+            if address.is_out_of_rotation() {
+                debug!("Address {:?} is out of rotation", address);
+                unavailable += 1;
+                continue;
+            }
+
             let mut force_healthcheck = false;
 
             if self.is_banned(address) {
@@ -749,15 +1920,80 @@ impl ConnectionPool {
                     force_healthcheck = true;
                 } else {
                     debug!("Address {:?} is banned", address);
+                    unavailable += 1;
                     continue;
                 }
             }
 
-            // Check if we can connect
-            let mut conn = match self.databases[address.shard][address.address_index]
-                .get()
-                .await
+            // Replicas too far behind the primary are skipped, until a health
+            // check finds they caught up. The lag is only as fresh as the last
+            // health check, so re-check it if it's old.
+            if self.is_lagging(address) {
+                if address.stats.replica_lag_age() > self.settings.healthcheck_delay {
+                    force_healthcheck = true;
+                } else {
+                    debug!("Address {:?} is lagging behind the primary", address);
+                    unavailable += 1;
+                    continue;
+                }
+            }
+
+            // The server is refusing connections, don't wait for a new one
+            // unless there is one already in the pool.
+            if address.connect_backoff.throttled()
+                && self
+                    .pool_state(address.shard, address.address_index)
+                    .idle_connections
+                    == 0
             {
+                debug!(
+                    "Address {:?} is throttled after connection failures",
+                    address
+                );
+                unavailable += 1;
+                continue;
+            }
+
+            // Don't queue behind other clients if we'd rather fail fast.
+            if self.settings.checkout_failure_mode == CheckoutFailureMode::Reject
+                && self.saturated(address)
+            {
+                debug!("Address {:?} has no connection available", address);
+                busy += 1;
+                continue;
+            }
+
+            // Wait for our turn, clients that came before us get a connection
+            // from this shard first. The turn ends once we have a connection,
+            // so a slow health check doesn't hold up the others.
+            let checkout_turn = if fair_checkout {
+                let queued_at = Instant::now();
+                let turn = tokio::select! {
+                    turn = self.checkout_queue[address.shard].lock() => turn,
+                    _ = &mut disconnected => {
+                        debug!("Client disconnected while waiting for its turn");
+                        client_stats.idle();
+                        return Err(Error::CheckoutCancelled);
+                    }
+                };
+                queue_time += queued_at.elapsed().as_micros() as u64;
+                Some(turn)
+            } else {
+                None
+            };
+
+            // Check if we can connect
+            let conn = tokio::select! {
+                conn = self.databases[address.shard][address.address_index].get() => conn,
+                _ = &mut disconnected => {
+                    debug!("Client disconnected while waiting for a connection to {:?}", address);
+                    client_stats.idle();
+                    return Err(Error::CheckoutCancelled);
+                }
+            };
+            drop(checkout_turn);
+
+            let mut conn = match conn {
                 Ok(conn) => {
                     address.reset_error_count();
                     conn
@@ -767,8 +2003,25 @@ impl ConnectionPool {
                         "Connection checkout error for instance {:?}, error: {:?}",
                         address, err
                     );
-                    self.ban(address, BanReason::FailedCheckout, Some(client_stats));
+                    match err {
+                        RunError::TimedOut => timed_out += 1,
+                        RunError::User(_) => failed += 1,
+                    }
+
+                    // A server out of connection slots or starting up isn't down,
+                    // the connection backoff keeps us away from it for a moment.
+                    if matches!(err, RunError::User(Error::ServerTemporarilyUnavailable(_)))
+                        || address.connect_backoff.temporary()
+                    {
+                        warn!(
+                            "{:?} is temporarily refusing connections, skipping it",
+                            address
+                        );
+                    } else {
+                        self.ban(address, BanReason::FailedCheckout, Some(client_stats));
+                    }
                     address.stats.error();
+                    address.stats.checkout_error();
                     client_stats.checkout_error();
                     continue;
                 }
@@ -787,35 +2040,242 @@ impl ConnectionPool {
             // Health checks are pretty expensive.
             if !require_healthcheck {
                 let checkout_time = now.elapsed().as_micros() as u64;
+                let checkout = SlowCheckout {
+                    at: Instant::now(),
+                    checkout_time,
+                    address: address.name(),
+                    health_check: false,
+                    candidates_tried,
+                    queue_time,
+                };
+                self.slow_checkouts.record(&checkout);
+                self.queue_time.record(queue_time);
                 client_stats.checkout_success();
                 server
                     .stats()
                     .checkout_time(checkout_time, client_stats.application_name());
                 server.stats().active(client_stats.application_name());
                 client_stats.active();
-                return Ok((conn, address.clone()));
+                if let Some(candidates) = &routing_candidates {
+                    self.log_routing_decision(effective_shard_id, role, candidates, address);
+                }
+                return Ok((conn, address.clone(), checkout, application_connection));
             }
 
-            if self
-                .run_health_check(address, server, now, client_stats)
-                .await
-            {
+            let healthy = tokio::select! {
+                healthy = self.run_health_check(address, server, now, client_stats) => Some(healthy),
+                _ = &mut disconnected => None,
+            };
+
+            let healthy = match healthy {
+                Some(healthy) => healthy,
+                None => {
+                    // The health check query may still be in flight.
+                    server.mark_bad("client disconnected during health check");
+                    client_stats.idle();
+                    return Err(Error::CheckoutCancelled);
+                }
+            };
+
+            if healthy {
+                address.health_check_passed();
                 let checkout_time = now.elapsed().as_micros() as u64;
+                let checkout = SlowCheckout {
+                    at: Instant::now(),
+                    checkout_time,
+                    address: address.name(),
+                    health_check: true,
+                    candidates_tried,
+                    queue_time,
+                };
+                self.slow_checkouts.record(&checkout);
+                self.queue_time.record(queue_time);
                 client_stats.checkout_success();
                 server
                     .stats()
                     .checkout_time(checkout_time, client_stats.application_name());
                 server.stats().active(client_stats.application_name());
                 client_stats.active();
-                return Ok((conn, address.clone()));
+                if let Some(candidates) = &routing_candidates {
+                    self.log_routing_decision(effective_shard_id, role, candidates, address);
+                }
+                return Ok((conn, address.clone(), checkout, application_connection));
+            } else if self.is_lagging(address) {
+                unavailable += 1;
+                continue;
             } else {
+                failed += 1;
                 continue;
             }
         }
 
         client_stats.checkout_error();
 
-        Err(Error::AllServersDown)
+        // Don't send the query somewhere else, the client asked for this server.
+        if let (Some(name), true) = (target_server, unavailable > 0) {
+            return Err(Error::ServerUnavailable(name.to_string()));
+        }
+
+        if timed_out > 0 && timed_out >= failed {
+            Err(Error::CheckoutTimeout)
+        } else if busy > 0 && timed_out == 0 && failed == 0 {
+            Err(Error::PoolBusy)
+        } else if unavailable > 0 && timed_out == 0 && failed == 0 {
+            Err(Error::AllServersBanned)
+        } else {
+            Err(Error::AllServersDown)
+        }
+    }
+
+    /// Servers that can take the query, in the order to try them, last one first.
+    fn balanced_candidates(
+        &self,
+        effective_shard_id: Option<usize>,
+        role: Option<Role>,
+        client_stats: &ClientStats,
+    ) -> Vec<&Address> {
+        let mut candidates = self
+            .addresses
+            .iter()
+            .flatten()
+            .filter(|address| self.routing_role(address) == role)
+            .collect::<Vec<&Address>>();
+
+        // We start with a shuffled list of addresses even if we end up resorting
+        // this is meant to avoid hitting instance 0 everytime if the sorting metric
+        // ends up being the same for all instances
+        if self.settings.load_balancing_mode == LoadBalancingMode::LeastOutstandingConnections
+            && !self.settings.loc_shuffle
+        {
+            // Candidates are tried from the end and the sort below is stable,
+            // so ties go to the server listed first.
+            candidates.reverse();
+        } else {
+            candidates.shuffle(&mut thread_rng());
+        }
+
+        match effective_shard_id {
+            Some(shard_id) => candidates.retain(|address| address.shard == shard_id),
+            None => match self.settings.default_shard {
+                DefaultShard::Shard(shard_id) => {
+                    candidates.retain(|address| address.shard == shard_id)
+                }
+                DefaultShard::Random => (),
+                DefaultShard::RandomHealthy => {
+                    candidates.sort_by(|a, b| {
+                        b.error_count
+                            .load(Ordering::Relaxed)
+                            .partial_cmp(&a.error_count.load(Ordering::Relaxed))
+                            .unwrap()
+                    });
+                }
+            },
+        };
+
+        // Reads that can go anywhere only consider the primary some of the time,
+        // as long as there are replicas to read from instead.
+        if let (None, Some(primary_read_weight)) = (role, self.settings.primary_read_weight) {
+            if candidates
+                .iter()
+                .any(|address| address.role == Role::Replica)
+                && !thread_rng().gen_bool(primary_read_weight.clamp(0.0, 1.0))
+            {
+                candidates.retain(|address| address.role != Role::Primary);
+            }
+        }
+
+        match self.settings.load_balancing_mode {
+            LoadBalancingMode::Random => (),
+            LoadBalancingMode::LeastOutstandingConnections => {
+                candidates.sort_by(|a, b| {
+                    self.busy_connection_count(b)
+                        .partial_cmp(&self.busy_connection_count(a))
+                        .unwrap()
+                });
+            }
+            LoadBalancingMode::ZoneAware => {
+                // Candidates are tried from the end, so servers in our zone go last.
+                // The sort is stable and keeps the shuffled order within each zone.
+                candidates.sort_by_key(|address| {
+                    address.zone.is_some() && address.zone == self.settings.zone
+                });
+            }
+            LoadBalancingMode::Weighted => weighted_order(&mut candidates),
+            LoadBalancingMode::StickyHash => {
+                sticky_order(&mut candidates, client_stats.client_id());
+            }
+        }
+
+        // Writes to a shard with several primaries always follow the weights,
+        // so one can take most of them while the others stay warm.
+        if role == Some(Role::Primary)
+            && self.settings.load_balancing_mode != LoadBalancingMode::Weighted
+            && candidates.len() > 1
+            && candidates
+                .iter()
+                .all(|address| address.shard == candidates[0].shard)
+        {
+            weighted_order(&mut candidates);
+        }
+
+        // Reads fall back to the primary when every replica is too far behind it,
+        // unless primary reads are disabled. Candidates are tried from the end, so the
+        // primary goes after the replicas that may have caught up since their last health check.
+        if role == Some(Role::Replica)
+            && self.settings.primary_reads_enabled
+            && !candidates.is_empty()
+            && candidates.iter().all(|address| self.is_lagging(address))
+        {
+            let primaries = self
+                .addresses
+                .iter()
+                .flatten()
+                .filter(|address| {
+                    self.routing_role(address) == Role::Primary
+                        && candidates
+                            .iter()
+                            .any(|replica| replica.shard == address.shard)
+                })
+                .collect::<Vec<&Address>>();
+            candidates.splice(0..0, primaries);
+        }
+
+        candidates
+    }
+
+    /// The server a query asked for by name, bypassing load balancing.
+    /// It must have the role the query was routed to, if any.
+    fn target_server(&self, name: &str, role: Option<Role>) -> Result<&Address, Error> {
+        let address = self
+            .addresses
+            .iter()
+            .flatten()
+            .find(|address| address.label.as_deref() == Some(name) || address.name() == name)
+            .ok_or_else(|| Error::UnknownServer(name.to_string()))?;
+
+        let server_role = self.routing_role(address);
+
+        let allowed = match (role, server_role) {
+            (None, _) => true,
+            // Reads can go to the primary, like with load balancing.
+            (Some(Role::Replica), Role::Primary) => self.settings.primary_reads_enabled,
+            (Some(role), server_role) => role == server_role,
+        };
+
+        match (allowed, role) {
+            (false, Some(role)) => Err(Error::WrongServerRole(
+                name.to_string(),
+                server_role.to_string(),
+                role.to_string(),
+            )),
+            _ => Ok(address),
+        }
+    }
+
+    /// Every connection to the server is in use and we can't open another one.
+    fn saturated(&self, address: &Address) -> bool {
+        let state = self.pool_state(address.shard, address.address_index);
+        state.idle_connections == 0 && state.connections >= self.settings.user.pool_size
     }
 
     async fn run_health_check(
@@ -829,15 +2289,44 @@ impl ConnectionPool {
 
         server.stats().tested();
 
+        let healthcheck = async {
+            match self.settings.healthcheck_mode {
+                HealthcheckMode::Query => server.query(&self.settings.healthcheck_query).await,
+                HealthcheckMode::Sync => server.ping().await,
+            }?;
+
+            if self.settings.max_replica_lag.is_some()
+                && self.routing_role(address) == Role::Replica
+            {
+                let replica_lag = server.replica_lag().await?;
+                address.stats.set_replica_lag(replica_lag);
+            }
+
+            Ok::<(), Error>(())
+        };
+
         match tokio::time::timeout(
             tokio::time::Duration::from_millis(self.settings.healthcheck_timeout),
-            server.query(";"), // Cheap query as it skips the query planner
+            healthcheck,
         )
         .await
         {
             // Check if health check succeeded.
             Ok(res) => match res {
+                // Healthy, but too far behind to serve reads. Not a failure,
+                // the caller tries another server.
+                Ok(_) if self.is_lagging(address) => {
+                    address.stats.healthcheck_succeeded();
+                    debug!(
+                        "Replica {:?} is {}ms behind the primary",
+                        address,
+                        address.stats.replica_lag()
+                    );
+                    return false;
+                }
+
                 Ok(_) => {
+                    address.stats.healthcheck_succeeded();
                     let checkout_time: u64 = start.elapsed().as_micros() as u64;
                     client_info.checkout_success();
                     server
@@ -850,26 +2339,32 @@ impl ConnectionPool {
 
                 // Health check failed.
                 Err(err) => {
-                    error!(
-                        "Failed health check on instance {:?}, error: {:?}",
-                        address, err
-                    );
+                    let _span = address.span().entered();
+                    tracing::error!(error = ?err, "Failed health check");
                 }
             },
 
             // Health check timed out.
-            Err(err) => {
-                error!(
-                    "Health check timeout on instance {:?}, error: {:?}",
-                    address, err
+            Err(_) => {
+                let _span = address.span().entered();
+                tracing::error!(
+                    timeout_ms = self.settings.healthcheck_timeout,
+                    "Health check timed out"
                 );
             }
         }
 
         // Don't leave a bad connection in the pool.
         server.mark_bad("failed health check");
+        address.stats.checkout_error();
+        address.stats.healthcheck_failed();
 
-        self.ban(address, BanReason::FailedHealthCheck, Some(client_info));
+        let failures = address.health_check_failed();
+        self.ban(
+            address,
+            BanReason::FailedHealthCheck(failures),
+            Some(client_info),
+        );
         false
     }
 
@@ -880,7 +2375,7 @@ impl ConnectionPool {
         // Count the number of errors since the last successful checkout
         // This is used to determine if the shard is down
         match reason {
-            BanReason::FailedHealthCheck
+            BanReason::FailedHealthCheck(_)
             | BanReason::FailedCheckout
             | BanReason::MessageSendFailed
             | BanReason::MessageReceiveFailed => {
@@ -889,12 +2384,18 @@ impl ConnectionPool {
             _ => (),
         };
 
-        // Primary can never be banned
-        if address.role == Role::Primary {
+        // Primary can only be banned by an operator, e.g. when it was demoted
+        // but is still reachable during a failover.
+        if self.routing_role(address) == Role::Primary && !matches!(reason, BanReason::AdminBan(_))
+        {
             return;
         }
 
-        error!("Banning instance {:?}, reason: {:?}", address, reason);
+        {
+            let _span = address.span().entered();
+            tracing::error!(reason = ?reason, "Banning instance");
+        }
+        address.stats.ban();
 
         let now = chrono::offset::Utc::now().naive_utc();
         let mut guard = self.banlist.write();
@@ -904,14 +2405,71 @@ impl ConnectionPool {
             address.stats.error();
         }
 
+        ban_events::emit(BanEvent::new(BanEventKind::Ban, address, Some(&reason)));
         guard[address.shard].insert(address.clone(), (reason, now));
     }
 
+    /// Keep the bans of servers still in the pool after it was re-created.
+    /// Servers are matched by host, port, shard and role, and the bans
+    /// keep their timestamp so they expire when they would have.
+    fn inherit_bans(&self, old_pool: &ConnectionPool) {
+        let old_banlist = old_pool.banlist.read();
+        let mut banlist = self.banlist.write();
+
+        for (old_address, ban) in old_banlist.iter().flatten() {
+            if let Some(address) = self.same_server(old_address) {
+                let _span = address.span().entered();
+                tracing::info!(
+                    reason = ?ban.0,
+                    "Keeping ban after the pool was re-created"
+                );
+                banlist[address.shard].insert(address.clone(), ban.clone());
+            }
+        }
+    }
+
+    /// Keep the roles the servers of the pool this one replaces reported,
+    /// see `check_roles()`, until they're checked again.
+    fn inherit_observed_roles(&self, old_pool: &ConnectionPool) {
+        for old_address in old_pool.addresses.iter().flatten() {
+            if let (Some(address), Some(role)) =
+                (self.same_server(old_address), old_address.observed_role())
+            {
+                address.set_observed_role(role);
+            }
+        }
+    }
+
+    /// The address of this pool pointing to the same server as an address
+    /// of a previous version of the pool.
+    fn same_server(&self, old_address: &Address) -> Option<&Address> {
+        self.addresses.iter().flatten().find(|address| {
+            address.host == old_address.host
+                && address.port == old_address.port
+                && address.shard == old_address.shard
+                && address.role == old_address.role
+        })
+    }
+
     /// Clear the replica to receive traffic again. Takes effect immediately
     /// for all new transactions.
     pub fn unban(&self, address: &Address) {
         let mut guard = self.banlist.write();
-        guard[address.shard].remove(address);
+        if guard[address.shard].remove(address).is_some() {
+            ban_events::emit(BanEvent::new(BanEventKind::Unban, address, None));
+        }
+    }
+
+    /// Check if the replica is further behind the primary than `max_replica_lag`,
+    /// as of its last health check.
+    pub fn is_lagging(&self, address: &Address) -> bool {
+        match self.settings.max_replica_lag {
+            Some(max_replica_lag) => {
+                self.routing_role(address) == Role::Replica
+                    && address.stats.replica_lag() > max_replica_lag
+            }
+            None => false,
+        }
     }
 
     /// Check if address is banned
@@ -930,29 +2488,62 @@ impl ConnectionPool {
 
     /// Determines trying to unban this server was successful
     pub async fn try_unban(&self, address: &Address) -> bool {
-        // If somehow primary ends up being banned we should return true here
-        if address.role == Role::Primary {
-            return true;
-        }
-
-        // Check if all replicas are banned, in that case unban all of them
+        // Check if all replicas are banned, in that case unban all of them,
+        // or just the one banned the longest ago, depending on `unban_strategy`.
+        // Draining replicas are out of rotation on purpose, they don't count.
+        // A primary banned by an operator stays banned until the ban expires.
         let replicas_available = self.addresses[address.shard]
             .iter()
-            .filter(|addr| addr.role == Role::Replica)
+            .filter(|addr| self.routing_role(addr) == Role::Replica && !addr.is_out_of_rotation())
             .count();
 
         debug!("Available targets: {}", replicas_available);
 
         let read_guard = self.banlist.read();
-        let all_replicas_banned = read_guard[address.shard].len() == replicas_available;
+        let replicas_banned = read_guard[address.shard]
+            .keys()
+            .filter(|addr| self.routing_role(addr) == Role::Replica && !addr.is_out_of_rotation())
+            .count();
+        let all_replicas_banned =
+            self.routing_role(address) == Role::Replica && replicas_banned == replicas_available;
         drop(read_guard);
 
         if all_replicas_banned {
             let mut write_guard = self.banlist.write();
-            warn!("Unbanning all replicas.");
-            write_guard[address.shard].clear();
 
-            return true;
+            match self.settings.unban_strategy {
+                UnbanStrategy::All => {
+                    tracing::warn!(
+                        pool = %address.pool_name,
+                        user = %address.username,
+                        shard = address.shard,
+                        "All replicas banned, unbanning all of them"
+                    );
+                    write_guard[address.shard]
+                        .retain(|addr, _| self.routing_role(addr) == Role::Primary);
+                    ban_events::emit(BanEvent::new(BanEventKind::UnbanAll, address, None));
+                    self.all_replicas_banned.fetch_add(1, Ordering::Relaxed);
+
+                    return true;
+                }
+
+                UnbanStrategy::One => {
+                    // Another client may have unbanned one while we waited for the lock.
+                    if let Some(oldest) = longest_banned(&write_guard[address.shard], |addr| {
+                        self.routing_role(addr) == Role::Replica
+                    }) {
+                        let _span = oldest.span().entered();
+                        tracing::warn!(
+                            "All replicas banned, unbanning the one banned the longest ago"
+                        );
+                        write_guard[address.shard].remove(&oldest);
+                        ban_events::emit(BanEvent::new(BanEventKind::Unban, &oldest, None));
+                        self.all_replicas_banned.fetch_add(1, Ordering::Relaxed);
+                    }
+
+                    return !write_guard[address.shard].contains_key(address);
+                }
+            }
         }
 
         // Check if ban time is expired
@@ -960,22 +2551,21 @@ impl ConnectionPool {
         let exceeded_ban_time = match read_guard[address.shard].get(address) {
             Some((ban_reason, timestamp)) => {
                 let now = chrono::offset::Utc::now().naive_utc();
-                match ban_reason {
-                    BanReason::AdminBan(duration) => {
-                        now.timestamp() - timestamp.timestamp() > *duration
-                    }
-                    _ => now.timestamp() - timestamp.timestamp() > self.settings.ban_time,
-                }
+                now.timestamp() - timestamp.timestamp() > self.ban_duration(ban_reason)
             }
             None => return true,
         };
         drop(read_guard);
 
         if exceeded_ban_time {
-            warn!("Unbanning {:?}", address);
+            {
+                let _span = address.span().entered();
+                tracing::warn!("Ban expired, unbanning");
+            }
             let mut write_guard = self.banlist.write();
             write_guard[address.shard].remove(address);
             drop(write_guard);
+            ban_events::emit(BanEvent::new(BanEventKind::Unban, address, None));
 
             true
         } else {
@@ -984,6 +2574,21 @@ impl ConnectionPool {
         }
     }
 
+    /// How long a ban lasts, in seconds. Servers that keep failing health checks
+    /// are banned for twice as long each time, up to 2^5 times `ban_time`.
+    pub fn ban_duration(&self, reason: &BanReason) -> i64 {
+        match reason {
+            BanReason::AdminBan(duration) => *duration,
+            BanReason::FailedHealthCheck(failures) => {
+                let exponent = failures
+                    .saturating_sub(1)
+                    .min(MAX_BAN_TIME_MULTIPLIER_EXPONENT);
+                self.settings.ban_time * (1 << exponent)
+            }
+            _ => self.settings.ban_time,
+        }
+    }
+
     /// Get the number of configured shards.
     pub fn shards(&self) -> usize {
         self.databases.len()
@@ -1014,6 +2619,50 @@ impl ConnectionPool {
         addresses
     }
 
+    /// Get the health of the primary and replicas of a shard.
+    pub fn shard_health(&self, shard: usize) -> ShardHealth {
+        let mut health = ShardHealth {
+            shard,
+            primary: PrimaryStatus::Missing,
+            replicas: 0,
+            healthy_replicas: 0,
+            banned_replicas: 0,
+        };
+
+        for address in self.addresses[shard].iter() {
+            let banned = self.is_banned(address);
+
+            match address.role {
+                // With several primaries, the healthiest one counts.
+                Role::Primary => {
+                    let status = if banned {
+                        PrimaryStatus::Banned
+                    } else if address.health_check_failing() || address.connect_backoff.failing() {
+                        PrimaryStatus::Down
+                    } else {
+                        PrimaryStatus::Up
+                    };
+
+                    health.primary = match (health.primary, status) {
+                        (PrimaryStatus::Up, _) | (_, PrimaryStatus::Up) => PrimaryStatus::Up,
+                        (PrimaryStatus::Down, _) | (_, PrimaryStatus::Down) => PrimaryStatus::Down,
+                        _ => PrimaryStatus::Banned,
+                    }
+                }
+                Role::Replica => {
+                    health.replicas += 1;
+                    match banned {
+                        true => health.banned_replicas += 1,
+                        false => health.healthy_replicas += 1,
+                    }
+                }
+                Role::Mirror => (),
+            }
+        }
+
+        health
+    }
+
     /// Get the number of servers (primary and replicas)
     /// configured for a shard.
     pub fn servers(&self, shard: usize) -> usize {
@@ -1043,8 +2692,86 @@ impl ConnectionPool {
         self.original_server_parameters.read().clone()
     }
 
+    /// Compare the idle connections of each server to `min_pool_size` and warn
+    /// about the ones that stay short for longer than `min_idle_shortfall_threshold`,
+    /// e.g. because connecting to the server keeps failing.
+    pub fn check_min_idle(&self) {
+        let min_idle = match self.settings.user.min_pool_size {
+            Some(min_idle) if min_idle > 0 => min_idle,
+            _ => return,
+        };
+        let threshold = std::time::Duration::from_millis(get_min_idle_shortfall_threshold());
+        let mut total = 0;
+
+        for shard in 0..self.shards() {
+            for server in 0..self.servers(shard) {
+                let state = self.pool_state(shard, server);
+                let busy = state.connections.saturating_sub(state.idle_connections);
+
+                // Busy connections count towards pool_size too, there may be no room left.
+                let target = min_idle.min(self.settings.user.pool_size.saturating_sub(busy));
+                let shortfall = target.saturating_sub(state.idle_connections);
+
+                match self.min_idle_shortfall.record(shard, server, shortfall) {
+                    Some(short_for) if short_for >= threshold => {
+                        warn!(
+                            "{:?} has {} idle connections, below min_pool_size of {} for {}s",
+                            self.address(shard, server),
+                            state.idle_connections,
+                            min_idle,
+                            short_for.as_secs()
+                        );
+                        total += shortfall as u64;
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        self.min_idle_shortfall.set(total);
+    }
+
+    /// Candidates in the order they're tried, with their busy connection counts.
+    fn describe_candidates(&self, candidates: &[&Address]) -> String {
+        candidates
+            .iter()
+            .rev()
+            .map(|address| {
+                format!(
+                    "{} (busy: {})",
+                    address.name(),
+                    self.busy_connection_count(address)
+                )
+            })
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+    fn log_routing_decision(
+        &self,
+        shard: Option<usize>,
+        role: Option<Role>,
+        candidates: &str,
+        address: &Address,
+    ) {
+        info!(
+            "Routing decision: shard {}, role {}, mode {}, candidates [{}], picked {}",
+            match shard {
+                Some(shard) => shard.to_string(),
+                None => "any".to_string(),
+            },
+            match role {
+                Some(role) => role.to_string(),
+                None => "any".to_string(),
+            },
+            self.settings.load_balancing_mode,
+            candidates,
+            address.name()
+        );
+    }
+
     /// Get the number of checked out connection for an address
-    fn busy_connection_count(&self, address: &Address) -> u32 {
+    pub fn busy_connection_count(&self, address: &Address) -> u32 {
         let state = self.pool_state(address.shard, address.address_index);
         let idle = state.idle_connections;
         let provisioned = state.connections;
@@ -1089,6 +2816,65 @@ impl ConnectionPool {
     }
 }
 
+/// How a `ServerPool` opens its server connections.
+#[derive(Clone, Debug)]
+pub struct ServerPoolSettings {
+    /// Server plugins.
+    pub plugins: Option<Plugins>,
+
+    /// Should we clean up dirty connections before putting them into the pool?
+    pub cleanup_connections: bool,
+
+    /// Query cleaning up dirty connections instead of the default one.
+    pub cleanup_query: Option<String>,
+
+    /// Log client parameter status changes
+    pub log_client_parameter_status_changes: bool,
+
+    /// Prepared statement cache size
+    pub prepared_statement_cache_size: usize,
+
+    /// Weakest authentication method the server can ask for
+    pub min_server_auth_method: AuthMethod,
+
+    /// Lifetime of server connections (ms), before jitter.
+    pub server_lifetime: u64,
+
+    /// Shorten each connection's lifetime by up to this percentage.
+    pub server_lifetime_jitter: u64,
+
+    /// Times to retry a connection that failed at the network level.
+    pub connect_retries: u32,
+
+    /// Delay before the first retry (ms), doubled after each one.
+    pub connect_retry_delay: u64,
+
+    /// Open the connections with `default_transaction_read_only` on.
+    pub read_only: bool,
+
+    /// TLS settings, `None` if `server_tls` is off.
+    pub server_tls: Option<ServerTls>,
+}
+
+impl Default for ServerPoolSettings {
+    fn default() -> ServerPoolSettings {
+        ServerPoolSettings {
+            plugins: None,
+            cleanup_connections: crate::config::Pool::default_cleanup_server_connections(),
+            cleanup_query: None,
+            log_client_parameter_status_changes: false,
+            prepared_statement_cache_size: 0,
+            min_server_auth_method: crate::config::Pool::default_min_server_auth_method(),
+            server_lifetime: General::default_server_lifetime(),
+            server_lifetime_jitter: 0,
+            connect_retries: 0,
+            connect_retry_delay: 0,
+            read_only: false,
+            server_tls: None,
+        }
+    }
+}
+
 /// Wrapper for the bb8 connection pool.
 pub struct ServerPool {
     /// Server address.
@@ -1106,31 +2892,22 @@ pub struct ServerPool {
     /// Server auth hash (for auth passthrough).
     auth_hash: Arc<RwLock<Option<String>>>,
 
-    /// Server plugins.
-    plugins: Option<Plugins>,
-
-    /// Should we clean up dirty connections before putting them into the pool?
-    cleanup_connections: bool,
-
-    /// Log client parameter status changes
-    log_client_parameter_status_changes: bool,
+    /// Open connections that ran the prewarmer queries, shared by the servers of the pool.
+    prewarmed_connections: Arc<AtomicU64>,
 
-    /// Prepared statement cache size
-    prepared_statement_cache_size: usize,
+    /// How the connections are opened.
+    settings: ServerPoolSettings,
 }
 
 impl ServerPool {
-    #[allow(clippy::too_many_arguments)]
     pub fn new(
         address: Address,
         user: User,
         database: &str,
         client_server_map: ClientServerMap,
         auth_hash: Arc<RwLock<Option<String>>>,
-        plugins: Option<Plugins>,
-        cleanup_connections: bool,
-        log_client_parameter_status_changes: bool,
-        prepared_statement_cache_size: usize,
+        prewarmed_connections: Arc<AtomicU64>,
+        settings: ServerPoolSettings,
     ) -> ServerPool {
         ServerPool {
             address,
@@ -1138,10 +2915,8 @@ impl ServerPool {
             database: database.to_string(),
             client_server_map,
             auth_hash,
-            plugins,
-            cleanup_connections,
-            log_client_parameter_status_changes,
-            prepared_statement_cache_size,
+            prewarmed_connections,
+            settings,
         }
     }
 }
@@ -1153,6 +2928,13 @@ impl ManageConnection for ServerPool {
 
     /// Attempts to create a new connection.
     async fn connect(&self) -> Result<Self::Connection, Self::Error> {
+        if self.address.connect_backoff.throttled() {
+            return Err(Error::SocketError(format!(
+                "Connection attempts to {} are throttled after failures",
+                self.address
+            )));
+        }
+
         info!("Creating a new server connection {:?}", self.address);
 
         let stats = Arc::new(ServerStats::new(
@@ -1162,37 +2944,81 @@ impl ManageConnection for ServerPool {
 
         stats.register(stats.clone());
 
-        // Connect to the PostgreSQL server.
-        match Server::startup(
-            &self.address,
-            &self.user,
-            &self.database,
-            self.client_server_map.clone(),
-            stats.clone(),
-            self.auth_hash.clone(),
-            self.cleanup_connections,
-            self.log_client_parameter_status_changes,
-            self.prepared_statement_cache_size,
-        )
-        .await
-        {
+        let mut retries = 0;
+        let mut retry_delay = self.settings.connect_retry_delay;
+
+        // Connect to the PostgreSQL server. Network errors are often a blip,
+        // e.g. the server restarting, so try again a few times before giving up.
+        // Anything else, like bad credentials, won't go away by retrying.
+        let result = loop {
+            match Server::startup(
+                &self.address,
+                &self.user,
+                &self.database,
+                self.client_server_map.clone(),
+                stats.clone(),
+                self.auth_hash.clone(),
+                self.settings.cleanup_connections,
+                self.settings.cleanup_query.clone(),
+                self.settings.log_client_parameter_status_changes,
+                self.settings.prepared_statement_cache_size,
+                self.settings.min_server_auth_method,
+                self.settings.read_only,
+                self.settings.server_tls.as_ref(),
+            )
+            .await
+            {
+                Err(Error::SocketError(err)) if retries < self.settings.connect_retries => {
+                    retries += 1;
+
+                    warn!(
+                        "Could not connect to {:?}: {}, retry {} of {} in {}ms",
+                        self.address, err, retries, self.settings.connect_retries, retry_delay
+                    );
+
+                    tokio::time::sleep(tokio::time::Duration::from_millis(retry_delay)).await;
+                    retry_delay = retry_delay.saturating_mul(2);
+                }
+                result => break result,
+            }
+        };
+
+        match result {
             Ok(mut conn) => {
-                if let Some(ref plugins) = self.plugins {
+                if let Some(ref plugins) = self.settings.plugins {
                     if let Some(ref prewarmer) = plugins.prewarmer {
                         let mut prewarmer = prewarmer::Prewarmer {
                             enabled: prewarmer.enabled,
                             server: &mut conn,
                             queries: &prewarmer.queries,
+                            pipeline_depth: prewarmer.pipeline_depth,
                         };
 
                         prewarmer.run().await?;
+
+                        if prewarmer.enabled {
+                            conn.set_prewarmed(self.prewarmed_connections.clone());
+                        }
                     }
                 }
 
+                // Expire connections at different times, so they're not all
+                // replaced at once when the pool was filled in one go.
+                if self.settings.server_lifetime_jitter > 0 {
+                    conn.set_lifetime(jittered(
+                        self.settings.server_lifetime,
+                        self.settings.server_lifetime_jitter,
+                    ));
+                }
+
+                self.address.connect_backoff.succeeded();
                 stats.idle();
                 Ok(conn)
             }
             Err(err) => {
+                self.address
+                    .connect_backoff
+                    .failed(matches!(err, Error::ServerTemporarilyUnavailable(_)));
                 stats.disconnect();
                 Err(err)
             }
@@ -1206,8 +3032,169 @@ impl ManageConnection for ServerPool {
 
     /// Synchronously determine if the connection is no longer usable, if possible.
     fn has_broken(&self, conn: &mut Self::Connection) -> bool {
-        conn.is_bad()
+        conn.is_bad() || conn.lifetime_expired()
+    }
+}
+
+/// Server parameters that must be the same on every server of a pool.
+const SHARED_SERVER_PARAMETERS: [&str; 3] =
+    ["server_encoding", "TimeZone", "standard_conforming_strings"];
+
+/// Whether a server accepts writes and its `SHARED_SERVER_PARAMETERS`,
+/// to tell if the server behind an address changed, e.g. after a failover.
+#[derive(Debug, Clone, PartialEq)]
+struct ServerSnapshot {
+    in_recovery: bool,
+    parameters: Vec<Option<String>>,
+}
+
+impl ServerSnapshot {
+    async fn take(server: &mut Server) -> Result<ServerSnapshot, Error> {
+        let in_recovery = server.in_recovery().await?;
+        let parameters = server.server_parameters();
+
+        Ok(ServerSnapshot {
+            in_recovery,
+            parameters: SHARED_SERVER_PARAMETERS
+                .iter()
+                .map(|key| parameters.get(key).map(|value| value.to_string()))
+                .collect(),
+        })
+    }
+
+    /// What changed compared to an earlier snapshot of the same server.
+    fn changes(&self, before: &ServerSnapshot) -> Vec<String> {
+        let mut changes = Vec::new();
+
+        if self.in_recovery != before.in_recovery {
+            changes.push(match self.in_recovery {
+                true => "it's read-only but accepted writes before".to_string(),
+                false => "it accepts writes but was read-only before".to_string(),
+            });
+        }
+
+        for ((key, now), was) in SHARED_SERVER_PARAMETERS
+            .iter()
+            .zip(&self.parameters)
+            .zip(&before.parameters)
+        {
+            if now != was {
+                changes.push(format!(
+                    "{} is {} but was {}",
+                    key,
+                    now.as_deref().unwrap_or("unset"),
+                    was.as_deref().unwrap_or("unset")
+                ));
+            }
+        }
+
+        changes
+    }
+}
+
+/// Differences between the parameters reported by the servers of a pool,
+/// compared to the first server of the first shard.
+fn server_parameter_mismatches(mut reported: Vec<(Address, ServerParameters)>) -> Vec<String> {
+    reported.sort_by_key(|(address, _)| (address.shard, address.address_index));
+
+    let (reference, rest) = match reported.split_first() {
+        Some(split) => split,
+        None => return Vec::new(),
+    };
+
+    let mut mismatches = Vec::new();
+
+    for (address, parameters) in rest {
+        for key in SHARED_SERVER_PARAMETERS {
+            let expected = reference.1.get(key);
+            let actual = parameters.get(key);
+
+            if expected != actual {
+                mismatches.push(format!(
+                    "{} is {} on {} (shard {}) but {} on {} (shard {})",
+                    key,
+                    actual.unwrap_or("unset"),
+                    address.name(),
+                    address.shard,
+                    expected.unwrap_or("unset"),
+                    reference.0.name(),
+                    reference.0.shard,
+                ));
+            }
+        }
+    }
+
+    mismatches
+}
+
+/// Weighted random order (Efraimidis-Spirakis): the heavier a server,
+/// the likelier it ends up last and gets tried first.
+fn weighted_order(candidates: &mut Vec<&Address>) {
+    let mut rng = thread_rng();
+    let mut keyed = candidates
+        .drain(..)
+        .map(|address| {
+            let key = rng.gen::<f64>().powf(1.0 / address.weight.max(1) as f64);
+            (key, address)
+        })
+        .collect::<Vec<(f64, &Address)>>();
+
+    keyed.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    candidates.extend(keyed.into_iter().map(|(_, address)| address));
+}
+
+/// Order candidates so the same client always tries the same server first,
+/// then the following ones in a fixed order. Candidates are popped from the end.
+fn sticky_order(candidates: &mut [&Address], client_id: i32) {
+    if candidates.is_empty() {
+        return;
+    }
+
+    candidates.sort_by_key(|address| (address.shard, address.address_index));
+
+    let mut hasher = DefaultHasher::new();
+    client_id.hash(&mut hasher);
+    let first = (hasher.finish() % candidates.len() as u64) as usize;
+
+    candidates.rotate_left(first);
+    candidates.reverse();
+}
+
+/// The replica banned the longest ago, replicas out of rotation excluded.
+#[allow(clippy::mutable_key_type)]
+fn longest_banned(
+    bans: &HashMap<Address, (BanReason, NaiveDateTime)>,
+    is_replica: impl Fn(&Address) -> bool,
+) -> Option<Address> {
+    bans.iter()
+        .filter(|(address, _)| is_replica(address) && !address.is_out_of_rotation())
+        .min_by_key(|(_, (_, timestamp))| *timestamp)
+        .map(|(address, _)| address.clone())
+}
+
+/// Checkout time histograms of a pool, kept across reloads unless the buckets changed.
+fn checkout_histograms(
+    old_pool: Option<&ConnectionPool>,
+    buckets: &[f64],
+) -> Arc<CheckoutHistograms> {
+    match old_pool {
+        Some(old_pool) if old_pool.checkout_histograms.bounds() == buckets => {
+            old_pool.checkout_histograms.clone()
+        }
+        _ => Arc::new(CheckoutHistograms::new(buckets)),
+    }
+}
+
+/// Shorten a reaper rate or a server lifetime by a random amount, up to `jitter` percent,
+/// so pools and connections don't all reap, expire and refill at the same time.
+/// Never longer than asked for, idle timeouts and lifetimes are still respected.
+fn jittered(duration: u64, jitter: u64) -> u64 {
+    if jitter == 0 {
+        return duration;
     }
+
+    let max_jitter = duration * jitter.min(100) / 100;
+    (duration - thread_rng().gen_range(0..=max_jitter)).max(1)
 }
 
 /// Get the connection pool
@@ -1221,3 +3208,686 @@ pub fn get_pool(db: &str, user: &str) -> Option<ConnectionPool> {
 pub fn get_all_pools() -> HashMap<PoolIdentifier, ConnectionPool> {
     (*(*POOLS.load())).clone()
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sticky_order() {
+        let addresses = (0..3)
+            .map(|address_index| Address {
+                address_index,
+                ..Default::default()
+            })
+            .collect::<Vec<Address>>();
+
+        let order = |client_id| {
+            let mut candidates = addresses.iter().rev().collect::<Vec<&Address>>();
+            sticky_order(&mut candidates, client_id);
+            candidates
+                .iter()
+                .rev()
+                .map(|address| address.address_index)
+                .collect::<Vec<usize>>()
+        };
+
+        // Same client, same order, whatever order the candidates came in.
+        assert_eq!(order(42), order(42));
+
+        // The following servers are tried in a fixed order.
+        let first = order(42)[0];
+        assert_eq!(order(42), vec![first, (first + 1) % 3, (first + 2) % 3]);
+
+        // Clients are spread over the servers.
+        let firsts = (0..100)
+            .map(|client_id| order(client_id)[0])
+            .collect::<std::collections::HashSet<usize>>();
+        assert_eq!(firsts.len(), 3);
+    }
+
+    #[test]
+    fn test_weighted_order() {
+        let addresses = [9, 1]
+            .into_iter()
+            .enumerate()
+            .map(|(replica_number, weight)| Address {
+                role: Role::Primary,
+                replica_number,
+                weight,
+                ..Default::default()
+            })
+            .collect::<Vec<Address>>();
+
+        // Candidates are tried from the end, the preferred primary is there most of the time.
+        let preferred = (0..1000)
+            .filter(|_| {
+                let mut candidates = addresses.iter().collect::<Vec<&Address>>();
+                weighted_order(&mut candidates);
+                assert_eq!(candidates.len(), 2);
+                candidates[1].replica_number == 0
+            })
+            .count();
+        assert!((800..=980).contains(&preferred), "{}", preferred);
+
+        assert_eq!(addresses[0].name(), "pool_name_shard_0_primary");
+        assert_eq!(addresses[1].name(), "pool_name_shard_0_primary_1");
+    }
+
+    #[test]
+    fn test_server_parameter_mismatches() {
+        let server = |shard, encoding: &str| {
+            let mut parameters = ServerParameters::new();
+            parameters.set_param("server_encoding".into(), encoding.into(), true);
+            (
+                Address {
+                    shard,
+                    ..Default::default()
+                },
+                parameters,
+            )
+        };
+
+        assert!(server_parameter_mismatches(vec![]).is_empty());
+        assert!(server_parameter_mismatches(vec![server(1, "UTF8"), server(0, "UTF8")]).is_empty());
+
+        let mismatches = server_parameter_mismatches(vec![
+            server(2, "UTF8"),
+            server(1, "SQL_ASCII"),
+            server(0, "UTF8"),
+        ]);
+        assert_eq!(
+            mismatches,
+            vec![
+                "server_encoding is SQL_ASCII on pool_name_shard_1_replica_0 (shard 1) \
+                 but UTF8 on pool_name_shard_0_replica_0 (shard 0)"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_server_snapshot_changes() {
+        let snapshot = |in_recovery, timezone: &str| ServerSnapshot {
+            in_recovery,
+            parameters: vec![
+                Some("UTF8".into()),
+                Some(timezone.into()),
+                Some("on".into()),
+            ],
+        };
+
+        // A replica that accepts writes, e.g. the same server as the primary,
+        // is only a change if it didn't before.
+        assert!(snapshot(false, "UTC")
+            .changes(&snapshot(false, "UTC"))
+            .is_empty());
+
+        assert_eq!(
+            snapshot(true, "UTC").changes(&snapshot(false, "UTC")),
+            vec!["it's read-only but accepted writes before"]
+        );
+        assert_eq!(
+            snapshot(false, "Etc/UTC").changes(&snapshot(true, "UTC")),
+            vec![
+                "it accepts writes but was read-only before",
+                "TimeZone is Etc/UTC but was UTC"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_jittered() {
+        assert_eq!(jittered(30_000, 0), 30_000);
+
+        for _ in 0..100 {
+            let reaper_rate = jittered(30_000, 10);
+            assert!((27_000..=30_000).contains(&reaper_rate));
+        }
+
+        assert!(jittered(1, 100) >= 1);
+    }
+
+    #[test]
+    #[allow(clippy::mutable_key_type)]
+    fn test_longest_banned() {
+        let now = chrono::offset::Utc::now().naive_utc();
+        let is_replica = |address: &Address| address.role == Role::Replica;
+        let mut bans = HashMap::new();
+        assert_eq!(longest_banned(&bans, is_replica), None);
+
+        for index in 0..3 {
+            let address = Address {
+                address_index: index,
+                ..Default::default()
+            };
+            let banned_at = now - chrono::Duration::seconds(index as i64);
+            bans.insert(address, (BanReason::FailedHealthCheck(1), banned_at));
+        }
+
+        assert_eq!(longest_banned(&bans, is_replica).unwrap().address_index, 2);
+    }
+
+    #[test]
+    fn test_shard_health() {
+        let address = |role, address_index| Address {
+            role,
+            address_index,
+            ..Default::default()
+        };
+        let primary = address(Role::Primary, 0);
+        let replicas = [address(Role::Replica, 1), address(Role::Replica, 2)];
+
+        let pool = ConnectionPool {
+            addresses: Arc::new(vec![vec![
+                primary.clone(),
+                replicas[0].clone(),
+                replicas[1].clone(),
+            ]]),
+            banlist: Arc::new(RwLock::new(vec![HashMap::new()])),
+            ..Default::default()
+        };
+        let ban = |address: &Address| {
+            pool.banlist.write()[0].insert(
+                address.clone(),
+                (
+                    BanReason::FailedHealthCheck(1),
+                    chrono::offset::Utc::now().naive_utc(),
+                ),
+            );
+        };
+        let health = |primary, healthy_replicas, banned_replicas| ShardHealth {
+            shard: 0,
+            primary,
+            replicas: 2,
+            healthy_replicas,
+            banned_replicas,
+        };
+
+        assert_eq!(pool.shard_health(0), health(PrimaryStatus::Up, 2, 0));
+
+        ban(&replicas[0]);
+        assert_eq!(pool.shard_health(0), health(PrimaryStatus::Up, 1, 1));
+
+        ban(&replicas[1]);
+        assert_eq!(pool.shard_health(0), health(PrimaryStatus::Up, 0, 2));
+
+        // The primary fails its health check before it's banned.
+        primary.health_check_failed();
+        assert_eq!(pool.shard_health(0), health(PrimaryStatus::Down, 0, 2));
+        primary.health_check_passed();
+
+        primary.connect_backoff.failed(false);
+        assert_eq!(pool.shard_health(0), health(PrimaryStatus::Down, 0, 2));
+
+        ban(&primary);
+        assert_eq!(pool.shard_health(0), health(PrimaryStatus::Banned, 0, 2));
+    }
+
+    #[test]
+    fn test_inherit_bans() {
+        let pool = |hosts: &[&str], first_id: usize| {
+            let addresses = hosts
+                .iter()
+                .enumerate()
+                .map(|(address_index, host)| Address {
+                    id: first_id + address_index,
+                    host: host.to_string(),
+                    address_index,
+                    ..Default::default()
+                })
+                .collect::<Vec<Address>>();
+
+            ConnectionPool {
+                addresses: Arc::new(vec![addresses]),
+                banlist: Arc::new(RwLock::new(vec![HashMap::new()])),
+                ..Default::default()
+            }
+        };
+
+        let old_pool = pool(&["replica-a", "replica-b", "replica-c"], 0);
+        let banned_at = chrono::offset::Utc::now().naive_utc() - chrono::Duration::seconds(30);
+        for address in &old_pool.addresses[0][1..] {
+            old_pool.banlist.write()[0].insert(
+                address.clone(),
+                (BanReason::FailedHealthCheck(1), banned_at),
+            );
+        }
+
+        // replica-c was removed, replica-b moved.
+        let new_pool = pool(&["replica-b", "replica-a", "replica-d"], 10);
+        new_pool.inherit_bans(&old_pool);
+
+        let banlist = new_pool.banlist.read();
+        assert_eq!(banlist[0].len(), 1);
+        assert_eq!(
+            banlist[0].get(&new_pool.addresses[0][0]),
+            Some(&(BanReason::FailedHealthCheck(1), banned_at))
+        );
+    }
+
+    #[test]
+    fn test_target_server_role() {
+        let primary = Address {
+            role: Role::Primary,
+            address_index: 0,
+            label: Some("primary".into()),
+            ..Default::default()
+        };
+        let replica = Address {
+            role: Role::Replica,
+            address_index: 1,
+            label: Some("replica".into()),
+            ..Default::default()
+        };
+        let mut pool = ConnectionPool {
+            addresses: Arc::new(vec![vec![primary.clone(), replica.clone()]]),
+            ..Default::default()
+        };
+
+        assert_eq!(pool.target_server("replica", None), Ok(&replica));
+        assert_eq!(
+            pool.target_server("replica", Some(Role::Replica)),
+            Ok(&replica)
+        );
+        assert_eq!(
+            pool.target_server("primary", Some(Role::Primary)),
+            Ok(&primary)
+        );
+        assert_eq!(
+            pool.target_server("unknown", None),
+            Err(Error::UnknownServer("unknown".into()))
+        );
+
+        // No writes on a replica.
+        assert_eq!(
+            pool.target_server("replica", Some(Role::Primary)),
+            Err(Error::WrongServerRole(
+                "replica".into(),
+                "replica".into(),
+                "primary".into()
+            ))
+        );
+
+        // Reads go to the primary only if it serves reads.
+        assert_eq!(
+            pool.target_server("primary", Some(Role::Replica)),
+            Ok(&primary)
+        );
+        pool.settings = Arc::new(PoolSettings {
+            primary_reads_enabled: false,
+            ..Default::default()
+        });
+        assert!(pool.target_server("primary", Some(Role::Replica)).is_err());
+    }
+
+    #[test]
+    fn test_inherit_observed_roles() {
+        let pool = |hosts: &[&str]| {
+            let addresses = hosts
+                .iter()
+                .enumerate()
+                .map(|(address_index, host)| Address {
+                    host: host.to_string(),
+                    address_index,
+                    role: Role::Replica,
+                    ..Default::default()
+                })
+                .collect::<Vec<Address>>();
+
+            ConnectionPool {
+                addresses: Arc::new(vec![addresses]),
+                ..Default::default()
+            }
+        };
+
+        let old_pool = pool(&["replica-a", "replica-b"]);
+        old_pool.addresses[0][1].set_observed_role(Role::Primary);
+
+        let new_pool = pool(&["replica-b", "replica-a"]);
+        new_pool.inherit_observed_roles(&old_pool);
+
+        assert_eq!(
+            new_pool.addresses[0][0].observed_role(),
+            Some(Role::Primary)
+        );
+        assert_eq!(new_pool.addresses[0][1].observed_role(), None);
+    }
+
+    #[test]
+    fn test_routing_role() {
+        let pool = |follow_role_changes| ConnectionPool {
+            settings: Arc::new(PoolSettings {
+                follow_role_changes,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let address = Address {
+            role: Role::Replica,
+            ..Default::default()
+        };
+
+        // Nothing observed yet.
+        assert_eq!(pool(true).routing_role(&address), Role::Replica);
+
+        // The replica was promoted.
+        address.set_observed_role(Role::Primary);
+        assert_eq!(pool(true).routing_role(&address), Role::Primary);
+        assert_eq!(pool(false).routing_role(&address), Role::Replica);
+    }
+
+    #[test]
+    fn test_validate_semaphore() {
+        assert!(validate_semaphore(None).is_none());
+
+        // Every pool validates with the same permits.
+        let semaphore = validate_semaphore(Some(2)).unwrap();
+        assert!(Arc::ptr_eq(
+            &semaphore,
+            &validate_semaphore(Some(2)).unwrap()
+        ));
+
+        let other = validate_semaphore(Some(3)).unwrap();
+        assert_eq!(other.available_permits(), 3);
+        assert!(!Arc::ptr_eq(&semaphore, &other));
+    }
+
+    #[tokio::test]
+    async fn test_acquire_query_permit() {
+        // Not limited.
+        assert!(ConnectionPool::default()
+            .acquire_query_permit()
+            .await
+            .unwrap()
+            .is_none());
+
+        let pool = |wait_timeout| ConnectionPool {
+            query_semaphore: Some(Arc::new(Semaphore::new(1))),
+            settings: Arc::new(PoolSettings {
+                max_concurrent_queries: Some(1),
+                max_concurrent_queries_wait_timeout: wait_timeout,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        // Queries over the limit wait for a slot...
+        let limited = pool(200);
+        let permit = limited.acquire_query_permit().await.unwrap();
+        assert!(permit.is_some());
+
+        let waiting = {
+            let limited = limited.clone();
+            tokio::spawn(async move { limited.acquire_query_permit().await.map(|p| p.is_some()) })
+        };
+        tokio::time::sleep(tokio::time::Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+
+        drop(permit);
+        assert!(waiting.await.unwrap().unwrap());
+
+        // ...until the timeout.
+        let _permit = limited.acquire_query_permit().await.unwrap();
+        let start = tokio::time::Instant::now();
+        assert!(matches!(
+            limited.acquire_query_permit().await,
+            Err(Error::MaxConcurrentQueries)
+        ));
+        assert!(start.elapsed() >= tokio::time::Duration::from_millis(200));
+
+        // Without a timeout, they're turned away right away.
+        let limited = pool(0);
+        let _permit = limited.acquire_query_permit().await.unwrap();
+        assert!(matches!(
+            limited.acquire_query_permit().await,
+            Err(Error::MaxConcurrentQueries)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_checkout_histograms() {
+        let pool = ConnectionPool {
+            draining: Arc::new(AtomicBool::new(true)),
+            checkout_histograms: Arc::new(CheckoutHistograms::new(&[1.0])),
+            ..Default::default()
+        };
+
+        // Failed checkouts are counted too.
+        assert!(pool
+            .get(
+                None,
+                Some(Role::Primary),
+                None,
+                &ClientStats::default(),
+                async {}
+            )
+            .await
+            .is_err());
+        assert_eq!(
+            pool.checkout_histograms
+                .role(Role::Primary, false)
+                .snapshot()
+                .1,
+            1
+        );
+        assert_eq!(
+            pool.checkout_histograms
+                .role(Role::Primary, true)
+                .snapshot()
+                .1,
+            0
+        );
+
+        // Reloads keep them, unless the buckets changed.
+        assert!(Arc::ptr_eq(
+            &checkout_histograms(Some(&pool), &[1.0]),
+            &pool.checkout_histograms
+        ));
+        assert!(!Arc::ptr_eq(
+            &checkout_histograms(Some(&pool), &[1.0, 2.0]),
+            &pool.checkout_histograms
+        ));
+    }
+
+    #[test]
+    fn test_lagging_replicas_fall_back_to_primary() {
+        let primary = Address {
+            role: Role::Primary,
+            address_index: 0,
+            ..Default::default()
+        };
+        let replica = Address {
+            role: Role::Replica,
+            address_index: 1,
+            ..Default::default()
+        };
+        let pool = ConnectionPool {
+            settings: Arc::new(PoolSettings {
+                max_replica_lag: Some(10),
+                ..Default::default()
+            }),
+            addresses: Arc::new(vec![vec![primary.clone(), replica.clone()]]),
+            ..Default::default()
+        };
+        let client_stats = ClientStats::default();
+
+        let candidates = pool.balanced_candidates(Some(0), Some(Role::Replica), &client_stats);
+        assert_eq!(candidates, vec![&replica]);
+
+        // The primary is tried after the lagging replica.
+        replica.stats.set_replica_lag(1000);
+        let candidates = pool.balanced_candidates(Some(0), Some(Role::Replica), &client_stats);
+        assert_eq!(candidates, vec![&primary, &replica]);
+
+        // Not when the primary is kept out of reads.
+        let pool = ConnectionPool {
+            settings: Arc::new(PoolSettings {
+                max_replica_lag: Some(10),
+                primary_reads_enabled: false,
+                ..Default::default()
+            }),
+            addresses: pool.addresses.clone(),
+            ..Default::default()
+        };
+        let candidates = pool.balanced_candidates(Some(0), Some(Role::Replica), &client_stats);
+        assert_eq!(candidates, vec![&replica]);
+    }
+
+    #[tokio::test]
+    async fn test_loc_shuffle() {
+        let replicas = (0..3)
+            .map(|address_index| Address {
+                role: Role::Replica,
+                address_index,
+                ..Default::default()
+            })
+            .collect::<Vec<Address>>();
+
+        // Servers without any connections, all equally busy.
+        let databases = replicas
+            .iter()
+            .map(|address| {
+                Pool::builder().build_unchecked(ServerPool::new(
+                    address.clone(),
+                    User::default(),
+                    "pgcat",
+                    Arc::new(Mutex::new(HashMap::new())),
+                    Arc::new(RwLock::new(None)),
+                    Arc::new(AtomicU64::new(0)),
+                    ServerPoolSettings::default(),
+                ))
+            })
+            .collect();
+
+        let pool = |loc_shuffle| ConnectionPool {
+            settings: Arc::new(PoolSettings {
+                load_balancing_mode: LoadBalancingMode::LeastOutstandingConnections,
+                loc_shuffle,
+                ..Default::default()
+            }),
+            addresses: Arc::new(vec![replicas.clone()]),
+            databases: Arc::new(vec![databases]),
+            ..Default::default()
+        };
+        let client_stats = ClientStats::default();
+
+        // Candidates are tried from the end, ties go to the server listed first.
+        let pool = pool(false);
+        for _ in 0..100 {
+            let candidates = pool.balanced_candidates(Some(0), Some(Role::Replica), &client_stats);
+            assert_eq!(candidates, vec![&replicas[2], &replicas[1], &replicas[0]]);
+        }
+
+        // Ties go to a random server otherwise.
+        let pool = ConnectionPool {
+            settings: Arc::new(PoolSettings {
+                loc_shuffle: true,
+                ..(*pool.settings).clone()
+            }),
+            ..pool
+        };
+        let first_tried = (0..100)
+            .map(|_| {
+                pool.balanced_candidates(Some(0), Some(Role::Replica), &client_stats)
+                    .last()
+                    .unwrap()
+                    .address_index
+            })
+            .collect::<std::collections::HashSet<usize>>();
+        assert!(first_tried.len() > 1);
+    }
+
+    #[test]
+    fn test_primary_read_weight() {
+        let primary = Address {
+            role: Role::Primary,
+            address_index: 0,
+            ..Default::default()
+        };
+        let replica = Address {
+            role: Role::Replica,
+            address_index: 1,
+            ..Default::default()
+        };
+        let client_stats = ClientStats::default();
+
+        // How many of 1000 reads that can go anywhere consider the primary.
+        let primary_reads = |primary_read_weight| {
+            let pool = ConnectionPool {
+                settings: Arc::new(PoolSettings {
+                    primary_read_weight: Some(primary_read_weight),
+                    ..Default::default()
+                }),
+                addresses: Arc::new(vec![vec![primary.clone(), replica.clone()]]),
+                ..Default::default()
+            };
+            (0..1000)
+                .filter(|_| {
+                    pool.balanced_candidates(Some(0), None, &client_stats)
+                        .contains(&&primary)
+                })
+                .count()
+        };
+
+        assert_eq!(primary_reads(0.0), 0);
+        assert!((300..700).contains(&primary_reads(0.5)));
+        assert_eq!(primary_reads(1.0), 1000);
+    }
+
+    #[test]
+    fn test_follow_role_changes_lag_and_bans() {
+        let pool = ConnectionPool {
+            settings: Arc::new(PoolSettings {
+                follow_role_changes: true,
+                max_replica_lag: Some(10),
+                ..Default::default()
+            }),
+            banlist: Arc::new(RwLock::new(vec![HashMap::new()])),
+            ..Default::default()
+        };
+        let replica = Address {
+            role: Role::Replica,
+            address_index: 1,
+            ..Default::default()
+        };
+        let primary = Address {
+            role: Role::Primary,
+            address_index: 0,
+            ..Default::default()
+        };
+
+        // A lagging replica.
+        replica.stats.set_replica_lag(1000);
+        assert!(pool.is_lagging(&replica));
+
+        // Promoted, its lag doesn't count and it can't be banned anymore.
+        replica.set_observed_role(Role::Primary);
+        assert!(!pool.is_lagging(&replica));
+        pool.ban(&replica, BanReason::FailedCheckout, None);
+        assert!(!pool.is_banned(&replica));
+
+        // The old primary was demoted and can be banned like any replica.
+        primary.set_observed_role(Role::Replica);
+        pool.ban(&primary, BanReason::FailedCheckout, None);
+        assert!(pool.is_banned(&primary));
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        use std::time::Duration;
+
+        let mut bucket = TokenBucket::new(2);
+
+        // Bursts of up to the rate go through right away.
+        assert_eq!(bucket.take(Duration::ZERO), Some(Duration::ZERO));
+        assert_eq!(bucket.take(Duration::ZERO), Some(Duration::ZERO));
+        assert_eq!(bucket.take(Duration::ZERO), None);
+
+        // The next client waits for the next token, the one after for the one after that.
+        let wait = bucket.take(Duration::from_secs(1)).unwrap();
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500));
+        let wait = bucket.take(Duration::from_secs(1)).unwrap();
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+        assert_eq!(bucket.take(Duration::from_secs(1)), None);
+    }
+}
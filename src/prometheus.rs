@@ -8,16 +8,19 @@ use hyper::{Method, Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
 use log::{debug, error, info};
 use phf::phf_map;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 use std::net::SocketAddr;
 use std::sync::atomic::Ordering;
 use tokio::net::TcpListener;
 
-use crate::config::Address;
+use crate::ban_events;
+use crate::config::{Address, Role};
+use crate::otel;
 use crate::pool::{get_all_pools, PoolIdentifier};
 use crate::stats::get_server_stats;
 use crate::stats::pool::PoolStats;
+use crate::stats::user::UserStats;
 
 struct MetricHelpType {
     help: &'static str,
@@ -34,6 +37,9 @@ struct ServerPrometheusStats {
     idle_count: u64,
     login_count: u64,
     tested_count: u64,
+    prepared_hit_count: u64,
+    prepared_miss_count: u64,
+    prepared_eviction_count: u64,
 }
 
 // reference for metric types: https://prometheus.io/docs/concepts/metric_types/
@@ -68,6 +74,10 @@ static METRIC_HELP_AND_TYPES_LOOKUP: phf::Map<&'static str, MetricHelpType> = ph
         help: "Total time client waited for a server connection",
         ty: "counter",
     },
+    "stats_total_statement_timeouts" => MetricHelpType {
+        help: "Number of queries canceled because they exceeded the pool statement timeout",
+        ty: "counter",
+    },
     "stats_avg_query_count" => MetricHelpType {
         help: "Average of total_query_count every 15 seconds",
         ty: "gauge",
@@ -112,6 +122,18 @@ static METRIC_HELP_AND_TYPES_LOOKUP: phf::Map<&'static str, MetricHelpType> = ph
         help: "How many clients are waiting for a connection from the pool",
         ty: "gauge",
     },
+    "users_cl_idle" => MetricHelpType {
+        help: "How many clients of the user are idle, across all pools",
+        ty: "gauge",
+    },
+    "users_cl_active" => MetricHelpType {
+        help: "How many clients of the user are actively communicating with a server, across all pools",
+        ty: "gauge",
+    },
+    "users_cl_waiting" => MetricHelpType {
+        help: "How many clients of the user are waiting for a server connection, across all pools",
+        ty: "gauge",
+    },
     "pools_cl_active" => MetricHelpType {
         help: "How many clients are actively communicating with a server",
         ty: "gauge",
@@ -136,6 +158,42 @@ static METRIC_HELP_AND_TYPES_LOOKUP: phf::Map<&'static str, MetricHelpType> = ph
         help: "How many server connections are currently waiting on a health check to succeed",
         ty: "gauge",
     },
+    "pools_queries_in_flight" => MetricHelpType {
+        help: "How many queries are executing when max_concurrent_queries is set",
+        ty: "gauge",
+    },
+    "pools_queue_depth" => MetricHelpType {
+        help: "How many clients are queued waiting for a connection from the pool",
+        ty: "gauge",
+    },
+    "pools_queue_depth_max" => MetricHelpType {
+        help: "Deepest the connection queue got over the last 15 to 30 seconds",
+        ty: "gauge",
+    },
+    "pools_queue_time_us" => MetricHelpType {
+        help: "Time clients waited for their turn to check out a connection with fair_checkout in microseconds",
+        ty: "counter",
+    },
+    "pools_queue_time_max_us" => MetricHelpType {
+        help: "Longest a client waited for its turn with fair_checkout over the last 15 to 30 seconds in microseconds",
+        ty: "gauge",
+    },
+    "pools_min_idle_shortfall" => MetricHelpType {
+        help: "Idle connections missing to reach min_pool_size, on servers short for longer than min_idle_shortfall_threshold",
+        ty: "gauge",
+    },
+    "pools_all_replicas_banned" => MetricHelpType {
+        help: "Number of times all replicas of a shard were banned and some were unbanned to keep serving reads",
+        ty: "counter",
+    },
+    "pools_parse_failures" => MetricHelpType {
+        help: "Number of queries the query parser couldn't parse",
+        ty: "counter",
+    },
+    "pools_auto_session_clients" => MetricHelpType {
+        help: "Number of clients in auto pool mode switched to session mode",
+        ty: "counter",
+    },
     "servers_bytes_received" => MetricHelpType {
         help: "Volume in bytes of network traffic received by server",
         ty: "counter",
@@ -152,6 +210,18 @@ static METRIC_HELP_AND_TYPES_LOOKUP: phf::Map<&'static str, MetricHelpType> = ph
         help: "Number of queries executed by server",
         ty: "counter",
     },
+    "servers_prepared_hit_count" => MetricHelpType {
+        help: "Number of Parse messages not sent because the server already had the prepared statement",
+        ty: "counter",
+    },
+    "servers_prepared_miss_count" => MetricHelpType {
+        help: "Number of Parse messages sent because the server didn't have the prepared statement",
+        ty: "counter",
+    },
+    "servers_prepared_eviction_count" => MetricHelpType {
+        help: "Number of prepared statements closed on the server to stay under max_prepared_statements",
+        ty: "counter",
+    },
     "servers_error_count" => MetricHelpType {
         help: "Number of errors",
         ty: "counter",
@@ -188,23 +258,60 @@ static METRIC_HELP_AND_TYPES_LOOKUP: phf::Map<&'static str, MetricHelpType> = ph
         help: "Current number of connections for this database",
         ty: "gauge",
     },
+    "ban_events_dropped" => MetricHelpType {
+        help: "Number of ban events dropped because the sink queue was full",
+        ty: "counter",
+    },
+    "otel_spans_dropped" => MetricHelpType {
+        help: "Number of routing spans dropped because the OTLP export queue was full",
+        ty: "counter",
+    },
+    "pools_checkout_time_seconds" => MetricHelpType {
+        help: "Time clients waited for a server connection",
+        ty: "histogram",
+    },
 };
 
+/// Every label PgCat sets on its metrics. Pools can't configure labels with these names.
+pub const METRIC_LABELS: [&str; 10] = [
+    "host", "shard", "role", "pool", "index", "database", "username", "user", "outcome", "le",
+];
+
+// Label values can come from the config, so quotes, backslashes
+// and newlines are escaped as the exposition format requires.
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
 struct PrometheusMetric<Value: fmt::Display> {
     name: String,
     help: String,
     ty: String,
     labels: HashMap<&'static str, String>,
+    // Labels from the pool config, see `with_pool_labels`.
+    pool_labels: BTreeMap<String, String>,
     value: Value,
 }
 
 impl<Value: fmt::Display> fmt::Display for PrometheusMetric<Value> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut sorted_labels: Vec<_> = self.labels.iter().collect();
+        let mut sorted_labels: Vec<(&str, &str)> = self
+            .labels
+            .iter()
+            .map(|(key, value)| (*key, value.as_str()))
+            .chain(
+                self.pool_labels
+                    .iter()
+                    .map(|(key, value)| (key.as_str(), value.as_str())),
+            )
+            .collect();
         sorted_labels.sort_by_key(|&(key, _)| key);
         let formatted_labels = sorted_labels
             .iter()
-            .map(|(key, value)| format!("{}=\"{}\"", key, value))
+            .map(|(key, value)| format!("{}=\"{}\"", key, escape_label_value(value)))
             .collect::<Vec<_>>()
             .join(",");
         write!(
@@ -223,6 +330,12 @@ impl<Value: fmt::Display> PrometheusMetric<Value> {
         value: V,
         labels: HashMap<&'static str, String>,
     ) -> Option<PrometheusMetric<V>> {
+        debug_assert!(
+            labels.keys().all(|label| METRIC_LABELS.contains(label)),
+            "metric {} sets a label missing from METRIC_LABELS",
+            name
+        );
+
         METRIC_HELP_AND_TYPES_LOOKUP
             .get(name)
             .map(|metric| PrometheusMetric::<V> {
@@ -231,9 +344,16 @@ impl<Value: fmt::Display> PrometheusMetric<Value> {
                 ty: metric.ty.to_owned(),
                 value,
                 labels,
+                pool_labels: BTreeMap::new(),
             })
     }
 
+    /// Add the labels configured on the pool the metric belongs to.
+    fn with_pool_labels(mut self, labels: &BTreeMap<String, String>) -> Self {
+        self.pool_labels = labels.clone();
+        self
+    }
+
     fn from_database_info(
         address: &Address,
         name: &str,
@@ -289,6 +409,40 @@ impl<Value: fmt::Display> PrometheusMetric<Value> {
         Self::from_name(&format!("pools_{}", name), value, labels)
     }
 
+    /// One series of the checkout time histogram, e.g. `_bucket` with its `le` label.
+    fn from_checkout_histogram<V: fmt::Display>(
+        pool_id: &PoolIdentifier,
+        role: Role,
+        success: bool,
+        suffix: &str,
+        le: Option<String>,
+        value: V,
+    ) -> Option<PrometheusMetric<V>> {
+        let mut labels = HashMap::new();
+        labels.insert("pool", pool_id.db.clone());
+        labels.insert("user", pool_id.user.clone());
+        labels.insert("role", role.to_string());
+        let outcome = match success {
+            true => "success",
+            false => "failure",
+        };
+        labels.insert("outcome", outcome.to_string());
+        if let Some(le) = le {
+            labels.insert("le", le);
+        }
+
+        let mut metric = Self::from_name("pools_checkout_time_seconds", value, labels)?;
+        metric.name.push_str(suffix);
+        Some(metric)
+    }
+
+    fn from_user(username: &str, name: &str, value: u64) -> Option<PrometheusMetric<u64>> {
+        let mut labels = HashMap::new();
+        labels.insert("user", username.to_string());
+
+        Self::from_name(&format!("users_{}", name), value, labels)
+    }
+
     fn get_header(&self) -> String {
         format!(
             "\n# HELP {name} {help}\n# TYPE {name} {ty}",
@@ -307,8 +461,11 @@ async fn prometheus_stats(
             let mut lines = Vec::new();
             push_address_stats(&mut lines);
             push_pool_stats(&mut lines);
+            push_checkout_time_stats(&mut lines);
+            push_user_stats(&mut lines);
             push_server_stats(&mut lines);
             push_database_stats(&mut lines);
+            push_ban_event_stats(&mut lines);
 
             Response::builder()
                 .header("content-type", "text/plain; version=0.0.4")
@@ -335,7 +492,7 @@ fn push_address_stats(lines: &mut Vec<String>) {
                         grouped_metrics
                             .entry(key)
                             .or_default()
-                            .push(prometheus_metric);
+                            .push(prometheus_metric.with_pool_labels(&pool.settings.labels));
                     } else {
                         debug!("Metric {} not implemented for {}", key, address.name());
                     }
@@ -365,7 +522,7 @@ fn push_pool_stats(lines: &mut Vec<String>) {
                 grouped_metrics
                     .entry(name)
                     .or_default()
-                    .push(prometheus_metric);
+                    .push(prometheus_metric.with_pool_labels(&stats.labels));
             } else {
                 debug!("Metric {} not implemented for ({})", name, *pool_id);
             }
@@ -381,6 +538,121 @@ fn push_pool_stats(lines: &mut Vec<String>) {
     }
 }
 
+// Adds the checkout time histogram of each pool, by role.
+fn push_checkout_time_stats(lines: &mut Vec<String>) {
+    let mut series = Vec::new();
+    for (pool_id, pool) in get_all_pools() {
+        for (role, success) in [
+            (Role::Primary, true),
+            (Role::Primary, false),
+            (Role::Replica, true),
+            (Role::Replica, false),
+        ] {
+            let has_role = (0..pool.shards()).any(|shard| {
+                (0..pool.servers(shard)).any(|server| pool.address(shard, server).role == role)
+            });
+            if !has_role {
+                continue;
+            }
+
+            // Prometheus histograms are in seconds, we count in microseconds.
+            let seconds = |us: u64| us as f64 / 1_000_000.0;
+            let (buckets, count, sum) = pool.checkout_histograms.role(role, success).snapshot();
+
+            let buckets = buckets
+                .into_iter()
+                .map(|(bound, cumulative)| (seconds(bound).to_string(), cumulative))
+                .chain(std::iter::once(("+Inf".to_string(), count)));
+            for (le, cumulative) in buckets {
+                series.extend(
+                    PrometheusMetric::<u64>::from_checkout_histogram(
+                        &pool_id,
+                        role,
+                        success,
+                        "_bucket",
+                        Some(le),
+                        cumulative,
+                    )
+                    .map(|metric| metric.with_pool_labels(&pool.settings.labels).to_string()),
+                );
+            }
+            series.extend(
+                PrometheusMetric::<f64>::from_checkout_histogram(
+                    &pool_id,
+                    role,
+                    success,
+                    "_sum",
+                    None,
+                    seconds(sum),
+                )
+                .map(|metric| metric.with_pool_labels(&pool.settings.labels).to_string()),
+            );
+            series.extend(
+                PrometheusMetric::<u64>::from_checkout_histogram(
+                    &pool_id, role, success, "_count", None, count,
+                )
+                .map(|metric| metric.with_pool_labels(&pool.settings.labels).to_string()),
+            );
+        }
+    }
+    if series.is_empty() {
+        return;
+    }
+    if let Some(metric) =
+        PrometheusMetric::<u64>::from_name("pools_checkout_time_seconds", 0, HashMap::new())
+    {
+        lines.push(metric.get_header());
+        lines.extend(series);
+    }
+}
+
+// Adds metrics shown in a SHOW USER_STATS admin command.
+fn push_user_stats(lines: &mut Vec<String>) {
+    let mut grouped_metrics: HashMap<String, Vec<PrometheusMetric<u64>>> = HashMap::new();
+    let user_stats = UserStats::construct_user_lookup();
+    for (username, stats) in user_stats.iter() {
+        for (name, value) in stats.clone() {
+            if let Some(prometheus_metric) =
+                PrometheusMetric::<u64>::from_user(username, &name, value)
+            {
+                grouped_metrics
+                    .entry(name)
+                    .or_default()
+                    .push(prometheus_metric);
+            } else {
+                debug!("Metric {} not implemented for ({})", name, username);
+            }
+        }
+    }
+    for (_key, metrics) in grouped_metrics {
+        if !metrics.is_empty() {
+            lines.push(metrics[0].get_header());
+            for metric in metrics {
+                lines.push(metric.to_string());
+            }
+        }
+    }
+}
+
+// Adds metrics of the ban event sink.
+fn push_ban_event_stats(lines: &mut Vec<String>) {
+    if let Some(metric) = PrometheusMetric::<u64>::from_name(
+        "ban_events_dropped",
+        ban_events::dropped(),
+        HashMap::new(),
+    ) {
+        lines.push(metric.get_header());
+        lines.push(metric.to_string());
+    }
+
+    if let Some(metric) =
+        PrometheusMetric::<u64>::from_name("otel_spans_dropped", otel::dropped(), HashMap::new())
+    {
+        lines.push(metric.get_header());
+        lines.push(metric.to_string());
+    }
+}
+
 // Adds relevant metrics shown in a SHOW DATABASES admin command.
 fn push_database_stats(lines: &mut Vec<String>) {
     let mut grouped_metrics: HashMap<String, Vec<PrometheusMetric<u32>>> = HashMap::new();
@@ -401,7 +673,7 @@ fn push_database_stats(lines: &mut Vec<String>) {
                         grouped_metrics
                             .entry(key.to_string())
                             .or_default()
-                            .push(prometheus_metric);
+                            .push(prometheus_metric.with_pool_labels(&pool_config.labels));
                     } else {
                         debug!("Metric {} not implemented for {}", key, address.name());
                     }
@@ -436,12 +708,18 @@ fn push_server_stats(lines: &mut Vec<String>) {
                 idle_count: 0,
                 login_count: 0,
                 tested_count: 0,
+                prepared_hit_count: 0,
+                prepared_miss_count: 0,
+                prepared_eviction_count: 0,
             });
         entry.bytes_received += stats.bytes_received.load(Ordering::Relaxed);
         entry.bytes_sent += stats.bytes_sent.load(Ordering::Relaxed);
         entry.transaction_count += stats.transaction_count.load(Ordering::Relaxed);
         entry.query_count += stats.query_count.load(Ordering::Relaxed);
         entry.error_count += stats.error_count.load(Ordering::Relaxed);
+        entry.prepared_hit_count += stats.prepared_hit_count.load(Ordering::Relaxed);
+        entry.prepared_miss_count += stats.prepared_miss_count.load(Ordering::Relaxed);
+        entry.prepared_eviction_count += stats.prepared_eviction_count.load(Ordering::Relaxed);
         match stats.state.load(Ordering::Relaxed) {
             crate::stats::ServerState::Login => entry.login_count += 1,
             crate::stats::ServerState::Active => entry.active_count += 1,
@@ -465,6 +743,12 @@ fn push_server_stats(lines: &mut Vec<String>) {
                         ("active_count", server_info.active_count),
                         ("login_count", server_info.login_count),
                         ("tested_count", server_info.tested_count),
+                        ("prepared_hit_count", server_info.prepared_hit_count),
+                        ("prepared_miss_count", server_info.prepared_miss_count),
+                        (
+                            "prepared_eviction_count",
+                            server_info.prepared_eviction_count,
+                        ),
                         ("is_banned", if pool.is_banned(address) { 1 } else { 0 }),
                         ("is_paused", if pool.paused() { 1 } else { 0 }),
                     ];
@@ -475,7 +759,7 @@ fn push_server_stats(lines: &mut Vec<String>) {
                             grouped_metrics
                                 .entry(key.to_string())
                                 .or_default()
-                                .push(prometheus_metric);
+                                .push(prometheus_metric.with_pool_labels(&pool.settings.labels));
                         } else {
                             debug!("Metric {} not implemented for {}", key, address.name());
                         }
@@ -527,3 +811,30 @@ pub async fn start_metric_server(http_addr: SocketAddr) {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metric_labels() {
+        // Building each kind of metric checks its labels against METRIC_LABELS.
+        let address = Address::default();
+        let pool_id = PoolIdentifier::new("db", "user");
+
+        assert!(PrometheusMetric::<u32>::from_database_info(&address, "pool_size", 1).is_some());
+        assert!(PrometheusMetric::<u64>::from_server_info(&address, "bytes_received", 1).is_some());
+        assert!(PrometheusMetric::<u64>::from_address(&address, "total_query_count", 1).is_some());
+        assert!(PrometheusMetric::<u64>::from_pool(pool_id.clone(), "maxwait_us", 1).is_some());
+        assert!(PrometheusMetric::<u64>::from_user("user", "cl_idle", 1).is_some());
+        assert!(PrometheusMetric::<u64>::from_checkout_histogram(
+            &pool_id,
+            Role::Primary,
+            false,
+            "_bucket",
+            Some("0.005".to_string()),
+            1
+        )
+        .is_some());
+    }
+}
@@ -1,31 +1,32 @@
 /// Route queries automatically based on explicitly requested
 /// or implied query characteristics.
 use bytes::{Buf, BytesMut};
-use log::{debug, error};
-use once_cell::sync::OnceCell;
+use log::{debug, error, warn};
+use once_cell::sync::{Lazy, OnceCell};
 use regex::{Regex, RegexSet};
 use sqlparser::ast::Statement::{Delete, Insert, Query, StartTransaction, Update};
 use sqlparser::ast::{
-    Assignment, BinaryOperator, Expr, Ident, JoinConstraint, JoinOperator, SetExpr, Statement,
-    TableFactor, TableWithJoins, Value,
+    Assignment, BinaryOperator, Expr, Ident, JoinConstraint, JoinOperator, ObjectName, SetExpr,
+    Statement, TableFactor, TableWithJoins, UnaryOperator, Value, Values,
 };
 use sqlparser::dialect::PostgreSqlDialect;
 use sqlparser::parser::Parser;
 
-use crate::config::Role;
+use crate::config::{ParseFailureAction, Role};
 use crate::errors::Error;
 use crate::messages::BytesMutReader;
 use crate::plugins::{Intercept, Plugin, PluginOutput, QueryLogger, TableAccess};
 use crate::pool::PoolSettings;
 use crate::sharding::Sharder;
 
+use std::borrow::Cow;
 use std::collections::BTreeSet;
 use std::io::Cursor;
 use std::{cmp, mem};
 
 /// Regexes used to parse custom commands.
 const CUSTOM_SQL_REGEXES: [&str; 7] = [
-    r"(?i)^ *SET SHARDING KEY TO '?([0-9]+)'? *;? *$",
+    r"(?i)^ *SET SHARDING KEY TO '?([^' ;]+)'? *;? *$",
     r"(?i)^ *SET SHARD TO '?([0-9]+|ANY)'? *;? *$",
     r"(?i)^ *SHOW SHARD *;? *$",
     r"(?i)^ *SET SERVER ROLE TO '(PRIMARY|REPLICA|ANY|AUTO|DEFAULT)' *;? *$",
@@ -48,7 +49,8 @@ pub enum Command {
 
 #[derive(PartialEq, Debug)]
 pub enum ShardingKey {
-    Value(i64),
+    /// The key as written in the query; the sharder decides whether it can hash it.
+    Value(String),
     Placeholder(i16),
 }
 
@@ -66,6 +68,20 @@ static CUSTOM_SQL_REGEX_SET: OnceCell<RegexSet> = OnceCell::new();
 // Get the value inside the custom command.
 static CUSTOM_SQL_REGEX_LIST: OnceCell<Vec<Regex>> = OnceCell::new();
 
+/// Server picked by name in a query comment, e.g. `/* pgcat: server=replica-3 */`.
+static TARGET_SERVER: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"/\*\s*pgcat:\s*server\s*=\s*([\w.-]+)\s*\*/").unwrap());
+
+/// Timeout in milliseconds for one query, e.g. `/* pgcat: timeout=5000 */`.
+static QUERY_TIMEOUT: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"/\*\s*pgcat:\s*timeout\s*=\s*([^\s*]+)\s*\*/").unwrap());
+
+/// Statements that leave state on the server past the transaction,
+/// e.g. `SET`, `LISTEN` or `PREPARE`.
+static SESSION_STATEMENT: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?is)(?:^|[;\x00])\s*(?:/\*.*?\*/\s*)*(?:SET\s+(\w+)|LISTEN\b|PREPARE\b)").unwrap()
+});
+
 /// The query router.
 pub struct QueryRouter {
     /// Which shard we should be talking to right now.
@@ -85,6 +101,9 @@ pub struct QueryRouter {
 
     // Placeholders from prepared statement.
     placeholders: Vec<i16>,
+
+    /// Server the next query asked for by name, if server targeting is enabled.
+    target_server: Option<String>,
 }
 
 impl QueryRouter {
@@ -124,6 +143,7 @@ impl QueryRouter {
             primary_reads_enabled: None,
             pool_settings: PoolSettings::default(),
             placeholders: Vec::new(),
+            target_server: None,
         }
     }
 
@@ -136,6 +156,16 @@ impl QueryRouter {
         &self.pool_settings
     }
 
+    /// Beginning of the query in a Parse or Query message, as much as
+    /// the pool settings let us search for comments.
+    fn initial_segment<'a>(&self, message_buffer: &'a BytesMut, len: usize) -> Cow<'a, str> {
+        let seg = cmp::min(len - 5, self.pool_settings.regex_search_limit);
+
+        let query_start_index = mem::size_of::<u8>() + mem::size_of::<i32>();
+
+        String::from_utf8_lossy(&message_buffer[query_start_index..query_start_index + seg])
+    }
+
     /// Try to parse a command and execute it.
     pub fn try_execute_command(&mut self, message_buffer: &BytesMut) -> Option<(Command, String)> {
         let mut message_cursor = Cursor::new(message_buffer);
@@ -143,6 +173,16 @@ impl QueryRouter {
         let code = message_cursor.get_u8() as char;
         let len = message_cursor.get_i32() as usize;
 
+        // Check for a server picked by name, it doesn't stop other commands.
+        if self.pool_settings.server_targeting_enabled && (code == 'P' || code == 'Q') {
+            let initial_segment = self.initial_segment(message_buffer, len);
+
+            if let Some(captures) = TARGET_SERVER.captures(&initial_segment) {
+                debug!("Setting target server to {}", &captures[1]);
+                self.target_server = Some(captures[1].to_string());
+            }
+        }
+
         let comment_shard_routing_enabled = self.pool_settings.shard_id_regex.is_some()
             || self.pool_settings.sharding_key_regex.is_some();
 
@@ -151,14 +191,7 @@ impl QueryRouter {
             match code {
                 // For Parse and Query messages peek to see if they specify a shard_id as a comment early in the statement
                 'P' | 'Q' => {
-                    // Check only the first block of bytes configured by the pool settings
-                    let seg = cmp::min(len - 5, self.pool_settings.regex_search_limit);
-
-                    let query_start_index = mem::size_of::<u8>() + mem::size_of::<i32>();
-
-                    let initial_segment = String::from_utf8_lossy(
-                        &message_buffer[query_start_index..query_start_index + seg],
-                    );
+                    let initial_segment = self.initial_segment(message_buffer, len);
 
                     // Check for a shard_id included in the query
                     if let Some(shard_id_regex) = &self.pool_settings.shard_id_regex {
@@ -175,15 +208,19 @@ impl QueryRouter {
 
                     // Check for a sharding_key included in the query
                     if let Some(sharding_key_regex) = &self.pool_settings.sharding_key_regex {
-                        let sharding_key =
-                            sharding_key_regex
-                                .captures(&initial_segment)
-                                .and_then(|cap| {
-                                    cap.get(1).and_then(|id| id.as_str().parse::<i64>().ok())
-                                });
-                        if let Some(sharding_key) = sharding_key {
+                        let sharder = self.sharder();
+                        let sharding_key = sharding_key_from_regex(
+                            sharding_key_regex,
+                            &self.pool_settings.sharding_key_regex_groups,
+                            &initial_segment,
+                        );
+                        // Text keys only work with sharding functions that can hash text.
+                        if let Some(shard) = sharding_key
+                            .as_deref()
+                            .and_then(|key| sharder.shard_text(key))
+                        {
                             debug!("Setting sharding_key to {:?}", sharding_key);
-                            self.set_sharding_key(sharding_key);
+                            self.set_shard(Some(shard));
                             // Skip other command processing since a sharding command was found
                             return None;
                         }
@@ -273,11 +310,10 @@ impl QueryRouter {
 
         match command {
             Command::SetShardingKey => {
-                // TODO: some error handling here
+                // An empty value tells the client the key couldn't be hashed.
                 value = self
-                    .set_sharding_key(value.parse::<i64>().unwrap())
-                    .unwrap()
-                    .to_string();
+                    .set_sharding_key(&value)
+                    .map_or_else(String::new, |shard| shard.to_string());
             }
 
             Command::SetShard => {
@@ -344,13 +380,14 @@ impl QueryRouter {
         let code = message_cursor.get_u8() as char;
         let len = message_cursor.get_i32() as usize;
 
-        if let Some(max_length) = self.pool_settings.query_parser_max_length {
-            if len > max_length {
-                return Err(Error::QueryRouterParserError(format!(
-                    "Query too long for parser: {} > {}",
-                    len, max_length
-                )));
-            }
+        if self.exceeds_max_length(message) {
+            return Err(Error::QueryRouterParserError(format!(
+                "Query too long for parser: {} > {}",
+                len,
+                self.pool_settings
+                    .query_parser_max_length
+                    .unwrap_or_default()
+            )));
         };
 
         let query = match code {
@@ -420,7 +457,7 @@ impl QueryRouter {
                             // or discard shard selection. If they point to the same shard though,
                             // we can let them through as-is.
                             // This is basically building a database now :)
-                            let inferred_shard = self.infer_shard(query);
+                            let inferred_shard = self.infer_shard(query)?;
                             self.handle_inferred_shard(inferred_shard, &mut prev_inferred_shard)?;
                         }
 
@@ -472,6 +509,11 @@ impl QueryRouter {
             if let Some(prev_shard) = *prev_inferred_shard {
                 if prev_shard != shard {
                     debug!("Found more than one shard in the query, not supported yet");
+
+                    if !self.pool_settings.allow_cross_shard {
+                        return Err(Error::BadQuery("cross-shard query not allowed".into()));
+                    }
+
                     return Err(Error::QueryRouterParserError(
                         "multiple shards in query".into(),
                     ));
@@ -512,6 +554,11 @@ impl QueryRouter {
 
                 Self::process_table(table_name, &mut table_names);
                 if let Some(source) = source {
+                    // INSERT ... VALUES, look up the sharding key by column name in every row.
+                    if let SetExpr::Values(values) = &*source.body {
+                        return self.infer_shard_from_insert_values(table_name, columns, values);
+                    }
+
                     Self::process_query(source, &mut exprs, &mut table_names, &Some(columns));
                 }
             }
@@ -560,7 +607,118 @@ impl QueryRouter {
             }
         };
 
-        Ok(self.infer_shard_from_exprs(exprs, table_names))
+        self.infer_shard_from_exprs(exprs, table_names)
+    }
+
+    /// Find the sharding key column in the INSERT column list and extract its value
+    /// from every row. All rows must go to the same shard. Placeholders are resolved
+    /// later from the Bind message.
+    fn infer_shard_from_insert_values(
+        &mut self,
+        table_name: &ObjectName,
+        columns: &[Ident],
+        values: &Values,
+    ) -> Result<Option<usize>, Error> {
+        let sharding_key = self
+            .pool_settings
+            .automatic_sharding_key
+            .as_ref()
+            .unwrap()
+            .split('.')
+            .map(|ident| Ident::new(ident.to_lowercase()))
+            .collect::<Vec<Ident>>();
+
+        // Sharding key must be always fully qualified
+        assert_eq!(sharding_key.len(), 2);
+
+        // Ignore the schema, if any, and only compare the table name.
+        if sharding_key[0].value != "*" {
+            match table_name.0.last() {
+                Some(table) if table.value.to_lowercase() == sharding_key[0].value => (),
+                _ => return Ok(None),
+            };
+        }
+
+        let position = match columns
+            .iter()
+            .position(|column| column.value.to_lowercase() == sharding_key[1].value)
+        {
+            Some(position) => position,
+            None => {
+                debug!("Sharding key is not in the INSERT column list");
+                return Ok(None);
+            }
+        };
+
+        let sharder = self.automatic_sharder();
+
+        let mut shards = BTreeSet::new();
+
+        for row in values.rows.iter() {
+            match row.get(position).and_then(Self::sharding_key_from_expr) {
+                Some(ShardingKey::Value(value)) => match sharder.shard_text(&value) {
+                    Some(shard) => {
+                        shards.insert(shard);
+                    }
+
+                    None => {
+                        debug!("Sharding key can't be hashed: {}", value);
+                        return Ok(None);
+                    }
+                },
+
+                Some(ShardingKey::Placeholder(placeholder)) => {
+                    self.placeholders.push(placeholder);
+                }
+
+                None => {
+                    debug!(
+                        "Could not extract the sharding key from INSERT row: {:?}",
+                        row
+                    );
+                    return Ok(None);
+                }
+            };
+        }
+
+        match shards.len() {
+            0 => Ok(None),
+            1 => Ok(shards.first().copied()),
+            _ => {
+                debug!("INSERT rows map to more than one shard: {:?}", shards);
+                Err(Error::BadQuery(
+                    "multi-row INSERT spans more than one shard".into(),
+                ))
+            }
+        }
+    }
+
+    /// Extract the sharding key from a literal value or a placeholder.
+    fn sharding_key_from_expr(expr: &Expr) -> Option<ShardingKey> {
+        match expr {
+            Expr::Value(Value::Number(value, ..))
+            | Expr::Value(Value::SingleQuotedString(value)) => {
+                Some(ShardingKey::Value(value.clone()))
+            }
+
+            Expr::Value(Value::Placeholder(placeholder)) => placeholder
+                .replace('$', "")
+                .parse::<i16>()
+                .ok()
+                .map(ShardingKey::Placeholder),
+
+            Expr::UnaryOp {
+                op: UnaryOperator::Minus,
+                expr,
+            } => match Self::sharding_key_from_expr(expr) {
+                Some(ShardingKey::Value(value)) => Some(ShardingKey::Value(format!("-{}", value))),
+                _ => None,
+            },
+
+            Expr::Nested(expr) | Expr::Cast { expr, .. } => Self::sharding_key_from_expr(expr),
+
+            _ => None,
+        }
     }
 
     fn process_query(
@@ -682,16 +840,15 @@ impl QueryRouter {
             return false;
         }
 
+        let sharding_key_parameter = self.pool_settings.automatic_sharding_key_parameter;
+
         // There are no shard keys in the prepared statement.
-        if self.placeholders.is_empty() {
+        if self.placeholders.is_empty() && sharding_key_parameter.is_none() {
             debug!("There are no placeholders in the prepared statement that matched the automatic sharding key");
             return false;
         }
 
-        let sharder = Sharder::new(
-            self.pool_settings.shards,
-            self.pool_settings.sharding_function,
-        );
+        let sharder = self.automatic_sharder();
 
         let mut shards = BTreeSet::new();
 
@@ -726,7 +883,7 @@ impl QueryRouter {
         let num_parameters = message_cursor.get_i16();
 
         for i in 0..num_parameters {
-            let mut len = message_cursor.get_i32() as usize;
+            let len = message_cursor.get_i32();
             let format = match &parameter_format {
                 ParameterFormat::Text => ParameterFormat::Text,
                 ParameterFormat::Uniform(format) => *format.clone(),
@@ -736,10 +893,19 @@ impl QueryRouter {
 
             debug!("Parameter {} (len: {}): {:?}", i, len, format);
 
+            // NULL, there is no value to read.
+            if len < 0 {
+                continue;
+            }
+
+            let mut len = len as usize;
+
             // Postgres counts placeholders starting at 1
             let placeholder = i + 1;
 
-            if self.placeholders.contains(&placeholder) {
+            if self.placeholders.contains(&placeholder)
+                || sharding_key_parameter == Some(placeholder)
+            {
                 let value = match format {
                     ParameterFormat::Text => {
                         let mut value = String::new();
@@ -748,24 +914,25 @@ impl QueryRouter {
                             len -= 1;
                         }
 
-                        match value.parse::<i64>() {
-                            Ok(value) => value,
-                            Err(_) => {
-                                debug!("Error parsing bind value: {}", value);
+                        match sharder.shard_text(&value) {
+                            Some(shard) => shard,
+                            None => {
+                                debug!("Bind value can't be hashed: {}", value);
                                 continue;
                             }
                         }
                     }
 
                     ParameterFormat::Binary => match len {
-                        2 => message_cursor.get_i16() as i64,
-                        4 => message_cursor.get_i32() as i64,
-                        8 => message_cursor.get_i64(),
+                        2 => sharder.shard(message_cursor.get_i16() as i64),
+                        4 => sharder.shard(message_cursor.get_i32() as i64),
+                        8 => sharder.shard(message_cursor.get_i64()),
                         _ => {
                             error!(
                                 "Got wrong length for integer type parameter in bind: {}",
                                 len
                             );
+                            message_cursor.advance(len);
                             continue;
                         }
                     },
@@ -773,7 +940,9 @@ impl QueryRouter {
                     _ => unreachable!(),
                 };
 
-                shards.insert(sharder.shard(value));
+                shards.insert(value);
+            } else {
+                message_cursor.advance(len);
             }
         }
 
@@ -819,6 +988,60 @@ impl QueryRouter {
         Ok(())
     }
 
+    /// Check if the column is the sharding key, e.g. `id` or `data.id`.
+    fn is_sharding_key(expr: &Expr, sharding_key: &[Ident], table_names: &[Vec<Ident>]) -> bool {
+        match expr {
+            Expr::Identifier(ident) => {
+                // Only if we're dealing with only one table
+                // and there is no ambiguity
+                if ident.value.to_lowercase() != sharding_key[1].value {
+                    return false;
+                }
+
+                // Sharding key is unique enough, don't worry about
+                // table names.
+                if &sharding_key[0].value == "*" {
+                    return true;
+                }
+
+                if table_names.len() != 1 {
+                    return false;
+                }
+
+                let table = &table_names[0];
+
+                if table.len() == 1 {
+                    // Table is not fully qualified, e.g.
+                    //      SELECT * FROM t WHERE sharding_key = 5
+                    // Make sure the table name from the sharding key matches
+                    // the table name from the query.
+                    sharding_key[0].value == table[0].value.to_lowercase()
+                } else if table.len() == 2 {
+                    // Table name is fully qualified with the schema: e.g.
+                    //      SELECT * FROM public.t WHERE sharding_key = 5
+                    // Ignore the schema (TODO: at some point, we want schema support)
+                    // and use the table name only.
+                    sharding_key[0].value == table[1].value.to_lowercase()
+                } else {
+                    debug!("Got table name with more than two idents, which is not possible");
+                    false
+                }
+            }
+
+            Expr::CompoundIdentifier(idents) => {
+                // The key is fully qualified in the query,
+                // it will exist or Postgres will throw an error.
+                // TODO: key can have schema as well, e.g. public.data.id (len == 3)
+                idents.len() == 2
+                    && (&sharding_key[0].value == "*"
+                        || sharding_key[0].value == idents[0].value.to_lowercase())
+                    && sharding_key[1].value == idents[1].value.to_lowercase()
+            }
+
+            _ => false,
+        }
+    }
+
     /// A `selection` is the `WHERE` clause. This parses
     /// the clause and extracts the sharding key, if present.
     fn selection_parser(&self, expr: &Expr, table_names: &Vec<Vec<Ident>>) -> Vec<ShardingKey> {
@@ -841,50 +1064,25 @@ impl QueryRouter {
         // legal to write `5 = sharding_key`. I don't judge the people
         // who do that, but I think ORMs will still use the first variant,
         // so we can leave the second as a TODO.
+        // `sharding_key IN (1, 2, 3)`, every value in the list is a sharding key.
+        if let Expr::InList {
+            expr,
+            list,
+            negated: false,
+        } = expr
+        {
+            if Self::is_sharding_key(expr, &sharding_key, table_names) {
+                result.extend(list.iter().filter_map(Self::sharding_key_from_expr));
+            }
+
+            return result;
+        }
+
         if let Expr::BinaryOp { left, op, right } = expr {
             match &**left {
                 Expr::BinaryOp { .. } => result.extend(self.selection_parser(left, table_names)),
-                Expr::Identifier(ident) => {
-                    // Only if we're dealing with only one table
-                    // and there is no ambiguity
-                    if ident.value.to_lowercase() == sharding_key[1].value {
-                        // Sharding key is unique enough, don't worry about
-                        // table names.
-                        if &sharding_key[0].value == "*" {
-                            found = true;
-                        } else if table_names.len() == 1 {
-                            let table = &table_names[0];
-
-                            if table.len() == 1 {
-                                // Table is not fully qualified, e.g.
-                                //      SELECT * FROM t WHERE sharding_key = 5
-                                // Make sure the table name from the sharding key matches
-                                // the table name from the query.
-                                found = sharding_key[0].value == table[0].value.to_lowercase();
-                            } else if table.len() == 2 {
-                                // Table name is fully qualified with the schema: e.g.
-                                //      SELECT * FROM public.t WHERE sharding_key = 5
-                                // Ignore the schema (TODO: at some point, we want schema support)
-                                // and use the table name only.
-                                found = sharding_key[0].value == table[1].value.to_lowercase();
-                            } else {
-                                debug!("Got table name with more than two idents, which is not possible");
-                            }
-                        }
-                    }
-                }
-
-                Expr::CompoundIdentifier(idents) => {
-                    // The key is fully qualified in the query,
-                    // it will exist or Postgres will throw an error.
-                    if idents.len() == 2 {
-                        found = (&sharding_key[0].value == "*"
-                            || sharding_key[0].value == idents[0].value.to_lowercase())
-                            && sharding_key[1].value == idents[1].value.to_lowercase();
-                    }
-                    // TODO: key can have schema as well, e.g. public.data.id (len == 3)
-                }
-                _ => (),
+                Expr::InList { .. } => result.extend(self.selection_parser(left, table_names)),
+                _ => found = Self::is_sharding_key(left, &sharding_key, table_names),
             };
 
             match op {
@@ -900,14 +1098,11 @@ impl QueryRouter {
 
             match &**right {
                 Expr::BinaryOp { .. } => result.extend(self.selection_parser(right, table_names)),
-                Expr::Value(Value::Number(value, ..)) => {
+                Expr::InList { .. } => result.extend(self.selection_parser(right, table_names)),
+                Expr::Value(Value::Number(value, ..))
+                | Expr::Value(Value::SingleQuotedString(value)) => {
                     if found {
-                        match value.parse::<i64>() {
-                            Ok(value) => result.push(ShardingKey::Value(value)),
-                            Err(_) => {
-                                debug!("Sharding key was not an integer: {}", value);
-                            }
-                        };
+                        result.push(ShardingKey::Value(value.clone()));
                     }
                 }
 
@@ -932,7 +1127,7 @@ impl QueryRouter {
     }
 
     /// Try to figure out which shard the query should go to.
-    fn infer_shard(&mut self, query: &sqlparser::ast::Query) -> Option<usize> {
+    fn infer_shard(&mut self, query: &sqlparser::ast::Query) -> Result<Option<usize>, Error> {
         let mut exprs = Vec::new();
 
         // Collect all table names from the query.
@@ -946,13 +1141,10 @@ impl QueryRouter {
         &mut self,
         exprs: Vec<Expr>,
         table_names: Vec<Vec<Ident>>,
-    ) -> Option<usize> {
+    ) -> Result<Option<usize>, Error> {
         let mut shards = BTreeSet::new();
 
-        let sharder = Sharder::new(
-            self.pool_settings.shards,
-            self.pool_settings.sharding_function,
-        );
+        let sharder = self.automatic_sharder();
 
         // Look for sharding keys in either the join condition
         // or the selection.
@@ -964,10 +1156,15 @@ impl QueryRouter {
 
             for value in sharding_keys {
                 match value {
-                    ShardingKey::Value(value) => {
-                        let shard = sharder.shard(value);
-                        shards.insert(shard);
-                    }
+                    ShardingKey::Value(value) => match sharder.shard_text(&value) {
+                        Some(shard) => {
+                            shards.insert(shard);
+                        }
+
+                        None => {
+                            debug!("Sharding key can't be hashed: {}", value);
+                        }
+                    },
 
                     ShardingKey::Placeholder(position) => {
                         self.placeholders.push(position);
@@ -979,19 +1176,33 @@ impl QueryRouter {
             // Didn't find a sharding key, you're on your own.
             0 => {
                 debug!("No sharding keys found");
-                None
+                Ok(None)
             }
 
-            1 => Some(shards.into_iter().last().unwrap()),
+            1 => Ok(Some(shards.into_iter().next_back().unwrap())),
 
             // TODO: support querying multiple shards (some day...)
             _ => {
                 debug!("More than one sharding key found");
-                None
+
+                if !self.pool_settings.allow_cross_shard {
+                    return Err(Error::BadQuery("cross-shard query not allowed".into()));
+                }
+
+                Ok(None)
             }
         }
     }
 
+    /// Run the query_rewrite plugin on a Query or Parse message, after the other plugins.
+    /// Returns `None` if the message doesn't change and `BadQuery` if it's rejected.
+    pub fn rewrite_query(&self, message: &BytesMut) -> Result<Option<BytesMut>, Error> {
+        match self.pool_settings.query_rewrite {
+            Some(ref query_rewrite) => query_rewrite.rewrite_message(message),
+            None => Ok(None),
+        }
+    }
+
     /// Add your plugins here and execute them.
     pub async fn execute_plugins(&self, ast: &Vec<Statement>) -> Result<PluginOutput, Error> {
         let plugins = match self.pool_settings.plugins {
@@ -1038,12 +1249,28 @@ impl QueryRouter {
         Ok(PluginOutput::Allow)
     }
 
-    fn set_sharding_key(&mut self, sharding_key: i64) -> Option<usize> {
-        let sharder = Sharder::new(
+    /// The sharder for this pool's sharding settings.
+    fn sharder(&self) -> Sharder {
+        Sharder::new(
             self.pool_settings.shards,
             self.pool_settings.sharding_function,
-        );
-        let shard = sharder.shard(sharding_key);
+        )
+        .with_ranges(self.pool_settings.shard_ranges.clone())
+    }
+
+    /// The sharder for the keys found by automatic sharding.
+    fn automatic_sharder(&self) -> Sharder {
+        Sharder::new(
+            self.pool_settings.shards,
+            self.pool_settings.automatic_sharding_function,
+        )
+        .with_ranges(self.pool_settings.shard_ranges.clone())
+    }
+
+    /// Route to the shard of `sharding_key`. Returns `None` and keeps the current shard
+    /// if the sharding function can't hash the key, e.g. text with `pg_bigint_hash`.
+    fn set_sharding_key(&mut self, sharding_key: &str) -> Option<usize> {
+        let shard = self.sharder().shard_text(sharding_key)?;
         self.set_shard(Some(shard));
         self.active_shard
     }
@@ -1058,10 +1285,106 @@ impl QueryRouter {
         self.active_shard
     }
 
+    /// Server the next query asked for by name. It only applies once,
+    /// so it's cleared when taken.
+    pub fn take_target_server(&mut self) -> Option<String> {
+        self.target_server.take()
+    }
+
+    /// Timeout in milliseconds the query in a Parse or Query message
+    /// asked for in a comment, if any.
+    pub fn query_timeout(&self, message_buffer: &BytesMut) -> Option<u64> {
+        let mut message_cursor = Cursor::new(message_buffer);
+
+        let code = message_cursor.get_u8() as char;
+        let len = message_cursor.get_i32() as usize;
+
+        if code != 'P' && code != 'Q' {
+            return None;
+        }
+
+        let initial_segment = self.initial_segment(message_buffer, len);
+        let captures = QUERY_TIMEOUT.captures(&initial_segment)?;
+
+        match captures[1].parse::<u64>() {
+            Ok(timeout) if timeout > 0 => {
+                debug!("Query asked for a {}ms timeout", timeout);
+                Some(timeout)
+            }
+
+            _ => {
+                warn!(
+                    "Ignoring invalid query timeout \"{}\", expected milliseconds",
+                    &captures[1]
+                );
+                None
+            }
+        }
+    }
+
+    /// Does the query in a Parse or Query message change the session,
+    /// i.e. would it break if the next transaction ran on another server?
+    pub fn session_statement(&self, message_buffer: &BytesMut) -> bool {
+        let mut message_cursor = Cursor::new(message_buffer);
+
+        let code = message_cursor.get_u8() as char;
+        let len = message_cursor.get_i32() as usize;
+
+        if code != 'P' && code != 'Q' {
+            return false;
+        }
+
+        let initial_segment = self.initial_segment(message_buffer, len);
+
+        SESSION_STATEMENT
+            .captures_iter(&initial_segment)
+            .any(|captures| match captures.get(1) {
+                // SET LOCAL, SET TRANSACTION and SET CONSTRAINTS end with the transaction.
+                Some(scope) => !["local", "transaction", "constraints"]
+                    .iter()
+                    .any(|s| scope.as_str().eq_ignore_ascii_case(s)),
+                None => true,
+            })
+    }
+
     pub fn set_shard(&mut self, shard: Option<usize>) {
         self.active_shard = shard;
     }
 
+    /// Is the query longer than the parser is allowed to handle?
+    pub fn exceeds_max_length(&self, message: &BytesMut) -> bool {
+        match self.pool_settings.query_parser_max_length {
+            Some(max_length) => {
+                let mut message_cursor = Cursor::new(message);
+                let _code = message_cursor.get_u8();
+                message_cursor.get_i32() as usize > max_length
+            }
+            None => false,
+        }
+    }
+
+    /// Route to the default role, e.g. when the query couldn't be parsed.
+    pub fn use_default_role(&mut self) {
+        self.active_role = self.pool_settings.default_role;
+    }
+
+    /// Route a query the parser couldn't parse according to `on_parse_failure`.
+    /// Returns `BadQuery` if it should be rejected instead.
+    pub fn parse_failed(&mut self, error: &Error) -> Result<(), Error> {
+        match self.pool_settings.on_parse_failure {
+            ParseFailureAction::DefaultRole => self.use_default_role(),
+            ParseFailureAction::Primary => self.active_role = Some(Role::Primary),
+            ParseFailureAction::Error => {
+                return Err(Error::BadQuery(format!(
+                    "Query could not be parsed: {}",
+                    error
+                )))
+            }
+        }
+
+        Ok(())
+    }
+
     /// Should we attempt to parse queries?
     pub fn query_parser_enabled(&self) -> bool {
         match self.query_parser_enabled {
@@ -1097,6 +1420,23 @@ impl Default for QueryRouter {
     }
 }
 
+/// Extract the sharding key from a query with `sharding_key_regex`.
+/// The named `groups` are joined in order with a NUL byte, so e.g. `1` and `23` don't
+/// make the same key as `12` and `3`. The first group is used if there are none.
+fn sharding_key_from_regex(regex: &Regex, groups: &[String], query: &str) -> Option<String> {
+    let captures = regex.captures(query)?;
+
+    if groups.is_empty() {
+        return captures.get(1).map(|key| key.as_str().to_string());
+    }
+
+    groups
+        .iter()
+        .map(|group| captures.name(group).map(|value| value.as_str()))
+        .collect::<Option<Vec<&str>>>()
+        .map(|values| values.join("\0"))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -1104,6 +1444,7 @@ mod test {
     use crate::messages::simple_query;
     use crate::sharding::ShardingFunction;
     use bytes::BufMut;
+    use std::sync::Arc;
 
     #[test]
     fn test_defaults() {
@@ -1419,27 +1760,63 @@ mod test {
         let pool_settings = PoolSettings {
             pool_mode: PoolMode::Transaction,
             load_balancing_mode: crate::config::LoadBalancingMode::Random,
+            loc_shuffle: true,
+            copy_handling: crate::config::CopyHandling::Pin,
+            server_parameter_mismatch: crate::config::ServerParameterMismatch::Warn,
+            pause_client_behavior: crate::config::PauseClientBehavior::Block,
+            checkout_failure_mode: crate::config::CheckoutFailureMode::Wait,
+            log_routing_decisions: false,
+            follow_role_changes: false,
+            labels: std::collections::BTreeMap::new(),
             shards: 2,
             user: crate::config::User::default(),
             default_role: Some(Role::Replica),
             query_parser_enabled: true,
             query_parser_max_length: None,
+            on_parse_failure: crate::config::ParseFailureAction::DefaultRole,
             query_parser_read_write_splitting: true,
             primary_reads_enabled: false,
+            primary_read_weight: None,
             sharding_function: ShardingFunction::PgBigintHash,
+            automatic_sharding_function: ShardingFunction::PgBigintHash,
+            shard_ranges: Arc::default(),
             automatic_sharding_key: Some(String::from("test.id")),
+            automatic_sharding_key_parameter: None,
+            allow_cross_shard: true,
+            server_targeting_enabled: false,
+            statement_timeout: PoolSettings::default().statement_timeout,
+            idle_in_transaction_timeout: PoolSettings::default().idle_in_transaction_timeout,
+            ban_on_statement_timeout: true,
+            required_extensions: Vec::new(),
+            zone: None,
             healthcheck_delay: PoolSettings::default().healthcheck_delay,
+            max_replica_lag: PoolSettings::default().max_replica_lag,
+            connect_timeout: PoolSettings::default().connect_timeout,
+            healthcheck_mode: PoolSettings::default().healthcheck_mode,
+            client_auth_mode: PoolSettings::default().client_auth_mode,
             healthcheck_timeout: PoolSettings::default().healthcheck_timeout,
+            healthcheck_query: PoolSettings::default().healthcheck_query,
             ban_time: PoolSettings::default().ban_time,
+            unban_strategy: PoolSettings::default().unban_strategy,
+            validate_concurrency: PoolSettings::default().validate_concurrency,
+            max_pause_wait: PoolSettings::default().max_pause_wait,
             sharding_key_regex: None,
+            sharding_key_regex_groups: Vec::new(),
             shard_id_regex: None,
             default_shard: crate::config::DefaultShard::Shard(0),
             regex_search_limit: 1000,
             auth_query: None,
             auth_query_password: None,
+            min_server_auth_method: crate::config::AuthMethod::Trust,
+            server_tls: None,
             auth_query_user: None,
+            max_concurrent_queries: None,
+            max_concurrent_queries_wait_timeout: 5000,
+            max_connections_per_second: None,
+            max_connections_per_second_wait_timeout: 1000,
             db: "test".to_string(),
             plugins: None,
+            query_rewrite: None,
         };
         let mut qr = QueryRouter::new();
         assert_eq!(qr.active_role, None);
@@ -1497,27 +1874,63 @@ mod test {
         let pool_settings = PoolSettings {
             pool_mode: PoolMode::Transaction,
             load_balancing_mode: crate::config::LoadBalancingMode::Random,
+            loc_shuffle: true,
+            copy_handling: crate::config::CopyHandling::Pin,
+            server_parameter_mismatch: crate::config::ServerParameterMismatch::Warn,
+            pause_client_behavior: crate::config::PauseClientBehavior::Block,
+            checkout_failure_mode: crate::config::CheckoutFailureMode::Wait,
+            log_routing_decisions: false,
+            follow_role_changes: false,
+            labels: std::collections::BTreeMap::new(),
             shards: 5,
             user: crate::config::User::default(),
             default_role: Some(Role::Replica),
             query_parser_enabled: true,
             query_parser_max_length: None,
+            on_parse_failure: crate::config::ParseFailureAction::DefaultRole,
             query_parser_read_write_splitting: true,
             primary_reads_enabled: false,
+            primary_read_weight: None,
             sharding_function: ShardingFunction::PgBigintHash,
+            automatic_sharding_function: ShardingFunction::PgBigintHash,
+            shard_ranges: Arc::default(),
             automatic_sharding_key: None,
+            automatic_sharding_key_parameter: None,
+            allow_cross_shard: true,
+            server_targeting_enabled: false,
+            statement_timeout: PoolSettings::default().statement_timeout,
+            idle_in_transaction_timeout: PoolSettings::default().idle_in_transaction_timeout,
+            ban_on_statement_timeout: true,
+            required_extensions: Vec::new(),
+            zone: None,
             healthcheck_delay: PoolSettings::default().healthcheck_delay,
+            max_replica_lag: PoolSettings::default().max_replica_lag,
+            connect_timeout: PoolSettings::default().connect_timeout,
+            healthcheck_mode: PoolSettings::default().healthcheck_mode,
+            client_auth_mode: PoolSettings::default().client_auth_mode,
             healthcheck_timeout: PoolSettings::default().healthcheck_timeout,
+            healthcheck_query: PoolSettings::default().healthcheck_query,
             ban_time: PoolSettings::default().ban_time,
+            unban_strategy: PoolSettings::default().unban_strategy,
+            validate_concurrency: PoolSettings::default().validate_concurrency,
+            max_pause_wait: PoolSettings::default().max_pause_wait,
             sharding_key_regex: Some(Regex::new(r"/\* sharding_key: (\d+) \*/").unwrap()),
+            sharding_key_regex_groups: Vec::new(),
             shard_id_regex: Some(Regex::new(r"/\* shard_id: (\d+) \*/").unwrap()),
             default_shard: crate::config::DefaultShard::Shard(0),
             regex_search_limit: 1000,
             auth_query: None,
             auth_query_password: None,
+            min_server_auth_method: crate::config::AuthMethod::Trust,
+            server_tls: None,
             auth_query_user: None,
+            max_concurrent_queries: None,
+            max_concurrent_queries_wait_timeout: 5000,
+            max_connections_per_second: None,
+            max_connections_per_second_wait_timeout: 1000,
             db: "test".to_string(),
             plugins: None,
+            query_rewrite: None,
         };
 
         let mut qr = QueryRouter::new();
@@ -1545,6 +1958,246 @@ mod test {
         let q2 = simple_query("/* sharding_key: 6 */ select 1 from foo;");
         assert!(qr.try_execute_command(&q2).is_none());
         assert_eq!(qr.active_shard, Some(2));
+
+        // Composite key, the groups are joined in the configured order.
+        // It's text, so it needs a sharding function that hashes text.
+        let pool_settings = PoolSettings {
+            sharding_function: ShardingFunction::Sha256Mod,
+            sharding_key_regex: Some(
+                Regex::new(r"/\* region: (?P<region>\d+), tenant: (?P<tenant>\d+) \*/").unwrap(),
+            ),
+            sharding_key_regex_groups: vec!["tenant".to_string(), "region".to_string()],
+            ..pool_settings
+        };
+        qr.update_pool_settings(&pool_settings);
+
+        let q3 = simple_query("/* region: 1, tenant: 6 */ select 1 from foo;");
+        assert!(qr.try_execute_command(&q3).is_none());
+        let sharder = Sharder::new(5, ShardingFunction::Sha256Mod);
+        assert_eq!(qr.active_shard, sharder.shard_text("6\u{0}1"));
+    }
+
+    #[test]
+    fn test_target_server() {
+        QueryRouter::setup();
+        let mut qr = QueryRouter::new();
+
+        // Ignored unless the pool allows it.
+        let query = simple_query("/* pgcat: server=replica-3 */ select 1");
+        assert!(qr.try_execute_command(&query).is_none());
+        assert_eq!(qr.take_target_server(), None);
+
+        qr.update_pool_settings(&PoolSettings {
+            server_targeting_enabled: true,
+            ..PoolSettings::default()
+        });
+
+        assert!(qr.try_execute_command(&query).is_none());
+        assert_eq!(qr.take_target_server(), Some("replica-3".to_string()));
+
+        // Only for the next query.
+        assert_eq!(qr.take_target_server(), None);
+
+        let query = simple_query("select 1 /*pgcat: server = app_shard_0_replica_1*/");
+        assert!(qr.try_execute_command(&query).is_none());
+        assert_eq!(
+            qr.take_target_server(),
+            Some("app_shard_0_replica_1".to_string())
+        );
+
+        // Commands still work.
+        let query = simple_query("SET SHARD TO '1'");
+        assert_eq!(
+            qr.try_execute_command(&query),
+            Some((Command::SetShard, String::from("1")))
+        );
+    }
+
+    #[test]
+    fn test_query_timeout() {
+        QueryRouter::setup();
+        let qr = QueryRouter::new();
+
+        let query = simple_query("/* pgcat: timeout=5000 */ select pg_sleep(10)");
+        assert_eq!(qr.query_timeout(&query), Some(5000));
+
+        let query = simple_query("select 1 /*pgcat: timeout = 250*/");
+        assert_eq!(qr.query_timeout(&query), Some(250));
+
+        // Invalid values are ignored.
+        for value in ["abc", "-1", "0", "1.5"] {
+            let query = simple_query(&format!("/* pgcat: timeout={} */ select 1", value));
+            assert_eq!(qr.query_timeout(&query), None);
+        }
+
+        let query = simple_query("select 1");
+        assert_eq!(qr.query_timeout(&query), None);
+    }
+
+    #[test]
+    fn test_session_statement() {
+        QueryRouter::setup();
+        let qr = QueryRouter::new();
+
+        for query in [
+            "SET statement_timeout TO 1000",
+            "set search_path = app",
+            "SET SESSION TIME ZONE 'UTC'",
+            "LISTEN events",
+            "PREPARE q AS SELECT 1",
+            "/* app: web */ SET application_name = 'web'",
+            "SELECT 1; SET work_mem = '64MB'",
+        ] {
+            assert!(qr.session_statement(&simple_query(query)), "{}", query);
+        }
+
+        for query in [
+            "SELECT 1",
+            "SET LOCAL statement_timeout TO 1000",
+            "SET TRANSACTION ISOLATION LEVEL SERIALIZABLE",
+            "SET CONSTRAINTS ALL DEFERRED",
+            "UPDATE settings SET value = 1",
+            "SELECT 'LISTEN'",
+        ] {
+            assert!(!qr.session_statement(&simple_query(query)), "{}", query);
+        }
+    }
+
+    #[test]
+    fn test_sharding_key_from_regex() {
+        let regex =
+            Regex::new(r"/\* region: (?P<region>\w+), tenant: (?P<tenant>\d+) \*/").unwrap();
+        let query = "/* region: eu, tenant: 42 */ select 1";
+
+        // No groups configured, use the first one.
+        assert_eq!(
+            sharding_key_from_regex(&regex, &[], query),
+            Some("eu".to_string())
+        );
+
+        let groups = vec!["region".to_string(), "tenant".to_string()];
+        assert_eq!(
+            sharding_key_from_regex(&regex, &groups, query),
+            Some("eu\u{0}42".to_string())
+        );
+
+        let groups = vec!["tenant".to_string(), "region".to_string()];
+        assert_eq!(
+            sharding_key_from_regex(&regex, &groups, query),
+            Some("42\0eu".to_string())
+        );
+
+        // A missing group means no key.
+        let regex =
+            Regex::new(r"/\* (region: (?P<region>\w+), )?tenant: (?P<tenant>\d+) \*/").unwrap();
+        assert_eq!(
+            sharding_key_from_regex(&regex, &groups, "/* tenant: 42 */ select 1"),
+            None
+        );
+        assert_eq!(sharding_key_from_regex(&regex, &groups, "select 1"), None);
+
+        // The separator keeps the values apart.
+        let regex = Regex::new(r"/\* a: (?P<a>\d+), b: (?P<b>\d+) \*/").unwrap();
+        let groups = vec!["a".to_string(), "b".to_string()];
+        assert_ne!(
+            sharding_key_from_regex(&regex, &groups, "/* a: 1, b: 23 */ select 1"),
+            sharding_key_from_regex(&regex, &groups, "/* a: 12, b: 3 */ select 1")
+        );
+    }
+
+    #[test]
+    fn test_automatic_sharding_function() {
+        QueryRouter::setup();
+
+        let mut qr = QueryRouter::new();
+        qr.pool_settings.automatic_sharding_key = Some("data.id".to_string());
+        qr.pool_settings.shards = 3;
+        qr.pool_settings.query_parser_read_write_splitting = true;
+        qr.pool_settings.sharding_function = ShardingFunction::Sha256Mod;
+        qr.pool_settings.automatic_sharding_function = ShardingFunction::PgBigintHash;
+
+        // A key the two functions send to different shards.
+        let manual_sharder = Sharder::new(3, ShardingFunction::Sha256Mod);
+        let automatic_sharder = Sharder::new(3, ShardingFunction::PgBigintHash);
+        let key = (1..100)
+            .find(|&key| manual_sharder.shard(key) != automatic_sharder.shard(key))
+            .unwrap();
+        let manual = manual_sharder.shard(key);
+        let automatic = automatic_sharder.shard(key);
+
+        // Keys found in the query use the automatic sharding function...
+        assert!(qr
+            .infer(
+                &qr.parse(&simple_query(&format!(
+                    "SELECT * FROM data WHERE id = {}",
+                    key
+                )))
+                .unwrap(),
+            )
+            .is_ok());
+        assert_eq!(qr.shard(), Some(automatic));
+
+        // ...and the ones set by the client the pool's sharding function.
+        assert_eq!(
+            qr.try_execute_command(&simple_query(&format!("SET SHARDING KEY TO {}", key))),
+            Some((Command::SetShardingKey, manual.to_string()))
+        );
+        assert_eq!(qr.shard(), Some(manual));
+    }
+
+    #[test]
+    fn test_text_sharding_key() {
+        QueryRouter::setup();
+
+        let uuid = "8f2b9c3e-1d4a-4b6e-9c7f-2a5d8e1b3c40";
+        let sharder = Sharder::new(3, ShardingFunction::Sha256Mod);
+        let shard = sharder.shard_text(uuid).unwrap();
+
+        let mut qr = QueryRouter::new();
+        qr.pool_settings.automatic_sharding_key = Some("data.id".to_string());
+        qr.pool_settings.shards = 3;
+        qr.pool_settings.query_parser_read_write_splitting = true;
+        qr.pool_settings.sharding_function = ShardingFunction::Sha256Mod;
+        qr.pool_settings.automatic_sharding_function = ShardingFunction::Sha256Mod;
+
+        assert_eq!(
+            qr.try_execute_command(&simple_query(&format!("SET SHARDING KEY TO '{}'", uuid))),
+            Some((Command::SetShardingKey, shard.to_string()))
+        );
+        assert_eq!(qr.shard(), Some(shard));
+
+        qr.set_shard(None);
+        assert!(qr
+            .infer(
+                &qr.parse(&simple_query(&format!(
+                    "SELECT * FROM data WHERE id = '{}'",
+                    uuid
+                )))
+                .unwrap(),
+            )
+            .is_ok());
+        assert_eq!(qr.shard(), Some(shard));
+
+        qr.set_shard(None);
+        assert!(qr
+            .infer(
+                &qr.parse(&simple_query(&format!(
+                    "INSERT INTO data (id, value) VALUES ('{}', 1)",
+                    uuid
+                )))
+                .unwrap(),
+            )
+            .is_ok());
+        assert_eq!(qr.shard(), Some(shard));
+
+        // Integer-only sharding functions refuse text keys instead of panicking.
+        qr.pool_settings.sharding_function = ShardingFunction::PgBigintHash;
+        qr.set_shard(Some(1));
+        assert_eq!(
+            qr.try_execute_command(&simple_query(&format!("SET SHARDING KEY TO '{}'", uuid))),
+            Some((Command::SetShardingKey, String::new()))
+        );
+        assert_eq!(qr.shard(), Some(1));
     }
 
     #[test]
@@ -1685,6 +2338,117 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_automatic_sharding_insert_column_list() {
+        QueryRouter::setup();
+
+        // Sharding key is found by column name, wherever it is in the list.
+        assert_eq!(
+            auto_shard("INSERT INTO orders (o_id, w_id) VALUES (3, 5)"),
+            Some(2)
+        );
+        assert_eq!(
+            auto_shard("INSERT INTO orders (w_id, o_id) VALUES ('5', 3)"),
+            Some(2)
+        );
+        assert_eq!(
+            auto_shard("INSERT INTO orders (o_id, w_id) VALUES (3, 5::bigint)"),
+            Some(2)
+        );
+
+        // Multi-row INSERT going to the same shard.
+        assert_eq!(
+            auto_shard("INSERT INTO orders (o_id, w_id) VALUES (1, 5), (2, 5)"),
+            Some(2)
+        );
+
+        // Multi-row INSERT spanning shards is an error.
+        assert_eq!(
+            auto_shard_fails("INSERT INTO orders (o_id, w_id) VALUES (1, 5), (2, 6)"),
+            None
+        );
+
+        // No sharding key in the column list or the value is not a literal.
+        assert_eq!(auto_shard("INSERT INTO orders (o_id) VALUES (5)"), None);
+        assert_eq!(
+            auto_shard("INSERT INTO orders (o_id, w_id) VALUES (1, 5), (2, DEFAULT)"),
+            None
+        );
+
+        // Only the sharded table is considered.
+        let mut qr = QueryRouter::new();
+        qr.pool_settings.automatic_sharding_key = Some("data.id".to_string());
+        qr.pool_settings.shards = 3;
+        qr.pool_settings.query_parser_read_write_splitting = true;
+
+        assert!(qr
+            .infer(
+                &qr.parse(&simple_query("INSERT INTO other (id) VALUES (5)"))
+                    .unwrap()
+            )
+            .is_ok());
+        assert_eq!(qr.shard(), None);
+
+        assert!(qr
+            .infer(
+                &qr.parse(&simple_query("INSERT INTO public.data (id) VALUES (5)"))
+                    .unwrap()
+            )
+            .is_ok());
+        assert_eq!(qr.shard(), Some(2));
+
+        // Placeholders are resolved from the Bind message.
+        assert!(qr
+            .infer(
+                &qr.parse(&simple_query(
+                    "INSERT INTO data (value, id) VALUES ($1, $2)"
+                ))
+                .unwrap()
+            )
+            .is_ok());
+        assert_eq!(qr.placeholders, vec![2]);
+    }
+
+    #[test]
+    fn test_automatic_sharding_cross_shard() {
+        QueryRouter::setup();
+
+        let mut qr = QueryRouter::new();
+        qr.pool_settings.automatic_sharding_key = Some("*.w_id".to_string());
+        qr.pool_settings.shards = 3;
+        qr.pool_settings.query_parser_read_write_splitting = true;
+
+        // Allowed by default, the shard is left up to the client.
+        let query = simple_query("SELECT * FROM orders WHERE w_id IN (5, 6)");
+        assert!(qr.infer(&qr.parse(&query).unwrap()).is_ok());
+        assert_eq!(qr.shard(), None);
+
+        qr.pool_settings.allow_cross_shard = false;
+
+        assert_eq!(
+            qr.infer(&qr.parse(&query).unwrap()),
+            Err(Error::BadQuery("cross-shard query not allowed".into()))
+        );
+        assert_eq!(
+            qr.infer(
+                &qr.parse(&simple_query(
+                    "SELECT * FROM orders WHERE w_id = 5; SELECT * FROM orders WHERE w_id = 6"
+                ))
+                .unwrap()
+            ),
+            Err(Error::BadQuery("cross-shard query not allowed".into()))
+        );
+
+        // Keys on the same shard are fine.
+        assert!(qr
+            .infer(
+                &qr.parse(&simple_query("SELECT * FROM orders WHERE w_id IN (5, 5)"))
+                    .unwrap()
+            )
+            .is_ok());
+        assert_eq!(qr.shard(), Some(2));
+    }
+
     #[test]
     fn test_automatic_sharding_key_tpcc() {
         QueryRouter::setup();
@@ -1884,6 +2648,82 @@ mod test {
         assert!(qr.placeholders.is_empty());
     }
 
+    #[test]
+    fn test_automatic_sharding_key_parameter() {
+        // Two text parameters: `hello` and `5`.
+        let mut bind = BytesMut::from(&b"B"[..]);
+
+        let mut payload = BytesMut::from(&b"\0\0"[..]);
+        payload.put_i16(0);
+        payload.put_i16(2);
+        payload.put_i32(5);
+        payload.put(&b"hello"[..]);
+        payload.put_i32(1);
+        payload.put(&b"5"[..]);
+        payload.put_i16(0);
+
+        bind.put_i32(payload.len() as i32 + 4);
+        bind.put(payload);
+
+        let mut qr = QueryRouter::new();
+        qr.pool_settings.shards = 3;
+        qr.pool_settings.query_parser_read_write_splitting = true;
+
+        // Without the setting, nothing in the query points to a sharding key.
+        assert!(!qr.infer_shard_from_bind(&bind));
+        assert_eq!(qr.shard(), None);
+
+        // The key is taken from the second parameter, whatever the query.
+        qr.pool_settings.automatic_sharding_key_parameter = Some(2);
+        assert!(qr.infer_shard_from_bind(&bind));
+        assert_eq!(qr.shard(), Some(2));
+
+        // The first parameter isn't an integer.
+        qr.set_shard(None);
+        qr.pool_settings.automatic_sharding_key_parameter = Some(1);
+        assert!(!qr.infer_shard_from_bind(&bind));
+        assert_eq!(qr.shard(), None);
+    }
+
+    #[test]
+    fn test_query_parser_max_length() {
+        let mut qr = QueryRouter::new();
+        qr.pool_settings.query_parser_read_write_splitting = true;
+        qr.pool_settings.default_role = Some(Role::Replica);
+        qr.pool_settings.query_parser_max_length = Some(20);
+
+        let query = simple_query("UPDATE items SET value = 1");
+        assert!(qr.exceeds_max_length(&query));
+        assert!(qr.parse(&query).is_err());
+
+        qr.active_role = Some(Role::Primary);
+        qr.use_default_role();
+        assert_eq!(qr.role(), Some(Role::Replica));
+
+        let query = simple_query("SELECT 1");
+        assert!(!qr.exceeds_max_length(&query));
+        assert!(qr.infer(&qr.parse(&query).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_parse_failed() {
+        let mut qr = QueryRouter::new();
+        qr.pool_settings.default_role = Some(Role::Replica);
+
+        let error = qr.parse(&simple_query("SELEKT 1")).unwrap_err();
+
+        qr.active_role = Some(Role::Primary);
+        assert!(qr.parse_failed(&error).is_ok());
+        assert_eq!(qr.role(), Some(Role::Replica));
+
+        qr.pool_settings.on_parse_failure = ParseFailureAction::Primary;
+        assert!(qr.parse_failed(&error).is_ok());
+        assert_eq!(qr.role(), Some(Role::Primary));
+
+        qr.pool_settings.on_parse_failure = ParseFailureAction::Error;
+        assert!(matches!(qr.parse_failed(&error), Err(Error::BadQuery(_))));
+    }
+
     #[tokio::test]
     async fn test_table_access_plugin() {
         use crate::config::{Plugins, TableAccess};
@@ -1896,6 +2736,7 @@ mod test {
             intercept: None,
             query_logger: None,
             prewarmer: None,
+            query_rewrite: None,
         };
 
         QueryRouter::setup();
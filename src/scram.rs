@@ -28,6 +28,20 @@ fn normalize(pass: &[u8]) -> Vec<u8> {
     }
 }
 
+/// Printable characters except ',' as required by the RFC.
+fn random_nonce() -> String {
+    let mut rng = rand::thread_rng();
+    (0..NONCE_LENGTH)
+        .map(|_| {
+            let mut v = rng.gen_range(0x21u8..0x7e);
+            if v == 0x2c {
+                v = 0x7e
+            }
+            v as char
+        })
+        .collect::<String>()
+}
+
 /// Keep the SASL state through the exchange.
 /// It takes 3 messages to complete the authentication.
 pub struct ScramSha256 {
@@ -42,18 +56,7 @@ impl ScramSha256 {
     /// Create the Scram state from a password. It'll automatically
     /// generate a nonce.
     pub fn new(password: &str) -> ScramSha256 {
-        let mut rng = rand::thread_rng();
-        let nonce = (0..NONCE_LENGTH)
-            .map(|_| {
-                let mut v = rng.gen_range(0x21u8..0x7e);
-                if v == 0x2c {
-                    v = 0x7e
-                }
-                v as char
-            })
-            .collect::<String>();
-
-        Self::from_nonce(password, &nonce)
+        Self::from_nonce(password, &random_nonce())
     }
 
     /// Used for testing.
@@ -214,6 +217,220 @@ impl ScramSha256 {
     }
 }
 
+/// Server side of SCRAM-SHA-256, used to authenticate clients
+/// with the password in the config.
+///
+/// If the client connected over TLS, `channel_binding` holds the
+/// tls-server-end-point data and SCRAM-SHA-256-PLUS is offered too.
+pub struct ScramServer {
+    password: String,
+    channel_binding: Option<Vec<u8>>,
+    nonce: String,
+    salt: Vec<u8>,
+    iterations: u32,
+    gs2_header: String,
+    client_nonce: String,
+    client_first_bare: String,
+    server_first: String,
+}
+
+impl ScramServer {
+    pub fn new(password: &str, channel_binding: Option<Vec<u8>>) -> ScramServer {
+        let salt = (0..16).map(|_| rand::random()).collect::<Vec<u8>>();
+        Self::from_nonce(
+            password,
+            channel_binding,
+            &random_nonce(),
+            &salt,
+            SCRAM_ITERATIONS,
+        )
+    }
+
+    /// Used for testing.
+    pub fn from_nonce(
+        password: &str,
+        channel_binding: Option<Vec<u8>>,
+        nonce: &str,
+        salt: &[u8],
+        iterations: u32,
+    ) -> ScramServer {
+        ScramServer {
+            password: password.to_string(),
+            channel_binding,
+            nonce: nonce.to_string(),
+            salt: salt.to_vec(),
+            iterations,
+            gs2_header: String::new(),
+            client_nonce: String::new(),
+            client_first_bare: String::new(),
+            server_first: String::new(),
+        }
+    }
+
+    /// Mechanisms to offer the client, in order of preference.
+    pub fn mechanisms(&self) -> Vec<&'static str> {
+        match self.channel_binding {
+            Some(_) => vec![SCRAM_SHA_256_PLUS, SCRAM_SHA_256],
+            None => vec![SCRAM_SHA_256],
+        }
+    }
+
+    /// Handle the client-first-message sent with the chosen mechanism,
+    /// returns the server-first-message.
+    pub fn server_first(&mut self, mechanism: &str, message: &[u8]) -> Result<BytesMut, Error> {
+        let message = String::from_utf8_lossy(message).to_string();
+        let mut parts = message.splitn(3, ',');
+
+        let (cbind_flag, authzid, client_first_bare) =
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(cbind_flag), Some(authzid), Some(client_first_bare)) => {
+                    (cbind_flag, authzid, client_first_bare)
+                }
+                _ => return Err(Error::AuthError("malformed SCRAM message".into())),
+            };
+
+        match (mechanism, cbind_flag) {
+            (SCRAM_SHA_256_PLUS, "p=tls-server-end-point") => {
+                if self.channel_binding.is_none() {
+                    return Err(Error::AuthError(
+                        "channel binding requested but the connection is not using TLS".into(),
+                    ));
+                }
+            }
+
+            (SCRAM_SHA_256_PLUS, _) => {
+                return Err(Error::AuthError(format!(
+                    "unsupported channel binding: {}",
+                    cbind_flag
+                )))
+            }
+
+            (SCRAM_SHA_256, "n") => (),
+
+            // The client supports channel binding but thinks we don't,
+            // someone removed SCRAM-SHA-256-PLUS from our list.
+            (SCRAM_SHA_256, "y") => {
+                if self.channel_binding.is_some() {
+                    return Err(Error::AuthError(
+                        "channel binding is supported but the client did not use it".into(),
+                    ));
+                }
+            }
+
+            (SCRAM_SHA_256, _) => {
+                return Err(Error::AuthError(
+                    "channel binding requested without SCRAM-SHA-256-PLUS".into(),
+                ))
+            }
+
+            _ => {
+                return Err(Error::AuthError(format!(
+                    "unsupported SASL mechanism: {}",
+                    mechanism
+                )))
+            }
+        };
+
+        let client_nonce = match client_first_bare
+            .split(',')
+            .find_map(|attr| attr.strip_prefix("r="))
+        {
+            Some(nonce) if !nonce.is_empty() => nonce,
+            _ => return Err(Error::AuthError("missing SCRAM client nonce".into())),
+        };
+
+        self.gs2_header = format!("{},{},", cbind_flag, authzid);
+        self.client_nonce = client_nonce.to_string();
+        self.client_first_bare = client_first_bare.to_string();
+        self.server_first = format!(
+            "r={}{},s={},i={}",
+            self.client_nonce,
+            self.nonce,
+            general_purpose::STANDARD.encode(&self.salt),
+            self.iterations
+        );
+
+        Ok(BytesMut::from(self.server_first.as_bytes()))
+    }
+
+    /// Verify the client-final-message, returns the server-final-message.
+    pub fn server_final(&mut self, message: &[u8]) -> Result<BytesMut, Error> {
+        let message = String::from_utf8_lossy(message).to_string();
+
+        let (without_proof, proof) = match message.rsplit_once(",p=") {
+            Some(parts) => parts,
+            None => return Err(Error::AuthError("missing SCRAM client proof".into())),
+        };
+
+        let mut cbind_input = self.gs2_header.as_bytes().to_vec();
+        if self.gs2_header.starts_with("p=") {
+            // Checked when the client picked the mechanism.
+            cbind_input.extend(self.channel_binding.as_deref().unwrap_or_default());
+        }
+        let expected_channel_binding =
+            format!("c={}", general_purpose::STANDARD.encode(cbind_input));
+
+        let mut attrs = without_proof.split(',');
+        if attrs.next() != Some(expected_channel_binding.as_str()) {
+            return Err(Error::AuthError(
+                "SCRAM channel binding does not match".into(),
+            ));
+        }
+
+        let expected_nonce = format!("r={}{}", self.client_nonce, self.nonce);
+        if attrs.next() != Some(expected_nonce.as_str()) {
+            return Err(Error::AuthError("SCRAM nonce does not match".into()));
+        }
+
+        let proof = match general_purpose::STANDARD.decode(proof) {
+            Ok(proof) => proof,
+            Err(_) => return Err(Error::AuthError("malformed SCRAM client proof".into())),
+        };
+
+        let salted_password = ScramSha256::hi(
+            &normalize(self.password.as_bytes()),
+            &self.salt,
+            self.iterations,
+        );
+
+        let auth_message = format!(
+            "{},{},{}",
+            self.client_first_bare, self.server_first, without_proof
+        );
+
+        let client_key = hmac(&salted_password, b"Client Key");
+        let stored_key = Sha256::digest(client_key);
+        let client_signature = hmac(&stored_key, auth_message.as_bytes());
+
+        // Look at every byte so the time taken doesn't tell how much of the proof was right.
+        let difference = proof
+            .iter()
+            .zip(client_signature)
+            .zip(client_key)
+            .fold(0u8, |difference, ((proof, signature), key)| {
+                difference | (proof ^ signature ^ key)
+            });
+
+        if proof.len() != client_key.len() || difference != 0 {
+            return Err(Error::AuthError("wrong password".into()));
+        }
+
+        let server_key = hmac(&salted_password, b"Server Key");
+        let server_signature = hmac(&server_key, auth_message.as_bytes());
+
+        Ok(BytesMut::from(
+            format!("v={}", general_purpose::STANDARD.encode(server_signature)).as_bytes(),
+        ))
+    }
+}
+
+fn hmac(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut hmac =
+        Hmac::<Sha256>::new_from_slice(key).expect("HMAC is able to accept all key sizes");
+    hmac.update(message);
+    hmac.finalize().into_bytes().into()
+}
+
 /// Parse the server challenge.
 struct Message {
     nonce: String,
@@ -322,4 +539,132 @@ mod test {
             .finish(&BytesMut::from(server_final.as_bytes()))
             .unwrap();
     }
+
+    // Same exchange as above, from the server's side.
+    #[test]
+    fn server_exchange() {
+        let client_first = "n,,n=,r=9IZ2O01zb9IgiIZ1WJ/zgpJB";
+        let server_first =
+            "r=9IZ2O01zb9IgiIZ1WJ/zgpJBjx/oIRLs02gGSHcw1KEty3eY,s=fs3IXBy7U7+IvVjZ,i\
+             =4096";
+        let client_final =
+            "c=biws,r=9IZ2O01zb9IgiIZ1WJ/zgpJBjx/oIRLs02gGSHcw1KEty3eY,p=AmNKosjJzS3\
+             1NTlQYNs5BTeQjdHdk7lOflDo5re2an8=";
+        let server_final = "v=U+ppxD5XUKtradnv8e2MkeupiA8FU87Sg8CXzXHDAzw=";
+
+        let salt = general_purpose::STANDARD
+            .decode("fs3IXBy7U7+IvVjZ")
+            .unwrap();
+        let mut scram =
+            ScramServer::from_nonce("foobar", None, "jx/oIRLs02gGSHcw1KEty3eY", &salt, 4096);
+        assert_eq!(scram.mechanisms(), vec![SCRAM_SHA_256]);
+
+        let result = scram
+            .server_first(SCRAM_SHA_256, client_first.as_bytes())
+            .unwrap();
+        assert_eq!(std::str::from_utf8(&result).unwrap(), server_first);
+
+        let result = scram.server_final(client_final.as_bytes()).unwrap();
+        assert_eq!(std::str::from_utf8(&result).unwrap(), server_final);
+
+        // Wrong password.
+        let mut scram =
+            ScramServer::from_nonce("barfoo", None, "jx/oIRLs02gGSHcw1KEty3eY", &salt, 4096);
+        scram
+            .server_first(SCRAM_SHA_256, client_first.as_bytes())
+            .unwrap();
+        assert!(scram.server_final(client_final.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn server_exchange_with_client() {
+        let mut client = ScramSha256::new("secret");
+        let mut server = ScramServer::new("secret", None);
+
+        let server_first = server
+            .server_first(SCRAM_SHA_256, &client.message())
+            .unwrap();
+        let client_final = client.update(&server_first).unwrap();
+        let server_final = server.server_final(&client_final).unwrap();
+        client.finish(&server_final).unwrap();
+    }
+
+    #[test]
+    fn server_channel_binding() {
+        let end_point = vec![1, 2, 3, 4];
+        let salt = [0u8; 16];
+        let client_first_bare = "n=,r=clientnonce";
+
+        // SCRAM-SHA-256-PLUS over TLS, the binding data must match.
+        let mut server = ScramServer::from_nonce(
+            "secret",
+            Some(end_point.clone()),
+            "servernonce",
+            &salt,
+            4096,
+        );
+        assert_eq!(server.mechanisms(), vec![SCRAM_SHA_256_PLUS, SCRAM_SHA_256]);
+        server
+            .server_first(
+                SCRAM_SHA_256_PLUS,
+                format!("p=tls-server-end-point,,{}", client_first_bare).as_bytes(),
+            )
+            .unwrap();
+
+        let mut cbind_input = b"p=tls-server-end-point,,".to_vec();
+        cbind_input.extend(&end_point);
+        let without_proof = format!(
+            "c={},r=clientnonceservernonce",
+            general_purpose::STANDARD.encode(cbind_input)
+        );
+        let auth_message = format!(
+            "{},r=clientnonceservernonce,s={},i=4096,{}",
+            client_first_bare,
+            general_purpose::STANDARD.encode(salt),
+            without_proof
+        );
+        let salted_password = ScramSha256::hi(b"secret", &salt, 4096);
+        let client_key = hmac(&salted_password, b"Client Key");
+        let client_signature = hmac(&Sha256::digest(client_key), auth_message.as_bytes());
+        let proof = client_key
+            .iter()
+            .zip(client_signature)
+            .map(|(key, signature)| key ^ signature)
+            .collect::<Vec<u8>>();
+        let client_final = format!(
+            "{},p={}",
+            without_proof,
+            general_purpose::STANDARD.encode(proof)
+        );
+        assert!(server.server_final(client_final.as_bytes()).is_ok());
+
+        // Bound to another TLS certificate.
+        let mut server =
+            ScramServer::from_nonce("secret", Some(vec![5, 6, 7, 8]), "servernonce", &salt, 4096);
+        server
+            .server_first(
+                SCRAM_SHA_256_PLUS,
+                format!("p=tls-server-end-point,,{}", client_first_bare).as_bytes(),
+            )
+            .unwrap();
+        assert!(server.server_final(client_final.as_bytes()).is_err());
+
+        // Channel binding without TLS.
+        let mut server = ScramServer::new("secret", None);
+        assert!(server
+            .server_first(
+                SCRAM_SHA_256_PLUS,
+                format!("p=tls-server-end-point,,{}", client_first_bare).as_bytes(),
+            )
+            .is_err());
+
+        // Client supports channel binding but didn't see SCRAM-SHA-256-PLUS.
+        let mut server = ScramServer::new("secret", Some(end_point));
+        assert!(server
+            .server_first(
+                SCRAM_SHA_256,
+                format!("y,,{}", client_first_bare).as_bytes()
+            )
+            .is_err());
+    }
 }
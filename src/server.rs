@@ -11,14 +11,17 @@ use std::collections::{HashMap, HashSet, VecDeque};
 use std::mem;
 use std::net::IpAddr;
 use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::SystemTime;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, BufStream};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, BufStream};
 use tokio::net::TcpStream;
-use tokio_rustls::rustls::{OwnedTrustAnchor, RootCertStore};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio_rustls::rustls::ServerName;
 use tokio_rustls::{client::TlsStream, TlsConnector};
 
-use crate::config::{get_config, Address, User};
+use crate::config::{Address, AuthMethod, User};
 use crate::constants::*;
 use crate::dns_cache::{AddrSet, CACHED_RESOLVER};
 use crate::errors::{Error, ServerIdentifier};
@@ -28,6 +31,7 @@ use crate::mirrors::MirroringManager;
 use crate::pool::ClientServerMap;
 use crate::scram::ScramSha256;
 use crate::stats::ServerStats;
+use crate::tls::ServerTls;
 use std::io::Write;
 
 use pin_project::pin_project;
@@ -42,6 +46,11 @@ pub enum StreamInner {
         #[pin]
         stream: TlsStream<TcpStream>,
     },
+    #[cfg(unix)]
+    Unix {
+        #[pin]
+        stream: UnixStream,
+    },
 }
 
 impl AsyncWrite for StreamInner {
@@ -54,6 +63,8 @@ impl AsyncWrite for StreamInner {
         match this {
             SteamInnerProj::Tls { stream } => stream.poll_write(cx, buf),
             SteamInnerProj::Plain { stream } => stream.poll_write(cx, buf),
+            #[cfg(unix)]
+            SteamInnerProj::Unix { stream } => stream.poll_write(cx, buf),
         }
     }
 
@@ -65,6 +76,8 @@ impl AsyncWrite for StreamInner {
         match this {
             SteamInnerProj::Tls { stream } => stream.poll_flush(cx),
             SteamInnerProj::Plain { stream } => stream.poll_flush(cx),
+            #[cfg(unix)]
+            SteamInnerProj::Unix { stream } => stream.poll_flush(cx),
         }
     }
 
@@ -76,6 +89,8 @@ impl AsyncWrite for StreamInner {
         match this {
             SteamInnerProj::Tls { stream } => stream.poll_shutdown(cx),
             SteamInnerProj::Plain { stream } => stream.poll_shutdown(cx),
+            #[cfg(unix)]
+            SteamInnerProj::Unix { stream } => stream.poll_shutdown(cx),
         }
     }
 }
@@ -90,6 +105,8 @@ impl AsyncRead for StreamInner {
         match this {
             SteamInnerProj::Tls { stream } => stream.poll_read(cx, buf),
             SteamInnerProj::Plain { stream } => stream.poll_read(cx, buf),
+            #[cfg(unix)]
+            SteamInnerProj::Unix { stream } => stream.poll_read(cx, buf),
         }
     }
 }
@@ -103,10 +120,59 @@ impl StreamInner {
                 w.write(buf)
             }
             StreamInner::Plain { stream } => stream.try_write(buf),
+            #[cfg(unix)]
+            StreamInner::Unix { stream } => stream.try_write(buf),
+        }
+    }
+}
+
+/// Hosts starting with `/` are Unix socket paths, the port is ignored.
+fn is_unix_socket(host: &str) -> bool {
+    host.starts_with('/')
+}
+
+/// Connect to the server over a Unix socket. Like libpq, a directory
+/// means the `.s.PGSQL.<port>` socket inside it.
+#[cfg(unix)]
+async fn connect_unix(path: &str, port: u16) -> Result<StreamInner, Error> {
+    let mut path = std::path::PathBuf::from(path);
+
+    if path.is_dir() {
+        path.push(format!(".s.PGSQL.{}", port));
+    }
+
+    if !path.exists() {
+        error!("Unix socket {} does not exist", path.display());
+        return Err(Error::SocketError(format!(
+            "Unix socket {} does not exist",
+            path.display()
+        )));
+    }
+
+    match UnixStream::connect(&path).await {
+        Ok(stream) => Ok(StreamInner::Unix { stream }),
+        Err(err) => {
+            error!("Could not connect to server: {}", err);
+            Err(Error::SocketError(format!(
+                "Could not connect to server: {}",
+                err
+            )))
         }
     }
 }
 
+#[cfg(not(unix))]
+async fn connect_unix(path: &str, _port: u16) -> Result<StreamInner, Error> {
+    Err(Error::SocketError(format!(
+        "Unix socket {} is not supported on this platform",
+        path
+    )))
+}
+
+/// Errors at startup that go away on their own:
+/// too_many_connections and cannot_connect_now, e.g. the database system is starting up.
+const TEMPORARY_STARTUP_ERRORS: [&str; 2] = ["53300", "57P03"];
+
 #[derive(Copy, Clone)]
 struct CleanupState {
     /// If server connection requires RESET ALL before checkin because of set statement
@@ -229,6 +295,10 @@ impl ServerParameters {
         diff
     }
 
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.parameters.get(key).map(|value| value.as_str())
+    }
+
     pub fn get_application_name(&self) -> &String {
         // Can unwrap because we set it in the constructor
         self.parameters.get("application_name").unwrap()
@@ -263,6 +333,16 @@ impl From<&ServerParameters> for BytesMut {
 
 // pub fn compare
 
+/// Pending cancellation of a query that asked for a timeout,
+/// stopped when dropped.
+pub struct QueryDeadline(tokio::task::JoinHandle<()>);
+
+impl Drop for QueryDeadline {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
 /// Server state.
 pub struct Server {
     /// Server host, e.g. localhost,
@@ -303,6 +383,13 @@ pub struct Server {
     /// Server connected at.
     connected_at: chrono::naive::NaiveDateTime,
 
+    /// Close the connection when it's returned to the pool after this long (ms),
+    /// before the pool's `server_lifetime`.
+    lifetime: Option<u64>,
+
+    /// The pool's count of open prewarmed connections, if this one ran the prewarmer.
+    prewarmed: Option<Arc<AtomicU64>>,
+
     /// Reports various metrics, e.g. data sent & received.
     stats: Arc<ServerStats>,
 
@@ -320,6 +407,9 @@ pub struct Server {
     /// Should clean up dirty connections?
     cleanup_connections: bool,
 
+    /// Query cleaning up dirty connections instead of the default one.
+    cleanup_query: Option<String>,
+
     /// Log client parameter status changes
     log_client_parameter_status_changes: bool,
 
@@ -342,14 +432,21 @@ impl Server {
         stats: Arc<ServerStats>,
         auth_hash: Arc<RwLock<Option<String>>>,
         cleanup_connections: bool,
+        cleanup_query: Option<String>,
         log_client_parameter_status_changes: bool,
         prepared_statement_cache_size: usize,
+        min_server_auth_method: AuthMethod,
+        read_only: bool,
+        server_tls: Option<&ServerTls>,
     ) -> Result<Server, Error> {
         let cached_resolver = CACHED_RESOLVER.load();
         let mut addr_set: Option<AddrSet> = None;
 
-        // If we are caching addresses and hostname is not an IP
-        if cached_resolver.enabled() && address.host.parse::<IpAddr>().is_err() {
+        // If we are caching addresses and hostname is not an IP or a socket path
+        if cached_resolver.enabled()
+            && address.host.parse::<IpAddr>().is_err()
+            && !is_unix_socket(&address.host)
+        {
             debug!("Resolving {}", &address.host);
             addr_set = match cached_resolver.lookup_ip(&address.host).await {
                 Ok(ok) => {
@@ -363,91 +460,94 @@ impl Server {
             }
         };
 
-        let mut stream =
-            match TcpStream::connect(&format!("{}:{}", &address.host, address.port)).await {
-                Ok(stream) => stream,
-                Err(err) => {
-                    error!("Could not connect to server: {}", err);
-                    return Err(Error::SocketError(format!(
-                        "Could not connect to server: {}",
-                        err
-                    )));
-                }
-            };
-
-        // TCP timeouts.
-        configure_socket(&stream);
-
-        let config = get_config();
-
-        let mut stream = if config.general.server_tls {
-            // Request a TLS connection
-            ssl_request(&mut stream).await?;
-
-            let response = match stream.read_u8().await {
-                Ok(response) => response as char,
-                Err(err) => {
-                    return Err(Error::SocketError(format!(
-                        "Server socket error: {:?}",
-                        err
-                    )))
-                }
-            };
-
-            match response {
-                // Server supports TLS
-                'S' => {
-                    debug!("Connecting to server using TLS");
-
-                    let mut root_store = RootCertStore::empty();
-                    root_store.add_server_trust_anchors(
-                        webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
-                            OwnedTrustAnchor::from_subject_spki_name_constraints(
-                                ta.subject,
-                                ta.spki,
-                                ta.name_constraints,
-                            )
-                        }),
-                    );
-
-                    let mut tls_config = rustls::ClientConfig::builder()
-                        .with_safe_defaults()
-                        .with_root_certificates(root_store)
-                        .with_no_client_auth();
-
-                    // Equivalent to sslmode=prefer which is fine most places.
-                    // If you want verify-full, change `verify_server_certificate` to true.
-                    if !config.general.verify_server_certificate {
-                        let mut dangerous = tls_config.dangerous();
-                        dangerous.set_certificate_verifier(Arc::new(
-                            crate::tls::NoCertificateVerification {},
-                        ));
+        let mut stream = if is_unix_socket(&address.host) {
+            // Postgres doesn't do TLS over Unix sockets, nothing to negotiate.
+            connect_unix(&address.host, address.port).await?
+        } else {
+            let mut stream =
+                match TcpStream::connect(&format!("{}:{}", &address.host, address.port)).await {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        error!("Could not connect to server: {}", err);
+                        return Err(Error::SocketError(format!(
+                            "Could not connect to server: {}",
+                            err
+                        )));
+                    }
+                };
+
+            // TCP timeouts.
+            configure_socket(&stream);
+
+            if let Some(server_tls) = server_tls {
+                // Request a TLS connection
+                ssl_request(&mut stream).await?;
+
+                let response = match stream.read_u8().await {
+                    Ok(response) => response as char,
+                    Err(err) => {
+                        return Err(Error::SocketError(format!(
+                            "Server socket error: {:?}",
+                            err
+                        )))
                     }
+                };
+
+                match response {
+                    // Server supports TLS
+                    'S' => {
+                        debug!(
+                            "Connecting to server using TLS, verify: {}",
+                            server_tls.verify
+                        );
+
+                        // The name sent with SNI and checked against the certificate.
+                        let server_name = server_tls
+                            .sni_hostname
+                            .as_deref()
+                            .unwrap_or(address.host.as_str());
+
+                        let server_name = match ServerName::try_from(server_name) {
+                            Ok(server_name) => server_name,
+                            Err(err) => {
+                                return Err(Error::TlsError(format!(
+                                    "invalid server name {}: {}",
+                                    server_name, err
+                                )))
+                            }
+                        };
+
+                        let connector = TlsConnector::from(server_tls.config.clone());
+                        let stream = match connector.connect(server_name, stream).await {
+                            Ok(stream) => stream,
+                            Err(err) => {
+                                error!("TLS handshake with {:?} failed: {}", address, err);
+                                return Err(Error::TlsError(err.to_string()));
+                            }
+                        };
 
-                    let connector = TlsConnector::from(Arc::new(tls_config));
-                    let stream = match connector
-                        .connect(address.host.as_str().try_into().unwrap(), stream)
-                        .await
-                    {
-                        Ok(stream) => stream,
-                        Err(err) => {
-                            return Err(Error::SocketError(format!("Server TLS error: {:?}", err)))
-                        }
-                    };
+                        StreamInner::Tls { stream }
+                    }
 
-                    StreamInner::Tls { stream }
-                }
+                    // Server does not support TLS, fine unless the pool requires it.
+                    'N' if server_tls.required => {
+                        return Err(Error::TlsError(format!(
+                            "{} does not support TLS, server_tls_verify is {}",
+                            address.name(),
+                            server_tls.verify
+                        )));
+                    }
 
-                // Server does not support TLS
-                'N' => StreamInner::Plain { stream },
+                    'N' => StreamInner::Plain { stream },
 
-                // Something else?
-                m => {
-                    return Err(Error::SocketError(format!("Unknown message: {}", { m })));
+                    // Something else?
+                    m => {
+                        return Err(Error::SocketError(format!("Unknown message: {}", { m })));
+                    }
                 }
+            } else {
+                StreamInner::Plain { stream }
             }
-        } else {
-            StreamInner::Plain { stream }
         };
 
         // let (read, write) = split(stream);
@@ -466,7 +566,7 @@ impl Server {
             None => user.password.as_ref(),
         };
 
-        startup(&mut stream, username, database).await?;
+        startup(&mut stream, username, database, read_only).await?;
 
         let mut process_id: i32 = 0;
         let mut secret_key: i32 = 0;
@@ -478,6 +578,9 @@ impl Server {
 
         let mut server_parameters = ServerParameters::new();
 
+        // Authentication method the server asked for, if any.
+        let mut server_auth_method: Option<AuthMethod> = None;
+
         loop {
             let code = match stream.read_u8().await {
                 Ok(code) => code as char,
@@ -517,6 +620,26 @@ impl Server {
 
                     trace!("Auth: {}", auth_code);
 
+                    // Don't let the server (or something pretending to be it)
+                    // downgrade us to a weaker authentication method than allowed.
+                    let requested_auth_method = match auth_code {
+                        MD5_ENCRYPTED_PASSWORD => Some(AuthMethod::Md5),
+                        SASL => Some(AuthMethod::ScramSha256),
+                        AUTHENTICATION_SUCCESSFUL if server_auth_method.is_none() => {
+                            Some(AuthMethod::Trust)
+                        }
+                        _ => None,
+                    };
+
+                    if let Some(requested_auth_method) = requested_auth_method {
+                        check_server_auth_method(
+                            requested_auth_method,
+                            min_server_auth_method,
+                            &server_identifier,
+                        )?;
+                        server_auth_method = Some(requested_auth_method);
+                    }
+
                     match auth_code {
                         MD5_ENCRYPTED_PASSWORD => {
                             // The salt is 4 bytes.
@@ -707,9 +830,15 @@ impl Server {
 
                         // An error message will be present.
                         _ => {
-                            let mut error = vec![0u8; len as usize];
-
-                            match stream.read_exact(&mut error).await {
+                            // The fields, starting with the type of the first one we just read.
+                            let mut error =
+                                vec![
+                                    0u8;
+                                    (len as usize).saturating_sub(mem::size_of::<i32>()).max(1)
+                                ];
+                            error[0] = error_code;
+
+                            match stream.read_exact(&mut error[1..]).await {
                                 Ok(_) => (),
                                 Err(_) => {
                                     return Err(Error::ServerStartupError(
@@ -727,6 +856,14 @@ impl Server {
                             };
                             trace!("error fields: {}", &fields);
                             error!("server error: {}: {}", fields.severity, fields.message);
+
+                            // The server is fine, it just can't take us right now.
+                            if TEMPORARY_STARTUP_ERRORS.contains(&fields.code.as_str()) {
+                                return Err(Error::ServerTemporarilyUnavailable(format!(
+                                    "{}: {}",
+                                    fields.code, fields.message
+                                )));
+                            }
                         }
                     };
 
@@ -811,6 +948,8 @@ impl Server {
                         client_server_map,
                         addr_set,
                         connected_at: chrono::offset::Utc::now().naive_utc(),
+                        lifetime: None,
+                        prewarmed: None,
                         stats,
                         application_name: "pgcat".to_string(),
                         last_activity: SystemTime::now(),
@@ -823,6 +962,7 @@ impl Server {
                             )),
                         },
                         cleanup_connections,
+                        cleanup_query,
                         log_client_parameter_status_changes,
                         prepared_statement_cache: match prepared_statement_cache_size {
                             0 => None,
@@ -857,6 +997,20 @@ impl Server {
         process_id: i32,
         secret_key: i32,
     ) -> Result<(), Error> {
+        let mut bytes = BytesMut::with_capacity(16);
+        bytes.put_i32(16);
+        bytes.put_i32(CANCEL_REQUEST_CODE);
+        bytes.put_i32(process_id);
+        bytes.put_i32(secret_key);
+
+        if is_unix_socket(host) {
+            let mut stream = connect_unix(host, port).await?;
+
+            debug!("Sending CancelRequest");
+
+            return write_all_flush(&mut stream, &bytes).await;
+        }
+
         let mut stream = match TcpStream::connect(&format!("{}:{}", host, port)).await {
             Ok(stream) => stream,
             Err(err) => {
@@ -868,15 +1022,44 @@ impl Server {
 
         debug!("Sending CancelRequest");
 
-        let mut bytes = BytesMut::with_capacity(16);
-        bytes.put_i32(16);
-        bytes.put_i32(CANCEL_REQUEST_CODE);
-        bytes.put_i32(process_id);
-        bytes.put_i32(secret_key);
-
         write_all_flush(&mut stream, &bytes).await
     }
 
+    /// Ask the server to cancel the query running on this connection.
+    pub async fn cancel_running_query(&self) -> Result<(), Error> {
+        Server::cancel(
+            &self.address.host,
+            self.address.port,
+            self.process_id,
+            self.secret_key,
+        )
+        .await
+    }
+
+    /// Ask the server to cancel the query running on this connection if it's
+    /// still running after the timeout. Dropping the returned guard stops the timer.
+    pub fn cancel_after(&self, timeout: Duration) -> QueryDeadline {
+        let host = self.address.host.clone();
+        let port = self.address.port;
+        let process_id = self.process_id;
+        let secret_key = self.secret_key;
+
+        QueryDeadline(tokio::task::spawn(async move {
+            tokio::time::sleep(timeout).await;
+
+            warn!(
+                "Query on {}:{} ran past its {}ms timeout, cancelling it",
+                host,
+                port,
+                timeout.as_millis()
+            );
+
+            if let Err(err) = Server::cancel(&host, port, process_id, secret_key).await {
+                warn!("Could not cancel query after timeout: {:?}", err);
+            }
+        }))
+    }
+
     /// Send messages to the server from the client.
     pub async fn send(&mut self, messages: &BytesMut) -> Result<(), Error> {
         self.mirror_send(messages);
@@ -941,6 +1124,9 @@ impl Server {
                         // Idle, transaction over.
                         'I' => {
                             self.in_transaction = false;
+                            if let Some(manager) = self.mirror_manager.as_mut() {
+                                manager.transaction_over();
+                            }
                         }
 
                         // Some error occurred, the transaction was rolled back.
@@ -1213,6 +1399,81 @@ impl Server {
         }
     }
 
+    /// Wait until the idle server sends something on its own, e.g. a LISTEN notification.
+    /// Nothing is read, so this can be cancelled safely.
+    pub async fn wait_readable(&mut self) -> Result<(), Error> {
+        match self.stream.fill_buf().await {
+            Ok(buf) if !buf.is_empty() => Ok(()),
+            Ok(_) => {
+                self.bad = true;
+                Err(Error::SocketError(format!(
+                    "Server {:?} closed the connection",
+                    self.address
+                )))
+            }
+            Err(err) => {
+                self.bad = true;
+                Err(Error::SocketError(format!(
+                    "Error reading from server {:?} - Error {:?}",
+                    self.address, err
+                )))
+            }
+        }
+    }
+
+    /// Read a message the idle server sent without being asked:
+    /// NotificationResponse, NoticeResponse, ParameterStatus or ErrorResponse,
+    /// e.g. when `idle_session_timeout` or an admin terminates the session.
+    pub async fn recv_async(&mut self) -> Result<BytesMut, Error> {
+        let message = match read_message(&mut self.stream).await {
+            Ok(message) => message,
+            Err(err) => {
+                self.bad = true;
+                return Err(err);
+            }
+        };
+
+        match message[0] as char {
+            'A' | 'N' => (),
+
+            // The server is going away after a FATAL error.
+            'E' => {
+                let fatal = match PgErrorMsg::parse(&message[5..]) {
+                    // V is only sent by Postgres 9.6 and later.
+                    Ok(error) if error.severity.is_empty() => {
+                        matches!(error.severity_localized.as_str(), "FATAL" | "PANIC")
+                    }
+                    Ok(error) => matches!(error.severity.as_str(), "FATAL" | "PANIC"),
+                    Err(_) => true,
+                };
+
+                if fatal {
+                    self.bad = true;
+                }
+            }
+
+            'S' => {
+                let mut params = message.clone();
+                params.advance(mem::size_of::<u8>() + mem::size_of::<i32>());
+                let key = params.read_string()?;
+                let value = params.read_string()?;
+                self.server_parameters.set_param(key, value, false);
+            }
+
+            code => {
+                self.bad = true;
+                return Err(Error::ProtocolSyncError(format!(
+                    "Unexpected message from idle server: {}",
+                    code
+                )));
+            }
+        };
+
+        self.stats().data_received(message.len());
+
+        Ok(message)
+    }
+
     /// If the server is still inside a transaction.
     /// If the client disconnects while the server is in a transaction, we will clean it up.
     pub fn in_transaction(&self) -> bool {
@@ -1253,6 +1514,28 @@ impl Server {
         false
     }
 
+    /// Close this connection earlier than the pool's `server_lifetime`.
+    pub fn set_lifetime(&mut self, lifetime: u64) {
+        self.lifetime = Some(lifetime);
+    }
+
+    /// Count this connection as prewarmed until it's closed.
+    pub fn set_prewarmed(&mut self, prewarmed_connections: Arc<AtomicU64>) {
+        prewarmed_connections.fetch_add(1, Ordering::Relaxed);
+        self.prewarmed = Some(prewarmed_connections);
+    }
+
+    /// The connection outlived its own lifetime and shouldn't be reused.
+    pub fn lifetime_expired(&self) -> bool {
+        match self.lifetime {
+            Some(lifetime) => {
+                let age = chrono::offset::Utc::now().naive_utc() - self.connected_at;
+                age.num_milliseconds() as u64 >= lifetime
+            }
+            None => false,
+        }
+    }
+
     /// Get server startup information to forward it to the client.
     pub fn server_parameters(&self) -> ServerParameters {
         self.server_parameters.clone()
@@ -1319,6 +1602,134 @@ impl Server {
         Ok(())
     }
 
+    /// Check the server is alive without running any SQL:
+    /// send a Sync and wait for the ReadyForQuery.
+    pub async fn ping(&mut self) -> Result<(), Error> {
+        debug!("Sending Sync to server {:?}", self.address);
+
+        self.send(&sync()).await?;
+
+        loop {
+            let _ = self.recv(None).await?;
+
+            if !self.data_available {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Send several queries at once and wait for all of them to complete.
+    /// Returns the error of every query that failed, in order.
+    pub async fn query_pipeline(
+        &mut self,
+        queries: &[&str],
+    ) -> Result<Vec<Option<PgErrorMsg>>, Error> {
+        debug!(
+            "Running {} queries at once on server {:?}",
+            queries.len(),
+            self.address
+        );
+
+        let mut messages = BytesMut::new();
+
+        for query in queries {
+            messages.put(simple_query(query));
+        }
+
+        self.send(&messages).await?;
+
+        let mut errors = Vec::with_capacity(queries.len());
+
+        // Each query ends with its own ReadyForQuery.
+        for _ in queries {
+            let mut error = None;
+
+            loop {
+                let mut response = self.recv(None).await?;
+
+                while error.is_none() && response.remaining() >= 5 {
+                    let code = response.get_u8();
+                    let len = response.get_i32() as usize - 4;
+                    let body = response.split_to(len);
+
+                    if code == b'E' {
+                        error = Some(PgErrorMsg::parse(&body)?);
+                    }
+                }
+
+                if !self.data_available {
+                    break;
+                }
+            }
+
+            errors.push(error);
+        }
+
+        Ok(errors)
+    }
+
+    /// Run a query and return all the values it returned as text.
+    pub async fn query_values(&mut self, query: &str) -> Result<Vec<String>, Error> {
+        self.send(&simple_query(query)).await?;
+
+        let mut message = BytesMut::new();
+
+        loop {
+            message.put(self.recv(None).await?);
+
+            if !self.data_available {
+                break;
+            }
+        }
+
+        parse_query_message(&mut message).await
+    }
+
+    /// Check if the server is a hot standby, i.e. it doesn't accept writes.
+    pub async fn in_recovery(&mut self) -> Result<bool, Error> {
+        let values = self.query_values("SELECT pg_is_in_recovery()").await?;
+
+        Ok(values.first().is_some_and(|value| value == "t"))
+    }
+
+    /// How far behind the primary this replica is, in milliseconds.
+    /// A replica streaming from the primary that replayed everything it received
+    /// is not lagging, even if the primary hasn't written anything in a while.
+    /// Without a WAL receiver it's as far behind as its last replayed transaction.
+    pub async fn replica_lag(&mut self) -> Result<u64, Error> {
+        let values = self
+            .query_values(
+                "SELECT CASE WHEN pg_last_wal_receive_lsn() = pg_last_wal_replay_lsn() \
+                 AND EXISTS (SELECT 1 FROM pg_stat_wal_receiver) THEN 0 \
+                 ELSE GREATEST(COALESCE(EXTRACT(EPOCH FROM now() - pg_last_xact_replay_timestamp()) * 1000, 0), 0) \
+                 END::bigint",
+            )
+            .await?;
+
+        match values.first().and_then(|value| value.parse::<u64>().ok()) {
+            Some(replica_lag) => Ok(replica_lag),
+            None => Err(Error::ServerMessageParserError(format!(
+                "unexpected replication lag: {:?}",
+                values
+            ))),
+        }
+    }
+
+    /// Get the extensions from `required` that are not installed in the database.
+    pub async fn missing_extensions(&mut self, required: &[String]) -> Result<Vec<String>, Error> {
+        let installed = self
+            .query_values("SELECT extname FROM pg_extension")
+            .await?;
+
+        Ok(required
+            .iter()
+            .filter(|extension| !installed.contains(extension))
+            .cloned()
+            .collect())
+    }
+
     /// Perform any necessary cleanup before putting the server
     /// connection back in the pool
     pub async fn checkin_cleanup(&mut self) -> Result<(), Error> {
@@ -1338,17 +1749,42 @@ impl Server {
         // it before each checkin.
         if self.cleanup_state.needs_cleanup() && self.cleanup_connections {
             info!(target: "pgcat::server::cleanup", "Server returned with session state altered, discarding state ({}) for application {}", self.cleanup_state, self.application_name);
-            let mut reset_string = String::from("RESET ROLE;");
 
-            if self.cleanup_state.needs_cleanup_set {
-                reset_string.push_str("RESET ALL;");
-            };
+            let reset_string = match self.cleanup_query.clone() {
+                Some(cleanup_query) => {
+                    // Forget the prepared statements it closes.
+                    match deallocated_statements(&cleanup_query) {
+                        Deallocated::All => {
+                            if let Some(cache) = &mut self.prepared_statement_cache {
+                                cache.clear();
+                            }
+                        }
+                        Deallocated::Named(names) => {
+                            for name in names {
+                                self.remove_prepared_statement_from_cache(&name);
+                            }
+                        }
+                    }
+
+                    cleanup_query
+                }
 
-            if self.cleanup_state.needs_cleanup_prepare {
-                reset_string.push_str("DEALLOCATE ALL;");
-                // Since we deallocated all prepared statements, we need to clear the cache
-                if let Some(cache) = &mut self.prepared_statement_cache {
-                    cache.clear();
+                None => {
+                    let mut reset_string = String::from("RESET ROLE;");
+
+                    if self.cleanup_state.needs_cleanup_set {
+                        reset_string.push_str("RESET ALL;");
+                    };
+
+                    if self.cleanup_state.needs_cleanup_prepare {
+                        reset_string.push_str("DEALLOCATE ALL;");
+                        // Since we deallocated all prepared statements, we need to clear the cache
+                        if let Some(cache) = &mut self.prepared_statement_cache {
+                            cache.clear();
+                        }
+                    };
+
+                    reset_string
                 }
             };
 
@@ -1402,6 +1838,8 @@ impl Server {
         address: &Address,
         user: &User,
         query: &str,
+        min_server_auth_method: AuthMethod,
+        server_tls: Option<&ServerTls>,
     ) -> Result<Vec<String>, Error> {
         let client_server_map: ClientServerMap = Arc::new(Mutex::new(HashMap::new()));
 
@@ -1414,8 +1852,12 @@ impl Server {
             Arc::new(ServerStats::default()),
             Arc::new(RwLock::new(None)),
             true,
+            None,
             false,
             0,
+            min_server_auth_method,
+            false,
+            server_tls,
         )
         .await?;
         debug!("Connected!, sending query.");
@@ -1426,6 +1868,27 @@ impl Server {
     }
 }
 
+/// Make sure the server is not asking for a weaker authentication method than allowed.
+fn check_server_auth_method(
+    requested: AuthMethod,
+    minimum: AuthMethod,
+    server_identifier: &ServerIdentifier,
+) -> Result<(), Error> {
+    if requested < minimum {
+        error!(
+            "Server requested {} authentication but min_server_auth_method is {} for {}",
+            requested, minimum, server_identifier
+        );
+
+        return Err(Error::AuthError(format!(
+            "server requested {} authentication, {} or stronger is required",
+            requested, minimum
+        )));
+    }
+
+    Ok(())
+}
+
 async fn parse_query_message(message: &mut BytesMut) -> Result<Vec<String>, Error> {
     let mut pair = Vec::<String>::new();
     match message::backend::Message::parse(message) {
@@ -1509,6 +1972,10 @@ impl Drop for Server {
         // Update statistics
         self.stats.disconnect();
 
+        if let Some(ref prewarmed_connections) = self.prewarmed {
+            prewarmed_connections.fetch_sub(1, Ordering::Relaxed);
+        }
+
         let mut bytes = BytesMut::with_capacity(5);
         bytes.put_u8(b'X');
         bytes.put_i32(4);
@@ -1535,3 +2002,379 @@ impl Drop for Server {
         );
     }
 }
+
+/// Prepared statements of the session a cleanup query closes.
+#[derive(Debug, PartialEq)]
+enum Deallocated {
+    /// `DISCARD ALL` or `DEALLOCATE [PREPARE] ALL`.
+    All,
+
+    /// `DEALLOCATE [PREPARE] name`, other statements like `DISCARD PLANS` close none.
+    Named(Vec<String>),
+}
+
+/// Which prepared statements a cleanup query closes.
+fn deallocated_statements(query: &str) -> Deallocated {
+    let mut names = Vec::new();
+
+    for statement in query.split(';') {
+        let words = statement.split_whitespace().collect::<Vec<&str>>();
+        let keywords = words
+            .iter()
+            .map(|word| word.to_ascii_uppercase())
+            .collect::<Vec<String>>();
+
+        let name = match keywords
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<&str>>()
+            .as_slice()
+        {
+            ["DISCARD", "ALL"] | ["DEALLOCATE", "ALL"] | ["DEALLOCATE", "PREPARE", "ALL"] => {
+                return Deallocated::All
+            }
+            ["DEALLOCATE", "PREPARE", _] => words[2],
+            ["DEALLOCATE", _] => words[1],
+            _ => continue,
+        };
+
+        // Postgres folds unquoted names to lower case.
+        names.push(
+            match name.strip_prefix('"').and_then(|n| n.strip_suffix('"')) {
+                Some(quoted) => quoted.to_string(),
+                None => name.to_lowercase(),
+            },
+        );
+    }
+
+    Deallocated::Named(names)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_check_server_auth_method() {
+        let server_identifier = ServerIdentifier::new("user", "db");
+
+        assert!(
+            check_server_auth_method(AuthMethod::Md5, AuthMethod::Trust, &server_identifier)
+                .is_ok()
+        );
+        assert!(check_server_auth_method(
+            AuthMethod::ScramSha256,
+            AuthMethod::ScramSha256,
+            &server_identifier
+        )
+        .is_ok());
+        assert!(matches!(
+            check_server_auth_method(AuthMethod::Md5, AuthMethod::ScramSha256, &server_identifier),
+            Err(Error::AuthError(_))
+        ));
+        assert!(matches!(
+            check_server_auth_method(AuthMethod::Trust, AuthMethod::Md5, &server_identifier),
+            Err(Error::AuthError(_))
+        ));
+    }
+
+    /// AuthenticationMD5Password
+    fn md5_auth_request() -> BytesMut {
+        let mut auth = BytesMut::new();
+        auth.put_u8(b'R');
+        auth.put_i32(12);
+        auth.put_i32(MD5_ENCRYPTED_PASSWORD);
+        auth.put_slice(&[1, 2, 3, 4]);
+        auth
+    }
+
+    /// AuthenticationOk, ReadyForQuery
+    fn auth_ok() -> BytesMut {
+        let mut res = BytesMut::new();
+        res.put_u8(b'R');
+        res.put_i32(8);
+        res.put_i32(0);
+        res.put_u8(b'Z');
+        res.put_i32(5);
+        res.put_u8(b'I');
+        res
+    }
+
+    fn error_message(severity: &str, code: &str, message: &str) -> BytesMut {
+        let mut fields = BytesMut::new();
+        fields.put_u8(b'S');
+        fields.put_slice(format!("{}\0", severity).as_bytes());
+        fields.put_u8(b'C');
+        fields.put_slice(format!("{}\0", code).as_bytes());
+        fields.put_u8(b'M');
+        fields.put_slice(format!("{}\0", message).as_bytes());
+        fields.put_u8(0);
+
+        let mut error = BytesMut::new();
+        error.put_u8(b'E');
+        error.put_i32(fields.len() as i32 + 4);
+        error.put(fields);
+        error
+    }
+
+    /// A fake Postgres backend: reads the StartupMessage, sends `greeting`,
+    /// then answers every simple query with OK until pgcat goes away.
+    async fn fake_backend<S>(mut socket: S, greeting: BytesMut)
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        // StartupMessage
+        let len = socket.read_i32().await.unwrap();
+        let mut startup = vec![0u8; len as usize - 4];
+        socket.read_exact(&mut startup).await.unwrap();
+
+        socket.write_all(&greeting).await.unwrap();
+
+        loop {
+            let code = match socket.read_u8().await {
+                Ok(code) => code,
+                Err(_) => return,
+            };
+            let len = match socket.read_i32().await {
+                Ok(len) => len,
+                Err(_) => return,
+            };
+            let mut message = vec![0u8; len as usize - 4];
+            if socket.read_exact(&mut message).await.is_err() {
+                return;
+            }
+
+            if code == b'Q' {
+                let mut res = BytesMut::new();
+                res.put_u8(b'C');
+                res.put_i32(4 + 3);
+                res.put_slice(b"OK\0");
+                res.put_u8(b'Z');
+                res.put_i32(5);
+                res.put_u8(b'I');
+                if socket.write_all(&res).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Port of a fake backend accepting one TCP connection, see `fake_backend`.
+    async fn fake_tcp_backend(greeting: BytesMut) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::task::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            fake_backend(socket, greeting).await;
+        });
+
+        port
+    }
+
+    /// A backend that asks for MD5 while SCRAM is required must be refused.
+    #[tokio::test]
+    async fn test_server_auth_downgrade_to_md5() {
+        let port = fake_tcp_backend(md5_auth_request()).await;
+
+        let result = startup_at("127.0.0.1", port).await;
+        assert!(matches!(result, Err(Error::AuthError(_))));
+    }
+
+    async fn startup_at(host: &str, port: u16) -> Result<Server, Error> {
+        let address = Address {
+            host: host.into(),
+            port,
+            ..Default::default()
+        };
+
+        let user = User {
+            password: Some("password".into()),
+            ..Default::default()
+        };
+
+        Server::startup(
+            &address,
+            &user,
+            "db",
+            Arc::new(Mutex::new(HashMap::new())),
+            Arc::new(ServerStats::default()),
+            Arc::new(RwLock::new(None)),
+            true,
+            None,
+            false,
+            0,
+            AuthMethod::ScramSha256,
+            false,
+            None,
+        )
+        .await
+    }
+
+    async fn server_with_cleanup_query(cleanup_query: &str) -> Server {
+        let address = Address {
+            host: "127.0.0.1".into(),
+            port: fake_tcp_backend(auth_ok()).await,
+            ..Default::default()
+        };
+
+        Server::startup(
+            &address,
+            &User::default(),
+            "db",
+            Arc::new(Mutex::new(HashMap::new())),
+            Arc::new(ServerStats::default()),
+            Arc::new(RwLock::new(None)),
+            true,
+            Some(cleanup_query.into()),
+            false,
+            10,
+            AuthMethod::Trust,
+            false,
+            None,
+        )
+        .await
+        .unwrap()
+    }
+
+    /// Prewarmed connections are counted while they're open.
+    #[tokio::test]
+    async fn test_prewarmed_connections() {
+        let prewarmed_connections = Arc::new(AtomicU64::new(0));
+
+        let mut first = server_with_cleanup_query("DISCARD ALL").await;
+        let mut second = server_with_cleanup_query("DISCARD ALL").await;
+        first.set_prewarmed(prewarmed_connections.clone());
+        second.set_prewarmed(prewarmed_connections.clone());
+        assert_eq!(prewarmed_connections.load(Ordering::Relaxed), 2);
+
+        drop(first);
+        assert_eq!(prewarmed_connections.load(Ordering::Relaxed), 1);
+
+        drop(second);
+        assert_eq!(prewarmed_connections.load(Ordering::Relaxed), 0);
+    }
+
+    /// The cleanup query forgets the cached statements it closes,
+    /// otherwise the next Parse would fail on the server with 42P05.
+    #[tokio::test]
+    async fn test_cleanup_query_prepared_statement_cache() {
+        for (cleanup_query, kept) in [
+            ("DISCARD PLANS", true),
+            ("DISCARD TEMP; DISCARD SEQUENCES", true),
+            ("DEALLOCATE s2", true),
+            ("DEALLOCATE s1", false),
+            ("DEALLOCATE PREPARE \"s1\"", false),
+            ("DISCARD ALL", false),
+            ("RESET ALL; deallocate all", false),
+            ("DEALLOCATE PREPARE ALL", false),
+        ] {
+            let mut server = server_with_cleanup_query(cleanup_query).await;
+            server.add_prepared_statement_to_cache("s1");
+            server.mark_dirty();
+
+            server.checkin_cleanup().await.unwrap();
+            assert_eq!(
+                server.has_prepared_statement("s1"),
+                kept,
+                "{}",
+                cleanup_query
+            );
+        }
+    }
+
+    #[test]
+    fn test_deallocated_statements() {
+        assert_eq!(deallocated_statements("DISCARD ALL"), Deallocated::All);
+        assert_eq!(
+            deallocated_statements("DEALLOCATE S1; deallocate prepare \"S2\"; RESET ALL"),
+            Deallocated::Named(vec!["s1".to_string(), "S2".to_string()])
+        );
+        assert_eq!(
+            deallocated_statements("DISCARD PLANS"),
+            Deallocated::Named(vec![])
+        );
+    }
+
+    /// Errors from an idle server reach the client, a FATAL one breaks the server.
+    #[tokio::test]
+    async fn test_recv_async_error() {
+        let mut greeting = auth_ok();
+        greeting.put(error_message(
+            "FATAL",
+            "57P05",
+            "terminating connection due to idle-session timeout",
+        ));
+        let address = Address {
+            host: "127.0.0.1".into(),
+            port: fake_tcp_backend(greeting).await,
+            ..Default::default()
+        };
+
+        let mut server = Server::startup(
+            &address,
+            &User::default(),
+            "db",
+            Arc::new(Mutex::new(HashMap::new())),
+            Arc::new(ServerStats::default()),
+            Arc::new(RwLock::new(None)),
+            true,
+            None,
+            false,
+            0,
+            AuthMethod::Trust,
+            false,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(!server.is_bad());
+
+        let message = server.recv_async().await.unwrap();
+        assert_eq!(message[0], b'E');
+        assert!(server.is_bad());
+    }
+
+    /// A backend out of connection slots isn't a broken backend.
+    #[tokio::test]
+    async fn test_too_many_connections() {
+        // Postgres closes the connection after the error.
+        let port = fake_tcp_backend(error_message(
+            "FATAL",
+            "53300",
+            "sorry, too many clients already",
+        ))
+        .await;
+
+        let result = startup_at("127.0.0.1", port).await;
+        assert!(matches!(
+            result,
+            Err(Error::ServerTemporarilyUnavailable(message)) if message.starts_with("53300")
+        ));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket() {
+        let dir = std::env::temp_dir().join(format!("pgcat_unix_socket_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let listener = tokio::net::UnixListener::bind(dir.join(".s.PGSQL.5433")).unwrap();
+
+        // No SSLRequest over a Unix socket.
+        tokio::task::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            fake_backend(socket, md5_auth_request()).await;
+        });
+
+        // Got far enough to authenticate.
+        let result = startup_at(dir.to_str().unwrap(), 5433).await;
+        assert!(matches!(result, Err(Error::AuthError(_))));
+
+        let result = startup_at(dir.join("missing").to_str().unwrap(), 5433).await;
+        assert!(matches!(result, Err(Error::SocketError(_))));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
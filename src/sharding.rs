@@ -1,10 +1,16 @@
 use serde_derive::{Deserialize, Serialize};
 /// Implements various sharding functions.
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use std::sync::Arc;
 
 /// See: <https://github.com/postgres/postgres/blob/27b77ecf9f4d5be211900eda54d8155ada50d696/src/include/catalog/partition.h#L20>.
 const PARTITION_HASH_SEED: u64 = 0x7A5B22367996DCFD;
 
+/// The hashes `range_hash` shard ranges must cover, all of the int32 space like Citus.
+pub const RANGE_HASH_MIN: i32 = i32::MIN;
+pub const RANGE_HASH_MAX: i32 = i32::MAX;
+
 /// The sharding functions we support.
 #[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize, Hash, std::cmp::Eq)]
 pub enum ShardingFunction {
@@ -12,6 +18,14 @@ pub enum ShardingFunction {
     PgBigintHash,
     #[serde(alias = "sha1", alias = "Sha1")]
     Sha1,
+    /// First 8 bytes of the SHA-256 of the key, as a big-endian integer, modulo the number of shards.
+    /// Works with text keys, e.g. UUIDs. Integer keys are hashed as their decimal text.
+    #[serde(alias = "sha256_mod", alias = "Sha256Mod")]
+    Sha256Mod,
+    /// Postgres `hashint8` of the key, the hash Citus uses, looked up in the
+    /// `range_start`..=`range_end` ranges of the shards.
+    #[serde(alias = "range_hash", alias = "RangeHash")]
+    RangeHash,
 }
 
 impl std::fmt::Display for ShardingFunction {
@@ -19,6 +33,8 @@ impl std::fmt::Display for ShardingFunction {
         match self {
             ShardingFunction::PgBigintHash => write!(f, "pg_bigint_hash"),
             ShardingFunction::Sha1 => write!(f, "sha1"),
+            ShardingFunction::Sha256Mod => write!(f, "sha256_mod"),
+            ShardingFunction::RangeHash => write!(f, "range_hash"),
         }
     }
 }
@@ -30,6 +46,9 @@ pub struct Sharder {
 
     /// The sharding function in use.
     sharding_function: ShardingFunction,
+
+    /// Hash range of each shard, for `range_hash`.
+    ranges: Arc<Vec<(i32, i32)>>,
 }
 
 impl Sharder {
@@ -38,14 +57,32 @@ impl Sharder {
         Sharder {
             shards,
             sharding_function,
+            ranges: Arc::default(),
         }
     }
 
+    /// Set the hash range of each shard, in shard order, for `range_hash`.
+    pub fn with_ranges(mut self, ranges: Arc<Vec<(i32, i32)>>) -> Sharder {
+        self.ranges = ranges;
+        self
+    }
+
     /// Compute the shard given sharding key.
     pub fn shard(&self, key: i64) -> usize {
         match self.sharding_function {
             ShardingFunction::PgBigintHash => self.pg_bigint_hash(key),
             ShardingFunction::Sha1 => self.sha1(key),
+            ShardingFunction::Sha256Mod => self.sha256_mod(key.to_string().as_bytes()),
+            ShardingFunction::RangeHash => self.range_hash(key),
+        }
+    }
+
+    /// Compute the shard given a text sharding key, e.g. a UUID.
+    /// Only `sha256_mod` can hash text, the other functions need an integer.
+    pub fn shard_text(&self, key: &str) -> Option<usize> {
+        match self.sharding_function {
+            ShardingFunction::Sha256Mod => Some(self.sha256_mod(key.as_bytes())),
+            _ => key.parse::<i64>().ok().map(|key| self.shard(key)),
         }
     }
 
@@ -60,6 +97,30 @@ impl Sharder {
         Self::combine(0, Self::pg_u32_hash(lohalf)) as usize % self.shards
     }
 
+    /// Find the shard whose range contains the Postgres `hashint8` of the key.
+    /// The ranges are validated to cover every hash when the config is loaded.
+    fn range_hash(&self, key: i64) -> usize {
+        let hash = Self::pg_hashint8(key);
+
+        self.ranges
+            .iter()
+            .position(|(start, end)| (*start..=*end).contains(&hash))
+            .unwrap_or(0)
+    }
+
+    /// Postgres `hashint8`, without the seed used for partitioning.
+    /// Source: <https://github.com/postgres/postgres/blob/27b77ecf9f4d5be211900eda54d8155ada50d696/src/backend/access/hash/hashfunc.c#L83>.
+    fn pg_hashint8(key: i64) -> i32 {
+        let mut lohalf = key as u32;
+        let hihalf = (key >> 32) as u32;
+        lohalf ^= if key >= 0 { hihalf } else { !hihalf };
+
+        let a: u32 = 0x9e3779b9_u32 + std::mem::size_of::<u32>() as u32 + 3923095_u32;
+        let (_a, _b, c) = Self::_final(a.wrapping_add(lohalf), a, a);
+
+        c as i32
+    }
+
     /// Example of a hashing function based on SHA1.
     fn sha1(&self, key: i64) -> usize {
         let mut hasher = Sha1::new();
@@ -77,6 +138,16 @@ impl Sharder {
         key % self.shards
     }
 
+    /// SHA-256 of the key bytes, simple enough to compute in the application too.
+    fn sha256_mod(&self, key: &[u8]) -> usize {
+        let result = Sha256::digest(key);
+
+        let mut first = [0u8; 8];
+        first.copy_from_slice(&result[..8]);
+
+        (u64::from_be_bytes(first) % self.shards as u64) as usize
+    }
+
     #[inline]
     fn rot(x: u32, k: u32) -> u32 {
         (x << k) | (x >> (32 - k))
@@ -213,4 +284,89 @@ mod test {
             assert_eq!(sharder.shard(*id), shards[i]);
         }
     }
+
+    #[test]
+    fn test_sha256_mod() {
+        let sharder = Sharder::new(12, ShardingFunction::Sha256Mod);
+        let ids = [
+            0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19,
+        ];
+        let shards = [
+            0, 1, 10, 7, 10, 11, 3, 2, 3, 0, 0, 10, 0, 2, 1, 2, 2, 3, 10, 7,
+        ];
+
+        for (i, id) in ids.iter().enumerate() {
+            assert_eq!(sharder.shard(*id), shards[i]);
+            assert_eq!(sharder.shard_text(&id.to_string()), Some(shards[i]));
+        }
+
+        assert_eq!(
+            sharder.shard_text("6b1e0d8c-2a4f-4b8e-9a36-0d2f5e7c9a14"),
+            Some(2)
+        );
+
+        // Other functions only take integers.
+        let sharder = Sharder::new(12, ShardingFunction::Sha1);
+        assert_eq!(sharder.shard_text("5"), Some(0));
+        assert_eq!(
+            sharder.shard_text("6b1e0d8c-2a4f-4b8e-9a36-0d2f5e7c9a14"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_pg_hashint8() {
+        // SELECT hashint8(1), hashint8(-1), hashint8(12345), hashint8(5000000000);
+        assert_eq!(Sharder::pg_hashint8(1), -1905060026);
+        assert_eq!(Sharder::pg_hashint8(-1), 385747274);
+        assert_eq!(Sharder::pg_hashint8(12345), -78097827);
+        assert_eq!(Sharder::pg_hashint8(5000000000), -694934712);
+    }
+
+    #[test]
+    fn test_range_hash() {
+        let ranges = [
+            (RANGE_HASH_MIN, -1073741825),
+            (-1073741824, -1),
+            (0, 1073741823),
+            (1073741824, RANGE_HASH_MAX),
+        ];
+        let sharder =
+            Sharder::new(4, ShardingFunction::RangeHash).with_ranges(Arc::new(ranges.to_vec()));
+
+        for key in 0..100 {
+            let hash = Sharder::pg_hashint8(key);
+            let (start, end) = ranges[sharder.shard(key)];
+            assert!(start <= hash && hash <= end);
+        }
+
+        // hashint8(1) = -1905060026
+        assert_eq!(sharder.shard(1), 0);
+        assert_eq!(sharder.shard_text("1"), Some(0));
+    }
+
+    #[test]
+    fn test_sha256_mod_distribution() {
+        let shards = 8;
+        let keys = 80_000;
+        let sharder = Sharder::new(shards, ShardingFunction::Sha256Mod);
+        let mut counts = vec![0; shards];
+
+        // UUID-looking keys.
+        for i in 0..keys {
+            let key = format!("{:08x}-0000-4000-8000-{:012x}", i * 7919, i);
+            counts[sharder.shard_text(&key).unwrap()] += 1;
+        }
+
+        // Every shard within 5% of an even split.
+        let expected = keys / shards;
+        for count in counts {
+            assert!(
+                count > expected * 95 / 100 && count < expected * 105 / 100,
+                "{} keys on a shard, expected about {}",
+                count,
+                expected
+            );
+        }
+    }
 }
@@ -8,13 +8,20 @@ use std::collections::HashMap;
 
 use std::sync::Arc;
 
+use crate::pool::get_all_pools;
+
 // Structs that hold stats for different resources
 pub mod address;
 pub mod client;
 pub mod pool;
 pub mod server;
+pub mod user;
 pub use address::AddressStats;
 pub use client::{ClientState, ClientStats};
+pub use pool::{
+    ApplicationConnections, ApplicationConnectionsGuard, CheckoutHistogram, CheckoutHistograms,
+    MinIdleShortfall, QueueDepth, QueueTime, SlowCheckout, SlowCheckouts,
+};
 pub use server::{ServerState, ServerStats};
 
 /// Convenience types for various stats
@@ -107,6 +114,12 @@ impl Collector {
                 for stats in server_stats.values() {
                     stats.set_address_stat_average_is_updated_status(false);
                 }
+
+                for pool in get_all_pools().values() {
+                    pool.queue_depth.rotate_window();
+                    pool.queue_time.rotate_window();
+                    pool.check_min_idle();
+                }
             }
         });
     }
@@ -1,3 +1,4 @@
+use chrono::naive::NaiveDateTime;
 use std::sync::atomic::*;
 use std::sync::Arc;
 
@@ -10,7 +11,9 @@ struct AddressStatFields {
     xact_time: Arc<AtomicU64>,
     query_time: Arc<AtomicU64>,
     wait_time: Arc<AtomicU64>,
+    waits: Arc<AtomicU64>,
     errors: Arc<AtomicU64>,
+    statement_timeouts: Arc<AtomicU64>,
 }
 
 /// Internal address stats
@@ -24,6 +27,28 @@ pub struct AddressStats {
 
     // Determines if the averages have been updated since the last time they were reported
     pub averages_updated: Arc<AtomicBool>,
+
+    // Time clients waited for a connection (us) and checkouts in the last stats period
+    period_wait_time: Arc<AtomicU64>,
+    period_waits: Arc<AtomicU64>,
+
+    // Replication lag (ms) measured by the last health check, replicas only
+    replica_lag: Arc<AtomicU64>,
+
+    // When the replication lag was measured (ms since epoch), 0 if never
+    replica_lag_measured_at: Arc<AtomicU64>,
+
+    // Failed checkouts, including failed health checks, since startup
+    checkout_errors: Arc<AtomicU64>,
+
+    // Times this address was banned since startup
+    bans: Arc<AtomicU64>,
+
+    // When the last health check passed (ms since epoch), 0 if never
+    last_healthcheck_success: Arc<AtomicU64>,
+
+    // When the last health check failed or timed out (ms since epoch), 0 if never
+    last_healthcheck_failure: Arc<AtomicU64>,
 }
 
 impl IntoIterator for AddressStats {
@@ -96,6 +121,10 @@ impl IntoIterator for AddressStats {
                 "avg_wait_time".to_string(),
                 self.averages.wait_time.load(Ordering::Relaxed),
             ),
+            (
+                "total_statement_timeouts".to_string(),
+                self.total.statement_timeouts.load(Ordering::Relaxed),
+            ),
         ]
         .into_iter()
     }
@@ -136,9 +165,20 @@ impl AddressStats {
         self.current.query_time.fetch_add(time, Ordering::Relaxed);
     }
 
+    /// Report a checkout and how long the client waited for it (us).
     pub fn wait_time_add(&self, time: u64) {
         self.total.wait_time.fetch_add(time, Ordering::Relaxed);
         self.current.wait_time.fetch_add(time, Ordering::Relaxed);
+        self.total.waits.fetch_add(1, Ordering::Relaxed);
+        self.current.waits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Time clients waited for a connection (us) and checkouts in the last stats period.
+    pub fn period_waits(&self) -> (u64, u64) {
+        (
+            self.period_wait_time.load(Ordering::Relaxed),
+            self.period_waits.load(Ordering::Relaxed),
+        )
     }
 
     pub fn error(&self) {
@@ -146,6 +186,71 @@ impl AddressStats {
         self.current.errors.fetch_add(1, Ordering::Relaxed);
     }
 
+    pub fn statement_timeout(&self) {
+        self.total
+            .statement_timeouts
+            .fetch_add(1, Ordering::Relaxed);
+        self.current
+            .statement_timeouts
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn checkout_error(&self) {
+        self.checkout_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn checkout_errors(&self) -> u64 {
+        self.checkout_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn ban(&self) {
+        self.bans.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn bans(&self) -> u64 {
+        self.bans.load(Ordering::Relaxed)
+    }
+
+    pub fn healthcheck_succeeded(&self) {
+        self.last_healthcheck_success.store(
+            chrono::offset::Utc::now().timestamp_millis() as u64,
+            Ordering::Relaxed,
+        );
+    }
+
+    pub fn healthcheck_failed(&self) {
+        self.last_healthcheck_failure.store(
+            chrono::offset::Utc::now().timestamp_millis() as u64,
+            Ordering::Relaxed,
+        );
+    }
+
+    pub fn last_healthcheck_success(&self) -> Option<NaiveDateTime> {
+        timestamp(&self.last_healthcheck_success)
+    }
+
+    pub fn last_healthcheck_failure(&self) -> Option<NaiveDateTime> {
+        timestamp(&self.last_healthcheck_failure)
+    }
+
+    pub fn set_replica_lag(&self, replica_lag: u64) {
+        self.replica_lag.store(replica_lag, Ordering::Relaxed);
+        self.replica_lag_measured_at.store(
+            chrono::offset::Utc::now().timestamp_millis() as u64,
+            Ordering::Relaxed,
+        );
+    }
+
+    pub fn replica_lag(&self) -> u64 {
+        self.replica_lag.load(Ordering::Relaxed)
+    }
+
+    /// Time since the replication lag was measured (ms).
+    pub fn replica_lag_age(&self) -> u64 {
+        let measured_at = self.replica_lag_measured_at.load(Ordering::Relaxed);
+        (chrono::offset::Utc::now().timestamp_millis() as u64).saturating_sub(measured_at)
+    }
+
     pub fn update_averages(&self) {
         let stat_period_per_second = crate::stats::STAT_PERIOD / 1_000;
 
@@ -199,6 +304,12 @@ impl AddressStats {
             current_wait_time / stat_period_per_second,
             Ordering::Relaxed,
         );
+        self.period_wait_time
+            .store(current_wait_time, Ordering::Relaxed);
+        self.period_waits.store(
+            self.current.waits.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
 
         // errors
         let current_errors = self.current.errors.load(Ordering::Relaxed);
@@ -215,7 +326,9 @@ impl AddressStats {
         self.current.bytes_received.store(0, Ordering::Relaxed);
         self.current.bytes_sent.store(0, Ordering::Relaxed);
         self.current.wait_time.store(0, Ordering::Relaxed);
+        self.current.waits.store(0, Ordering::Relaxed);
         self.current.errors.store(0, Ordering::Relaxed);
+        self.current.statement_timeouts.store(0, Ordering::Relaxed);
     }
 
     pub fn populate_row(&self, row: &mut Vec<String>) {
@@ -224,3 +337,11 @@ impl AddressStats {
         }
     }
 }
+
+/// Time stored as ms since epoch, None if it was never set.
+fn timestamp(millis: &AtomicU64) -> Option<NaiveDateTime> {
+    match millis.load(Ordering::Relaxed) {
+        0 => None,
+        millis => NaiveDateTime::from_timestamp_millis(millis as i64),
+    }
+}
@@ -1,12 +1,391 @@
 use log::debug;
 
 use super::{ClientState, ServerState};
-use crate::{config::PoolMode, messages::DataType, pool::PoolIdentifier};
-use std::collections::HashMap;
+use crate::{
+    config::{PoolMode, Role},
+    messages::DataType,
+    pool::PoolIdentifier,
+};
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::atomic::*;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::pool::get_all_pools;
 
+/// Number of clients waiting for a connection from a pool,
+/// along with the deepest the queue got recently.
+#[derive(Debug, Default)]
+pub struct QueueDepth {
+    /// Clients waiting right now.
+    current: AtomicU32,
+
+    /// Deepest queue seen in the current stats period.
+    window_max: AtomicU32,
+
+    /// Deepest queue seen in the previous stats period.
+    last_window_max: AtomicU32,
+}
+
+impl QueueDepth {
+    /// A client starts waiting for a connection. It stops waiting
+    /// when the returned guard is dropped.
+    pub fn enter(&self) -> QueueDepthGuard<'_> {
+        let depth = self.current.fetch_add(1, Ordering::Relaxed) + 1;
+        self.window_max.fetch_max(depth, Ordering::Relaxed);
+        QueueDepthGuard { queue_depth: self }
+    }
+
+    /// Number of clients waiting right now.
+    pub fn current(&self) -> u32 {
+        self.current.load(Ordering::Relaxed)
+    }
+
+    /// Deepest queue seen over the previous and the current stats periods.
+    pub fn max(&self) -> u32 {
+        std::cmp::max(
+            self.window_max.load(Ordering::Relaxed),
+            self.last_window_max.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Start a new stats period, called by the collector.
+    pub fn rotate_window(&self) {
+        let window_max = self.window_max.swap(self.current(), Ordering::Relaxed);
+        self.last_window_max.store(window_max, Ordering::Relaxed);
+    }
+}
+
+/// Removes a client from the queue when dropped, however the wait ends.
+pub struct QueueDepthGuard<'a> {
+    queue_depth: &'a QueueDepth,
+}
+
+impl Drop for QueueDepthGuard<'_> {
+    fn drop(&mut self) {
+        self.queue_depth.current.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Time clients of a pool spent waiting for their turn with `fair_checkout`.
+#[derive(Debug, Default)]
+pub struct QueueTime {
+    /// Time waited by all the checkouts so far, in microseconds.
+    total: AtomicU64,
+
+    /// Longest wait in the current stats period, in microseconds.
+    window_max: AtomicU64,
+
+    /// Longest wait in the previous stats period, in microseconds.
+    last_window_max: AtomicU64,
+}
+
+impl QueueTime {
+    /// A checkout waited `queue_time` microseconds for its turn.
+    pub fn record(&self, queue_time: u64) {
+        self.total.fetch_add(queue_time, Ordering::Relaxed);
+        self.window_max.fetch_max(queue_time, Ordering::Relaxed);
+    }
+
+    /// Time waited by all the checkouts so far, in microseconds.
+    pub fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// Longest wait over the previous and the current stats periods, in microseconds.
+    pub fn max(&self) -> u64 {
+        std::cmp::max(
+            self.window_max.load(Ordering::Relaxed),
+            self.last_window_max.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Start a new stats period, called by the collector.
+    pub fn rotate_window(&self) {
+        let window_max = self.window_max.swap(0, Ordering::Relaxed);
+        self.last_window_max.store(window_max, Ordering::Relaxed);
+    }
+}
+
+/// Server connections held by the clients of a pool for each `application_name`,
+/// to enforce `max_client_connections`. Applications without any are forgotten.
+#[derive(Debug, Default, Clone)]
+pub struct ApplicationConnections {
+    held: Arc<Mutex<HashMap<String, AtomicU32>>>,
+}
+
+impl ApplicationConnections {
+    /// Count one more connection for `application_name`, unless it already holds `max`.
+    /// The connection is given back when the returned guard is dropped.
+    pub fn enter(&self, application_name: &str, max: u32) -> Option<ApplicationConnectionsGuard> {
+        // Counted under the lock, an application can't be forgotten while it's entering.
+        let mut held = self.held.lock();
+
+        held.entry(application_name.to_string())
+            .or_default()
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                (count < max).then_some(count + 1)
+            })
+            .ok()?;
+
+        Some(ApplicationConnectionsGuard {
+            connections: self.clone(),
+            application_name: application_name.to_string(),
+        })
+    }
+
+    /// Connections held by `application_name` right now.
+    pub fn current(&self, application_name: &str) -> u32 {
+        self.held
+            .lock()
+            .get(application_name)
+            .map(|count| count.load(Ordering::Relaxed))
+            .unwrap_or(0)
+    }
+}
+
+/// Gives a connection back to its application when dropped.
+#[derive(Debug)]
+pub struct ApplicationConnectionsGuard {
+    connections: ApplicationConnections,
+    application_name: String,
+}
+
+impl Drop for ApplicationConnectionsGuard {
+    fn drop(&mut self) {
+        let mut held = self.connections.held.lock();
+
+        if let Some(count) = held.get(&self.application_name) {
+            if count.fetch_sub(1, Ordering::Relaxed) == 1 {
+                held.remove(&self.application_name);
+            }
+        }
+    }
+}
+
+/// Number of slowest checkouts kept per pool.
+const SLOW_CHECKOUTS_SIZE: usize = 10;
+
+/// How long a slow checkout is kept around before it's forgotten.
+const SLOW_CHECKOUTS_RETENTION: Duration = Duration::from_secs(300);
+
+/// A checkout that took a while.
+#[derive(Debug, Clone)]
+pub struct SlowCheckout {
+    /// When the checkout finished.
+    pub at: Instant,
+
+    /// How long the checkout took, in microseconds.
+    pub checkout_time: u64,
+
+    /// Name of the server the client got.
+    pub address: String,
+
+    /// Whether a health check ran on the server we ended up with.
+    pub health_check: bool,
+
+    /// Number of servers tried, including the one we ended up with.
+    pub candidates_tried: usize,
+
+    /// Time spent waiting for our turn with `fair_checkout`, in microseconds.
+    pub queue_time: u64,
+}
+
+impl SlowCheckout {
+    /// The same server, used again without checking it out.
+    pub fn reused(&self) -> SlowCheckout {
+        SlowCheckout {
+            at: Instant::now(),
+            checkout_time: 0,
+            address: self.address.clone(),
+            health_check: false,
+            candidates_tried: 0,
+            queue_time: 0,
+        }
+    }
+}
+
+/// The slowest recent checkouts of a pool.
+#[derive(Debug, Default)]
+pub struct SlowCheckouts {
+    checkouts: Mutex<Vec<SlowCheckout>>,
+
+    /// Fastest checkout in the list once it's full, anything faster
+    /// is skipped without taking the lock.
+    threshold: AtomicU64,
+}
+
+impl SlowCheckouts {
+    /// Record a checkout, keeping it only if it's among the slowest.
+    pub fn record(&self, checkout: &SlowCheckout) {
+        if checkout.checkout_time <= self.threshold.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let mut checkouts = self.checkouts.lock();
+        checkouts.retain(|c| c.at.elapsed() < SLOW_CHECKOUTS_RETENTION);
+
+        if checkouts.len() < SLOW_CHECKOUTS_SIZE {
+            checkouts.push(checkout.clone());
+        } else if let Some(fastest) = checkouts
+            .iter_mut()
+            .min_by_key(|c| c.checkout_time)
+            .filter(|c| c.checkout_time < checkout.checkout_time)
+        {
+            *fastest = checkout.clone();
+        }
+
+        let threshold = match checkouts.len() {
+            SLOW_CHECKOUTS_SIZE => checkouts.iter().map(|c| c.checkout_time).min().unwrap(),
+            _ => 0,
+        };
+
+        self.threshold.store(threshold, Ordering::Relaxed);
+    }
+
+    /// Slowest recent checkouts, slowest first.
+    pub fn get(&self) -> Vec<SlowCheckout> {
+        let mut checkouts = self.checkouts.lock();
+        checkouts.retain(|c| c.at.elapsed() < SLOW_CHECKOUTS_RETENTION);
+
+        if checkouts.len() < SLOW_CHECKOUTS_SIZE {
+            self.threshold.store(0, Ordering::Relaxed);
+        }
+
+        let mut checkouts = checkouts.clone();
+        checkouts.sort_by_key(|c| std::cmp::Reverse(c.checkout_time));
+        checkouts
+    }
+}
+
+/// Servers of a pool that have fewer idle connections than `min_pool_size`.
+#[derive(Debug, Default)]
+pub struct MinIdleShortfall {
+    /// When each server, by shard and index, started falling short.
+    since: Mutex<HashMap<(usize, usize), Instant>>,
+
+    /// Idle connections missing on the servers short for longer than the threshold.
+    current: AtomicU64,
+}
+
+impl MinIdleShortfall {
+    /// Record how many idle connections a server is missing.
+    /// Returns how long it's been short for, `None` if it isn't.
+    pub fn record(&self, shard: usize, server: usize, shortfall: u32) -> Option<Duration> {
+        let mut since = self.since.lock();
+
+        if shortfall == 0 {
+            since.remove(&(shard, server));
+            return None;
+        }
+
+        Some(
+            since
+                .entry((shard, server))
+                .or_insert_with(Instant::now)
+                .elapsed(),
+        )
+    }
+
+    pub fn set(&self, shortfall: u64) {
+        self.current.store(shortfall, Ordering::Relaxed);
+    }
+
+    pub fn current(&self) -> u64 {
+        self.current.load(Ordering::Relaxed)
+    }
+}
+
+/// Checkout times of a pool, counted in buckets for a Prometheus histogram.
+#[derive(Debug, Default)]
+pub struct CheckoutHistogram {
+    /// Upper bounds of the buckets, in microseconds.
+    bounds: Vec<u64>,
+
+    /// Checkouts in each bucket, the last one is for everything slower.
+    buckets: Vec<AtomicU64>,
+
+    /// Sum of the checkout times, in microseconds.
+    sum: AtomicU64,
+}
+
+impl CheckoutHistogram {
+    /// Histogram with the given bucket upper bounds, in milliseconds.
+    pub fn new(bounds: &[f64]) -> CheckoutHistogram {
+        CheckoutHistogram {
+            bounds: bounds.iter().map(|bound| (bound * 1000.0) as u64).collect(),
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    /// Count a checkout that took `checkout_time` microseconds.
+    pub fn observe(&self, checkout_time: u64) {
+        let bucket = self.bounds.partition_point(|bound| *bound < checkout_time);
+
+        if let Some(bucket) = self.buckets.get(bucket) {
+            bucket.fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum.fetch_add(checkout_time, Ordering::Relaxed);
+    }
+
+    /// Cumulative count of checkouts at or under each bound (µs),
+    /// the total count and the sum of the checkout times (µs).
+    pub fn snapshot(&self) -> (Vec<(u64, u64)>, u64, u64) {
+        let mut count = 0;
+        let mut cumulative = Vec::with_capacity(self.bounds.len());
+
+        for (bucket, bound) in self.buckets.iter().zip(self.bounds.iter()) {
+            count += bucket.load(Ordering::Relaxed);
+            cumulative.push((*bound, count));
+        }
+
+        if let Some(overflow) = self.buckets.get(self.bounds.len()) {
+            count += overflow.load(Ordering::Relaxed);
+        }
+
+        (cumulative, count, self.sum.load(Ordering::Relaxed))
+    }
+}
+
+/// Checkout time histograms of a pool, by role and whether the client got a server.
+#[derive(Debug, Default)]
+pub struct CheckoutHistograms {
+    /// Bucket upper bounds they count in, in milliseconds.
+    bounds: Vec<f64>,
+    primary: CheckoutHistogram,
+    replica: CheckoutHistogram,
+    primary_failed: CheckoutHistogram,
+    replica_failed: CheckoutHistogram,
+}
+
+impl CheckoutHistograms {
+    pub fn new(bounds: &[f64]) -> CheckoutHistograms {
+        CheckoutHistograms {
+            bounds: bounds.to_vec(),
+            primary: CheckoutHistogram::new(bounds),
+            replica: CheckoutHistogram::new(bounds),
+            primary_failed: CheckoutHistogram::new(bounds),
+            replica_failed: CheckoutHistogram::new(bounds),
+        }
+    }
+
+    /// Bucket upper bounds, in milliseconds.
+    pub fn bounds(&self) -> &[f64] {
+        &self.bounds
+    }
+
+    pub fn role(&self, role: Role, success: bool) -> &CheckoutHistogram {
+        match (role, success) {
+            (Role::Primary, true) => &self.primary,
+            (Role::Primary, false) => &self.primary_failed,
+            (_, true) => &self.replica,
+            (_, false) => &self.replica_failed,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 /// A struct that holds information about a Pool .
 pub struct PoolStats {
@@ -22,6 +401,17 @@ pub struct PoolStats {
     pub sv_tested: u64,
     pub sv_login: u64,
     pub maxwait: u64,
+    pub queries_in_flight: u64,
+    pub queue_depth: u64,
+    pub queue_depth_max: u64,
+    pub queue_time_us: u64,
+    pub queue_time_max_us: u64,
+    pub min_idle_shortfall: u64,
+    pub all_replicas_banned: u64,
+    pub parse_failures: u64,
+    pub auto_session_clients: u64,
+    pub config_hash: u64,
+    pub labels: BTreeMap<String, String>,
 }
 impl PoolStats {
     pub fn new(identifier: PoolIdentifier, mode: PoolMode) -> Self {
@@ -38,6 +428,17 @@ impl PoolStats {
             sv_tested: 0,
             sv_login: 0,
             maxwait: 0,
+            queries_in_flight: 0,
+            queue_depth: 0,
+            queue_depth_max: 0,
+            queue_time_us: 0,
+            queue_time_max_us: 0,
+            min_idle_shortfall: 0,
+            all_replicas_banned: 0,
+            parse_failures: 0,
+            auto_session_clients: 0,
+            config_hash: 0,
+            labels: BTreeMap::new(),
         }
     }
 
@@ -47,10 +448,19 @@ impl PoolStats {
         let server_map = super::get_server_stats();
 
         for (identifier, pool) in get_all_pools() {
-            map.insert(
-                identifier.clone(),
-                PoolStats::new(identifier, pool.settings.pool_mode),
-            );
+            let mut pool_stats = PoolStats::new(identifier.clone(), pool.settings.pool_mode);
+            pool_stats.queries_in_flight = pool.queries_in_flight();
+            pool_stats.queue_depth = pool.queue_depth.current() as u64;
+            pool_stats.queue_depth_max = pool.queue_depth.max() as u64;
+            pool_stats.queue_time_us = pool.queue_time.total();
+            pool_stats.queue_time_max_us = pool.queue_time.max();
+            pool_stats.min_idle_shortfall = pool.min_idle_shortfall.current();
+            pool_stats.all_replicas_banned = pool.all_replicas_banned();
+            pool_stats.parse_failures = pool.parse_failures();
+            pool_stats.auto_session_clients = pool.auto_session_clients();
+            pool_stats.config_hash = pool.config_hash;
+            pool_stats.labels = pool.settings.labels.clone();
+            map.insert(identifier, pool_stats);
         }
 
         for client in client_map.values() {
@@ -108,6 +518,16 @@ impl PoolStats {
             ("sv_login", DataType::Numeric),
             ("maxwait", DataType::Numeric),
             ("maxwait_us", DataType::Numeric),
+            ("queries_in_flight", DataType::Numeric),
+            ("queue_depth", DataType::Numeric),
+            ("queue_depth_max", DataType::Numeric),
+            ("queue_time_us", DataType::Numeric),
+            ("queue_time_max_us", DataType::Numeric),
+            ("min_idle_shortfall", DataType::Numeric),
+            ("all_replicas_banned", DataType::Numeric),
+            ("parse_failures", DataType::Numeric),
+            ("auto_session_clients", DataType::Numeric),
+            ("config_hash", DataType::Text),
         ]
     }
 
@@ -127,6 +547,17 @@ impl PoolStats {
             self.sv_login.to_string(),
             (self.maxwait / 1_000_000).to_string(),
             (self.maxwait % 1_000_000).to_string(),
+            self.queries_in_flight.to_string(),
+            self.queue_depth.to_string(),
+            self.queue_depth_max.to_string(),
+            self.queue_time_us.to_string(),
+            self.queue_time_max_us.to_string(),
+            self.min_idle_shortfall.to_string(),
+            self.all_replicas_banned.to_string(),
+            self.parse_failures.to_string(),
+            self.auto_session_clients.to_string(),
+            // Changes when RELOAD re-creates the pool.
+            format!("{:016x}", self.config_hash),
         ]
     }
 }
@@ -148,7 +579,128 @@ impl IntoIterator for PoolStats {
             ("sv_login".to_string(), self.sv_login),
             ("maxwait".to_string(), self.maxwait / 1_000_000),
             ("maxwait_us".to_string(), self.maxwait % 1_000_000),
+            ("queries_in_flight".to_string(), self.queries_in_flight),
+            ("queue_depth".to_string(), self.queue_depth),
+            ("queue_depth_max".to_string(), self.queue_depth_max),
+            ("queue_time_us".to_string(), self.queue_time_us),
+            ("queue_time_max_us".to_string(), self.queue_time_max_us),
+            ("min_idle_shortfall".to_string(), self.min_idle_shortfall),
+            ("all_replicas_banned".to_string(), self.all_replicas_banned),
+            ("parse_failures".to_string(), self.parse_failures),
+            (
+                "auto_session_clients".to_string(),
+                self.auto_session_clients,
+            ),
         ]
         .into_iter()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn checkout(checkout_time: u64) -> SlowCheckout {
+        SlowCheckout {
+            at: Instant::now(),
+            checkout_time,
+            address: String::from("pool_shard_0_primary"),
+            health_check: false,
+            candidates_tried: 1,
+            queue_time: 0,
+        }
+    }
+
+    #[test]
+    fn test_checkout_histogram() {
+        let histogram = CheckoutHistogram::new(&[1.0, 10.0]);
+
+        for checkout_time in [200, 1000, 1001, 9000, 50_000] {
+            histogram.observe(checkout_time);
+        }
+
+        let (buckets, count, sum) = histogram.snapshot();
+        assert_eq!(buckets, vec![(1000, 2), (10_000, 4)]);
+        assert_eq!(count, 5);
+        assert_eq!(sum, 61_201);
+
+        // Only the overflow bucket.
+        let histogram = CheckoutHistogram::new(&[]);
+        histogram.observe(5);
+        assert_eq!(histogram.snapshot(), (vec![], 1, 5));
+    }
+
+    #[test]
+    fn test_slow_checkouts() {
+        let slow_checkouts = SlowCheckouts::default();
+
+        for checkout_time in 1..=(SLOW_CHECKOUTS_SIZE as u64 * 2) {
+            slow_checkouts.record(&checkout(checkout_time));
+        }
+        slow_checkouts.record(&checkout(5));
+
+        let checkouts = slow_checkouts.get();
+        assert_eq!(checkouts.len(), SLOW_CHECKOUTS_SIZE);
+        assert_eq!(checkouts[0].checkout_time, SLOW_CHECKOUTS_SIZE as u64 * 2);
+        assert_eq!(
+            checkouts[SLOW_CHECKOUTS_SIZE - 1].checkout_time,
+            SLOW_CHECKOUTS_SIZE as u64 + 1
+        );
+    }
+
+    #[test]
+    fn test_queue_time() {
+        let queue_time = QueueTime::default();
+
+        queue_time.record(30);
+        queue_time.record(10);
+        assert_eq!(queue_time.total(), 40);
+        assert_eq!(queue_time.max(), 30);
+
+        // The longest wait is kept for one more period.
+        queue_time.rotate_window();
+        queue_time.record(20);
+        assert_eq!(queue_time.max(), 30);
+
+        queue_time.rotate_window();
+        assert_eq!(queue_time.max(), 20);
+        assert_eq!(queue_time.total(), 60);
+    }
+
+    #[test]
+    fn test_application_connections() {
+        let connections = ApplicationConnections::default();
+
+        let first = connections.enter("app_a", 2);
+        let second = connections.enter("app_a", 2);
+        assert!(first.is_some() && second.is_some());
+        assert!(connections.enter("app_a", 2).is_none());
+        assert_eq!(connections.current("app_a"), 2);
+
+        // Other applications have their own share.
+        assert!(connections.enter("app_b", 2).is_some());
+
+        drop(first);
+        assert_eq!(connections.current("app_a"), 1);
+        assert!(connections.enter("app_a", 2).is_some());
+
+        // Applications without connections are forgotten.
+        drop(second);
+        assert!(connections.held.lock().is_empty());
+    }
+
+    #[test]
+    fn test_min_idle_shortfall() {
+        let shortfall = MinIdleShortfall::default();
+
+        assert_eq!(shortfall.record(0, 0, 0), None);
+        assert!(shortfall.record(0, 0, 2).is_some());
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(shortfall.record(0, 0, 1).unwrap() >= Duration::from_millis(10));
+        assert!(shortfall.record(0, 1, 1).unwrap() < Duration::from_millis(10));
+
+        // Back to min_pool_size resets the timer.
+        assert_eq!(shortfall.record(0, 0, 0), None);
+        assert!(shortfall.record(0, 0, 1).unwrap() < Duration::from_millis(10));
+    }
+}
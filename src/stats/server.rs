@@ -51,6 +51,9 @@ pub struct ServerStats {
     pub prepared_miss_count: Arc<AtomicU64>,
     pub prepared_eviction_count: Arc<AtomicU64>,
     pub prepared_cache_size: Arc<AtomicU64>,
+
+    /// When the transaction in progress started, if any.
+    xact_start: Arc<RwLock<Option<Instant>>>,
 }
 
 impl Default for ServerStats {
@@ -71,6 +74,7 @@ impl Default for ServerStats {
             prepared_miss_count: Arc::new(AtomicU64::new(0)),
             prepared_eviction_count: Arc::new(AtomicU64::new(0)),
             prepared_cache_size: Arc::new(AtomicU64::new(0)),
+            xact_start: Arc::new(RwLock::new(None)),
         }
     }
 }
@@ -126,6 +130,8 @@ impl ServerStats {
     pub fn active(&self, application_name: String) {
         self.state.store(ServerState::Active, Ordering::Relaxed);
         self.set_application(application_name);
+        // Don't count a transaction the previous client left unfinished.
+        *self.xact_start.write() = None;
     }
 
     pub fn address_stats(&self) -> Arc<AddressStats> {
@@ -156,6 +162,10 @@ impl ServerStats {
         self.address.name()
     }
 
+    pub fn zone(&self) -> Option<String> {
+        self.address.zone.clone()
+    }
+
     pub fn connect_time(&self) -> Instant {
         self.connect_time
     }
@@ -181,6 +191,16 @@ impl ServerStats {
         self.address.stats.query_count_add();
         self.address.stats.query_time_add(milliseconds);
         self.query_count.fetch_add(1, Ordering::Relaxed);
+
+        // The first query of a transaction started it.
+        let mut xact_start = self.xact_start.write();
+        if xact_start.is_none() {
+            let now = Instant::now();
+            *xact_start = Some(
+                now.checked_sub(std::time::Duration::from_millis(milliseconds))
+                    .unwrap_or(now),
+            );
+        }
     }
 
     /// Report a transaction executed by a client a server
@@ -192,6 +212,12 @@ impl ServerStats {
 
         self.transaction_count.fetch_add(1, Ordering::Relaxed);
         self.address.stats.xact_count_add();
+
+        if let Some(xact_start) = self.xact_start.write().take() {
+            self.address
+                .stats
+                .xact_time_add(xact_start.elapsed().as_millis() as u64);
+        }
     }
 
     /// Report data sent to a server
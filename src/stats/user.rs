@@ -0,0 +1,73 @@
+use super::ClientState;
+use crate::messages::DataType;
+use std::collections::HashMap;
+use std::sync::atomic::*;
+
+#[derive(Debug, Clone, Default)]
+/// Client counts for a user, aggregated across all the pools it connects to.
+pub struct UserStats {
+    pub username: String,
+    pub cl_idle: u64,
+    pub cl_active: u64,
+    pub cl_waiting: u64,
+}
+
+impl UserStats {
+    pub fn new(username: String) -> Self {
+        UserStats {
+            username,
+            ..Default::default()
+        }
+    }
+
+    pub fn construct_user_lookup() -> HashMap<String, UserStats> {
+        let mut map: HashMap<String, UserStats> = HashMap::new();
+        let client_map = super::get_client_stats();
+
+        for client in client_map.values() {
+            let user_stats = map
+                .entry(client.username())
+                .or_insert_with(|| UserStats::new(client.username()));
+
+            match client.state.load(Ordering::Relaxed) {
+                ClientState::Active => user_stats.cl_active += 1,
+                ClientState::Idle => user_stats.cl_idle += 1,
+                ClientState::Waiting => user_stats.cl_waiting += 1,
+            }
+        }
+
+        map
+    }
+
+    pub fn generate_header() -> Vec<(&'static str, DataType)> {
+        vec![
+            ("user", DataType::Text),
+            ("cl_idle", DataType::Numeric),
+            ("cl_active", DataType::Numeric),
+            ("cl_waiting", DataType::Numeric),
+        ]
+    }
+
+    pub fn generate_row(&self) -> Vec<String> {
+        vec![
+            self.username.clone(),
+            self.cl_idle.to_string(),
+            self.cl_active.to_string(),
+            self.cl_waiting.to_string(),
+        ]
+    }
+}
+
+impl IntoIterator for UserStats {
+    type Item = (String, u64);
+    type IntoIter = std::vec::IntoIter<Self::Item>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        vec![
+            ("cl_idle".to_string(), self.cl_idle),
+            ("cl_active".to_string(), self.cl_active),
+            ("cl_waiting".to_string(), self.cl_waiting),
+        ]
+        .into_iter()
+    }
+}
@@ -1,18 +1,20 @@
 // Stream wrapper.
 
 use rustls_pemfile::{certs, read_one, Item};
+use sha2::{Digest, Sha256, Sha384, Sha512};
 use std::iter;
 use std::path::Path;
 use std::sync::Arc;
 use std::time::SystemTime;
 use tokio_rustls::rustls::{
     self,
-    client::{ServerCertVerified, ServerCertVerifier},
-    Certificate, PrivateKey, ServerName,
+    client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    server::AllowAnyAnonymousOrAuthenticatedClient,
+    Certificate, CertificateError, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerName,
 };
 use tokio_rustls::TlsAcceptor;
 
-use crate::config::get_config;
+use crate::config::{get_config, Config, ServerTlsVerify};
 use crate::errors::Error;
 
 // TLS
@@ -38,34 +40,77 @@ pub fn load_keys(path: &Path) -> std::io::Result<Vec<PrivateKey>> {
 
 pub struct Tls {
     pub acceptor: TlsAcceptor,
+
+    /// tls-server-end-point channel binding data of our certificate, for SCRAM-SHA-256-PLUS.
+    pub server_end_point: Vec<u8>,
 }
 
 impl Tls {
     pub fn new() -> Result<Self, Error> {
         let config = get_config();
 
-        let certs = match load_certs(Path::new(&config.general.tls_certificate.unwrap())) {
+        let certs = match load_certs(Path::new(config.general.tls_certificate.as_ref().unwrap())) {
             Ok(certs) => certs,
-            Err(_) => return Err(Error::TlsError),
+            Err(err) => return Err(Error::TlsError(format!("tls_certificate: {}", err))),
         };
 
-        let mut keys = match load_keys(Path::new(&config.general.tls_private_key.unwrap())) {
+        let mut keys = match load_keys(Path::new(config.general.tls_private_key.as_ref().unwrap()))
+        {
             Ok(keys) => keys,
-            Err(_) => return Err(Error::TlsError),
+            Err(err) => return Err(Error::TlsError(format!("tls_private_key: {}", err))),
+        };
+
+        // Ask clients for a certificate if we know who to trust,
+        // clients using password auth don't need one.
+        let builder = rustls::ServerConfig::builder().with_safe_defaults();
+
+        let builder = match config.general.tls_client_ca_certificate {
+            Some(ref tls_client_ca_certificate) => {
+                let mut roots = RootCertStore::empty();
+
+                match load_certs(Path::new(tls_client_ca_certificate)) {
+                    Ok(certs) => {
+                        for cert in certs {
+                            if let Err(err) = roots.add(&cert) {
+                                return Err(Error::TlsError(format!(
+                                    "tls_client_ca_certificate: {}",
+                                    err
+                                )));
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        return Err(Error::TlsError(format!(
+                            "tls_client_ca_certificate: {}",
+                            err
+                        )))
+                    }
+                };
+
+                builder.with_client_cert_verifier(
+                    AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed(),
+                )
+            }
+
+            None => builder.with_no_client_auth(),
+        };
+
+        let server_end_point = match certs.first() {
+            Some(cert) => tls_server_end_point(cert),
+            None => return Err(Error::TlsError("tls_certificate has no certificate".into())),
         };
 
-        let config = match rustls::ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
+        let config = match builder
             .with_single_cert(certs, keys.remove(0))
             .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))
         {
             Ok(c) => c,
-            Err(_) => return Err(Error::TlsError),
+            Err(err) => return Err(Error::TlsError(err.to_string())),
         };
 
         Ok(Tls {
             acceptor: TlsAcceptor::from(Arc::new(config)),
+            server_end_point,
         })
     }
 }
@@ -85,3 +130,373 @@ impl ServerCertVerifier for NoCertificateVerification {
         Ok(ServerCertVerified::assertion())
     }
 }
+
+/// Checks the certificate was signed by a trusted CA, but not the name it was issued for.
+pub struct CaCertificateVerification(WebPkiVerifier);
+
+impl ServerCertVerifier for CaCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        // The name is checked last, after the chain of trust.
+        match self.0.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        ) {
+            Err(rustls::Error::InvalidCertificate(CertificateError::NotValidForName)) => {
+                Ok(ServerCertVerified::assertion())
+            }
+            result => result,
+        }
+    }
+}
+
+/// TLS settings of a pool's connections to its servers, built once when the pool
+/// is created so connecting doesn't read the CA file every time.
+#[derive(Clone)]
+pub struct ServerTls {
+    pub verify: ServerTlsVerify,
+
+    /// Refuse servers that don't support TLS.
+    pub required: bool,
+
+    /// Name sent with SNI and checked against the certificate, instead of the server host.
+    pub sni_hostname: Option<String>,
+
+    pub config: Arc<rustls::ClientConfig>,
+}
+
+impl std::fmt::Debug for ServerTls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServerTls")
+            .field("verify", &self.verify)
+            .field("required", &self.required)
+            .field("sni_hostname", &self.sni_hostname)
+            .finish()
+    }
+}
+
+impl ServerTls {
+    /// The server TLS settings of a pool, `None` if `server_tls` is off.
+    pub fn from_config(config: &Config, pool_name: &str) -> Result<Option<ServerTls>, Error> {
+        if !config.general.server_tls {
+            return Ok(None);
+        }
+
+        let pool_config = config.pools.get(pool_name);
+        let verify = config.server_tls_verify(pool_name);
+
+        Ok(Some(ServerTls {
+            verify,
+            // Only pools asking for verification with `server_tls_verify` refuse plain
+            // connections, `verify_server_certificate` alone still falls back to them.
+            required: verify != ServerTlsVerify::None
+                && pool_config.is_some_and(|pool| pool.server_tls_verify.is_some()),
+            sni_hostname: pool_config.and_then(|pool| pool.server_tls_sni_hostname.clone()),
+            config: Arc::new(server_tls_config(
+                verify,
+                pool_config.and_then(|pool| pool.server_tls_ca_file.as_deref()),
+            )?),
+        }))
+    }
+}
+
+/// TLS client settings for connections to the servers: the CAs in `ca_file`
+/// or the usual web roots are trusted, as `verify` says.
+pub fn server_tls_config(
+    verify: ServerTlsVerify,
+    ca_file: Option<&str>,
+) -> Result<rustls::ClientConfig, Error> {
+    let mut root_store = RootCertStore::empty();
+
+    match ca_file {
+        Some(ca_file) => {
+            let certs = load_certs(Path::new(ca_file))
+                .map_err(|err| Error::TlsError(format!("server_tls_ca_file: {}", err)))?;
+
+            for cert in certs {
+                root_store
+                    .add(&cert)
+                    .map_err(|err| Error::TlsError(format!("server_tls_ca_file: {}", err)))?;
+            }
+        }
+
+        None => {
+            root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    ta.subject,
+                    ta.spki,
+                    ta.name_constraints,
+                )
+            }))
+        }
+    };
+
+    let mut tls_config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store.clone())
+        .with_no_client_auth();
+
+    match verify {
+        ServerTlsVerify::Full => (),
+        ServerTlsVerify::Ca => {
+            tls_config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(CaCertificateVerification(WebPkiVerifier::new(
+                    root_store, None,
+                ))))
+        }
+        ServerTlsVerify::None => tls_config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(NoCertificateVerification {})),
+    };
+
+    Ok(tls_config)
+}
+
+/// Common name (CN) of the subject of a DER encoded X.509 certificate.
+pub fn certificate_common_name(cert: &Certificate) -> Option<String> {
+    // Certificate ::= SEQUENCE { tbsCertificate, signatureAlgorithm, signature }
+    let (_, certificate, _) = der_next(&cert.0)?;
+    let (_, tbs_certificate, _) = der_next(certificate)?;
+
+    // The version is optional, the serial number always comes next.
+    let (tag, _, mut rest) = der_next(tbs_certificate)?;
+    if tag == 0xa0 {
+        rest = der_next(rest)?.2;
+    }
+
+    // Skip the signature algorithm, the issuer and the validity.
+    for _ in 0..3 {
+        rest = der_next(rest)?.2;
+    }
+
+    // Name ::= SEQUENCE OF SET OF SEQUENCE { type OID, value }
+    let (_, mut subject, _) = der_next(rest)?;
+
+    while !subject.is_empty() {
+        let (_, mut names, next) = der_next(subject)?;
+        subject = next;
+
+        while !names.is_empty() {
+            let (_, name, next) = der_next(names)?;
+            names = next;
+
+            let (_, oid, value) = der_next(name)?;
+
+            if oid == COMMON_NAME_OID {
+                let (_, value, _) = der_next(value)?;
+                return String::from_utf8(value.to_vec()).ok();
+            }
+        }
+    }
+
+    None
+}
+
+/// id-at-commonName, 2.5.4.3.
+const COMMON_NAME_OID: &[u8] = &[0x55, 0x04, 0x03];
+
+/// Hash of a DER encoded X.509 certificate for tls-server-end-point channel binding (RFC 5929):
+/// the hash of its signature algorithm, SHA-256 if that's MD5 or SHA-1, or one we don't know.
+pub fn tls_server_end_point(cert: &Certificate) -> Vec<u8> {
+    match certificate_signature_hash(cert) {
+        Some(SignatureHash::Sha384) => Sha384::digest(&cert.0).to_vec(),
+        Some(SignatureHash::Sha512) => Sha512::digest(&cert.0).to_vec(),
+        _ => Sha256::digest(&cert.0).to_vec(),
+    }
+}
+
+enum SignatureHash {
+    Sha384,
+    Sha512,
+}
+
+/// Hash used by the signature algorithm of a DER encoded X.509 certificate, if it's SHA-384 or SHA-512.
+fn certificate_signature_hash(cert: &Certificate) -> Option<SignatureHash> {
+    // Certificate ::= SEQUENCE { tbsCertificate, signatureAlgorithm, signature }
+    let (_, certificate, _) = der_next(&cert.0)?;
+    let (_, _, rest) = der_next(certificate)?;
+
+    // AlgorithmIdentifier ::= SEQUENCE { algorithm OID, parameters }
+    let (_, algorithm, _) = der_next(rest)?;
+    let (_, oid, _) = der_next(algorithm)?;
+
+    match oid {
+        SHA384_WITH_RSA_OID | ECDSA_WITH_SHA384_OID => Some(SignatureHash::Sha384),
+        SHA512_WITH_RSA_OID | ECDSA_WITH_SHA512_OID => Some(SignatureHash::Sha512),
+        _ => None,
+    }
+}
+
+/// sha384WithRSAEncryption, 1.2.840.113549.1.1.12.
+const SHA384_WITH_RSA_OID: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0c];
+
+/// sha512WithRSAEncryption, 1.2.840.113549.1.1.13.
+const SHA512_WITH_RSA_OID: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0d];
+
+/// ecdsa-with-SHA384, 1.2.840.10045.4.3.3.
+const ECDSA_WITH_SHA384_OID: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03];
+
+/// ecdsa-with-SHA512, 1.2.840.10045.4.3.4.
+const ECDSA_WITH_SHA512_OID: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x04];
+
+/// Split the next DER element off the input: its tag, its contents and what follows it.
+fn der_next(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *input.first()?;
+    let first = *input.get(1)? as usize;
+
+    let (len, header) = if first & 0x80 == 0 {
+        (first, 2)
+    } else {
+        let octets = first & 0x7f;
+
+        if octets == 0 || octets > 4 {
+            return None;
+        }
+
+        let len = input
+            .get(2..2 + octets)?
+            .iter()
+            .fold(0, |len, byte| (len << 8) | *byte as usize);
+
+        (len, 2 + octets)
+    };
+
+    let contents = input.get(header..header + len)?;
+
+    Some((tag, contents, &input[header + len..]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CLIENT_CERT: &str = "-----BEGIN CERTIFICATE-----
+MIIBpjCCAU2gAwIBAgIUVONzOO9/ALw1tlPcneH/KNCxLYMwCgYIKoZIzj0EAwIw
+KDEOMAwGA1UECgwFUGdDYXQxFjAUBgNVBAMMDXNoYXJkaW5nX3VzZXIwIBcNMjYx
+MDE2MTUwNDMyWhgPMjEyNjA5MjIxNTA0MzJaMCgxDjAMBgNVBAoMBVBnQ2F0MRYw
+FAYDVQQDDA1zaGFyZGluZ191c2VyMFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAE
+sEwg+pkFRBf7Iy997tQlApJt/roTClFf65K8pXOHAkDFjMW/VfKqtTLD8bQJ7EaF
+/5zaGBzXeJwGB5U3Kl1jwaNTMFEwHQYDVR0OBBYEFMsPeiH8RMxsU/6ZLSoTbOFC
+wSjKMB8GA1UdIwQYMBaAFMsPeiH8RMxsU/6ZLSoTbOFCwSjKMA8GA1UdEwEB/wQF
+MAMBAf8wCgYIKoZIzj0EAwIDRwAwRAIgDwpLaaC0lOTcoAUjUVURwJXHcw4hSMXY
+Q5LfNZSNsuUCIDnwTfDqTzWhF1wQb77moZansW1vn5ok0IFXy1JmrYkb
+-----END CERTIFICATE-----
+";
+
+    #[test]
+    fn test_certificate_common_name() {
+        let cert = certs(&mut CLIENT_CERT.as_bytes()).unwrap().remove(0);
+        assert_eq!(
+            certificate_common_name(&Certificate(cert)),
+            Some("sharding_user".to_string())
+        );
+
+        // The CN names the user, not the subject alternative name.
+        let cert = certs(&mut CLIENT_CERT_ALT_NAME.as_bytes())
+            .unwrap()
+            .remove(0);
+        assert_eq!(
+            certificate_common_name(&Certificate(cert)),
+            Some("someone_else".to_string())
+        );
+
+        // No CN in the subject.
+        let cert = load_certs(Path::new(".circleci/server.cert"))
+            .unwrap()
+            .remove(0);
+        assert_eq!(certificate_common_name(&cert), None);
+
+        assert_eq!(
+            certificate_common_name(&Certificate(vec![0x30, 0x05])),
+            None
+        );
+    }
+
+    /// CN=someone_else, subjectAltName=DNS:sharding_user.
+    const CLIENT_CERT_ALT_NAME: &str = "-----BEGIN CERTIFICATE-----
+MIIBvjCCAWWgAwIBAgIUTbrfG6/F3BakTxp9ynSOpzEjfLUwCgYIKoZIzj0EAwIw
+JzEOMAwGA1UECgwFUGdDYXQxFTATBgNVBAMMDHNvbWVvbmVfZWxzZTAgFw0yNjEw
+MTYxODMzMjVaGA8yMTI2MDkyMjE4MzMyNVowJzEOMAwGA1UECgwFUGdDYXQxFTAT
+BgNVBAMMDHNvbWVvbmVfZWxzZTBZMBMGByqGSM49AgEGCCqGSM49AwEHA0IABDn8
+r2+aUoOICgxh9MIPj1e6EbHwM+fNRZ2f2GdKjLC/MbI2fSya4pki9rDLIrxrQ+fS
+j7u8iR2RHrGrz3/j3FajbTBrMB0GA1UdDgQWBBROcwhuR/n8GVFckoCXCmNc6yje
+sTAfBgNVHSMEGDAWgBROcwhuR/n8GVFckoCXCmNc6yjesTAPBgNVHRMBAf8EBTAD
+AQH/MBgGA1UdEQQRMA+CDXNoYXJkaW5nX3VzZXIwCgYIKoZIzj0EAwIDRwAwRAIg
+DXRC4/May96AsRnVaMJsQcI0b7si5kHl+jf8KiaT61cCIHPxR8RIcA/CZvq1RGsr
++HnoOFpJidbom0xNn3lOBji6
+-----END CERTIFICATE-----
+";
+
+    #[test]
+    fn test_server_tls_config() {
+        for verify in [
+            ServerTlsVerify::None,
+            ServerTlsVerify::Ca,
+            ServerTlsVerify::Full,
+        ] {
+            assert!(server_tls_config(verify, None).is_ok());
+            assert!(server_tls_config(verify, Some(".circleci/server.cert")).is_ok());
+        }
+
+        assert!(matches!(
+            server_tls_config(ServerTlsVerify::Full, Some("does-not-exist.pem")),
+            Err(Error::TlsError(_))
+        ));
+    }
+
+    #[test]
+    fn test_server_tls_from_config() {
+        let mut config = Config::default();
+        config
+            .pools
+            .insert("sharded".to_string(), crate::config::Pool::default());
+        assert!(ServerTls::from_config(&config, "sharded")
+            .unwrap()
+            .is_none());
+
+        // verify_server_certificate alone doesn't refuse plain connections.
+        config.general.server_tls = true;
+        config.general.verify_server_certificate = true;
+        let server_tls = ServerTls::from_config(&config, "sharded").unwrap().unwrap();
+        assert_eq!(server_tls.verify, ServerTlsVerify::Full);
+        assert!(!server_tls.required);
+
+        let pool = config.pools.get_mut("sharded").unwrap();
+        pool.server_tls_verify = Some(ServerTlsVerify::Ca);
+        pool.server_tls_ca_file = Some(".circleci/server.cert".to_string());
+        let server_tls = ServerTls::from_config(&config, "sharded").unwrap().unwrap();
+        assert_eq!(server_tls.verify, ServerTlsVerify::Ca);
+        assert!(server_tls.required);
+
+        config.pools.get_mut("sharded").unwrap().server_tls_ca_file =
+            Some("does-not-exist.pem".to_string());
+        assert!(ServerTls::from_config(&config, "sharded").is_err());
+    }
+
+    #[test]
+    fn test_tls_server_end_point() {
+        // Signed with ecdsa-with-SHA256.
+        let cert = Certificate(certs(&mut CLIENT_CERT.as_bytes()).unwrap().remove(0));
+        assert_eq!(
+            tls_server_end_point(&cert),
+            Sha256::digest(&cert.0).to_vec()
+        );
+
+        let cert = load_certs(Path::new(".circleci/server.cert"))
+            .unwrap()
+            .remove(0);
+        assert_eq!(tls_server_end_point(&cert).len(), 32);
+    }
+}
@@ -114,6 +114,43 @@ def test_shutdown_logic():
     utils.cleanup_conn(conn, cur)
     utils.pg_cat_send_signal(signal.SIGTERM)
 
+    # - - - - - - - - - - - - - - - - - -
+    # HANDLE TRANSACTION WITH SIGTERM
+
+    # Start pgcat
+    utils.pgcat_start()
+
+    # Create client connection and begin transaction
+    conn, cur = utils.connect_db()
+
+    cur.execute("BEGIN;")
+    cur.execute("SELECT 1;")
+
+    # Send sigterm to pgcat while still in transaction, it drains like sigint
+    utils.pg_cat_send_signal(signal.SIGTERM, check_closed=False)
+    time.sleep(1)
+
+    # Check that the transaction can still complete
+    try:
+        cur.execute("SELECT 1;")
+        cur.execute("COMMIT;")
+    except psycopg2.OperationalError as e:
+        # Fail if query fails since server closed
+        raise Exception("Server closed while in transaction", e.pgerror)
+
+    # Once the transaction is done, the client is disconnected
+    time.sleep(1)
+    try:
+        cur.execute("SELECT 1;")
+    except psycopg2.OperationalError as e:
+        pass
+    else:
+        # Fail if query execution succeeded
+        raise Exception("Server not closed after sigterm")
+
+    utils.cleanup_conn(conn, cur)
+    utils.pg_cat_send_signal(signal.SIGTERM)
+
     # - - - - - - - - - - - - - - - - - -
     # HANDLE TRANSACTION WITH ADMIN SHUTDOWN COMMAND
 
@@ -15,7 +15,7 @@ def pgcat_start():
     time.sleep(2)
 
 
-def pg_cat_send_signal(signal: signal.Signals):
+def pg_cat_send_signal(signal: signal.Signals, check_closed: bool = True):
     try:
         for proc in psutil.process_iter(["pid", "name"]):
             if "pgcat" == proc.name():
@@ -24,7 +24,7 @@ def pg_cat_send_signal(signal: signal.Signals):
         # The process can be gone when we send this signal
         print(e)
 
-    if signal == signal.SIGTERM:
+    if signal == signal.SIGTERM and check_closed:
         # Returns 0 if pgcat process exists
         time.sleep(2)
         if not os.system('pgrep pgcat'):
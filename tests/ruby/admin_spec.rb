@@ -1,5 +1,6 @@
 # frozen_string_literal: true
 require 'uri'
+require 'time'
 require_relative 'spec_helper'
 
 describe "Admin" do
@@ -38,6 +39,11 @@ describe "Admin" do
         results = admin_conn.async_exec("SHOW BANS").to_a
         expect(results.count).to eq(2)
         expect(results.map{ |r| r["host"] }.uniq).to eq(["localhost"])
+        expect(results.map{ |r| r["shard"] }.uniq).to eq(["0"])
+        expect(results.map{ |r| r["reason"] }.uniq).to eq(["AdminBan(10)"])
+        results.each do |r|
+          expect(Time.parse(r["expires_at"] + " UTC") - Time.parse(r["ban_time"] + " UTC")).to eq(10)
+        end
 
         # Returns a list of the unbanned addresses
         results = admin_conn.async_exec("UNBAN localhost").to_a
@@ -67,6 +73,25 @@ describe "Admin" do
         expect(results.count).to eq(0)
       end
 
+      it "bans the primary only with FORCE_PRIMARY" do
+        admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+
+        # 127.0.0.1 is the primary and the first replica
+        results = admin_conn.async_exec("BAN 127.0.0.1 10").to_a
+        expect(results.map{ |r| r["role"] }).to eq(["replica"])
+
+        results = admin_conn.async_exec("BAN 127.0.0.1 10 FORCE_PRIMARY").to_a
+        expect(results.map{ |r| r["role"] }).to eq(["primary"])
+
+        results = admin_conn.async_exec("SHOW BANS").to_a
+        expect(results.count).to eq(2)
+
+        expect { admin_conn.async_exec("BAN 127.0.0.1 10 yes").to_a }.to raise_error(PG::SystemError)
+
+        admin_conn.async_exec("UNBAN 127.0.0.1")
+        expect(admin_conn.async_exec("SHOW BANS").to_a.count).to eq(0)
+      end
+
       it "can handle bad input" do
         admin_conn = PG::connect(processes.pgcat.admin_connection_string)
 
@@ -91,6 +116,179 @@ describe "Admin" do
     end
   end
 
+  describe "SHOW USER_STATS" do
+    it "counts clients per user" do
+      conn = PG::connect(pgcat_conn_str)
+      conn.async_exec("SELECT 1")
+
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      results = admin_conn.async_exec("SHOW USER_STATS").to_a
+      admin_conn.close
+      conn.close
+
+      user = results.find { |r| r["user"] == "sharding_user" }
+      expect(user["cl_idle"]).to eq("1")
+      expect(user["cl_active"]).to eq("0")
+      expect(user["cl_waiting"]).to eq("0")
+    end
+  end
+
+  describe "SHOW SLOW_CHECKOUTS" do
+    it "lists recent checkouts" do
+      conn = PG::connect(pgcat_conn_str)
+      conn.async_exec("SELECT 1")
+      conn.close
+
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      results = admin_conn.async_exec("SHOW SLOW_CHECKOUTS").to_a
+      admin_conn.close
+
+      checkout = results.find { |r| r["database"] == "sharded_db" }
+      expect(checkout["user"]).to eq("sharding_user")
+      expect(checkout["candidates_tried"].to_i).to be >= 1
+      expect(checkout["queue_time_us"]).to eq("0")
+    end
+
+    it "serves waiting clients in the order they arrived with fair_checkout" do
+      current_configs = processes.pgcat.current_config
+      current_configs["general"]["fair_checkout"] = true
+      current_configs["pools"]["sharded_db"]["default_role"] = "primary"
+      current_configs["pools"]["sharded_db"]["query_parser_enabled"] = false
+      current_configs["pools"]["sharded_db"]["users"]["0"]["pool_size"] = 1
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+
+      # Hold the only server connection so the others queue up.
+      holder = PG::connect(pgcat_conn_str)
+      holder.async_exec("BEGIN")
+      holder.async_exec("SELECT 1")
+
+      served = Queue.new
+      threads = (0...4).map do |index|
+        conn = PG::connect(pgcat_conn_str)
+        thread = Thread.new do
+          conn.async_exec("SELECT pg_sleep(0.05)")
+          served << index
+          conn.close
+        end
+        sleep(0.2) # Let it get in line before the next one.
+        thread
+      end
+
+      holder.async_exec("COMMIT")
+      threads.each(&:join)
+      holder.close
+
+      expect(Array.new(served.size) { served.pop }).to eq([0, 1, 2, 3])
+
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      results = admin_conn.async_exec("SHOW SLOW_CHECKOUTS").to_a
+      pool = admin_conn.async_exec("SHOW POOLS").to_a.find { |r| r["database"] == "sharded_db" }
+      admin_conn.close
+
+      # The clients behind the first one waited for their turn.
+      expect(results.count { |r| r["queue_time_us"].to_i > 0 }).to be >= 3
+      expect(pool["queue_time_us"].to_i).to be > 0
+      expect(pool["queue_time_max_us"].to_i).to be > 0
+      expect(pool["queue_time_max_us"].to_i).to be <= pool["queue_time_us"].to_i
+    end
+  end
+
+  describe "SHOW POOLS_EXTENDED" do
+    let(:processes) { Helpers::Pgcat.single_shard_setup("sharded_db", 10) }
+
+    it "shows busy and idle connections of each server" do
+      conn = PG::connect(pgcat_conn_str)
+      conn.async_exec("BEGIN")
+      conn.async_exec("SELECT 1")
+
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      results = admin_conn.async_exec("SHOW POOLS_EXTENDED").to_a
+      expect(results.map { |r| r["role"] }).to include("primary", "replica")
+      expect(results.map { |r| r["busy_connections"].to_i }.sum).to be >= 1
+      expect(results.map { |r| r["banned"] }.uniq).to eq(["0"])
+      expect(results.map { |r| r["bans"] }.uniq).to eq(["0"])
+      expect(results.map { |r| r["checkout_errors"] }.uniq).to eq(["0"])
+
+      admin_conn.async_exec("BAN localhost 10")
+      results = admin_conn.async_exec("SHOW POOLS_EXTENDED").to_a
+      expect(results.map { |r| r["banned"] }).to include("1")
+      expect(results.select { |r| r["banned"] == "1" }.map { |r| r["bans"] }.uniq).to eq(["1"])
+
+      conn.async_exec("COMMIT")
+      conn.close
+      admin_conn.close
+    end
+
+    it "shows the replication lag measured by health checks" do
+      current_configs = processes.pgcat.current_config
+      current_configs["pools"]["sharded_db"]["max_replica_lag"] = 10_000
+      current_configs["pools"]["sharded_db"]["healthcheck_delay"] = 0
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+
+      conn = PG::connect(pgcat_conn_str)
+      10.times { conn.async_exec("SELECT 1") }
+
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      results = admin_conn.async_exec("SHOW POOLS_EXTENDED").to_a
+      replicas = results.select { |r| r["role"] == "replica" }
+      expect(replicas).not_to be_empty
+      expect(replicas.map { |r| r["replica_lag_ms"] }.uniq).to eq(["0"])
+      expect(replicas.map { |r| r["lagging"] }.uniq).to eq(["0"])
+      expect(replicas.map { |r| r["last_healthcheck_success"] }).to all(match(/^\d{4}-\d{2}-\d{2} /))
+      expect(replicas.map { |r| r["last_healthcheck_failure"] }.uniq).to eq([""])
+
+      conn.close
+      admin_conn.close
+    end
+  end
+
+  describe "SHOW PREWARM" do
+    let(:processes) { Helpers::Pgcat.single_shard_setup("sharded_db", 10) }
+
+    it "reports prewarmed connections of each pool" do
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      results = admin_conn.async_exec("SHOW PREWARM").to_a
+      expect(results.map { |r| r["prewarmed"] }.uniq).to eq(["1"])
+      expect(results.map { |r| r["startup_connections"] }.uniq).to eq(["0"])
+
+      current_configs = processes.pgcat.current_config
+      current_configs["pools"]["sharded_db"]["users"]["0"]["min_pool_size"] = 1
+      current_configs["plugins"] = { "prewarmer" => { "enabled" => true, "queries" => ["SELECT 1"] } }
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+      sleep(1)
+
+      results = admin_conn.async_exec("SHOW PREWARM").to_a
+      result = results.detect { |r| r["user"] == "sharding_user" }
+      expect(result["startup_connections"].to_i).to be > 0
+      expect(result["prewarmed_connections"]).to eq(result["startup_connections"])
+      expect(result["prewarmed"]).to eq("1")
+
+      admin_conn.close
+    end
+  end
+
+  describe "SHOW SHARD HEALTH" do
+    let(:processes) { Helpers::Pgcat.single_shard_setup("sharded_db", 10) }
+
+    it "reports the primary and replicas of each shard" do
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      results = admin_conn.async_exec("SHOW SHARD HEALTH").to_a
+      expect(results.count).to eq(1)
+      expect(results[0]["shard"]).to eq("0")
+      expect(results[0]["primary"]).to eq("up")
+      expect(results[0]["banned_replicas"]).to eq("0")
+      expect(results[0]["healthy_replicas"]).to eq(results[0]["replicas"])
+
+      admin_conn.async_exec("BAN localhost 10")
+      results = admin_conn.async_exec("SHOW SHARD HEALTH").to_a
+      expect(results[0]["banned_replicas"].to_i).to be > 0
+      admin_conn.close
+    end
+  end
+
   [
     "SHOW ME THE MONEY", 
     "SHOW ME THE WAY", 
@@ -112,6 +310,44 @@ describe "Admin" do
     end
   end
 
+  describe "DRAIN" do
+    let(:processes) { Helpers::Pgcat.single_shard_setup("sharded_db", 10) }
+
+    it "takes a server out of rotation until resumed" do
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      replica = processes.replicas[0]
+
+      results = admin_conn.async_exec("DRAIN localhost #{replica.port}").to_a
+      expect(results.count).to eq(1)
+
+      replica.reset_stats
+      conn = PG::connect(pgcat_conn_str)
+      10.times { conn.async_exec("SELECT 1 + 2") }
+      conn.close
+      expect(replica.count_select_1_plus_2).to eq(0)
+
+      results = admin_conn.async_exec("RESUME localhost #{replica.port}").to_a
+      expect(results.count).to eq(1)
+
+      # UNDRAIN is an alias
+      admin_conn.async_exec("DRAIN localhost #{replica.port}")
+      results = admin_conn.async_exec("UNDRAIN localhost #{replica.port}").to_a
+      expect(results.count).to eq(1)
+
+      # Draining is not a ban
+      expect(admin_conn.async_exec("SHOW BANS").to_a.count).to eq(0)
+    end
+
+    it "leaves RESUME db, user to paused pools" do
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      admin_conn.async_exec("PAUSE sharded_db,sharding_user")
+      admin_conn.async_exec("RESUME sharded_db, sharding_user")
+
+      results = admin_conn.async_exec("SHOW DATABASES").to_a
+      expect(results.map{ |r| r["paused"] }.uniq).to eq(["0"])
+    end
+  end
+
   describe "PAUSE" do
     it "pauses all pools" do
       admin_conn = PG::connect(processes.pgcat.admin_connection_string)
@@ -129,10 +365,95 @@ describe "Admin" do
       expect(results.map{ |r| r["paused"] }.uniq).to eq(["0"])
     end
 
+    it "rejects queries with a retryable error when configured" do
+      current_configs = processes.pgcat.current_config
+      current_configs["pools"]["sharded_db"]["pause_client_behavior"] = "reject_with_retry"
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+
+      conn = PG::connect(pgcat_conn_str)
+      conn.async_exec("SELECT 1")
+
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      admin_conn.async_exec("PAUSE")
+
+      expect { conn.async_exec("SELECT 1") }.to raise_error(PG::CannotConnectNow)
+
+      admin_conn.async_exec("RESUME")
+      expect(conn.async_exec("SELECT 1").to_a).to eq([{ "?column?" => "1" }])
+    end
+
+    it "keeps the session usable after rejecting a query" do
+      current_configs = processes.pgcat.current_config
+      current_configs["pools"]["sharded_db"]["pause_client_behavior"] = "reject_with_retry"
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+
+      conn = PG::connect(pgcat_conn_str)
+      conn.async_exec("SELECT 1")
+
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      admin_conn.async_exec("PAUSE")
+
+      begin
+        conn.async_exec("SELECT 1")
+        raise "query should have been rejected"
+      rescue PG::CannotConnectNow => e
+        expect(e.result.error_field(PG::PG_DIAG_SEVERITY)).to eq("ERROR")
+      end
+
+      # The client got ReadyForQuery and can keep going on the same session.
+      expect(conn.status).to eq(PG::CONNECTION_OK)
+      expect(conn.transaction_status).to eq(PG::PQTRANS_IDLE)
+      expect { conn.async_exec("SELECT 1") }.to raise_error(PG::CannotConnectNow)
+
+      admin_conn.async_exec("RESUME")
+      expect(conn.async_exec("SELECT 1").to_a).to eq([{ "?column?" => "1" }])
+    end
+
+    it "gives up waiting after max_pause_wait" do
+      current_configs = processes.pgcat.current_config
+      current_configs["general"]["max_pause_wait"] = 500
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+
+      conn = PG::connect(pgcat_conn_str)
+      conn.async_exec("SELECT 1")
+
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      admin_conn.async_exec("PAUSE")
+
+      expect { conn.async_exec("SELECT 1") }.to raise_error(PG::CannotConnectNow, /maintenance/)
+      expect(conn.transaction_status).to eq(PG::PQTRANS_IDLE)
+
+      admin_conn.async_exec("RESUME")
+      expect(conn.async_exec("SELECT 1").to_a).to eq([{ "?column?" => "1" }])
+    end
+
     it "handles errors" do
       admin_conn = PG::connect(processes.pgcat.admin_connection_string)
       expect { admin_conn.async_exec("PAUSE foo").to_a }.to raise_error(PG::SystemError)
       expect { admin_conn.async_exec("PAUSE foo,bar").to_a }.to raise_error(PG::SystemError)
     end
   end
+
+  describe "RECONNECT" do
+    it "re-creates a single pool" do
+      conn = PG::connect(pgcat_conn_str)
+      conn.async_exec("SELECT 1")
+
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      admin_conn.async_exec("RECONNECT sharded_db sharding_user")
+
+      expect(conn.async_exec("SELECT 1").to_a).to eq([{ "?column?" => "1" }])
+      expect(PG::connect(pgcat_conn_str).async_exec("SELECT 1").to_a).to eq([{ "?column?" => "1" }])
+    end
+
+    it "handles errors" do
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      expect { admin_conn.async_exec("RECONNECT").to_a }.to raise_error(PG::SystemError)
+      expect { admin_conn.async_exec("RECONNECT foo").to_a }.to raise_error(PG::SystemError)
+      expect { admin_conn.async_exec("RECONNECT foo bar").to_a }.to raise_error(PG::SystemError)
+    end
+  end
 end
@@ -75,6 +75,61 @@ describe "COPY Handling" do
     end
   end
 
+  describe "COPY FROM STDIN in transaction mode" do
+    it "holds the server connection until the copy is done" do
+      conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+
+      conn.copy_data "COPY copy_test_table FROM STDIN CSV" do
+        conn.put_copy_data "some,data,to,copy\n"
+
+        results = admin_conn.async_exec("SHOW POOLS")[0]
+        expect(results["cl_active"]).to eq("1")
+        expect(results["sv_active"]).to eq("1")
+
+        conn.put_copy_data "more,data,to,copy\n"
+      end
+
+      results = admin_conn.async_exec("SHOW POOLS")[0]
+      expect(results["cl_active"]).to eq("0")
+      expect(results["sv_active"]).to eq("0")
+
+      res = conn.async_exec("SELECT count(*) FROM copy_test_table")[0]
+      expect(res["count"]).to eq("2")
+    end
+
+    context "with copy_handling set to reject" do
+      before do
+        new_configs = processes.pgcat.current_config
+        new_configs["pools"]["sharded_db"]["copy_handling"] = "reject"
+        processes.pgcat.update_config(new_configs)
+        processes.pgcat.reload_config
+      end
+
+      it "refuses the COPY and keeps the client usable" do
+        conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+
+        expect {
+          conn.copy_data "COPY copy_test_table FROM STDIN CSV" do
+            conn.put_copy_data "some,data,to,copy\n"
+          end
+        }.to raise_error(PG::SystemError, /COPY is not allowed in transaction mode/)
+
+        expect(conn.async_exec("SELECT 1")[0]["?column?"]).to eq("1")
+      end
+
+      it "refuses a prepared COPY" do
+        conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+
+        expect {
+          conn.prepare("copy_stmt", "COPY copy_test_table FROM STDIN CSV")
+        }.to raise_error(PG::SystemError, /COPY is not allowed in transaction mode/)
+
+        expect(conn.async_exec("SELECT 1")[0]["?column?"]).to eq("1")
+      end
+    end
+  end
+
   describe "COPY TO" do
     before do
       conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
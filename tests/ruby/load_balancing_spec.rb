@@ -29,6 +29,61 @@ describe "Random Load Balancing" do
     end
   end
 
+  context "with a primary_read_weight" do
+    it "keeps reads off the primary" do
+      current_configs = processes.pgcat.current_config
+      current_configs["pools"]["sharded_db"]["primary_read_weight"] = 0.0
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+
+      conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      expected_share = QUERY_COUNT / processes.replicas.count
+
+      QUERY_COUNT.times { conn.async_exec("SELECT 1 + 2") }
+
+      expect(processes.primary.count_select_1_plus_2).to eq(0)
+      processes.replicas.map(&:count_select_1_plus_2).each do |instance_share|
+        expect(instance_share).to be_within(expected_share * MARGIN_OF_ERROR).of(expected_share)
+      end
+    end
+  end
+
+  context "with server_targeting_enabled" do
+    before do
+      current_configs = processes.pgcat.current_config
+      current_configs["pools"]["sharded_db"]["server_targeting_enabled"] = true
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+    end
+
+    it "sends queries to the server they ask for" do
+      conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+
+      QUERY_COUNT.times { conn.async_exec("/* pgcat: server=sharded_db_shard_0_replica_2 */ SELECT 1 + 2") }
+
+      processes.all_databases.each do |instance|
+        expected = instance == processes.replicas[2] ? QUERY_COUNT : 0
+        expect(instance.count_select_1_plus_2).to eq(expected)
+      end
+    end
+
+    it "fails instead of falling back to another server" do
+      conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+
+      expect { conn.async_exec("/* pgcat: server=replica-9 */ SELECT 1 + 2") }
+        .to raise_error(PG::Error, /server replica-9 is not in this pool/)
+
+      processes.replicas[2].take_down do
+        expect { conn.async_exec("/* pgcat: server=sharded_db_shard_0_replica_2 */ SELECT 1 + 2") }
+          .to raise_error(PG::Error)
+      end
+
+      processes.all_databases.each do |instance|
+        expect(instance.count_select_1_plus_2).to eq(0)
+      end
+    end
+  end
+
   context "when some replicas are down" do
     it "balances query volume between working instances" do
       conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
@@ -56,6 +111,28 @@ describe "Random Load Balancing" do
       end
     end
   end
+
+  context "when all replicas are down" do
+    it "counts how often all of them had to be unbanned" do
+      conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+
+      processes[:replicas][0].take_down do
+        processes[:replicas][1].take_down do
+          processes[:replicas][2].take_down do
+            20.times do
+              conn.async_exec("SELECT 1 + 2")
+            rescue
+              conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+            end
+          end
+        end
+      end
+
+      results = admin_conn.async_exec("SHOW POOLS")[0]
+      expect(results["all_replicas_banned"].to_i).to be > 0
+    end
+  end
 end
 
 describe "Least Outstanding Queries Load Balancing" do
@@ -35,6 +35,22 @@ describe "Query Mirroing" do
     expect(mirror_pg.count_select_1_plus_2).to be > ((runs - 5) * 3)
   end
 
+  it "doesn't mirror queries with a sample rate of 0" do
+    new_configs = processes.pgcat.current_config
+    new_configs["pools"]["sharded_db"]["shards"]["0"]["mirrors"] = [
+      [mirror_host, mirror_pg.port.to_i, 0, 0.0],
+    ]
+    processes.pgcat.update_config(new_configs)
+    processes.pgcat.reload_config
+
+    conn = PG.connect(pgcat_conn_str)
+    runs = 15
+    runs.times { conn.async_exec("SELECT 1 + 2") }
+    sleep 0.5
+    expect(processes.all_databases.first.count_select_1_plus_2).to eq(runs)
+    expect(mirror_pg.count_select_1_plus_2).to eq(0)
+  end
+
   context "when main server connection is closed" do
     it "closes the mirror connection" do
       baseline_count = processes.all_databases.first.count_connections
@@ -188,6 +188,162 @@ describe "Miscellaneous" do
     end
   end
 
+  describe "Required extensions" do
+    it "refuses clients when a server is missing an extension" do
+      current_configs = processes.pgcat.current_config
+      current_configs["pools"]["sharded_db"]["required_extensions"] = ["pgcat_does_not_exist"]
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+
+      expect { PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user")) }.to raise_error(PG::ConnectionBad, /Pool down/)
+
+      current_configs["pools"]["sharded_db"]["required_extensions"] = ["plpgsql"]
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+
+      conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      expect(conn.async_exec("SELECT 1").to_a.count).to eq(1)
+      conn.close
+    end
+  end
+
+  describe "Max client connections" do
+    it "limits the server connections of each application" do
+      current_configs = processes.pgcat.current_config
+      current_configs["pools"]["sharded_db"]["users"]["0"]["max_client_connections"] = 1
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+
+      conn_str = lambda do |application_name|
+        processes.pgcat.connection_string("sharded_db", "sharding_user", parameters: { "application_name" => application_name })
+      end
+
+      first = PG::connect(conn_str.call("app_a"))
+      first.async_exec("BEGIN")
+      first.async_exec("SELECT 1")
+
+      second = PG::connect(conn_str.call("app_a"))
+      expect { second.async_exec("SELECT 1") }.to raise_error(PG::SystemError, /max_client_connections/)
+
+      # Other applications have their own share.
+      other = PG::connect(conn_str.call("app_b"))
+      expect(other.async_exec("SELECT 1").to_a.count).to eq(1)
+
+      first.async_exec("COMMIT")
+      expect(second.async_exec("SELECT 1").to_a.count).to eq(1)
+
+      [first, second, other].each(&:close)
+    end
+
+    it "gives the connection back when the client disconnects" do
+      current_configs = processes.pgcat.current_config
+      current_configs["pools"]["sharded_db"]["users"]["0"]["max_client_connections"] = 1
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+
+      conn_str = processes.pgcat.connection_string("sharded_db", "sharding_user", parameters: { "application_name" => "app_a" })
+
+      first = PG::connect(conn_str)
+      first.async_exec("BEGIN")
+      first.async_exec("SELECT 1")
+      first.close
+      sleep(0.5)
+
+      second = PG::connect(conn_str)
+      expect(second.async_exec("SELECT 1").to_a.count).to eq(1)
+      second.close
+    end
+  end
+
+  describe "Checkout failure mode" do
+    it "rejects queries right away when all server connections are busy" do
+      current_configs = processes.pgcat.current_config
+      current_configs["pools"]["sharded_db"]["checkout_failure_mode"] = "reject"
+      current_configs["pools"]["sharded_db"]["users"]["0"]["pool_size"] = 1
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+
+      first = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      first.async_exec("BEGIN")
+      first.async_exec("SELECT 1")
+
+      second = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      expect { second.async_exec("SELECT 1") }.to raise_error(PG::TooManyConnections, /busy/)
+      expect(second.transaction_status).to eq(PG::PQTRANS_IDLE)
+
+      first.async_exec("COMMIT")
+      expect(second.async_exec("SELECT 1").to_a.count).to eq(1)
+
+      [first, second].each(&:close)
+    end
+  end
+
+  describe "Pool statement timeout" do
+    it "cancels queries running longer than the pool's statement_timeout" do
+      current_configs = processes.pgcat.current_config
+      current_configs["pools"]["sharded_db"]["statement_timeout"] = 500
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+
+      conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      expect { conn.async_exec("SELECT pg_sleep(2)") }.to raise_error(PG::QueryCanceled, /pool statement timeout/)
+
+      # Only the query failed, the session is still usable.
+      expect(conn.async_exec("SELECT 1").to_a.count).to eq(1)
+      conn.close
+
+      # Quick queries are not affected.
+      conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      expect(conn.async_exec("SELECT 1").to_a.count).to eq(1)
+      conn.close
+    end
+  end
+
+  describe "Replication connections" do
+    it "are rejected at startup" do
+      conn_str = processes.pgcat.connection_string("sharded_db", "sharding_user", parameters: { "replication" => "database" })
+      expect { PG::connect(conn_str) }.to raise_error(PG::ConnectionBad, /replication/)
+
+      # Regular connections still work
+      conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user", parameters: { "replication" => "false" }))
+      expect(conn.async_exec("SELECT 1").to_a.count).to eq(1)
+      conn.close
+    end
+  end
+
+  describe "Connection rules" do
+    it "turns away clients matching a deny rule" do
+      current_configs = processes.pgcat.current_config
+      current_configs["connection_rules"] = [
+        {
+          "parameter" => "application_name",
+          "pattern" => "^misbehaving_app$",
+          "action" => "deny",
+          "message" => "misbehaving_app is disabled",
+        },
+      ]
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+
+      conn_str = processes.pgcat.connection_string("sharded_db", "sharding_user", parameters: { "application_name" => "misbehaving_app" })
+      expect { PG::connect(conn_str) }.to raise_error(PG::ConnectionBad, /misbehaving_app is disabled/)
+
+      # Other applications still get in
+      conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user", parameters: { "application_name" => "good_app" }))
+      expect(conn.async_exec("SELECT 1").to_a.count).to eq(1)
+      conn.close
+    end
+  end
+
+  describe "Unknown database or user" do
+    it "tells the client which one is wrong" do
+      conn_str = processes.pgcat.connection_string("sharded_db", "sharding_user")
+
+      expect { PG::connect(conn_str.sub("/sharded_db", "/no_such_db")) }.to raise_error(PG::ConnectionBad, /no such database/)
+      expect { PG::connect(conn_str.sub("sharding_user:", "no_such_user:")) }.to raise_error(PG::ConnectionBad, /no such user/)
+    end
+  end
+
   describe "Server version reporting" do
     it "reports correct version for normal and admin databases" do
       server_conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
@@ -200,6 +356,91 @@ describe "Miscellaneous" do
     end
   end
 
+  describe "SCRAM authentication" do
+    let(:processes) { Helpers::Pgcat.single_shard_setup("sharded_db", 5, "transaction", "random", "info", { "client_auth_mode" => "scram" }) }
+
+    it "authenticates clients with SCRAM-SHA-256" do
+      conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user", parameters: { "sslmode" => "disable" }))
+      expect(conn.async_exec("SELECT 1").to_a.count).to eq(1)
+      conn.close
+
+      conn_str = processes.pgcat.connection_string("sharded_db", "sharding_user", "wrong_password", parameters: { "sslmode" => "disable" })
+      expect { PG::connect(conn_str) }.to raise_error(PG::ConnectionBad, /SCRAM authentication failed/)
+    end
+
+    it "refuses channel binding without TLS" do
+      conn_str = processes.pgcat.connection_string("sharded_db", "sharding_user", parameters: { "sslmode" => "disable", "channel_binding" => "require" })
+      expect { PG::connect(conn_str) }.to raise_error(PG::ConnectionBad, /channel binding/)
+    end
+
+    it "binds the exchange to the TLS connection with SCRAM-SHA-256-PLUS" do
+      current_configs = processes.pgcat.current_config
+      current_configs["general"]["tls_certificate"] = File.expand_path("../../.circleci/server.cert")
+      current_configs["general"]["tls_private_key"] = File.expand_path("../../.circleci/server.key")
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+
+      conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user", parameters: { "sslmode" => "require", "channel_binding" => "require" }))
+      expect(conn.async_exec("SELECT 1").to_a.count).to eq(1)
+      conn.close
+    end
+  end
+
+  describe "LISTEN/NOTIFY" do
+    context "session mode" do
+      let(:processes) { Helpers::Pgcat.single_shard_setup("sharded_db", 5, "session") }
+
+      it "forwards notifications to idle clients" do
+        listener = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+        listener.async_exec("LISTEN pgcat_test")
+
+        notifier = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+        notifier.async_exec("NOTIFY pgcat_test, 'hello'")
+
+        received = nil
+        channel = listener.wait_for_notify(5) { |_channel, _pid, payload| received = payload }
+        expect(channel).to eq("pgcat_test")
+        expect(received).to eq("hello")
+        expect(listener.wait_for_notify(0)).to be_nil
+
+        # The listener keeps working after the notification.
+        expect(listener.async_exec("SELECT 1").to_a.count).to eq(1)
+
+        listener.close
+        notifier.close
+      end
+    end
+  end
+
+  describe "Auto pool mode" do
+    let(:processes) { Helpers::Pgcat.single_shard_setup("sharded_db", 5, "auto") }
+
+    def auto_session_clients
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      count = admin_conn.async_exec("SHOW POOLS")[0]["auto_session_clients"].to_i
+      admin_conn.close
+      count
+    end
+
+    it "keeps the server once the client uses session state" do
+      conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      conn.async_exec("SELECT 1")
+      conn.async_exec("SET LOCAL statement_timeout TO 1000")
+      expect(auto_session_clients).to eq(0)
+
+      conn.async_exec("SET statement_timeout TO 1000")
+      expect(auto_session_clients).to eq(1)
+
+      pid = conn.async_exec("SELECT pg_backend_pid()")[0]["pg_backend_pid"]
+      5.times do
+        expect(conn.async_exec("SHOW statement_timeout")[0]["statement_timeout"]).to eq("1s")
+        expect(conn.async_exec("SELECT pg_backend_pid()")[0]["pg_backend_pid"]).to eq(pid)
+      end
+
+      conn.close
+    end
+  end
+
   describe "State clearance" do
     context "session mode" do
       let(:processes) { Helpers::Pgcat.single_shard_setup("sharded_db", 5, "session") }
@@ -420,5 +661,28 @@ describe "Miscellaneous" do
         conn.close
       end
     end
+
+    context "pool idle_in_transaction_timeout set to 500ms" do
+      before do
+        current_configs = processes.pgcat.current_config
+        current_configs["general"]["idle_client_in_transaction_timeout"] = 0
+        current_configs["pools"]["sharded_db"]["idle_in_transaction_timeout"] = 500
+
+        processes.pgcat.update_config(current_configs)
+        processes.pgcat.reload_config
+      end
+
+      it "rolls back the transaction and frees the server connection" do
+        conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+        conn.async_exec("BEGIN")
+        conn.async_exec("CREATE TEMP TABLE idle_in_transaction_test (id INT)")
+        sleep(1) # above 500ms
+        expect{ conn.async_exec("COMMIT") }.to raise_error(PG::SystemError, /idle transaction timeout/)
+
+        # The transaction was rolled back
+        expect(conn.async_exec("SELECT to_regclass('idle_in_transaction_test')")[0]["to_regclass"]).to be_nil
+        conn.close
+      end
+    end
   end
 end
@@ -11,4 +11,32 @@ describe "Plugins" do
       expect(res.values).to eq([["sharded_db", "{public}"]])
     end
   end
+
+  context "query_rewrite" do
+    before do
+      new_configs = processes.pgcat.current_config
+      new_configs["pools"]["sharded_db"]["plugins"]["query_rewrite"] = {
+        "enabled" => true,
+        "rules" => [
+          { "pattern" => "(?i)^\\s*VACUUM\\b", "reject" => "VACUUM is not allowed" },
+          { "pattern" => "\\bold_answer\\b", "replacement" => "42" },
+        ]
+      }
+      processes.pgcat.update_config(new_configs)
+      processes.pgcat.reload_config
+    end
+
+    it "rewrites simple and extended protocol queries" do
+      conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      expect(conn.exec("SELECT old_answer AS answer").values).to eq([["42"]])
+      expect(conn.exec_params("SELECT old_answer + $1::int AS answer", [1]).values).to eq([["43"]])
+    end
+
+    it "rejects matching queries" do
+      conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      expect { conn.exec("VACUUM") }.to raise_error(PG::Error, /VACUUM is not allowed/)
+      expect { conn.exec_params("vacuum", []) }.to raise_error(PG::Error, /VACUUM is not allowed/)
+      expect(conn.exec("SELECT 1").values).to eq([["1"]])
+    end
+  end
 end
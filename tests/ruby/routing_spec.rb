@@ -78,4 +78,29 @@ describe "Routing" do
       end
     end
   end
+
+  describe "enforce_read_only_on_replicas" do
+    before do
+      current_configs = processes.pgcat.current_config
+      current_configs["pools"]["sharded_db"]["enforce_read_only_on_replicas"] = true
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+    end
+
+    it "rejects writes sent to replicas" do
+      conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+
+      conn.async_exec("SET SERVER ROLE TO 'replica'")
+      expect { conn.async_exec("CREATE TEMPORARY TABLE read_only_test (id BIGINT)") }
+        .to raise_error(PG::ReadOnlySqlTransaction)
+
+      # RESET ALL on checkin doesn't turn it off.
+      conn.async_exec("SET default_transaction_read_only TO off")
+      expect { conn.async_exec("CREATE TEMPORARY TABLE read_only_test (id BIGINT)") }
+        .to raise_error(PG::ReadOnlySqlTransaction)
+
+      conn.async_exec("SET SERVER ROLE TO 'primary'")
+      conn.async_exec("CREATE TEMPORARY TABLE read_only_test (id BIGINT)")
+    end
+  end
 end
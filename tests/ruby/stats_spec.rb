@@ -33,6 +33,33 @@ describe "Stats" do
         expect(results["avg_wait_time"].to_i).to_not eq(0)
       end
     end
+
+    context "pgbouncer_compatible_stats is enabled" do
+      it "uses PgBouncer's columns, one row per database" do
+        current_configs = processes.pgcat.current_config
+        current_configs["general"]["pgbouncer_compatible_stats"] = true
+        processes.pgcat.update_config(current_configs)
+        processes.pgcat.reload_config
+
+        conn = PG::connect(pgcat_conn_str)
+        3.times { conn.async_exec("SELECT pg_sleep(0.01)") }
+        conn.close
+
+        admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+        results = admin_conn.async_exec("SHOW STATS")
+        admin_conn.close
+
+        expect(results.fields).to eq(%w[
+          database total_xact_count total_query_count total_received total_sent
+          total_xact_time total_query_time total_wait_time avg_xact_count avg_query_count
+          avg_recv avg_sent avg_xact_time avg_query_time avg_wait_time
+        ])
+        expect(results.map { |r| r["database"] }).to eq(["sharded_db"])
+        expect(results[0]["total_query_count"].to_i).to be >= 3
+        # Microseconds, each transaction sleeps for 10ms.
+        expect(results[0]["total_xact_time"].to_i).to be >= 30_000
+      end
+    end
   end
 
   describe "SHOW POOLS" do
@@ -278,9 +305,51 @@ describe "Stats" do
         threads.map(&:join)
       end
     end
+
+    context "config is reloaded" do
+      it "shows a new config_hash for re-created pools only" do
+        admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+        before_reload = admin_conn.async_exec("SHOW POOLS")[0]["config_hash"]
+        expect(before_reload).to match(/\A[0-9a-f]{16}\z/)
+
+        admin_conn.async_exec("RELOAD")
+        expect(admin_conn.async_exec("SHOW POOLS")[0]["config_hash"]).to eq(before_reload)
+
+        current_configs = processes.pgcat.current_config
+        current_configs["pools"]["sharded_db"]["idle_timeout"] = 12345
+        processes.pgcat.update_config(current_configs)
+        processes.pgcat.reload_config
+
+        expect(admin_conn.async_exec("SHOW POOLS")[0]["config_hash"]).not_to eq(before_reload)
+        admin_conn.close
+      end
+    end
   end
 
   describe "SHOW CLIENTS" do
+    it "filters clients by state and pool" do
+      conn_str = processes.pgcat.connection_string("sharded_db", "sharding_user")
+      connections = Array.new(3) { |i| PG::connect("#{conn_str}?application_name=app#{i}") }
+
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      sleep(1) # Wait for stats to be updated
+
+      expect(admin_conn.async_exec("SHOW CLIENTS idle sharded_db").count).to eq(3)
+      expect(admin_conn.async_exec("SHOW CLIENTS active sharded_db").count).to eq(0)
+      expect(admin_conn.async_exec("SHOW CLIENTS waiting").count).to eq(0)
+      expect(admin_conn.async_exec("SHOW CLIENTS WHERE database = 'pgcat'").count).to eq(1)
+
+      results = admin_conn.async_exec("SHOW CLIENTS WHERE state = 'idle' AND application_name = 'app1'")
+      expect(results.count).to eq(1)
+      expect(results[0]["application_name"]).to eq("app1")
+
+      expect { admin_conn.async_exec("SHOW CLIENTS WHERE state = 'stuck'") }.to raise_error(PG::Error, /Unknown client state/)
+      expect { admin_conn.async_exec("SHOW CLIENTS WHERE address = 'x'") }.to raise_error(PG::Error, /Can't filter clients by address/)
+
+      connections.map(&:close)
+      admin_conn.close
+    end
+
     it "reports correct number and application names" do
       conn_str = processes.pgcat.connection_string("sharded_db", "sharding_user")
       connections = Array.new(20) { |i| PG::connect("#{conn_str}?application_name=app#{i % 5}") }